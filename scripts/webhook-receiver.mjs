@@ -0,0 +1,59 @@
+// Local stand-in for a webhook endpoint, for testing against a local replica.
+//
+//   WEBHOOK_SECRET=whsec_... node scripts/webhook-receiver.mjs [port]
+//
+// Register it with `dfx canister call backend set_webhook '("http://localhost:8787")'`
+// and set WEBHOOK_SECRET to the returned secret. Every event is printed with
+// the result of its signature check. Events are de-duplicated on their
+// Idempotency-Key, since each replica on the subnet sends the same request.
+// Set FAIL_STATUS=503 to answer with an error and exercise retries.
+
+import { createHmac, timingSafeEqual } from "node:crypto";
+import { createServer } from "node:http";
+
+const port = Number(process.argv[2] ?? 8787);
+const secret = process.env.WEBHOOK_SECRET;
+const failStatus = process.env.FAIL_STATUS ? Number(process.env.FAIL_STATUS) : null;
+const seen = new Set();
+
+function verify(timestamp, body, signature) {
+  if (!secret) return "skipped (WEBHOOK_SECRET not set)";
+  const expected = `v1=${createHmac("sha256", secret).update(`${timestamp}.${body}`).digest("hex")}`;
+  const valid =
+    typeof signature === "string" &&
+    signature.length === expected.length &&
+    timingSafeEqual(Buffer.from(signature), Buffer.from(expected));
+  return valid ? "valid" : "INVALID";
+}
+
+createServer((req, res) => {
+  let body = "";
+  req.on("data", (chunk) => (body += chunk));
+  req.on("end", () => {
+    if (req.method !== "POST") {
+      res.writeHead(405).end();
+      return;
+    }
+
+    const key = req.headers["idempotency-key"];
+    const event = req.headers["x-marketplace-event"];
+    const signature = verify(
+      req.headers["x-marketplace-timestamp"],
+      body,
+      req.headers["x-marketplace-signature"],
+    );
+
+    if (failStatus) {
+      console.log(`${event} ${key}: answering ${failStatus}`);
+      res.writeHead(failStatus).end();
+      return;
+    }
+
+    if (!seen.has(key)) {
+      seen.add(key);
+      console.log(`${event} ${key} (signature ${signature})`);
+      console.log(JSON.stringify(JSON.parse(body), null, 2));
+    }
+    res.writeHead(200, { "Content-Type": "application/json" }).end('{"received":true}');
+  });
+}).listen(port, () => console.log(`Webhook receiver listening on http://localhost:${port}`));
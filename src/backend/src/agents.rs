@@ -0,0 +1,218 @@
+use candid::{CandidType, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::csv_analyzer::{self, AnalysisOptions, CsvAnalyzer};
+use crate::error::MarketplaceError;
+use crate::pdf;
+use crate::text_summarizer::{self, SummarizationOptions, TextSummarizer};
+
+pub const GENERAL_AGENT: &str = "general";
+pub const PDF_COMPRESSOR: &str = "pdf-compressor";
+pub const TEXT_SUMMARIZER: &str = "text-summarizer";
+pub const CSV_ANALYZER: &str = "csv-analyzer";
+pub const PDF_TEXT_EXTRACTOR: &str = "pdf-text-extractor";
+
+/// Pseudo agent id recorded on jobs that run a multi-step pipeline.
+pub const PIPELINE: &str = "pipeline";
+/// Pseudo agent id recorded on jobs that run one agent over many inputs.
+pub const BATCH: &str = "batch";
+
+/// Version of the general agent, which forwards the request to the LLM as-is.
+const GENERAL_VERSION: &str = "1.0.0";
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum VersionStatus {
+    /// The version new quotes are pinned to by default.
+    Current,
+    /// An older version that can still be requested explicitly.
+    Supported,
+    /// Still runnable, but scheduled for retirement.
+    Deprecated,
+    /// No longer runnable.
+    Retired,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AgentVersion {
+    pub agent_id: String,
+    pub version: String,
+    pub prompt_hash: Option<String>,
+    pub status: VersionStatus,
+    pub deprecated_at: Option<u64>,
+    pub retires_at: Option<u64>,
+}
+
+/// Deprecation window recorded for a single agent version.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Deprecation {
+    pub deprecated_at: u64,
+    pub retires_at: u64,
+}
+
+pub type Deprecations = HashMap<(String, String), Deprecation>;
+
+/// All versions an agent ships with, oldest first. The last one is current.
+pub fn known_versions(agent_id: &str) -> Option<Vec<&'static str>> {
+    match agent_id {
+        GENERAL_AGENT => Some(vec![GENERAL_VERSION]),
+        PDF_COMPRESSOR => Some(vec![pdf::VERSION]),
+        PDF_TEXT_EXTRACTOR => Some(vec![pdf::TEXT_EXTRACTION_VERSION]),
+        TEXT_SUMMARIZER => Some(text_summarizer::versions()),
+        CSV_ANALYZER => Some(csv_analyzer::versions()),
+        _ => None,
+    }
+}
+
+pub fn current_version(agent_id: &str) -> Option<&'static str> {
+    known_versions(agent_id).and_then(|versions| versions.last().copied())
+}
+
+/// Fingerprint of a prompt template, or `None` for agents without prompts.
+///
+/// The hash covers the template rendered with placeholder inputs for every
+/// preset, so any wording change in a template shows up as a new hash.
+pub fn prompt_hash(agent_id: &str, version: &str) -> Option<String> {
+    let samples = match agent_id {
+        TEXT_SUMMARIZER => text_summarizer::template_samples(version)?,
+        CSV_ANALYZER => csv_analyzer::template_samples(version)?,
+        _ => return None,
+    };
+
+    let mut hasher = Sha256::new();
+    for sample in samples {
+        hasher.update(sample.as_bytes());
+        hasher.update([0u8]);
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// The prompt a quoted job runs: its request rendered with the template of
+/// the version it was pinned to, or the request as-is for agents without
+/// templates and jobs quoted before templates were pinned. The template must
+/// still hash to `pinned_hash`, so a changed template cannot change what an
+/// existing job runs.
+pub fn job_prompt(
+    agent_id: &str,
+    version: &str,
+    pinned_hash: Option<&str>,
+    request: &str,
+) -> Result<String, MarketplaceError> {
+    let Some(pinned_hash) = pinned_hash else {
+        return Ok(request.to_string());
+    };
+    if prompt_hash(agent_id, version).as_deref() != Some(pinned_hash) {
+        return Err(MarketplaceError::AgentUnavailable {
+            agent_id: agent_id.to_string(),
+            reason: format!(
+                "The prompt template of {} {} changed after the job was quoted",
+                agent_id, version
+            ),
+        });
+    }
+
+    Ok(match agent_id {
+        TEXT_SUMMARIZER => {
+            let options = SummarizationOptions::new(String::new(), false);
+            TextSummarizer::with_version(options, version)?.prompt(request)
+        }
+        CSV_ANALYZER => {
+            let options = AnalysisOptions::new(String::new(), None, None, false);
+            CsvAnalyzer::with_version(options, version)?.prompt(request)
+        }
+        _ => request.to_string(),
+    })
+}
+
+/// Describe a version, taking its deprecation window into account.
+pub fn describe(
+    agent_id: &str,
+    version: &str,
+    deprecations: &Deprecations,
+    now: u64,
+) -> AgentVersion {
+    let deprecation = deprecations.get(&(agent_id.to_string(), version.to_string()));
+    let status = match deprecation {
+        Some(deprecation) if deprecation.retires_at <= now => VersionStatus::Retired,
+        Some(_) => VersionStatus::Deprecated,
+        None if current_version(agent_id) == Some(version) => VersionStatus::Current,
+        None => VersionStatus::Supported,
+    };
+
+    AgentVersion {
+        agent_id: agent_id.to_string(),
+        version: version.to_string(),
+        prompt_hash: prompt_hash(agent_id, version),
+        status,
+        deprecated_at: deprecation.map(|d| d.deprecated_at),
+        retires_at: deprecation.map(|d| d.retires_at),
+    }
+}
+
+/// Resolve the version a job should run with.
+///
+/// Without an explicit request the current version is used. Unknown agents,
+/// unknown versions and retired versions are rejected.
+pub fn resolve_version(
+    agent_id: &str,
+    requested: Option<&str>,
+    deprecations: &Deprecations,
+    now: u64,
+) -> Result<String, MarketplaceError> {
+    let versions = known_versions(agent_id).ok_or_else(|| MarketplaceError::UnknownAgent {
+        agent_id: agent_id.to_string(),
+    })?;
+    let unavailable = |reason: String| MarketplaceError::AgentUnavailable {
+        agent_id: agent_id.to_string(),
+        reason,
+    };
+
+    let version = match requested {
+        Some(requested) => versions
+            .iter()
+            .find(|version| **version == requested)
+            .copied()
+            .ok_or_else(|| {
+                unavailable(format!("Unknown version {} for agent {}", requested, agent_id))
+            })?,
+        None => *versions
+            .last()
+            .ok_or_else(|| unavailable(format!("Agent {} has no versions", agent_id)))?,
+    };
+
+    if describe(agent_id, version, deprecations, now).status == VersionStatus::Retired {
+        return Err(unavailable(format!(
+            "Version {} of agent {} has been retired",
+            version, agent_id
+        )));
+    }
+
+    Ok(version.to_string())
+}
+
+/// Price bounds `(min, max, default)` in ICP used when quoting an agent.
+pub fn price_range(agent_id: &str) -> (f64, f64, f64) {
+    match agent_id {
+        TEXT_SUMMARIZER => (0.1, 0.5, 0.3), // Text summarization: 0.1 - 0.5 ICP
+        CSV_ANALYZER => (0.2, 1.0, 0.5),    // CSV analysis: 0.2 - 1.0 ICP
+        _ => (0.1, 2.0, 0.5),               // Other requests: 0.1 - 2.0 ICP
+    }
+}
+
+/// Guess which agent a free-form quote request is for.
+pub fn classify_request(request: &str) -> &'static str {
+    let request_lower = request.to_lowercase();
+
+    if request_lower.contains("summarize") {
+        TEXT_SUMMARIZER
+    } else if request_lower.contains("csv")
+        || request_lower.contains("analyze")
+            && (request_lower.contains("dataset") || request_lower.contains("spreadsheet"))
+    {
+        CSV_ANALYZER
+    } else if request_lower.contains("compress") && request_lower.contains("pdf") {
+        PDF_COMPRESSOR
+    } else {
+        GENERAL_AGENT
+    }
+}
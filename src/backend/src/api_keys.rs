@@ -0,0 +1,379 @@
+use candid::{CandidType, Deserialize, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::error::MarketplaceError;
+use crate::{agents, API_KEYS, API_KEY_COUNTER, API_KEY_USAGE};
+
+/// Prefix of every issued key, so leaked keys are easy to recognise.
+const KEY_PREFIX: &str = "mk_";
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Maximum number of active keys per principal.
+const MAX_KEYS_PER_OWNER: usize = 20;
+
+/// An API key as stored by the canister. Only a hash of the key is kept.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub owner: Principal,
+    pub name: String,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+    /// Agents the key may use; `None` allows every agent.
+    pub agents: Option<Vec<String>>,
+    /// Most the key may spend on job executions, in ICP.
+    pub spend_limit: Option<f64>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    pub agents: Option<Vec<String>>,
+    pub spend_limit: Option<f64>,
+    pub expires_at: Option<u64>,
+}
+
+/// Returned once when a key is created; the key cannot be read back later.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NewApiKey {
+    pub id: String,
+    pub key: String,
+    pub created_at: u64,
+}
+
+/// What has been done with a key. Query calls are not counted.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct KeyUsage {
+    pub quotes: u64,
+    pub executions: u64,
+    /// Price of the jobs executed with the key, in ICP.
+    pub spent: f64,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApiKeyInfo {
+    pub key: ApiKey,
+    pub usage: KeyUsage,
+}
+
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn round_price(price: f64) -> f64 {
+    (price * 100.0).round() / 100.0
+}
+
+/// Issue a new key that acts on behalf of `owner`.
+pub async fn create(
+    owner: Principal,
+    request: ApiKeyRequest,
+) -> Result<NewApiKey, MarketplaceError> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(MarketplaceError::invalid(
+            "name",
+            format!(
+                "Key name must be between 1 and {} characters",
+                MAX_NAME_LENGTH
+            ),
+        ));
+    }
+    if let Some(agent_ids) = &request.agents {
+        if agent_ids.is_empty() {
+            return Err(MarketplaceError::invalid(
+                "agents",
+                "A scoped key must allow at least one agent",
+            ));
+        }
+        if let Some(unknown) = agent_ids.iter().find(|agent_id| {
+            agents::known_versions(agent_id).is_none()
+                && !matches!(agent_id.as_str(), agents::PIPELINE | agents::BATCH)
+        }) {
+            return Err(MarketplaceError::UnknownAgent {
+                agent_id: unknown.clone(),
+            });
+        }
+    }
+    if let Some(limit) = request.spend_limit {
+        if !limit.is_finite() || limit <= 0.0 {
+            return Err(MarketplaceError::invalid(
+                "spend_limit",
+                "Spend limit must be positive",
+            ));
+        }
+    }
+    let now = ic_cdk::api::time();
+    if request
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err(MarketplaceError::invalid(
+            "expires_at",
+            "Expiry must be in the future",
+        ));
+    }
+
+    let active = API_KEYS.with(|keys| {
+        keys.borrow()
+            .values()
+            .filter(|key| key.owner == owner && is_active(key, now))
+            .count()
+    });
+    if active >= MAX_KEYS_PER_OWNER {
+        return Err(MarketplaceError::conflict(format!(
+            "Cannot have more than {} active API keys",
+            MAX_KEYS_PER_OWNER
+        )));
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| {
+            MarketplaceError::internal(format!(
+                "Failed to generate key ({:?}): {}",
+                code, message
+            ))
+        })?;
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(random));
+    let created_at = ic_cdk::api::time();
+
+    let id = API_KEY_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        format!("key_{:016}", *counter)
+    });
+
+    API_KEYS.with(|keys| {
+        keys.borrow_mut().insert(
+            hash(&key),
+            ApiKey {
+                id: id.clone(),
+                owner,
+                name,
+                created_at,
+                revoked_at: None,
+                agents: request.agents,
+                spend_limit: request.spend_limit,
+                expires_at: request.expires_at,
+            },
+        );
+    });
+
+    Ok(NewApiKey {
+        id,
+        key,
+        created_at,
+    })
+}
+
+/// Every key issued to `owner`, including revoked and expired ones.
+pub fn list(owner: Principal) -> Vec<ApiKeyInfo> {
+    let mut keys: Vec<ApiKeyInfo> = API_KEYS.with(|keys| {
+        keys.borrow()
+            .values()
+            .filter(|key| key.owner == owner)
+            .map(|key| ApiKeyInfo {
+                key: key.clone(),
+                usage: usage(&key.id),
+            })
+            .collect()
+    });
+    keys.sort_by(|a, b| a.key.id.cmp(&b.key.id));
+    keys
+}
+
+/// Revoke one of `owner`'s keys.
+pub fn revoke(owner: Principal, key_id: &str) -> Result<(), MarketplaceError> {
+    let now = ic_cdk::api::time();
+    API_KEYS.with(|keys| {
+        let mut keys = keys.borrow_mut();
+        let key = keys
+            .values_mut()
+            .find(|key| key.id == key_id && key.owner == owner)
+            .ok_or_else(|| MarketplaceError::not_found("API key"))?;
+        if key.revoked_at.is_some() {
+            return Err(MarketplaceError::conflict("API key is already revoked"));
+        }
+        key.revoked_at = Some(now);
+        Ok(())
+    })
+}
+
+fn is_active(key: &ApiKey, now: u64) -> bool {
+    key.revoked_at.is_none() && key.expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+/// Look up a key that is active at `now`.
+pub fn authenticate(key: &str, now: u64) -> Result<ApiKey, MarketplaceError> {
+    if !key.starts_with(KEY_PREFIX) {
+        return Err(MarketplaceError::unauthorized("Invalid API key"));
+    }
+
+    let key = API_KEYS.with(|keys| keys.borrow().get(&hash(key)).cloned());
+    let key = key
+        .filter(|key| key.revoked_at.is_none())
+        .ok_or_else(|| MarketplaceError::unauthorized("Invalid API key"))?;
+
+    if !is_active(&key, now) {
+        return Err(MarketplaceError::unauthorized("API key has expired"));
+    }
+    Ok(key)
+}
+
+/// Check that a key is scoped to an agent.
+pub fn authorize_agent(key: &ApiKey, agent_id: &str) -> Result<(), MarketplaceError> {
+    match &key.agents {
+        Some(agent_ids) if !agent_ids.iter().any(|allowed| allowed == agent_id) => Err(
+            MarketplaceError::unauthorized(format!(
+                "API key is not allowed to use agent {}",
+                agent_id
+            )),
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub fn usage(key_id: &str) -> KeyUsage {
+    API_KEY_USAGE.with(|usage| usage.borrow().get(key_id).cloned().unwrap_or_default())
+}
+
+fn update_usage(key_id: &str, now: u64, update: impl FnOnce(&mut KeyUsage)) {
+    API_KEY_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let usage = usage.entry(key_id.to_string()).or_default();
+        usage.last_used_at = Some(now);
+        update(usage);
+    });
+}
+
+pub fn record_quote(key: &ApiKey, now: u64) {
+    update_usage(&key.id, now, |usage| usage.quotes += 1);
+}
+
+/// Charge a job execution against a key's spend limit.
+pub fn charge(key: &ApiKey, price: f64, now: u64) -> Result<(), MarketplaceError> {
+    let spent = usage(&key.id).spent;
+    if let Some(limit) = key.spend_limit {
+        if spent + price > limit + f64::EPSILON {
+            return Err(MarketplaceError::unauthorized(format!(
+                "API key spend limit reached: {:.2} of {:.2} ICP spent, job costs {:.2} ICP",
+                spent, limit, price
+            )));
+        }
+    }
+
+    update_usage(&key.id, now, |usage| {
+        usage.executions += 1;
+        usage.spent = round_price(usage.spent + price);
+    });
+    Ok(())
+}
+
+/// Return a charge for an execution that did not start.
+pub fn release(key: &ApiKey, price: f64, now: u64) {
+    update_usage(&key.id, now, |usage| {
+        usage.executions = usage.executions.saturating_sub(1);
+        usage.spent = round_price((usage.spent - price).max(0.0));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    /// Store a key under `secret`, as `create` would.
+    fn issue(secret: &str, spend_limit: Option<f64>, expires_at: Option<u64>) -> ApiKey {
+        let key = ApiKey {
+            id: format!("key_{}", secret),
+            owner: Principal::anonymous(),
+            name: "test".to_string(),
+            created_at: 0,
+            revoked_at: None,
+            agents: None,
+            spend_limit,
+            expires_at,
+        };
+        API_KEYS.with(|keys| keys.borrow_mut().insert(hash(secret), key.clone()));
+        key
+    }
+
+    fn unauthorized<T: std::fmt::Debug>(result: Result<T, MarketplaceError>) -> String {
+        match result {
+            Err(MarketplaceError::Unauthorized { reason }) => reason,
+            other => panic!("Expected Unauthorized, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn authenticate_accepts_active_keys_only() {
+        issue("mk_live", None, Some(NOW + 1));
+        assert_eq!(authenticate("mk_live", NOW).unwrap().id, "key_mk_live");
+        // Expiry is exclusive.
+        assert_eq!(
+            unauthorized(authenticate("mk_live", NOW + 1)),
+            "API key has expired"
+        );
+
+        let mut revoked = issue("mk_revoked", None, None);
+        revoked.revoked_at = Some(NOW - 1);
+        API_KEYS.with(|keys| keys.borrow_mut().insert(hash("mk_revoked"), revoked));
+        assert_eq!(
+            unauthorized(authenticate("mk_revoked", NOW)),
+            "Invalid API key"
+        );
+
+        assert_eq!(
+            unauthorized(authenticate("mk_unknown", NOW)),
+            "Invalid API key"
+        );
+        // Keys are only looked up with the prefix.
+        issue("live", None, None);
+        assert_eq!(unauthorized(authenticate("live", NOW)), "Invalid API key");
+    }
+
+    #[test]
+    fn charges_stop_at_the_spend_limit() {
+        let key = issue("mk_limited", Some(1.0), None);
+
+        charge(&key, 0.3, NOW).unwrap();
+        charge(&key, 0.3, NOW).unwrap();
+        // 0.3 + 0.3 + 0.4 is not exactly 1.0 in floating point.
+        charge(&key, 0.4, NOW + 5).unwrap();
+        let used = usage(&key.id);
+        assert_eq!((used.executions, used.spent), (3, 1.0));
+        assert_eq!(used.last_used_at, Some(NOW + 5));
+
+        let refused = unauthorized(charge(&key, 0.01, NOW));
+        assert!(
+            refused.starts_with("API key spend limit reached"),
+            "{}",
+            refused
+        );
+        assert_eq!(usage(&key.id).executions, 3);
+
+        // Releasing a charge makes room for another job of that price.
+        release(&key, 0.4, NOW);
+        let used = usage(&key.id);
+        assert_eq!((used.executions, used.spent), (2, 0.6));
+        charge(&key, 0.4, NOW).unwrap();
+        assert!(charge(&key, 0.4, NOW).is_err());
+    }
+
+    #[test]
+    fn release_never_goes_below_zero() {
+        let key = issue("mk_release", None, None);
+        release(&key, 0.5, NOW);
+        let used = usage(&key.id);
+        assert_eq!((used.executions, used.spent), (0, 0.0));
+
+        // Without a limit every charge is accepted.
+        charge(&key, 1_000.0, NOW).unwrap();
+        assert_eq!(usage(&key.id).spent, 1_000.0);
+    }
+}
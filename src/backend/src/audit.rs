@@ -0,0 +1,110 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::log;
+use crate::memory::{Memory, Region};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+// The audit log is kept in its own region of stable memory, so it is never
+// held on the heap or copied on upgrade. The region starts with the number
+// of entries, and entry `seq` is in slot `seq` after the header: a length
+// and the Candid-encoded entry.
+
+const HEADER_SIZE: u64 = 64;
+const SLOT_SIZE: u64 = 512;
+
+/// Longest details kept in an entry; longer ones are cut to fit a slot.
+const MAX_DETAILS_LENGTH: usize = 300;
+
+/// Security-relevant actions recorded in the audit log.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AuditAction {
+    PaymentCompleted,
+    RefundIssued,
+    CreditDeposited,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    WebhookSet,
+    WebhookDeleted,
+    AgentVersionDeprecated,
+    LogRedactionChanged,
+}
+
+/// An audit log entry. Entries are only ever appended.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub at: u64,
+    /// Principal that made the call, or the canister itself for timers.
+    pub caller: Principal,
+    pub action: AuditAction,
+    pub job_id: Option<String>,
+    /// What changed; never secrets or user content.
+    pub details: String,
+}
+
+pub fn record(action: AuditAction, job_id: Option<&str>, details: impl Into<String>) {
+    append(AuditEntry {
+        seq: 0,
+        at: ic_cdk::api::time(),
+        caller: ic_cdk::caller(),
+        action,
+        job_id: job_id.map(str::to_string),
+        details: details.into(),
+    });
+}
+
+/// Add entries kept on the heap by earlier versions, after an upgrade.
+pub fn restore(entries: Vec<AuditEntry>) {
+    if len() > 0 {
+        return;
+    }
+    for entry in entries {
+        append(entry);
+    }
+}
+
+/// Number of entries recorded.
+fn len() -> u64 {
+    Region::Audit.read_u64(0)
+}
+
+fn append(mut entry: AuditEntry) {
+    let seq = len();
+    entry.seq = seq;
+    log::truncate(&mut entry.details, MAX_DETAILS_LENGTH);
+    let mut bytes = candid::encode_one(&entry).expect("Failed to encode audit entry");
+    if bytes.len() as u64 > SLOT_SIZE - 4 {
+        // Only an overlong job id gets here.
+        entry.details = "…".to_string();
+        if let Some(job_id) = &mut entry.job_id {
+            log::truncate(job_id, MAX_DETAILS_LENGTH / 2);
+        }
+        bytes = candid::encode_one(&entry).expect("Failed to encode audit entry");
+    }
+    let offset = HEADER_SIZE + seq * SLOT_SIZE;
+    Region::Audit.write(offset, &(bytes.len() as u32).to_le_bytes());
+    Region::Audit.write(offset + 4, &bytes);
+    Region::Audit.write_u64(0, seq + 1);
+}
+
+fn read(seq: u64) -> Option<AuditEntry> {
+    let offset = HEADER_SIZE + seq * SLOT_SIZE;
+    let mut len = [0; 4];
+    Region::Audit.read(offset, &mut len);
+    let len = (u32::from_le_bytes(len) as u64).min(SLOT_SIZE - 4);
+    let mut bytes = vec![0; len as usize];
+    Region::Audit.read(offset + 4, &mut bytes);
+    candid::decode_one(&bytes).ok()
+}
+
+/// Entries after `after_seq`, oldest first.
+pub fn entries(after_seq: Option<u64>, limit: Option<u32>) -> Vec<AuditEntry> {
+    let start = after_seq.map_or(0, |seq| seq.saturating_add(1));
+    let limit = limit
+        .map_or(DEFAULT_QUERY_LIMIT, |limit| limit as usize)
+        .min(MAX_QUERY_LIMIT) as u64;
+    let end = len().min(start.saturating_add(limit));
+    (start..end).filter_map(read).collect()
+}
@@ -0,0 +1,481 @@
+use candid::{CandidType, Deserialize};
+
+use crate::callbacks::JobOutcome;
+use crate::costs::{self, Usage};
+use crate::error::MarketplaceError;
+use crate::log;
+use crate::pipeline::{StepAgent, StepData};
+use crate::queue::{self, Task};
+use crate::{JobResult, BATCHES, BATCH_INPUTS, RESULTS};
+
+/// Version recorded on batch jobs.
+pub const VERSION: &str = "1.0.0";
+
+/// Maximum number of items a single batch may contain.
+pub const MAX_ITEMS: usize = 100;
+
+/// Volume discounts as `(minimum items, discount fraction)`, largest first.
+const VOLUME_DISCOUNTS: &[(usize, f64)] = &[(50, 0.15), (25, 0.10), (10, 0.05)];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchRequest {
+    pub agent: StepAgent,
+    pub version: Option<String>,
+    pub items: Vec<StepData>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ItemStatus {
+    Pending,
+    Queued,
+    Running,
+    Succeeded,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum BatchStatus {
+    /// Quoted, waiting for payment and execution.
+    Quoted,
+    Running,
+    Succeeded,
+    /// Some items failed; their price is refunded.
+    PartiallyFailed,
+    /// Every item failed; the full price is refunded.
+    Failed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchItem {
+    pub index: u32,
+    pub price: f64,
+    pub status: ItemStatus,
+    pub output: Option<StepData>,
+    pub started_at: Option<u64>,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Batch {
+    pub job_id: String,
+    pub agent: StepAgent,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub status: BatchStatus,
+    pub items: Vec<BatchItem>,
+    pub unit_price: f64,
+    pub discount: f64,
+    pub price: f64,
+    pub refunded: f64,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ItemQuote {
+    pub index: u32,
+    pub price: f64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchQuote {
+    pub quote: crate::Quote,
+    pub unit_price: f64,
+    pub subtotal: f64,
+    pub discount: f64,
+    pub items: Vec<ItemQuote>,
+}
+
+fn round_price(price: f64) -> f64 {
+    (price * 100.0).round() / 100.0
+}
+
+/// Discount fraction applied to a batch of the given size.
+pub fn volume_discount(item_count: usize) -> f64 {
+    VOLUME_DISCOUNTS
+        .iter()
+        .find(|(min_items, _)| item_count >= *min_items)
+        .map(|(_, discount)| *discount)
+        .unwrap_or(0.0)
+}
+
+/// Check that a batch request has a usable set of items.
+pub fn validate(request: &BatchRequest) -> Result<(), MarketplaceError> {
+    if request.items.is_empty() {
+        return Err(MarketplaceError::invalid(
+            "items",
+            "Batch must contain at least one item",
+        ));
+    }
+    if request.items.len() > MAX_ITEMS {
+        return Err(MarketplaceError::invalid(
+            "items",
+            format!("Batch cannot have more than {} items", MAX_ITEMS),
+        ));
+    }
+
+    for (index, item) in request.items.iter().enumerate() {
+        if !request.agent.accepts_data(item) {
+            return Err(MarketplaceError::invalid(
+                "items",
+                format!(
+                    "Item {} is not a valid input for {}",
+                    index,
+                    request.agent.agent_id()
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the unstarted items of a batch, each charged the discounted unit price.
+pub fn price_items(item_count: usize, unit_price: f64) -> (Vec<BatchItem>, f64) {
+    let discount = volume_discount(item_count);
+    let item_price = round_price(unit_price * (1.0 - discount));
+
+    let items = (0..item_count)
+        .map(|index| BatchItem {
+            index: index as u32,
+            price: item_price,
+            status: ItemStatus::Pending,
+            output: None,
+            started_at: None,
+            completed_at: None,
+        })
+        .collect();
+
+    (items, discount)
+}
+
+impl Batch {
+    fn item_mut(&mut self, index: u32) -> Option<&mut BatchItem> {
+        self.items.get_mut(index as usize)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.items
+            .iter()
+            .all(|item| matches!(item.status, ItemStatus::Succeeded | ItemStatus::Failed(_)))
+    }
+
+    /// Settle a finished batch and return the amount to refund: the price of
+    /// every failed item.
+    fn finish(&mut self, now: u64) -> f64 {
+        let succeeded = self
+            .items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Succeeded)
+            .count();
+
+        self.status = if succeeded == self.items.len() {
+            BatchStatus::Succeeded
+        } else if succeeded == 0 {
+            BatchStatus::Failed
+        } else {
+            BatchStatus::PartiallyFailed
+        };
+
+        let refund: f64 = self
+            .items
+            .iter()
+            .filter(|item| item.status != ItemStatus::Succeeded)
+            .map(|item| item.price)
+            .sum();
+        self.refunded = round_price(refund);
+        self.completed_at = Some(now);
+        self.refunded
+    }
+
+    fn summary(&self) -> String {
+        let succeeded = self
+            .items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Succeeded)
+            .count();
+        format!(
+            "Batch finished: {} of {} items succeeded",
+            succeeded,
+            self.items.len()
+        )
+    }
+}
+
+/// Queue every item of a batch.
+pub fn enqueue_all(job_id: &str) {
+    let indexes = BATCHES.with(|batches| {
+        let mut batches = batches.borrow_mut();
+        let Some(batch) = batches.get_mut(job_id) else {
+            return Vec::new();
+        };
+
+        batch.status = BatchStatus::Running;
+        batch
+            .items
+            .iter_mut()
+            .filter(|item| item.status == ItemStatus::Pending)
+            .map(|item| {
+                item.status = ItemStatus::Queued;
+                item.index
+            })
+            .collect::<Vec<_>>()
+    });
+
+    crate::job_index::update(job_id);
+
+    for index in indexes {
+        queue::enqueue(Task::BatchItem {
+            job_id: job_id.to_string(),
+            index,
+        });
+    }
+}
+
+/// Execute one queued batch item.
+pub async fn run_item(job_id: &str, index: u32) {
+    let started_at = ic_cdk::api::time();
+    let prepared = BATCHES.with(|batches| {
+        let mut batches = batches.borrow_mut();
+        let batch = batches.get_mut(job_id)?;
+        let input = BATCH_INPUTS.with(|inputs| {
+            inputs
+                .borrow_mut()
+                .get_mut(job_id)
+                .and_then(|items| items.get_mut(index as usize))
+                .and_then(Option::take)
+        })?;
+
+        let agent = batch.agent.clone();
+        let version = batch.agent_version.clone();
+        let item = batch.item_mut(index)?;
+        item.status = ItemStatus::Running;
+        item.started_at = Some(started_at);
+        Some((agent, version, input))
+    });
+
+    let Some((agent, version, input)) = prepared else {
+        return;
+    };
+
+    let started = costs::instructions();
+    let mut usage = Usage::default();
+    let outcome = agent.run(&version, &input, &mut usage).await;
+    usage.instructions = costs::instructions() - started;
+    costs::charge(job_id, &usage);
+
+    complete_item(job_id, index, outcome);
+    costs::track_storage(job_id);
+}
+
+/// Fail an item whose execution was cut short by a trap.
+pub fn interrupt_item(job_id: &str, index: u32) {
+    log::error(Some(job_id), format!("Item {} was interrupted by a trap", index));
+    complete_item(
+        job_id,
+        index,
+        Err(MarketplaceError::internal(
+            "Item was interrupted before completing",
+        )),
+    );
+    costs::track_storage(job_id);
+}
+
+/// Fail every item that was running when the canister was upgraded. Its
+/// task and input were lost with the message that ran it, so it would
+/// otherwise stay `Running` and its batch would never settle or refund.
+pub fn recover_interrupted() {
+    let running: Vec<(String, u32)> = BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .iter()
+            .flat_map(|(job_id, batch)| {
+                batch
+                    .items
+                    .iter()
+                    .filter(|item| item.status == ItemStatus::Running)
+                    .map(move |item| (job_id.clone(), item.index))
+            })
+            .collect()
+    });
+
+    for (job_id, index) in running {
+        log::error(Some(&job_id), format!("Item {} was interrupted by an upgrade", index));
+        complete_item(
+            &job_id,
+            index,
+            Err(MarketplaceError::internal(
+                "Item was interrupted by an upgrade before completing",
+            )),
+        );
+    }
+}
+
+/// Record the outcome of an item and settle the batch once every item has
+/// finished.
+fn complete_item(job_id: &str, index: u32, outcome: Result<StepData, MarketplaceError>) {
+    let completed_at = ic_cdk::api::time();
+
+    let settled = BATCHES.with(|batches| {
+        let mut batches = batches.borrow_mut();
+        let batch = batches.get_mut(job_id)?;
+        let item = batch.item_mut(index)?;
+        item.completed_at = Some(completed_at);
+
+        match outcome {
+            Ok(output) => {
+                item.status = ItemStatus::Succeeded;
+                item.output = Some(output);
+            }
+            Err(err) => {
+                log::warn(Some(job_id), format!("Item {} failed: {}", index, err));
+                item.status = ItemStatus::Failed(err.to_string());
+            }
+        }
+
+        if !batch.is_finished() {
+            return None;
+        }
+        let refund = batch.finish(completed_at);
+        let outcome = match batch.status {
+            BatchStatus::Succeeded => JobOutcome::Succeeded,
+            BatchStatus::PartiallyFailed => JobOutcome::PartiallyFailed,
+            _ => JobOutcome::Failed,
+        };
+        Some((refund, outcome, batch.summary()))
+    });
+
+    let Some((refund, outcome, summary)) = settled else {
+        return;
+    };
+
+    BATCH_INPUTS.with(|inputs| inputs.borrow_mut().remove(job_id));
+    let result = JobResult {
+        job_id: job_id.to_string(),
+        output: summary,
+        completed_at,
+    };
+    RESULTS.with(|results| {
+        results
+            .borrow_mut()
+            .insert(job_id.to_string(), result.clone());
+    });
+
+    if refund > 0.0 {
+        crate::record_refund(job_id, refund, "Batch items failed");
+    }
+    crate::job_finished(job_id, outcome, &result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(statuses: Vec<ItemStatus>, unit_price: f64) -> Batch {
+        let (mut items, discount) = price_items(statuses.len(), unit_price);
+        for (item, status) in items.iter_mut().zip(statuses) {
+            item.status = status;
+        }
+        Batch {
+            job_id: "job".to_string(),
+            agent: StepAgent::ExtractPdfText,
+            agent_id: "pdf-text-extractor".to_string(),
+            agent_version: "1.0.0".to_string(),
+            status: BatchStatus::Running,
+            items,
+            unit_price,
+            discount,
+            price: 0.0,
+            refunded: 0.0,
+            created_at: 0,
+            completed_at: None,
+        }
+    }
+
+    fn failed() -> ItemStatus {
+        ItemStatus::Failed("boom".to_string())
+    }
+
+    #[test]
+    fn discount_tiers_start_at_10_25_and_50_items() {
+        for (count, discount) in [
+            (1, 0.0),
+            (9, 0.0),
+            (10, 0.05),
+            (24, 0.05),
+            (25, 0.10),
+            (49, 0.10),
+            (50, 0.15),
+            (MAX_ITEMS, 0.15),
+        ] {
+            assert_eq!(volume_discount(count), discount, "{} items", count);
+        }
+    }
+
+    #[test]
+    fn items_are_priced_at_the_discounted_unit_price() {
+        for (count, item_price) in [(9, 1.0), (10, 0.95), (25, 0.9), (50, 0.85)] {
+            let (items, _) = price_items(count, 1.0);
+            assert_eq!(items.len(), count);
+            assert!(
+                items.iter().all(|item| item.price == item_price),
+                "{} items",
+                count
+            );
+            assert!(items
+                .iter()
+                .enumerate()
+                .all(|(i, item)| item.index as usize == i));
+        }
+
+        // Rounded to the cent: 0.35 less 5% is 0.3325.
+        let (items, discount) = price_items(10, 0.35);
+        assert_eq!(discount, 0.05);
+        assert_eq!(items[0].price, 0.33);
+    }
+
+    #[test]
+    fn finish_refunds_the_failed_items() {
+        let mut all_good = batch(vec![ItemStatus::Succeeded; 3], 0.5);
+        assert_eq!(all_good.finish(7), 0.0);
+        assert_eq!(all_good.status, BatchStatus::Succeeded);
+        assert_eq!(all_good.completed_at, Some(7));
+
+        // Ten items earn 5% off, so each costs 0.95 and three are refunded.
+        let mut statuses = vec![ItemStatus::Succeeded; 7];
+        statuses.extend([failed(), failed(), failed()]);
+        let mut partial = batch(statuses, 1.0);
+        assert!(partial.is_finished());
+        assert_eq!(partial.finish(7), 2.85);
+        assert_eq!(partial.refunded, 2.85);
+        assert_eq!(partial.status, BatchStatus::PartiallyFailed);
+        assert_eq!(partial.summary(), "Batch finished: 7 of 10 items succeeded");
+
+        let mut none = batch(vec![failed(), failed()], 0.4);
+        assert_eq!(none.finish(7), 0.8);
+        assert_eq!(none.status, BatchStatus::Failed);
+    }
+
+    #[test]
+    fn unfinished_items_keep_the_batch_open() {
+        let running = batch(vec![ItemStatus::Succeeded, ItemStatus::Running], 1.0);
+        assert!(!running.is_finished());
+        let queued = batch(vec![failed(), ItemStatus::Queued], 1.0);
+        assert!(!queued.is_finished());
+    }
+
+    #[test]
+    fn validate_checks_item_count_and_kind() {
+        let request = |items: Vec<StepData>| BatchRequest {
+            agent: StepAgent::ExtractPdfText,
+            version: None,
+            items,
+        };
+        let pdf = || StepData::Bytes(b"%PDF-1.4".to_vec());
+
+        assert!(validate(&request(vec![pdf(); MAX_ITEMS])).is_ok());
+        assert!(validate(&request(Vec::new())).is_err());
+        assert!(validate(&request(vec![pdf(); MAX_ITEMS + 1])).is_err());
+        assert!(validate(&request(vec![pdf(), StepData::Text("text".to_string())])).is_err());
+    }
+}
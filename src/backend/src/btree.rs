@@ -0,0 +1,574 @@
+use std::ops::Bound;
+
+use crate::memory::Memory;
+
+// A B-tree map from fixed-size keys to u64 values, kept in a `Memory`.
+//
+// Layout, from offset 0 of the memory:
+//
+//   0          magic, identifying an initialized tree
+//   8          address of the root node, 0 while the tree is empty
+//   16         number of node slots handed out
+//   24         address of the first free node, 0 if none
+//   32         number of entries
+//   NODE_SIZE  node slots
+//
+// Nodes hold a leaf flag and key count, then the keys, the values and the
+// child addresses. Freed nodes are chained through their first 8 bytes.
+
+const MAGIC: &[u8; 8] = b"BTREE001";
+const ROOT: u64 = 8;
+const NODES: u64 = 16;
+const FREE: u64 = 24;
+const LEN: u64 = 32;
+
+/// Minimum degree: nodes other than the root hold between `DEGREE - 1` and
+/// `MAX_KEYS` keys.
+const DEGREE: usize = 32;
+const MAX_KEYS: usize = 2 * DEGREE - 1;
+
+pub struct BTree<M: Memory, const K: usize> {
+    memory: M,
+}
+
+struct Node<const K: usize> {
+    addr: u64,
+    leaf: bool,
+    keys: Vec<[u8; K]>,
+    values: Vec<u64>,
+    children: Vec<u64>,
+}
+
+impl<M: Memory, const K: usize> BTree<M, K> {
+    const VALUES: usize = 8 + MAX_KEYS * K;
+    const CHILDREN: usize = Self::VALUES + MAX_KEYS * 8;
+    const NODE_SIZE: usize = Self::CHILDREN + (MAX_KEYS + 1) * 8;
+
+    pub fn new(memory: M) -> Self {
+        BTree { memory }
+    }
+
+    /// Whether the memory holds a tree, even an empty one.
+    pub fn is_initialized(&self) -> bool {
+        let mut magic = [0; 8];
+        self.memory.read(0, &mut magic);
+        &magic == MAGIC
+    }
+
+    /// Start an empty tree, discarding whatever the memory held.
+    pub fn clear(&self) {
+        let mut header = [0; 40];
+        header[..8].copy_from_slice(MAGIC);
+        self.memory.write(0, &header);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.memory.read_u64(LEN)
+    }
+
+    pub fn get(&self, key: &[u8; K]) -> Option<u64> {
+        let mut addr = self.memory.read_u64(ROOT);
+        while addr != 0 {
+            let node = self.load(addr);
+            match node.keys.binary_search(key) {
+                Ok(i) => return Some(node.values[i]),
+                Err(_) if node.leaf => return None,
+                Err(i) => addr = node.children[i],
+            }
+        }
+        None
+    }
+
+    /// Insert or replace an entry, returning the value it replaced.
+    pub fn insert(&self, key: [u8; K], value: u64) -> Option<u64> {
+        if !self.is_initialized() {
+            self.clear();
+        }
+        if let Some(old) = self.replace(&key, value) {
+            return Some(old);
+        }
+
+        let root = self.memory.read_u64(ROOT);
+        let mut node = if root == 0 {
+            let node = self.allocate(true);
+            self.memory.write_u64(ROOT, node.addr);
+            node
+        } else {
+            self.load(root)
+        };
+        if node.keys.len() == MAX_KEYS {
+            let mut parent = self.allocate(false);
+            parent.children.push(node.addr);
+            self.memory.write_u64(ROOT, parent.addr);
+            self.split_child(&mut parent, 0, &mut node);
+            node = parent;
+        }
+        self.insert_nonfull(node, key, value);
+        self.memory.write_u64(LEN, self.len() + 1);
+        None
+    }
+
+    /// Remove an entry, returning its value.
+    pub fn remove(&self, key: &[u8; K]) -> Option<u64> {
+        self.get(key)?;
+        let root = self.load(self.memory.read_u64(ROOT));
+        let value = self.remove_from(root, key);
+
+        let root = self.load(self.memory.read_u64(ROOT));
+        if root.keys.is_empty() {
+            let next = if root.leaf { 0 } else { root.children[0] };
+            self.memory.write_u64(ROOT, next);
+            self.free(root.addr);
+        }
+        self.memory.write_u64(LEN, self.len() - 1);
+        value
+    }
+
+    /// Visit the entries between `lower` and `upper` in key order, or in
+    /// reverse, until `visit` returns false.
+    pub fn range(
+        &self,
+        lower: Bound<&[u8; K]>,
+        upper: Bound<&[u8; K]>,
+        ascending: bool,
+        mut visit: impl FnMut(&[u8; K], u64) -> bool,
+    ) {
+        let root = self.memory.read_u64(ROOT);
+        if root != 0 {
+            self.visit(root, lower, upper, ascending, &mut visit);
+        }
+    }
+
+    fn visit(
+        &self,
+        addr: u64,
+        lower: Bound<&[u8; K]>,
+        upper: Bound<&[u8; K]>,
+        ascending: bool,
+        visit: &mut impl FnMut(&[u8; K], u64) -> bool,
+    ) -> bool {
+        let node = self.load(addr);
+        let below = |key: &[u8; K]| match lower {
+            Bound::Included(lower) => key < lower,
+            Bound::Excluded(lower) => key <= lower,
+            Bound::Unbounded => false,
+        };
+        let above = |key: &[u8; K]| match upper {
+            Bound::Included(upper) => key > upper,
+            Bound::Excluded(upper) => key >= upper,
+            Bound::Unbounded => false,
+        };
+
+        // Child `i` holds the keys between keys `i - 1` and `i`; position
+        // `2 * i` is child `i` and `2 * i + 1` is key `i`.
+        let positions = 2 * node.keys.len() + 1;
+        for step in 0..positions {
+            let position = if ascending {
+                step
+            } else {
+                positions - 1 - step
+            };
+            let i = position / 2;
+            if position % 2 == 1 {
+                let key = &node.keys[i];
+                if ascending && above(key) || !ascending && below(key) {
+                    return false;
+                }
+                if !below(key) && !above(key) && !visit(key, node.values[i]) {
+                    return false;
+                }
+            } else if !node.leaf {
+                let all_below = node.keys.get(i).is_some_and(below);
+                let all_above = i > 0 && above(&node.keys[i - 1]);
+                if !all_below
+                    && !all_above
+                    && !self.visit(node.children[i], lower, upper, ascending, visit)
+                {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn replace(&self, key: &[u8; K], value: u64) -> Option<u64> {
+        let mut addr = self.memory.read_u64(ROOT);
+        while addr != 0 {
+            let mut node = self.load(addr);
+            match node.keys.binary_search(key) {
+                Ok(i) => {
+                    let old = std::mem::replace(&mut node.values[i], value);
+                    self.save(&node);
+                    return Some(old);
+                }
+                Err(_) if node.leaf => return None,
+                Err(i) => addr = node.children[i],
+            }
+        }
+        None
+    }
+
+    fn insert_nonfull(&self, mut node: Node<K>, key: [u8; K], value: u64) {
+        loop {
+            let Err(i) = node.keys.binary_search(&key) else {
+                unreachable!("Existing keys are replaced before inserting");
+            };
+            if node.leaf {
+                node.keys.insert(i, key);
+                node.values.insert(i, value);
+                self.save(&node);
+                return;
+            }
+            let mut child = self.load(node.children[i]);
+            if child.keys.len() == MAX_KEYS {
+                let right = self.split_child(&mut node, i, &mut child);
+                if key > node.keys[i] {
+                    child = right;
+                }
+            }
+            node = child;
+        }
+    }
+
+    /// Split the full child `i` of `parent`, moving its middle key up, and
+    /// return the new right half.
+    fn split_child(&self, parent: &mut Node<K>, i: usize, child: &mut Node<K>) -> Node<K> {
+        let mut right = self.allocate(child.leaf);
+        right.keys = child.keys.split_off(DEGREE);
+        right.values = child.values.split_off(DEGREE);
+        if !child.leaf {
+            right.children = child.children.split_off(DEGREE);
+        }
+        let key = child.keys.pop().expect("A full node has a middle key");
+        let value = child.values.pop().expect("A full node has a middle value");
+        parent.keys.insert(i, key);
+        parent.values.insert(i, value);
+        parent.children.insert(i + 1, right.addr);
+        self.save(child);
+        self.save(&right);
+        self.save(parent);
+        right
+    }
+
+    /// Remove `key` from the subtree at `node`, which holds at least
+    /// `DEGREE` keys unless it is the root.
+    fn remove_from(&self, mut node: Node<K>, key: &[u8; K]) -> Option<u64> {
+        loop {
+            let found = node.keys.binary_search(key);
+            match found {
+                Ok(i) if node.leaf => {
+                    node.keys.remove(i);
+                    let value = node.values.remove(i);
+                    self.save(&node);
+                    return Some(value);
+                }
+                Ok(i) => {
+                    let value = node.values[i];
+                    let left = self.load(node.children[i]);
+                    if left.keys.len() >= DEGREE {
+                        let (key, next) = self.last(&left);
+                        node.keys[i] = key;
+                        node.values[i] = next;
+                        self.save(&node);
+                        self.remove_from(left, &key);
+                        return Some(value);
+                    }
+                    let right = self.load(node.children[i + 1]);
+                    if right.keys.len() >= DEGREE {
+                        let (key, next) = self.first(&right);
+                        node.keys[i] = key;
+                        node.values[i] = next;
+                        self.save(&node);
+                        self.remove_from(right, &key);
+                        return Some(value);
+                    }
+                    node = self.merge(&mut node, i, left, right);
+                }
+                Err(_) if node.leaf => return None,
+                Err(i) => node = self.fill_child(&mut node, i),
+            }
+        }
+    }
+
+    /// Make sure child `i` of `node` holds at least `DEGREE` keys, by
+    /// borrowing from a sibling or merging with one, and return the child
+    /// that now covers its keys.
+    fn fill_child(&self, node: &mut Node<K>, i: usize) -> Node<K> {
+        let mut child = self.load(node.children[i]);
+        if child.keys.len() >= DEGREE {
+            return child;
+        }
+        if i > 0 {
+            let mut left = self.load(node.children[i - 1]);
+            if left.keys.len() >= DEGREE {
+                let key = left.keys.pop().expect("Sibling has keys");
+                let value = left.values.pop().expect("Sibling has values");
+                child
+                    .keys
+                    .insert(0, std::mem::replace(&mut node.keys[i - 1], key));
+                child
+                    .values
+                    .insert(0, std::mem::replace(&mut node.values[i - 1], value));
+                if !left.leaf {
+                    let moved = left.children.pop().expect("Sibling has children");
+                    child.children.insert(0, moved);
+                }
+                self.save(&left);
+                self.save(&child);
+                self.save(node);
+                return child;
+            }
+        }
+        if i < node.keys.len() {
+            let mut right = self.load(node.children[i + 1]);
+            if right.keys.len() >= DEGREE {
+                let key = right.keys.remove(0);
+                let value = right.values.remove(0);
+                child.keys.push(std::mem::replace(&mut node.keys[i], key));
+                child
+                    .values
+                    .push(std::mem::replace(&mut node.values[i], value));
+                if !right.leaf {
+                    child.children.push(right.children.remove(0));
+                }
+                self.save(&right);
+                self.save(&child);
+                self.save(node);
+                return child;
+            }
+            return self.merge(node, i, child, right);
+        }
+        let left = self.load(node.children[i - 1]);
+        self.merge(node, i - 1, left, child)
+    }
+
+    /// Merge child `i + 1` of `node` and key `i` into child `i`.
+    fn merge(&self, node: &mut Node<K>, i: usize, mut left: Node<K>, right: Node<K>) -> Node<K> {
+        left.keys.push(node.keys.remove(i));
+        left.values.push(node.values.remove(i));
+        node.children.remove(i + 1);
+        left.keys.extend(right.keys);
+        left.values.extend(right.values);
+        left.children.extend(right.children);
+        self.save(&left);
+        self.save(node);
+        self.free(right.addr);
+        left
+    }
+
+    fn first(&self, node: &Node<K>) -> ([u8; K], u64) {
+        let mut addr = node.addr;
+        loop {
+            let node = self.load(addr);
+            if node.leaf {
+                return (node.keys[0], node.values[0]);
+            }
+            addr = node.children[0];
+        }
+    }
+
+    fn last(&self, node: &Node<K>) -> ([u8; K], u64) {
+        let mut addr = node.addr;
+        loop {
+            let node = self.load(addr);
+            if node.leaf {
+                let i = node.keys.len() - 1;
+                return (node.keys[i], node.values[i]);
+            }
+            addr = node.children[node.keys.len()];
+        }
+    }
+
+    fn allocate(&self, leaf: bool) -> Node<K> {
+        let free = self.memory.read_u64(FREE);
+        let addr = if free != 0 {
+            self.memory.write_u64(FREE, self.memory.read_u64(free));
+            free
+        } else {
+            let nodes = self.memory.read_u64(NODES) + 1;
+            self.memory.write_u64(NODES, nodes);
+            nodes * Self::NODE_SIZE as u64
+        };
+        Node {
+            addr,
+            leaf,
+            keys: Vec::new(),
+            values: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn free(&self, addr: u64) {
+        self.memory.write_u64(addr, self.memory.read_u64(FREE));
+        self.memory.write_u64(FREE, addr);
+    }
+
+    fn load(&self, addr: u64) -> Node<K> {
+        let mut bytes = vec![0; Self::NODE_SIZE];
+        self.memory.read(addr, &mut bytes);
+        let leaf = bytes[0] == 1;
+        let len = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+        let keys = (0..len)
+            .map(|i| bytes[8 + i * K..][..K].try_into().expect("Key is K bytes"))
+            .collect();
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let values = (0..len).map(|i| u64_at(Self::VALUES + i * 8)).collect();
+        let children = if leaf {
+            Vec::new()
+        } else {
+            (0..=len).map(|i| u64_at(Self::CHILDREN + i * 8)).collect()
+        };
+        Node {
+            addr,
+            leaf,
+            keys,
+            values,
+            children,
+        }
+    }
+
+    fn save(&self, node: &Node<K>) {
+        let mut bytes = vec![0; Self::NODE_SIZE];
+        bytes[0] = node.leaf as u8;
+        bytes[1..3].copy_from_slice(&(node.keys.len() as u16).to_le_bytes());
+        for (i, key) in node.keys.iter().enumerate() {
+            bytes[8 + i * K..][..K].copy_from_slice(key);
+        }
+        for (i, value) in node.values.iter().enumerate() {
+            bytes[Self::VALUES + i * 8..][..8].copy_from_slice(&value.to_le_bytes());
+        }
+        for (i, child) in node.children.iter().enumerate() {
+            bytes[Self::CHILDREN + i * 8..][..8].copy_from_slice(&child.to_le_bytes());
+        }
+        self.memory.write(node.addr, &bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    impl Memory for RefCell<Vec<u8>> {
+        fn read(&self, offset: u64, buf: &mut [u8]) {
+            let bytes = self.borrow();
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = bytes.get(offset as usize + i).copied().unwrap_or(0);
+            }
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) {
+            let mut bytes = self.borrow_mut();
+            let end = offset as usize + data.len();
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[offset as usize..end].copy_from_slice(data);
+        }
+    }
+
+    /// Small keys from a fixed pseudo-random sequence, so that keys repeat.
+    fn keys(count: usize) -> Vec<[u8; 3]> {
+        let mut state: u32 = 12345;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let n = (state >> 8) % 5000;
+                [(n >> 16) as u8, (n >> 8) as u8, n as u8]
+            })
+            .collect()
+    }
+
+    fn entries(tree: &BTree<RefCell<Vec<u8>>, 3>, ascending: bool) -> Vec<([u8; 3], u64)> {
+        let mut entries = Vec::new();
+        tree.range(
+            Bound::Unbounded,
+            Bound::Unbounded,
+            ascending,
+            |key, value| {
+                entries.push((*key, value));
+                true
+            },
+        );
+        entries
+    }
+
+    #[test]
+    fn matches_a_btreemap_through_inserts_and_removes() {
+        let tree = BTree::<_, 3>::new(RefCell::new(Vec::new()));
+        let mut expected = BTreeMap::new();
+        for (i, key) in keys(20_000).into_iter().enumerate() {
+            if i % 3 == 2 {
+                assert_eq!(tree.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(tree.insert(key, i as u64), expected.insert(key, i as u64));
+            }
+        }
+        assert_eq!(tree.len(), expected.len() as u64);
+        let all: Vec<_> = expected.iter().map(|(key, value)| (*key, *value)).collect();
+        assert_eq!(entries(&tree, true), all);
+        let reversed: Vec<_> = all.iter().rev().copied().collect();
+        assert_eq!(entries(&tree, false), reversed);
+
+        for key in expected.keys() {
+            assert!(tree.remove(key).is_some());
+        }
+        assert_eq!(tree.len(), 0);
+        assert!(entries(&tree, true).is_empty());
+        assert_eq!(tree.get(&[0, 0, 1]), None);
+    }
+
+    #[test]
+    fn freed_nodes_are_reused() {
+        let tree = BTree::<_, 3>::new(RefCell::new(Vec::new()));
+        let keys = keys(5000);
+        for key in &keys {
+            tree.insert(*key, 1);
+        }
+        let size = tree.memory.borrow().len();
+        for key in &keys {
+            tree.remove(key);
+        }
+        for key in &keys {
+            tree.insert(*key, 2);
+        }
+        assert_eq!(tree.memory.borrow().len(), size);
+    }
+
+    #[test]
+    fn range_respects_bounds_and_stops_early() {
+        let tree = BTree::<_, 3>::new(RefCell::new(Vec::new()));
+        for n in 0..1000u16 {
+            tree.insert([0, (n >> 8) as u8, n as u8], n as u64);
+        }
+        let key = |n: u16| [0, (n >> 8) as u8, n as u8];
+        let collect = |lower, upper, ascending, limit: usize| {
+            let mut values = Vec::new();
+            tree.range(lower, upper, ascending, |_, value| {
+                values.push(value);
+                values.len() < limit
+            });
+            values
+        };
+
+        let (low, high) = (key(100), key(900));
+        assert_eq!(
+            collect(Bound::Included(&low), Bound::Excluded(&high), true, 3),
+            [100, 101, 102]
+        );
+        assert_eq!(
+            collect(Bound::Excluded(&low), Bound::Included(&high), false, 3),
+            [900, 899, 898]
+        );
+        let all = collect(
+            Bound::Excluded(&low),
+            Bound::Excluded(&high),
+            true,
+            usize::MAX,
+        );
+        assert_eq!(all, (101..900).collect::<Vec<_>>());
+        let (missing, past) = (key(2000), key(3000));
+        assert!(collect(Bound::Included(&missing), Bound::Included(&past), true, 10).is_empty());
+    }
+}
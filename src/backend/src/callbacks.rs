@@ -0,0 +1,287 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::error::MarketplaceError;
+use crate::timers::{self, TimerTask};
+use crate::{JobResult, CALLBACKS};
+
+/// Maximum number of callbacks a single job may register.
+pub const MAX_CALLBACKS_PER_JOB: usize = 5;
+
+/// Delivery attempts made before a callback is given up on.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Delay before the first retry; doubled after every failed attempt.
+const RETRY_BASE_SECONDS: u64 = 10;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A method on a client canister that is called when a job finishes.
+///
+/// The method receives a single `JobNotification` argument. Its reply is
+/// ignored; a reject counts as a failed delivery and is retried.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobCallback {
+    pub canister: Principal,
+    pub method: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum JobOutcome {
+    Succeeded,
+    /// Some pipeline steps or batch items failed and were refunded.
+    PartiallyFailed,
+    Failed,
+}
+
+/// Argument passed to a job callback.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobNotification {
+    pub job_id: String,
+    pub outcome: JobOutcome,
+    pub result: JobResult,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    /// Waiting for the job to finish.
+    Waiting,
+    /// Due to be attempted at `next_attempt_at`.
+    Pending,
+    Delivered,
+    /// Every attempt failed.
+    Failed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeliveryAttempt {
+    pub at: u64,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CallbackDelivery {
+    pub callback: JobCallback,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub next_attempt_at: Option<u64>,
+    pub registered_at: u64,
+}
+
+/// Callbacks registered on a job and the notification sent once it finished.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct JobCallbacks {
+    pub notification: Option<JobNotification>,
+    pub deliveries: Vec<CallbackDelivery>,
+}
+
+/// Register a callback on a job. If the job already finished the callback
+/// is delivered straight away.
+pub fn register(
+    job_id: &str,
+    callback: JobCallback,
+) -> Result<CallbackDelivery, MarketplaceError> {
+    if callback.method.trim().is_empty() {
+        return Err(MarketplaceError::invalid(
+            "method",
+            "Callback method cannot be empty",
+        ));
+    }
+    if callback.canister == Principal::anonymous()
+        || callback.canister == Principal::management_canister()
+    {
+        return Err(MarketplaceError::invalid(
+            "canister",
+            "Callback target must be a canister",
+        ));
+    }
+
+    let now = ic_cdk::api::time();
+    let (index, delivery) = CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        let entry = callbacks.entry(job_id.to_string()).or_default();
+        if entry.deliveries.len() >= MAX_CALLBACKS_PER_JOB {
+            return Err(MarketplaceError::conflict(format!(
+                "A job cannot have more than {} callbacks",
+                MAX_CALLBACKS_PER_JOB
+            )));
+        }
+        if entry
+            .deliveries
+            .iter()
+            .any(|delivery| delivery.callback == callback)
+        {
+            return Err(MarketplaceError::conflict(
+                "Callback is already registered for this job",
+            ));
+        }
+
+        let finished = entry.notification.is_some();
+        entry.deliveries.push(CallbackDelivery {
+            callback,
+            status: if finished {
+                DeliveryStatus::Pending
+            } else {
+                DeliveryStatus::Waiting
+            },
+            attempts: Vec::new(),
+            next_attempt_at: finished.then_some(now),
+            registered_at: now,
+        });
+
+        let index = entry.deliveries.len() - 1;
+        Ok((index, entry.deliveries[index].clone()))
+    })?;
+
+    arm(job_id, index, &delivery);
+    Ok(delivery)
+}
+
+/// Delivery log of a job's callbacks.
+pub fn deliveries(job_id: &str) -> Vec<CallbackDelivery> {
+    CALLBACKS.with(|callbacks| {
+        callbacks
+            .borrow()
+            .get(job_id)
+            .map(|entry| entry.deliveries.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Record that a job finished and start delivering its callbacks.
+pub fn notify(job_id: &str, outcome: JobOutcome, result: &JobResult) {
+    let now = ic_cdk::api::time();
+    let due = CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        let entry = callbacks.entry(job_id.to_string()).or_default();
+        if entry.notification.is_some() {
+            return Vec::new();
+        }
+
+        entry.notification = Some(JobNotification {
+            job_id: job_id.to_string(),
+            outcome,
+            result: result.clone(),
+        });
+
+        entry
+            .deliveries
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, delivery)| delivery.status == DeliveryStatus::Waiting)
+            .map(|(index, delivery)| {
+                delivery.status = DeliveryStatus::Pending;
+                delivery.next_attempt_at = Some(now);
+                (index, delivery.clone())
+            })
+            .collect::<Vec<_>>()
+    });
+
+    for (index, delivery) in due {
+        arm(job_id, index, &delivery);
+    }
+}
+
+fn arm(job_id: &str, index: usize, delivery: &CallbackDelivery) {
+    if delivery.status != DeliveryStatus::Pending {
+        return;
+    }
+    if let Some(at) = delivery.next_attempt_at {
+        timers::set_timer_at(
+            at,
+            TimerTask::DeliverCallback {
+                job_id: job_id.to_string(),
+                index: index as u32,
+                at,
+            },
+        );
+    }
+}
+
+/// Re-arm every pending delivery after an upgrade.
+///
+/// A delivery whose call was in flight during the upgrade has no attempt
+/// time left; it is counted as a failed attempt and retried as usual.
+pub fn rearm_all() {
+    let interrupted: Vec<(String, u32)> = CALLBACKS.with(|callbacks| {
+        let mut interrupted = Vec::new();
+        for (job_id, entry) in callbacks.borrow().iter() {
+            for (index, delivery) in entry.deliveries.iter().enumerate() {
+                let pending = delivery.status == DeliveryStatus::Pending;
+                if pending && delivery.next_attempt_at.is_none() {
+                    interrupted.push((job_id.clone(), index as u32));
+                }
+                arm(job_id, index, delivery);
+            }
+        }
+        interrupted
+    });
+
+    let now = ic_cdk::api::time();
+    for (job_id, index) in interrupted {
+        let error = "Interrupted by a canister upgrade".to_string();
+        if let Some(delivery) = record_attempt(&job_id, index, Some(error), now) {
+            arm(&job_id, index as usize, &delivery);
+        }
+    }
+}
+
+/// Attempt a delivery that was due at `at`, scheduling a retry on failure.
+pub async fn deliver(job_id: &str, index: u32, at: u64) {
+    // Clear `next_attempt_at` while the call is in flight so a stale timer
+    // cannot start a second attempt.
+    let prepared = CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        let entry = callbacks.get_mut(job_id)?;
+        let notification = entry.notification.clone()?;
+        let delivery = entry.deliveries.get_mut(index as usize)?;
+        if delivery.status != DeliveryStatus::Pending || delivery.next_attempt_at != Some(at) {
+            return None;
+        }
+        delivery.next_attempt_at = None;
+        Some((delivery.callback.clone(), notification))
+    });
+
+    let Some((callback, notification)) = prepared else {
+        return;
+    };
+
+    let error = match candid::encode_one(&notification) {
+        Ok(args) => ic_cdk::api::call::call_raw(callback.canister, &callback.method, args, 0)
+            .await
+            .err()
+            .map(|(code, message)| format!("Call rejected ({:?}): {}", code, message)),
+        Err(err) => Some(format!("Failed to encode notification: {}", err)),
+    };
+
+    if let Some(delivery) = record_attempt(job_id, index, error, ic_cdk::api::time()) {
+        arm(job_id, index as usize, &delivery);
+    }
+}
+
+/// Log an attempt made at `now` and schedule the retry if it failed.
+fn record_attempt(
+    job_id: &str,
+    index: u32,
+    error: Option<String>,
+    now: u64,
+) -> Option<CallbackDelivery> {
+    CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        let delivery = callbacks
+            .get_mut(job_id)
+            .and_then(|entry| entry.deliveries.get_mut(index as usize))?;
+
+        let failed = error.is_some();
+        delivery.attempts.push(DeliveryAttempt { at: now, error });
+
+        if !failed {
+            delivery.status = DeliveryStatus::Delivered;
+        } else if delivery.attempts.len() >= MAX_ATTEMPTS {
+            delivery.status = DeliveryStatus::Failed;
+        } else {
+            let backoff = RETRY_BASE_SECONDS << (delivery.attempts.len() - 1);
+            delivery.next_attempt_at = Some(now + backoff * NANOS_PER_SECOND);
+        }
+        Some(delivery.clone())
+    })
+}
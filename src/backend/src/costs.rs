@@ -0,0 +1,282 @@
+use candid::{CandidType, Deserialize};
+use ic_llm::Model;
+use std::collections::BTreeMap;
+
+use crate::error::MarketplaceError;
+use crate::metrics::LlmCall;
+use crate::pipeline::StepData;
+use crate::{
+    agents, PaymentStatus, BATCHES, BATCH_INPUTS, JOBS, PAYMENTS, PIPELINES, PIPELINE_INPUTS,
+    REFUNDS, RESULTS,
+};
+
+// Fees on a 13-node application subnet, in cycles.
+// https://internetcomputer.org/docs/current/developer-docs/gas-cost
+
+/// Fee per ten instructions executed.
+const CYCLES_PER_TEN_INSTRUCTIONS: u128 = 4;
+/// Fee for making an inter-canister call, such as one to the LLM canister.
+const CALL_FEE: u128 = 260_000;
+/// Fee per byte sent or received in an inter-canister call.
+const CALL_BYTE_FEE: u128 = 1_000;
+/// Fee for holding one GiB of memory for one second.
+const GIB_SECOND_FEE: u128 = 127_000;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Resources measured while working on a job.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Usage {
+    pub instructions: u64,
+    pub llm_calls: u32,
+    /// Bytes sent to and received from the LLM canister.
+    pub llm_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.instructions = self.instructions.saturating_add(other.instructions);
+        self.llm_calls = self.llm_calls.saturating_add(other.llm_calls);
+        self.llm_bytes = self.llm_bytes.saturating_add(other.llm_bytes);
+    }
+}
+
+/// What a job has cost the canister so far, stored on its job record.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct JobCost {
+    pub usage: Usage,
+    /// Bytes currently held for the job: its request, inputs and outputs.
+    pub storage_bytes: u64,
+    /// Storage held up to `storage_updated_at`, in byte-seconds.
+    pub byte_seconds: u64,
+    pub storage_updated_at: u64,
+}
+
+impl JobCost {
+    fn byte_seconds_at(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.storage_updated_at) / NANOS_PER_SECOND;
+        self.byte_seconds
+            .saturating_add(self.storage_bytes.saturating_mul(elapsed))
+    }
+
+    /// Estimated cycles spent on the job up to `now`.
+    pub fn cycles(&self, now: u64) -> CycleBreakdown {
+        let usage = &self.usage;
+        CycleBreakdown {
+            execution: usage.instructions as u128 * CYCLES_PER_TEN_INSTRUCTIONS / 10,
+            llm: usage.llm_calls as u128 * CALL_FEE + usage.llm_bytes as u128 * CALL_BYTE_FEE,
+            storage: self.byte_seconds_at(now) as u128 * GIB_SECOND_FEE / (1 << 30),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct CycleBreakdown {
+    pub execution: u128,
+    pub llm: u128,
+    pub storage: u128,
+}
+
+impl CycleBreakdown {
+    pub fn total(&self) -> u128 {
+        self.execution + self.llm + self.storage
+    }
+
+    fn add(&mut self, other: &CycleBreakdown) {
+        self.execution += other.execution;
+        self.llm += other.llm;
+        self.storage += other.storage;
+    }
+}
+
+/// Cost against revenue for one agent.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AgentCostReport {
+    pub agent_id: String,
+    pub jobs: u64,
+    pub paid_jobs: u64,
+    /// Paid job prices less refunds, in ICP.
+    pub revenue: f64,
+    pub cycles: CycleBreakdown,
+    /// `cycles` converted at the rate the report was requested with, in ICP.
+    pub cost: f64,
+    pub margin: f64,
+    /// Paid jobs whose cost exceeded what they brought in.
+    pub unprofitable_jobs: u64,
+}
+
+/// Instructions executed so far in the current call context. Unlike the
+/// message counter it keeps counting across awaits.
+pub fn instructions() -> u64 {
+    ic_cdk::api::call_context_instruction_counter()
+}
+
+/// Send a prompt to the LLM canister, adding the call to `usage`.
+pub async fn prompt(usage: &mut Usage, prompt: &str) -> String {
+    let call = LlmCall::start();
+    let response = ic_llm::prompt(Model::Qwen3_32B, prompt).await;
+    call.succeeded();
+    usage.llm_calls += 1;
+    usage.llm_bytes += (prompt.len() + response.len()) as u64;
+    response
+}
+
+fn update(job_id: &str, update: impl FnOnce(&mut JobCost)) {
+    JOBS.with(|jobs| {
+        if let Some(job) = jobs.borrow_mut().get_mut(job_id) {
+            update(job.cost.get_or_insert_with(Default::default));
+        }
+    });
+}
+
+/// Add measured usage to a job.
+pub fn charge(job_id: &str, usage: &Usage) {
+    update(job_id, |cost| cost.usage.add(usage));
+}
+
+fn data_len(data: &StepData) -> u64 {
+    match data {
+        StepData::Bytes(bytes) => bytes.len() as u64,
+        StepData::Text(text) => text.len() as u64,
+    }
+}
+
+/// Bytes the canister currently holds for a job.
+fn stored_bytes(job_id: &str) -> u64 {
+    let request = JOBS.with(|jobs| {
+        jobs.borrow()
+            .get(job_id)
+            .map_or(0, |job| job.request.len() as u64)
+    });
+    let result = RESULTS.with(|results| {
+        results
+            .borrow()
+            .get(job_id)
+            .map_or(0, |result| result.output.len() as u64)
+    });
+    let pipeline = PIPELINES.with(|pipelines| {
+        pipelines.borrow().get(job_id).map_or(0, |pipeline| {
+            pipeline
+                .steps
+                .iter()
+                .filter_map(|step| step.output.as_ref())
+                .map(data_len)
+                .sum::<u64>()
+        })
+    }) + PIPELINE_INPUTS
+        .with(|inputs| inputs.borrow().get(job_id).map_or(0, data_len));
+    let batch = BATCHES.with(|batches| {
+        batches.borrow().get(job_id).map_or(0, |batch| {
+            batch
+                .items
+                .iter()
+                .filter_map(|item| item.output.as_ref())
+                .map(data_len)
+                .sum::<u64>()
+        })
+    }) + BATCH_INPUTS.with(|inputs| {
+        inputs
+            .borrow()
+            .get(job_id)
+            .map_or(0, |items| items.iter().flatten().map(data_len).sum::<u64>())
+    });
+    request + result + pipeline + batch
+}
+
+/// Bring a job's storage up to date after data was stored for it or freed.
+pub fn track_storage(job_id: &str) {
+    let now = ic_cdk::api::time();
+    let bytes = stored_bytes(job_id);
+    update(job_id, |cost| {
+        cost.byte_seconds = cost.byte_seconds_at(now);
+        cost.storage_bytes = bytes;
+        cost.storage_updated_at = now;
+    });
+}
+
+/// The agent a job's costs are reported under. Batches run a single agent and
+/// are counted against it; pipelines mix agents and are reported on their own.
+fn report_agent(job_id: &str, agent_id: &str) -> String {
+    if agent_id == agents::BATCH {
+        if let Some(batch_agent) =
+            BATCHES.with(|batches| batches.borrow().get(job_id).map(|b| b.agent_id.clone()))
+        {
+            return batch_agent;
+        }
+    }
+    agent_id.to_string()
+}
+
+/// Cost against revenue per agent, converting cycles at `cycles_per_icp`.
+pub fn report(cycles_per_icp: u64) -> Result<Vec<AgentCostReport>, MarketplaceError> {
+    if cycles_per_icp == 0 {
+        return Err(MarketplaceError::invalid(
+            "cycles_per_icp",
+            "cycles_per_icp must be positive",
+        ));
+    }
+    let now = ic_cdk::api::time();
+    let to_icp = |cycles: u128| cycles as f64 / cycles_per_icp as f64;
+
+    let mut reports: BTreeMap<String, AgentCostReport> = BTreeMap::new();
+    JOBS.with(|jobs| {
+        for (job_id, job) in jobs.borrow().iter() {
+            let agent_id = report_agent(job_id, &job.agent_id);
+            let report = reports
+                .entry(agent_id.clone())
+                .or_insert_with(|| AgentCostReport {
+                    agent_id,
+                    jobs: 0,
+                    paid_jobs: 0,
+                    revenue: 0.0,
+                    cycles: CycleBreakdown::default(),
+                    cost: 0.0,
+                    margin: 0.0,
+                    unprofitable_jobs: 0,
+                });
+
+            let cycles = job
+                .cost
+                .as_ref()
+                .map(|cost| cost.cycles(now))
+                .unwrap_or_default();
+            let paid = PAYMENTS.with(|payments| {
+                payments
+                    .borrow()
+                    .get(job_id)
+                    .is_some_and(|payment| matches!(payment.status, PaymentStatus::Completed))
+            });
+            let revenue = if paid {
+                let refunded = REFUNDS.with(|refunds| {
+                    refunds
+                        .borrow()
+                        .get(job_id)
+                        .map_or(0.0, |refund| refund.amount)
+                });
+                job.price - refunded
+            } else {
+                0.0
+            };
+
+            report.jobs += 1;
+            if paid {
+                report.paid_jobs += 1;
+                if to_icp(cycles.total()) > revenue {
+                    report.unprofitable_jobs += 1;
+                }
+            }
+            report.revenue += revenue;
+            report.cycles.add(&cycles);
+        }
+    });
+
+    Ok(reports
+        .into_values()
+        .map(|mut report| {
+            report.revenue = (report.revenue * 100.0).round() / 100.0;
+            report.cost = to_icp(report.cycles.total());
+            report.margin = report.revenue - report.cost;
+            report
+        })
+        .collect())
+}
@@ -0,0 +1,206 @@
+/// A parsed five-field cron expression: `minute hour day-of-month month day-of-week`.
+///
+/// Each field accepts `*`, single values, ranges (`1-5`), steps (`*/15`,
+/// `0-30/10`) and comma-separated lists. Day of week runs from 0 (Sunday) to
+/// 6, with 7 also meaning Sunday. Times are evaluated in UTC.
+#[derive(Clone, Debug)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    day_of_month_any: bool,
+    day_of_week_any: bool,
+}
+
+/// How far ahead to search for the next matching minute.
+const MAX_SEARCH_DAYS: u64 = 366 * 5;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Cron expression must have 5 fields, got {}",
+                fields.len()
+            ));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            day_of_month_any: fields[2] == "*",
+            day_of_week_any: fields[4] == "*",
+        })
+    }
+
+    /// First matching time strictly after `after` (both in seconds since the epoch).
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start_minute = after / 60 + 1;
+        let first_day = start_minute * 60 / SECONDS_PER_DAY;
+
+        for day in first_day..first_day + MAX_SEARCH_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+
+            let day_start = day * SECONDS_PER_DAY;
+            for hour in 0..24u64 {
+                if self.hours & (1 << hour) == 0 {
+                    continue;
+                }
+                for minute in 0..60u64 {
+                    if self.minutes & (1 << minute) == 0 {
+                        continue;
+                    }
+                    let candidate = day_start + hour * 3_600 + minute * 60;
+                    if candidate >= start_minute * 60 {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = civil_from_days(day);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        // Thursday, 1 January 1970 was day 0.
+        let day_of_week = (day + 4) % 7;
+        let dom_match = self.days_of_month & (1 << day_of_month) != 0;
+        let dow_match = self.days_of_week & (1 << day_of_week) != 0;
+
+        // As in cron, when both day fields are restricted either one may match.
+        match (self.day_of_month_any, self.day_of_week_any) {
+            (true, true) => true,
+            (true, false) => dow_match,
+            (false, true) => dom_match,
+            (false, false) => dom_match || dow_match,
+        }
+    }
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u64 = step
+                    .parse()
+                    .map_err(|_| format!("Invalid cron step: {}", part))?;
+                if step == 0 {
+                    return Err(format!("Cron step cannot be zero: {}", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, part)?, parse_value(end, part)?)
+        } else {
+            let value = parse_value(range, part)?;
+            // `5/10` means every 10 starting at 5.
+            (value, if step > 1 { max } else { value })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!("Cron value out of range {}-{}: {}", min, max, part));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+fn parse_value(value: &str, part: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid cron value: {}", part))
+}
+
+/// Convert days since the epoch to a `(year, month, day)` civil date.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(expr: &str, after: u64) -> Option<u64> {
+        CronExpr::parse(expr).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn civil_dates_around_leap_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2000 is a leap year, 2100 is not.
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn next_after_crosses_month_ends() {
+        // 2024-01-31 12:00 to 2024-02-01 00:00.
+        assert_eq!(next("0 0 1 * *", 1_706_702_400), Some(1_706_745_600));
+        // April has no 31st: 2024-04-15 08:00 to 2024-05-31 12:30.
+        assert_eq!(next("30 12 31 * *", 1_713_168_000), Some(1_717_158_600));
+        // Strictly after: 2024-12-31 23:59 to 2025-12-31 23:59.
+        assert_eq!(next("59 23 31 12 *", 1_735_689_540), Some(1_767_225_540));
+    }
+
+    #[test]
+    fn next_after_finds_leap_days() {
+        // 2023-03-01 to 2024-02-29, then on to 2028-02-29.
+        assert_eq!(next("0 0 29 2 *", 1_677_628_800), Some(1_709_164_800));
+        assert_eq!(next("0 0 29 2 *", 1_709_164_800), Some(1_835_395_200));
+        // 2099-03-01 and 2100-03-01 to 2104-02-29, skipping 2100.
+        assert_eq!(next("0 0 29 2 *", 4_076_006_400), Some(4_233_686_400));
+        assert_eq!(next("0 0 29 2 *", 4_107_542_400), Some(4_233_686_400));
+        // A date that never comes is given up on after the search window.
+        assert_eq!(next("0 0 30 2 *", 1_677_628_800), None);
+    }
+
+    #[test]
+    fn next_after_matches_either_day_field() {
+        // From Sunday 2024-09-01 10:00, Friday the 6th comes before the 13th.
+        assert_eq!(next("0 9 13 * 5", 1_725_184_800), Some(1_725_613_200));
+        assert_eq!(next("0 9 13 * *", 1_725_184_800), Some(1_726_218_000));
+        // 7 is Sunday too: Monday 2024-09-02 to Sunday 2024-09-08.
+        assert_eq!(next("0 0 * * 7", 1_725_235_200), Some(1_725_753_600));
+    }
+}
@@ -1,4 +1,7 @@
-use ic_llm::Model;
+use crate::agents;
+use crate::costs::{self, Usage};
+use crate::error::MarketplaceError;
+use crate::log;
 
 #[derive(Clone, Debug)]
 pub struct AnalysisOptions {
@@ -24,62 +27,133 @@ impl AnalysisOptions {
     }
 }
 
+type PromptBuilder = fn(&CsvAnalyzer, &str, &str) -> String;
+
+/// Prompt templates by semantic version, oldest first. The last entry is the
+/// current version; older entries stay until they are retired so that jobs
+/// pinned to them keep producing the same prompt.
+const PROMPT_TEMPLATES: &[(&str, PromptBuilder)] = &[("1.0.0", CsvAnalyzer::build_prompt_v1)];
+
+/// Presets with dedicated instructions, plus the fallback.
+const PRESETS: &[&str] = &["Trends", "Anomalies", "Forecast", "Summary", ""];
+
+/// All prompt template versions, oldest first.
+pub fn versions() -> Vec<&'static str> {
+    PROMPT_TEMPLATES
+        .iter()
+        .map(|(version, _)| *version)
+        .collect()
+}
+
+/// Render a template version with placeholder inputs for every preset.
+pub fn template_samples(version: &str) -> Option<Vec<String>> {
+    let (_, build_prompt) = PROMPT_TEMPLATES.iter().find(|(v, _)| *v == version)?;
+
+    let mut samples = Vec::new();
+    for preset in PRESETS {
+        for include_visuals in [false, true] {
+            let analyzer = CsvAnalyzer::new(AnalysisOptions::new(
+                preset.to_string(),
+                Some("{primary_metric}".to_string()),
+                Some("{segment_column}".to_string()),
+                include_visuals,
+            ));
+            samples.push(build_prompt(&analyzer, "{preview}", "{structure}"));
+        }
+    }
+    Some(samples)
+}
+
 pub struct CsvAnalyzer {
     options: AnalysisOptions,
+    build_prompt: PromptBuilder,
 }
 
 impl CsvAnalyzer {
+    /// Create an analyzer using the current prompt template.
     pub fn new(options: AnalysisOptions) -> Self {
-        Self { options }
+        let (_, build_prompt) = PROMPT_TEMPLATES[PROMPT_TEMPLATES.len() - 1];
+        Self {
+            options,
+            build_prompt,
+        }
+    }
+
+    /// Create an analyzer pinned to a specific prompt template version.
+    pub fn with_version(
+        options: AnalysisOptions,
+        version: &str,
+    ) -> Result<Self, MarketplaceError> {
+        let (_, build_prompt) = PROMPT_TEMPLATES
+            .iter()
+            .find(|(v, _)| *v == version)
+            .ok_or_else(|| MarketplaceError::AgentUnavailable {
+                agent_id: agents::CSV_ANALYZER.to_string(),
+                reason: format!("Unknown CSV analyzer version: {}", version),
+            })?;
+        Ok(Self {
+            options,
+            build_prompt: *build_prompt,
+        })
     }
 
     /// Analyze CSV data and generate insights
-    pub async fn analyze(&self, csv_data: &[u8]) -> Result<String, String> {
+    pub async fn analyze(
+        &self,
+        csv_data: &[u8],
+        usage: &mut Usage,
+    ) -> Result<String, MarketplaceError> {
         if csv_data.is_empty() {
-            return Err("CSV data cannot be empty".to_string());
+            return Err(MarketplaceError::invalid("csv", "CSV data cannot be empty"));
         }
 
         // Validate CSV size (150 MB max)
         if csv_data.len() > 150 * 1024 * 1024 {
-            return Err("CSV file exceeds maximum size of 150 MB".to_string());
+            return Err(MarketplaceError::InputTooLarge {
+                max: 150 * 1024 * 1024,
+            });
         }
 
         // Parse CSV to get a preview and structure
-        let csv_string = String::from_utf8(csv_data.to_vec())
-            .map_err(|e| format!("Failed to parse CSV as UTF-8: {}", e))?;
-
-        // Extract first few rows for context
-        let preview = self.extract_preview(&csv_string);
-        let structure = self.analyze_structure(&csv_string);
+        let csv_string = String::from_utf8(csv_data.to_vec()).map_err(|e| {
+            MarketplaceError::invalid("csv", format!("Failed to parse CSV as UTF-8: {}", e))
+        })?;
 
         // Build the prompt based on preset and options
-        let prompt = self.build_prompt(&preview, &structure);
+        let prompt = self.prompt(&csv_string);
+        let structure = self.analyze_structure(&csv_string);
 
-        ic_cdk::println!(
-            "Analyzing CSV with preset: {}, include_visuals: {}, preview length: {}, structure: {}",
-            self.options.preset,
-            self.options.include_visuals,
-            preview.len(),
-            structure
+        log::debug(
+            None,
+            format!(
+                "Analyzing CSV with preset: {}, include_visuals: {}, structure: {}, prompt length: {}",
+                self.options.preset,
+                self.options.include_visuals,
+                log::content(&structure),
+                prompt.len()
+            ),
         );
-        
-        ic_cdk::println!("Prompt length: {}", prompt.len());
-        ic_cdk::println!("Prompt preview (first 500 chars): {}", &prompt.chars().take(500).collect::<String>());
 
         // Call LLM to generate analysis
-        ic_cdk::println!("Calling LLM...");
-        let analysis = ic_llm::prompt(Model::Qwen3_32B, &prompt).await;
-        ic_cdk::println!("LLM response length: {}", analysis.len());
+        let analysis = costs::prompt(usage, &prompt).await;
         
         if analysis.is_empty() {
-            ic_cdk::println!("WARNING: LLM returned empty response");
-            return Err("LLM returned an empty analysis. Please try again.".to_string());
+            log::warn(None, "LLM returned an empty CSV analysis");
+            return Err(MarketplaceError::LlmUnavailable);
         }
 
-        ic_cdk::println!("Analysis generated successfully, length: {}", analysis.len());
+        log::debug(None, format!("CSV analysis generated, length: {}", analysis.len()));
         Ok(analysis)
     }
 
+    /// The prompt for `csv`, built from its first rows and structure with
+    /// this analyzer's template version.
+    pub fn prompt(&self, csv: &str) -> String {
+        let preview = self.extract_preview(csv);
+        let structure = self.analyze_structure(csv);
+        (self.build_prompt)(self, &preview, &structure)
+    }
+
     fn extract_preview(&self, csv: &str) -> String {
         // Extract first 10 rows for context
         csv.lines()
@@ -105,7 +179,7 @@ impl CsvAnalyzer {
         )
     }
 
-    fn build_prompt(&self, preview: &str, structure: &str) -> String {
+    fn build_prompt_v1(&self, preview: &str, structure: &str) -> String {
         let preset_instructions = match self.options.preset.as_str() {
             "Trends" => {
                 "Identify key movements, patterns, and correlated variables in the data. \
@@ -0,0 +1,105 @@
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+/// Errors returned by the `v2_` methods. The original methods return the same
+/// errors rendered as text.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum MarketplaceError {
+    JobNotFound,
+    /// Something other than a job, such as a pipeline or an API key.
+    NotFound { resource: String },
+    UnknownAgent { agent_id: String },
+    /// The agent exists but the requested version cannot be used.
+    AgentUnavailable { agent_id: String, reason: String },
+    PaymentPending,
+    PaymentFailed,
+    /// The quote lapsed at `expired_at`; request a new one.
+    QuoteExpired { expired_at: u64 },
+    /// Funds could not be taken from the ledger or prepaid credit.
+    TransferFailed { reason: String },
+    AlreadyExecuted,
+    InvalidInput { field: String, reason: String },
+    /// Input larger than `max` bytes.
+    InputTooLarge { max: u64 },
+    /// The LLM has no capacity left or produced no output.
+    LlmUnavailable,
+    Unauthorized { reason: String },
+    RateLimited { retry_after: u64 },
+    /// The request does not fit the current state, e.g. pausing a paused
+    /// schedule.
+    Conflict { reason: String },
+    Internal { reason: String },
+}
+
+impl MarketplaceError {
+    pub fn not_found(resource: &str) -> Self {
+        Self::NotFound {
+            resource: resource.to_string(),
+        }
+    }
+
+    pub fn invalid(field: &str, reason: impl Into<String>) -> Self {
+        Self::InvalidInput {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        Self::Unauthorized {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn conflict(reason: impl Into<String>) -> Self {
+        Self::Conflict {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn internal(reason: impl Into<String>) -> Self {
+        Self::Internal {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for MarketplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JobNotFound => f.write_str("Job not found"),
+            Self::NotFound { resource } => write!(f, "{} not found", resource),
+            Self::UnknownAgent { agent_id } => write!(f, "Unknown agent: {}", agent_id),
+            Self::PaymentPending => f.write_str(
+                "Payment not yet completed. Please wait for payment confirmation.",
+            ),
+            Self::PaymentFailed => f.write_str("Payment failed"),
+            Self::QuoteExpired { .. } => {
+                f.write_str("The quote has expired, request a new one")
+            }
+            Self::AlreadyExecuted => f.write_str("Job already executed"),
+            Self::InputTooLarge { max } => {
+                write!(f, "Input exceeds the maximum size of {} bytes", max)
+            }
+            Self::LlmUnavailable => {
+                f.write_str("The LLM is unavailable at the moment, try again shortly")
+            }
+            Self::RateLimited { retry_after } => {
+                write!(f, "Rate limit exceeded, retry in {} seconds", retry_after)
+            }
+            Self::AgentUnavailable { reason, .. }
+            | Self::TransferFailed { reason }
+            | Self::InvalidInput { reason, .. }
+            | Self::Unauthorized { reason }
+            | Self::Conflict { reason }
+            | Self::Internal { reason } => f.write_str(reason),
+        }
+    }
+}
+
+/// The text the original methods return.
+impl From<MarketplaceError> for String {
+    fn from(err: MarketplaceError) -> Self {
+        err.to_string()
+    }
+}
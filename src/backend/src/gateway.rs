@@ -0,0 +1,492 @@
+use candid::{CandidType, Deserialize};
+
+use crate::api_keys::{self, ApiKey};
+use crate::error::MarketplaceError;
+use crate::job_index;
+use crate::json::Json;
+use crate::{limits, metrics};
+use crate::pipeline::{StepData, StepStatus};
+use crate::{
+    agents, JobRequest, JobResult, PaymentInfo, PaymentRequest, PaymentStatus, Quote, BATCHES,
+    JOBS, JOB_OWNERS, PAYMENTS, PIPELINES, REFUNDS, RESULTS,
+};
+
+/// Largest request body accepted by the gateway.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// Request passed to `http_request` by the HTTP gateway.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    /// Asks the gateway to replay the request as an update call.
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body,
+            upgrade: None,
+        }
+    }
+
+    fn json(status_code: u16, body: Json) -> Self {
+        Self::new(
+            status_code,
+            "application/json",
+            body.to_string().into_bytes(),
+        )
+    }
+
+    fn error(status_code: u16, message: &str) -> Self {
+        Self::json(status_code, Json::object([("error", message.into())]))
+    }
+
+    fn upgrade() -> Self {
+        Self {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            upgrade: Some(true),
+        }
+    }
+
+    fn with_header(mut self, name: &str, value: String) -> Self {
+        self.headers.push((name.to_string(), value));
+        self
+    }
+}
+
+/// The routes served by the gateway.
+enum Route<'a> {
+    /// `POST /v1/agents/{id}/quote`
+    Quote { agent_id: &'a str },
+    /// `POST /v1/jobs/{id}/payment`
+    Payment { job_id: &'a str },
+    /// `POST /v1/jobs/{id}/execute`
+    Execute { job_id: &'a str },
+    /// `GET /v1/jobs/{id}`
+    Job { job_id: &'a str },
+    /// `GET /v1/jobs/{id}/output`, optionally `?step={step_id}` for pipelines
+    Output { job_id: &'a str },
+    /// `GET /v1/jobs/{id}/items/{index}/output`
+    ItemOutput { job_id: &'a str, index: &'a str },
+}
+
+impl<'a> Route<'a> {
+    fn parse(path: &'a str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        Some(match segments.as_slice() {
+            ["v1", "agents", agent_id, "quote"] => Route::Quote { agent_id },
+            ["v1", "jobs", job_id, "payment"] => Route::Payment { job_id },
+            ["v1", "jobs", job_id, "execute"] => Route::Execute { job_id },
+            ["v1", "jobs", job_id] => Route::Job { job_id },
+            ["v1", "jobs", job_id, "output"] => Route::Output { job_id },
+            ["v1", "jobs", job_id, "items", index, "output"] => Route::ItemOutput { job_id, index },
+            _ => return None,
+        })
+    }
+
+    fn method(&self) -> &'static str {
+        match self {
+            Route::Quote { .. } | Route::Payment { .. } | Route::Execute { .. } => "POST",
+            Route::Job { .. } | Route::Output { .. } | Route::ItemOutput { .. } => "GET",
+        }
+    }
+}
+
+/// Split a request URL into its path and query parameters.
+fn split_url(url: &str) -> (&str, Vec<(&str, &str)>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect();
+    (path, params)
+}
+
+fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Resolve the API key sent in `Authorization: Bearer` or `X-Api-Key`.
+fn authenticate(request: &HttpRequest) -> Result<ApiKey, HttpResponse> {
+    let key = header(request, "authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| header(request, "x-api-key"))
+        .ok_or_else(|| {
+            HttpResponse::error(401, "Missing API key")
+                .with_header("WWW-Authenticate", "Bearer".to_string())
+        })?;
+
+    api_keys::authenticate(key.trim(), ic_cdk::api::time())
+        .map_err(|err| HttpResponse::error(401, &err.to_string()))
+}
+
+/// Match a request to a route and check its method and credentials.
+fn prepare(request: &HttpRequest) -> Result<(Route<'_>, ApiKey), HttpResponse> {
+    let (path, _) = split_url(&request.url);
+    let route = Route::parse(path).ok_or_else(|| HttpResponse::error(404, "Not found"))?;
+
+    if !request.method.eq_ignore_ascii_case(route.method()) {
+        return Err(HttpResponse::error(405, "Method not allowed")
+            .with_header("Allow", route.method().to_string()));
+    }
+    if request.body.len() > MAX_BODY_BYTES {
+        return Err(HttpResponse::error(413, "Request body is too large"));
+    }
+
+    let key = authenticate(request)?;
+    Ok((route, key))
+}
+
+/// Serve read-only routes; anything that changes state is upgraded to an
+/// update call.
+pub fn handle_query(request: HttpRequest) -> HttpResponse {
+    // Scraped without an API key; it only exposes aggregates.
+    if split_url(&request.url).0 == "/metrics" {
+        if !request.method.eq_ignore_ascii_case("GET") {
+            return HttpResponse::error(405, "Method not allowed")
+                .with_header("Allow", "GET".to_string());
+        }
+        return HttpResponse::new(
+            200,
+            "text/plain; version=0.0.4",
+            metrics::render().into_bytes(),
+        );
+    }
+
+    let (route, key) = match prepare(&request) {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    match route {
+        Route::Job { job_id } => get_job(&key, job_id),
+        Route::Output { job_id } => {
+            let (_, params) = split_url(&request.url);
+            let step = params
+                .iter()
+                .find(|(key, _)| *key == "step")
+                .map(|(_, value)| *value);
+            get_output(&key, job_id, step)
+        }
+        Route::ItemOutput { job_id, index } => get_item_output(&key, job_id, index),
+        Route::Quote { .. } | Route::Payment { .. } | Route::Execute { .. } => {
+            HttpResponse::upgrade()
+        }
+    }
+}
+
+pub async fn handle_update(request: HttpRequest) -> HttpResponse {
+    let (route, key) = match prepare(&request) {
+        Ok(prepared) => prepared,
+        Err(response) => return response,
+    };
+
+    match route {
+        Route::Quote { agent_id } => quote(&key, agent_id, &request.body).await,
+        Route::Payment { job_id } => initiate_payment(&key, job_id).await,
+        Route::Execute { job_id } => execute(&key, job_id).await,
+        Route::Job { .. } | Route::Output { .. } | Route::ItemOutput { .. } => {
+            handle_query(request)
+        }
+    }
+}
+
+/// A job owned by the key's principal and within the key's scope. Jobs of
+/// other accounts are reported as missing.
+fn owned_job(key: &ApiKey, job_id: &str) -> Result<JobRequest, HttpResponse> {
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(job_id).copied());
+    let job = JOBS.with(|jobs| jobs.borrow().get(job_id).cloned());
+
+    match job {
+        Some(job) if owner == Some(key.owner) => {
+            api_keys::authorize_agent(key, &job.agent_id).map_err(|err| failure(&err))?;
+            Ok(job)
+        }
+        _ => Err(HttpResponse::error(404, "Job not found")),
+    }
+}
+
+async fn quote(key: &ApiKey, agent_id: &str, body: &[u8]) -> HttpResponse {
+    if agents::known_versions(agent_id).is_none() {
+        return HttpResponse::error(404, "Agent not found");
+    }
+    if let Err(err) = api_keys::authorize_agent(key, agent_id) {
+        return failure(&err);
+    }
+
+    let body = match std::str::from_utf8(body)
+        .map_err(|_| "Body must be UTF-8".to_string())
+        .and_then(Json::parse)
+    {
+        Ok(body) => body,
+        Err(err) => return HttpResponse::error(400, &format!("Invalid JSON: {}", err)),
+    };
+    let Some(request) = body.get("request").and_then(Json::as_str) else {
+        return HttpResponse::error(400, "Field 'request' must be a string");
+    };
+    let version = match body.get("version") {
+        None | Some(Json::Null) => None,
+        Some(Json::String(version)) => Some(version.clone()),
+        Some(_) => return HttpResponse::error(400, "Field 'version' must be a string"),
+    };
+
+    if let Err(throttled) = limits::check(key.owner, agent_id, 1, ic_cdk::api::time()) {
+        return HttpResponse::error(429, &throttled.to_string())
+            .with_header("Retry-After", throttled.retry_after.to_string());
+    }
+
+    match crate::quote_job(key.owner, agent_id, request.to_string(), version).await {
+        Ok(quote) => {
+            api_keys::record_quote(key, ic_cdk::api::time());
+            HttpResponse::json(201, quote_json(&quote))
+        }
+        Err(err) => failure(&err),
+    }
+}
+
+async fn initiate_payment(key: &ApiKey, job_id: &str) -> HttpResponse {
+    if let Err(response) = owned_job(key, job_id) {
+        return response;
+    }
+    if PAYMENTS.with(|payments| payments.borrow().contains_key(job_id)) {
+        return HttpResponse::error(409, "Payment already initiated for this job");
+    }
+
+    match crate::initiate_payment(job_id.to_string(), None).await {
+        Ok(request) => HttpResponse::json(201, payment_request_json(&request)),
+        Err(err) => failure(&err),
+    }
+}
+
+async fn execute(key: &ApiKey, job_id: &str) -> HttpResponse {
+    let job = match owned_job(key, job_id) {
+        Ok(job) => job,
+        Err(response) => return response,
+    };
+    if job_status(job_id) != "paid" {
+        if let Err(err) = crate::ensure_paid(job_id) {
+            // A job without a payment has not been paid for either.
+            return HttpResponse::error(402, &err.to_string());
+        }
+        return HttpResponse::error(409, "Job already executed");
+    }
+    if let Err(err) = api_keys::charge(key, job.price, ic_cdk::api::time()) {
+        return HttpResponse::error(402, &err.to_string());
+    }
+
+    let job_id = job_id.to_string();
+    let response = match job.agent_id.as_str() {
+        agents::PIPELINE => crate::execute_pipeline(job_id.clone())
+            .map(|_| HttpResponse::json(202, job_json(&job_id, &job))),
+        agents::BATCH => crate::execute_batch(job_id.clone())
+            .map(|_| HttpResponse::json(202, job_json(&job_id, &job))),
+        _ => crate::execute_job(job_id, None)
+            .await
+            .map(|result| HttpResponse::json(200, result_json(&result))),
+    };
+
+    response.unwrap_or_else(|err| {
+        api_keys::release(key, job.price, ic_cdk::api::time());
+        failure(&err)
+    })
+}
+
+/// Response for an error returned by the canister API.
+fn failure(err: &MarketplaceError) -> HttpResponse {
+    let status = match err {
+        MarketplaceError::JobNotFound
+        | MarketplaceError::NotFound { .. }
+        | MarketplaceError::UnknownAgent { .. } => 404,
+        MarketplaceError::PaymentPending
+        | MarketplaceError::PaymentFailed
+        | MarketplaceError::TransferFailed { .. } => 402,
+        MarketplaceError::AlreadyExecuted | MarketplaceError::Conflict { .. } => 409,
+        MarketplaceError::AgentUnavailable { .. } | MarketplaceError::InvalidInput { .. } => 422,
+        MarketplaceError::InputTooLarge { .. } => 413,
+        MarketplaceError::QuoteExpired { .. } => 410,
+        MarketplaceError::Unauthorized { .. } => 403,
+        MarketplaceError::RateLimited { .. } => 429,
+        MarketplaceError::LlmUnavailable => 503,
+        MarketplaceError::Internal { .. } => 500,
+    };
+
+    let response = HttpResponse::error(status, &err.to_string());
+    match err {
+        MarketplaceError::RateLimited { retry_after } => {
+            response.with_header("Retry-After", retry_after.to_string())
+        }
+        MarketplaceError::LlmUnavailable => response.with_header("Retry-After", "5".to_string()),
+        _ => response,
+    }
+}
+
+fn get_job(key: &ApiKey, job_id: &str) -> HttpResponse {
+    match owned_job(key, job_id) {
+        Ok(job) => HttpResponse::json(200, job_json(job_id, &job)),
+        Err(response) => response,
+    }
+}
+
+fn get_output(key: &ApiKey, job_id: &str, step: Option<&str>) -> HttpResponse {
+    let job = match owned_job(key, job_id) {
+        Ok(job) => job,
+        Err(response) => return response,
+    };
+
+    match job.agent_id.as_str() {
+        agents::PIPELINE => {
+            let output = PIPELINES.with(|pipelines| {
+                let pipelines = pipelines.borrow();
+                let pipeline = pipelines.get(job_id)?;
+                let step = match step {
+                    Some(step_id) => pipeline.step(step_id),
+                    None => pipeline.steps.last(),
+                };
+                Some(step.map(|step| (step.status.clone(), step.output.clone())))
+            });
+
+            match output.flatten() {
+                Some((StepStatus::Succeeded, Some(output))) => data_response(job_id, output),
+                Some(_) => HttpResponse::error(409, "Step has not produced an output"),
+                None => HttpResponse::error(404, "Step not found"),
+            }
+        }
+        agents::BATCH => HttpResponse::error(400, "Batch outputs are served per item"),
+        _ => match RESULTS.with(|results| results.borrow().get(job_id).cloned()) {
+            Some(result) => data_response(job_id, StepData::Text(result.output)),
+            None => HttpResponse::error(409, "Job has not produced an output"),
+        },
+    }
+}
+
+fn get_item_output(key: &ApiKey, job_id: &str, index: &str) -> HttpResponse {
+    if let Err(response) = owned_job(key, job_id) {
+        return response;
+    }
+    let Ok(index) = index.parse::<usize>() else {
+        return HttpResponse::error(400, "Item index must be a number");
+    };
+
+    let output = BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .get(job_id)
+            .map(|batch| batch.items.get(index).map(|item| item.output.clone()))
+    });
+
+    match output {
+        None => HttpResponse::error(404, "Job is not a batch"),
+        Some(None) => HttpResponse::error(404, "Item not found"),
+        Some(Some(None)) => HttpResponse::error(409, "Item has not produced an output"),
+        Some(Some(Some(output))) => data_response(&format!("{}-{}", job_id, index), output),
+    }
+}
+
+/// Serve a step or item output: PDFs as downloads, other bytes as binary
+/// and text as plain text.
+fn data_response(name: &str, data: StepData) -> HttpResponse {
+    match data {
+        StepData::Bytes(bytes) if bytes.starts_with(b"%PDF") => {
+            HttpResponse::new(200, "application/pdf", bytes).with_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.pdf\"", name),
+            )
+        }
+        StepData::Bytes(bytes) => HttpResponse::new(200, "application/octet-stream", bytes),
+        StepData::Text(text) => {
+            HttpResponse::new(200, "text/plain; charset=utf-8", text.into_bytes())
+        }
+    }
+}
+
+/// Where a job is in its lifecycle.
+pub fn job_status(job_id: &str) -> &'static str {
+    job_index::status(job_id).name()
+}
+
+fn quote_json(quote: &Quote) -> Json {
+    Json::object([
+        ("job_id", quote.job_id.as_str().into()),
+        ("price", quote.price.into()),
+        ("currency", quote.currency.as_str().into()),
+        ("agent_id", quote.agent_id.as_str().into()),
+        ("agent_version", quote.agent_version.as_str().into()),
+        ("prompt_hash", quote.prompt_hash.clone().into()),
+        ("expires_at", quote.expires_at.into()),
+    ])
+}
+
+fn payment_request_json(request: &PaymentRequest) -> Json {
+    Json::object([
+        ("job_id", request.job_id.as_str().into()),
+        ("amount", request.amount.into()),
+        ("currency", request.currency.as_str().into()),
+    ])
+}
+
+fn payment_json(payment: &PaymentInfo) -> Json {
+    let status = match payment.status {
+        PaymentStatus::Pending => "pending",
+        PaymentStatus::Completed => "completed",
+        PaymentStatus::Failed => "failed",
+    };
+    Json::object([
+        ("job_id", payment.job_id.as_str().into()),
+        ("status", status.into()),
+        ("transaction_id", payment.transaction_id.clone().into()),
+    ])
+}
+
+fn result_json(result: &JobResult) -> Json {
+    Json::object([
+        ("job_id", result.job_id.as_str().into()),
+        ("output", result.output.as_str().into()),
+        ("completed_at", result.completed_at.into()),
+    ])
+}
+
+fn job_json(job_id: &str, job: &JobRequest) -> Json {
+    let payment = PAYMENTS.with(|payments| payments.borrow().get(job_id).map(payment_json));
+    let result = RESULTS.with(|results| results.borrow().get(job_id).map(result_json));
+    let refunded = REFUNDS.with(|refunds| refunds.borrow().get(job_id).map(|r| r.amount));
+
+    Json::object([
+        ("job_id", job_id.into()),
+        ("status", job_status(job_id).into()),
+        ("agent_id", job.agent_id.as_str().into()),
+        ("agent_version", job.agent_version.as_str().into()),
+        ("price", job.price.into()),
+        ("currency", "ICP".into()),
+        ("created_at", job.created_at.into()),
+        ("expires_at", job.expires_at.into()),
+        ("payment", payment.unwrap_or(Json::Null)),
+        ("result", result.unwrap_or(Json::Null)),
+        ("refunded", refunded.into()),
+    ])
+}
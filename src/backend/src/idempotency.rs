@@ -0,0 +1,358 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::future::Future;
+
+use crate::error::MarketplaceError;
+use crate::IDEMPOTENCY_KEYS;
+
+/// How long a key keeps returning the original response.
+const WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Minimum time between sweeps for expired keys.
+const SWEEP_INTERVAL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+const MAX_KEY_LENGTH: usize = 128;
+
+/// A key is scoped to the principal that used it.
+pub type Scope = (Principal, String);
+
+/// A call made with an idempotency key.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IdempotentCall {
+    pub method: String,
+    /// Hash of the arguments, so a key cannot be reused for another request.
+    pub fingerprint: String,
+    /// Candid encoding of the successful response; `None` while the call is
+    /// still running or after it failed.
+    pub response: Option<Vec<u8>>,
+    pub expires_at: u64,
+    /// Whether an attempt is running; `None` in snapshots taken before this
+    /// field was added, whose calls are released on upgrade anyway.
+    pub running: Option<bool>,
+    /// When the key was first used. Failed attempts keep it, so a retried
+    /// ledger transfer repeats the first one's creation time.
+    pub first_used_at: Option<u64>,
+}
+
+impl IdempotentCall {
+    fn running(&self) -> bool {
+        self.running.unwrap_or(false) && self.response.is_none()
+    }
+}
+
+thread_local! {
+    static LAST_SWEEP: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Holds a key while its call runs. Dropping it without `finish`, including
+/// when the call traps, releases the key so the request can be retried.
+struct Claim {
+    scope: Scope,
+    finished: bool,
+}
+
+impl Claim {
+    fn finish(mut self, response: Vec<u8>) {
+        self.finished = true;
+        IDEMPOTENCY_KEYS.with(|keys| {
+            if let Some(call) = keys.borrow_mut().get_mut(&self.scope) {
+                call.response = Some(response);
+                call.running = Some(false);
+            }
+        });
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if !self.finished {
+            IDEMPOTENCY_KEYS.with(|keys| {
+                if let Some(call) = keys.borrow_mut().get_mut(&self.scope) {
+                    call.running = Some(false);
+                }
+            });
+        }
+    }
+}
+
+fn sweep(now: u64) {
+    if now.saturating_sub(LAST_SWEEP.with(Cell::get)) < SWEEP_INTERVAL_NANOS {
+        return;
+    }
+    LAST_SWEEP.with(|last| last.set(now));
+    IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().retain(|_, call| call.expires_at > now));
+}
+
+/// Release calls that were still running when the canister was upgraded;
+/// their futures are gone, so they will never finish.
+pub fn forget_unfinished() {
+    IDEMPOTENCY_KEYS.with(|keys| {
+        for call in keys.borrow_mut().values_mut() {
+            call.running = Some(false);
+        }
+    });
+}
+
+/// The memo and creation time for a ledger transfer made by `method` for
+/// `owner`. Under an idempotency key both are the same for every attempt,
+/// so the ledger rejects a retried transfer that already went through as
+/// a duplicate. Call it from within the call [`once`] runs.
+pub fn ledger_tag(owner: Principal, key: Option<&str>, method: &str) -> (Vec<u8>, u64) {
+    let now = ic_cdk::api::time();
+    let Some(key) = key else {
+        return (method.as_bytes().to_vec(), now);
+    };
+    let first_used_at = IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow()
+            .get(&(owner, key.to_string()))
+            .and_then(|call| call.first_used_at)
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(owner.as_slice());
+    hasher.update(key.as_bytes());
+    (hasher.finalize().to_vec(), first_used_at.unwrap_or(now))
+}
+
+/// A call to `method` made by `owner` with an idempotency key.
+pub struct Keyed {
+    scope: Scope,
+    method: &'static str,
+    fingerprint: String,
+}
+
+/// Describe a call for [`once`]; `None` when the client sent no key.
+///
+/// The arguments are hashed here, so the call itself can take ownership of
+/// them afterwards.
+pub fn keyed(
+    owner: Principal,
+    key: Option<String>,
+    method: &'static str,
+    args: &impl CandidType,
+) -> Option<Keyed> {
+    let key = key?;
+    let fingerprint = candid::encode_one(args)
+        .map(|encoded| hex::encode(Sha256::digest(&encoded)))
+        .unwrap_or_default();
+    Some(Keyed {
+        scope: (owner, key),
+        method,
+        fingerprint,
+    })
+}
+
+/// Run `call` at most once per key within the window.
+///
+/// A repeated key with the same arguments returns the first successful
+/// response without running `call` again. Errors are not kept, so a failed
+/// call can be retried with the same key, or the key used for another
+/// request. Without a key `call` always runs.
+pub async fn once<T>(
+    keyed: Option<Keyed>,
+    call: impl Future<Output = Result<T, MarketplaceError>>,
+) -> Result<T, MarketplaceError>
+where
+    T: CandidType + DeserializeOwned,
+{
+    once_at(keyed, ic_cdk::api::time(), call).await
+}
+
+async fn once_at<T>(
+    keyed: Option<Keyed>,
+    now: u64,
+    call: impl Future<Output = Result<T, MarketplaceError>>,
+) -> Result<T, MarketplaceError>
+where
+    T: CandidType + DeserializeOwned,
+{
+    let Some(Keyed {
+        scope,
+        method,
+        fingerprint,
+    }) = keyed
+    else {
+        return call.await;
+    };
+    if scope.1.is_empty() || scope.1.len() > MAX_KEY_LENGTH {
+        return Err(MarketplaceError::invalid(
+            "idempotency_key",
+            format!(
+                "Idempotency key must be between 1 and {} characters",
+                MAX_KEY_LENGTH
+            ),
+        ));
+    }
+
+    sweep(now);
+
+    let previous = IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow()
+            .get(&scope)
+            .filter(|call| call.expires_at > now)
+            .cloned()
+    });
+    let mut first_used_at = now;
+    if let Some(previous) = previous {
+        let same = previous.method == method && previous.fingerprint == fingerprint;
+        if let Some(response) = previous.response.as_ref().filter(|_| same) {
+            return candid::decode_one(response)
+                .map_err(|e| MarketplaceError::internal(e.to_string()));
+        }
+        if previous.running() || previous.response.is_some() {
+            return Err(MarketplaceError::conflict(match same {
+                true => "A request with this idempotency key is still in progress",
+                false => "Idempotency key was already used for a different request",
+            }));
+        }
+        // A failed attempt: retry, from the same start when it is the same
+        // request.
+        if same {
+            first_used_at = previous.first_used_at.unwrap_or(now);
+        }
+    }
+
+    IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow_mut().insert(
+            scope.clone(),
+            IdempotentCall {
+                method: method.to_string(),
+                fingerprint,
+                response: None,
+                expires_at: first_used_at.saturating_add(WINDOW_NANOS),
+                running: Some(true),
+                first_used_at: Some(first_used_at),
+            },
+        )
+    });
+    let claim = Claim {
+        scope,
+        finished: false,
+    };
+
+    let result = call.await?;
+    if let Ok(response) = candid::encode_one(&result) {
+        claim.finish(response);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    /// Drive a call whose future never waits, as every call here is.
+    fn run<T: CandidType + DeserializeOwned>(
+        args: &str,
+        now: u64,
+        result: Result<T, MarketplaceError>,
+        runs: &Cell<u32>,
+    ) -> Result<T, MarketplaceError> {
+        let keyed = keyed(
+            Principal::anonymous(),
+            Some("key".to_string()),
+            "test",
+            &args,
+        );
+        let call = once_at(keyed, now, async {
+            runs.set(runs.get() + 1);
+            result
+        });
+        match pin!(call).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => panic!("Call did not finish"),
+        }
+    }
+
+    fn first_used_at() -> Option<u64> {
+        IDEMPOTENCY_KEYS.with(|keys| {
+            keys.borrow()
+                .get(&(Principal::anonymous(), "key".to_string()))
+                .and_then(|call| call.first_used_at)
+        })
+    }
+
+    #[test]
+    fn replays_the_first_response_within_the_window() {
+        let runs = Cell::new(0);
+        assert_eq!(run("a", HOUR, Ok(1u64), &runs).unwrap(), 1);
+        assert_eq!(run("a", 24 * HOUR, Ok(2u64), &runs).unwrap(), 1);
+        assert_eq!(runs.get(), 1);
+
+        let other = run("b", 2 * HOUR, Ok(3u64), &runs).unwrap_err();
+        assert!(
+            matches!(other, MarketplaceError::Conflict { .. }),
+            "{:?}",
+            other
+        );
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    fn keys_expire_after_the_window() {
+        let runs = Cell::new(0);
+        run("a", HOUR, Ok(1u64), &runs).unwrap();
+        assert_eq!(run("a", 25 * HOUR, Ok(2u64), &runs).unwrap(), 2);
+        assert_eq!(run("b", 49 * HOUR, Ok(3u64), &runs).unwrap(), 3);
+        assert_eq!(runs.get(), 3);
+        assert_eq!(first_used_at(), Some(49 * HOUR));
+
+        // Expired keys are swept once an hour has passed since the last
+        // sweep.
+        IDEMPOTENCY_KEYS.with(|keys| {
+            keys.borrow_mut().insert(
+                (Principal::anonymous(), "stale".to_string()),
+                IdempotentCall {
+                    method: "test".to_string(),
+                    fingerprint: String::new(),
+                    response: None,
+                    expires_at: 50 * HOUR,
+                    running: Some(false),
+                    first_used_at: None,
+                },
+            )
+        });
+        run("b", 72 * HOUR, Ok(4u64), &runs).unwrap();
+        assert_eq!(IDEMPOTENCY_KEYS.with(|keys| keys.borrow().len()), 1);
+    }
+
+    #[test]
+    fn failed_calls_can_be_retried_from_the_same_start() {
+        let runs = Cell::new(0);
+        let failed: Result<u64, _> = Err(MarketplaceError::internal("ledger unavailable"));
+        assert!(run("a", HOUR, failed, &runs).is_err());
+        assert_eq!(run("a", 2 * HOUR, Ok(1u64), &runs).unwrap(), 1);
+        assert_eq!(runs.get(), 2);
+        assert_eq!(first_used_at(), Some(HOUR));
+
+        // The window still runs from the first attempt.
+        assert_eq!(run("a", 25 * HOUR, Ok(2u64), &runs).unwrap(), 2);
+    }
+
+    #[test]
+    fn running_calls_block_the_key() {
+        let runs = Cell::new(0);
+        let failed: Result<u64, _> = Err(MarketplaceError::internal("trap"));
+        run("a", HOUR, failed, &runs).unwrap_err();
+        IDEMPOTENCY_KEYS.with(|keys| {
+            for call in keys.borrow_mut().values_mut() {
+                call.running = Some(true);
+            }
+        });
+        let conflict = run("a", HOUR, Ok(1u64), &runs).unwrap_err();
+        assert!(
+            matches!(conflict, MarketplaceError::Conflict { .. }),
+            "{:?}",
+            conflict
+        );
+
+        forget_unfinished();
+        assert_eq!(run("a", HOUR, Ok(1u64), &runs).unwrap(), 1);
+    }
+}
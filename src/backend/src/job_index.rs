@@ -0,0 +1,653 @@
+use candid::{CandidType, Deserialize, Principal};
+use sha2::{Digest, Sha256};
+use std::ops::Bound;
+
+use crate::batch::BatchStatus;
+use crate::btree::BTree;
+use crate::error::MarketplaceError;
+use crate::memory::{Memory, Region};
+use crate::pipeline::PipelineStatus;
+use crate::{JobRequest, PaymentStatus, BATCHES, JOBS, JOB_OWNERS, PAYMENTS, PIPELINES, RESULTS};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum JobStatus {
+    Quoted,
+    AwaitingPayment,
+    PaymentFailed,
+    Paid,
+    Running,
+    Completed,
+}
+
+impl JobStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Quoted => "quoted",
+            JobStatus::AwaitingPayment => "awaiting_payment",
+            JobStatus::PaymentFailed => "payment_failed",
+            JobStatus::Paid => "paid",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JobSort {
+    #[default]
+    CreatedAt,
+    /// Only completed jobs have a completion time, so other jobs are left out.
+    CompletedAt,
+}
+
+/// Which jobs to list or count. Every field narrows the result.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct JobFilter {
+    pub owner: Option<Principal>,
+    pub agent_id: Option<String>,
+    pub status: Option<JobStatus>,
+    /// Earliest time to include, compared with the sort time.
+    pub from: Option<u64>,
+    /// Latest time to include, compared with the sort time.
+    pub to: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct JobQuery {
+    pub filter: JobFilter,
+    pub sort: Option<JobSort>,
+    /// Newest first unless set.
+    pub ascending: Option<bool>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobListing {
+    pub job_id: String,
+    pub job: JobRequest,
+    pub status: JobStatus,
+    pub owner: Option<Principal>,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobPage {
+    pub jobs: Vec<JobListing>,
+    /// Set when more jobs match; pass it back to get the next page.
+    pub next_cursor: Option<String>,
+}
+
+/// The indexed fields of a job, as kept in stable memory. Owners and agents
+/// are kept as hashes, which is all filtering needs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    created_at: u64,
+    completed_at: Option<u64>,
+    owner: Option<Hash>,
+    agent: Hash,
+    status: JobStatus,
+}
+
+type Hash = [u8; 16];
+
+// The indexes are kept in one stable B-tree, so they survive upgrades
+// without being rebuilt. Each index is named by a tag and, for the owner,
+// agent and status indexes, the hash of the value it is for. A key is that
+// name followed by the time the index orders by and the job number, both big
+// endian so keys sort by time and then by job:
+//
+//   tag (1) | value hash (16) | time (8) | job number (8)
+//
+// Every index also has a count entry, keyed by its tag with COUNT set and its
+// value hash, whose value is the number of jobs in the index. The indexed
+// fields of each job are kept by job number in their own region, to find the
+// keys to remove when the job changes.
+
+const KEY_SIZE: usize = 33;
+type Key = [u8; KEY_SIZE];
+
+const CREATED: u8 = 0;
+const COMPLETED: u8 = 1;
+const BY_OWNER: u8 = 2;
+const BY_AGENT: u8 = 3;
+const BY_STATUS: u8 = 4;
+const COUNT: u8 = 0x80;
+
+const ENTRY_SIZE: u64 = 64;
+const PRESENT: u8 = 1;
+const HAS_COMPLETED: u8 = 2;
+const HAS_OWNER: u8 = 4;
+
+/// An index: its tag and the hash of the value it is for, or zeros.
+type Index = (u8, Hash);
+
+const STATUSES: [JobStatus; 6] = [
+    JobStatus::Quoted,
+    JobStatus::AwaitingPayment,
+    JobStatus::PaymentFailed,
+    JobStatus::Paid,
+    JobStatus::Running,
+    JobStatus::Completed,
+];
+
+fn tree() -> BTree<Region, KEY_SIZE> {
+    BTree::new(Region::JobIndex)
+}
+
+fn hash(bytes: &[u8]) -> Hash {
+    let digest = Sha256::digest(bytes);
+    digest[..16]
+        .try_into()
+        .expect("SHA-256 is longer than 16 bytes")
+}
+
+fn owner_index(owner: &Principal) -> Index {
+    (BY_OWNER, hash(owner.as_slice()))
+}
+
+fn agent_index(agent_id: &str) -> Index {
+    (BY_AGENT, hash(agent_id.as_bytes()))
+}
+
+fn status_index(status: JobStatus) -> Index {
+    (BY_STATUS, hash(status.name().as_bytes()))
+}
+
+fn key((tag, value): Index, at: u64, number: u64) -> Key {
+    let mut key = [0; KEY_SIZE];
+    key[0] = tag;
+    key[1..17].copy_from_slice(&value);
+    key[17..25].copy_from_slice(&at.to_be_bytes());
+    key[25..].copy_from_slice(&number.to_be_bytes());
+    key
+}
+
+/// Time and job number of an index key.
+fn position(key: &Key) -> (u64, u64) {
+    let at = u64::from_be_bytes(key[17..25].try_into().unwrap());
+    let number = u64::from_be_bytes(key[25..].try_into().unwrap());
+    (at, number)
+}
+
+fn count_key((tag, value): Index) -> Key {
+    key((tag | COUNT, value), 0, 0)
+}
+
+/// Number of a job, from its id as made by `generate_job_id`.
+fn job_number(job_id: &str) -> Option<u64> {
+    job_id.strip_prefix("job_")?.parse().ok()
+}
+
+fn job_id(number: u64) -> String {
+    format!("job_{:016}", number)
+}
+
+fn read_entry(number: u64) -> Option<Entry> {
+    let mut bytes = [0; ENTRY_SIZE as usize];
+    Region::JobEntries.read(number * ENTRY_SIZE, &mut bytes);
+    let flags = bytes[0];
+    if flags & PRESENT == 0 {
+        return None;
+    }
+    let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    Some(Entry {
+        created_at: u64_at(8),
+        completed_at: (flags & HAS_COMPLETED != 0).then(|| u64_at(16)),
+        owner: (flags & HAS_OWNER != 0).then(|| bytes[24..40].try_into().unwrap()),
+        agent: bytes[40..56].try_into().unwrap(),
+        status: STATUSES[(bytes[1] as usize).min(STATUSES.len() - 1)],
+    })
+}
+
+fn write_entry(number: u64, entry: &Entry) {
+    let mut bytes = [0; ENTRY_SIZE as usize];
+    bytes[0] = PRESENT;
+    bytes[1] = STATUSES
+        .iter()
+        .position(|status| *status == entry.status)
+        .unwrap_or_default() as u8;
+    bytes[8..16].copy_from_slice(&entry.created_at.to_le_bytes());
+    if let Some(completed_at) = entry.completed_at {
+        bytes[0] |= HAS_COMPLETED;
+        bytes[16..24].copy_from_slice(&completed_at.to_le_bytes());
+    }
+    if let Some(owner) = entry.owner {
+        bytes[0] |= HAS_OWNER;
+        bytes[24..40].copy_from_slice(&owner);
+    }
+    bytes[40..56].copy_from_slice(&entry.agent);
+    Region::JobEntries.write(number * ENTRY_SIZE, &bytes);
+}
+
+/// The index keys of a job.
+fn keys(entry: &Entry) -> Vec<(Index, u64)> {
+    let none = [0; 16];
+    let mut keys = vec![
+        ((CREATED, none), entry.created_at),
+        ((BY_AGENT, entry.agent), entry.created_at),
+        (status_index(entry.status), entry.created_at),
+    ];
+    if let Some(completed_at) = entry.completed_at {
+        keys.push(((COMPLETED, none), completed_at));
+    }
+    if let Some(owner) = entry.owner {
+        keys.push(((BY_OWNER, owner), entry.created_at));
+    }
+    keys
+}
+
+/// Status of a job, derived from its payment, execution and result.
+pub fn status(job_id: &str) -> JobStatus {
+    if RESULTS.with(|results| results.borrow().contains_key(job_id)) {
+        return JobStatus::Completed;
+    }
+
+    let started = PIPELINES.with(|pipelines| {
+        pipelines
+            .borrow()
+            .get(job_id)
+            .is_some_and(|pipeline| pipeline.status != PipelineStatus::Quoted)
+    }) || BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .get(job_id)
+            .is_some_and(|batch| batch.status != BatchStatus::Quoted)
+    });
+    if started {
+        return JobStatus::Running;
+    }
+
+    match PAYMENTS.with(|payments| payments.borrow().get(job_id).map(|p| p.status.clone())) {
+        Some(PaymentStatus::Completed) => JobStatus::Paid,
+        Some(PaymentStatus::Pending) => JobStatus::AwaitingPayment,
+        Some(PaymentStatus::Failed) => JobStatus::PaymentFailed,
+        None => JobStatus::Quoted,
+    }
+}
+
+/// Re-index a job after it was created or its status changed.
+pub fn update(job_id: &str) {
+    let Some(number) = job_number(job_id) else {
+        return;
+    };
+    let job = JOBS.with(|jobs| {
+        jobs.borrow()
+            .get(job_id)
+            .map(|job| (job.created_at, job.agent_id.clone()))
+    });
+    let Some((created_at, agent_id)) = job else {
+        return;
+    };
+    let entry = Entry {
+        created_at,
+        completed_at: RESULTS.with(|results| {
+            results
+                .borrow()
+                .get(job_id)
+                .map(|result| result.completed_at)
+        }),
+        owner: JOB_OWNERS.with(|owners| {
+            owners
+                .borrow()
+                .get(job_id)
+                .map(|owner| owner_index(owner).1)
+        }),
+        agent: agent_index(&agent_id).1,
+        status: status(job_id),
+    };
+
+    let old = read_entry(number);
+    if old.as_ref() == Some(&entry) {
+        return;
+    }
+    let tree = tree();
+    if let Some(old) = old {
+        for (index, at) in keys(&old) {
+            tree.remove(&key(index, at, number));
+            let count = tree.get(&count_key(index)).unwrap_or_default();
+            if count > 1 {
+                tree.insert(count_key(index), count - 1);
+            } else {
+                tree.remove(&count_key(index));
+            }
+        }
+    }
+    for (index, at) in keys(&entry) {
+        tree.insert(key(index, at, number), 0);
+        let count = tree.get(&count_key(index)).unwrap_or_default();
+        tree.insert(count_key(index), count + 1);
+    }
+    write_entry(number, &entry);
+}
+
+/// Build the indexes from the jobs if stable memory holds none yet, as on
+/// the first upgrade to a version that keeps them there.
+pub fn restore() {
+    let tree = tree();
+    if tree.is_initialized() {
+        return;
+    }
+    tree.clear();
+    let job_ids: Vec<String> = JOBS.with(|jobs| jobs.borrow().keys().cloned().collect());
+    for job_id in job_ids {
+        update(&job_id);
+    }
+}
+
+/// The index to walk for a query: the completion index when sorting by
+/// completion, otherwise the narrowest creation-ordered index the filter
+/// names.
+fn candidates(filter: &JobFilter, sort: JobSort) -> Option<Index> {
+    if sort == JobSort::CompletedAt {
+        return Some((COMPLETED, [0; 16]));
+    }
+    let tree = tree();
+    let mut narrowest = ((CREATED, [0; 16]), tree.get(&count_key((CREATED, [0; 16]))));
+    let named = [
+        filter.owner.as_ref().map(owner_index),
+        filter.agent_id.as_deref().map(agent_index),
+        filter.status.map(status_index),
+    ];
+    for index in named.into_iter().flatten() {
+        // A named value with no index entry matches no job.
+        let count = tree.get(&count_key(index))?;
+        if narrowest.1.is_none_or(|narrowest| count < narrowest) {
+            narrowest = (index, Some(count));
+        }
+    }
+    Some(narrowest.0)
+}
+
+fn matches(number: u64, filter: &JobFilter) -> bool {
+    read_entry(number).is_some_and(|entry| {
+        filter
+            .owner
+            .is_none_or(|owner| entry.owner == Some(owner_index(&owner).1))
+            && filter
+                .agent_id
+                .as_deref()
+                .is_none_or(|agent_id| entry.agent == agent_index(agent_id).1)
+            && filter.status.is_none_or(|status| entry.status == status)
+    })
+}
+
+/// Position of a job in a time-ordered index.
+type Cursor = (u64, u64);
+
+fn parse_cursor(cursor: &str) -> Result<Cursor, MarketplaceError> {
+    cursor
+        .split_once(':')
+        .and_then(|(at, job_id)| Some((at.parse().ok()?, job_number(job_id)?)))
+        .ok_or_else(|| MarketplaceError::invalid("cursor", "Invalid cursor"))
+}
+
+/// Walk the jobs in `index` within the filter's time range in sort order,
+/// starting after `cursor`, and pass the position of each one to `visit`
+/// until it returns false.
+fn walk(
+    index: Index,
+    filter: &JobFilter,
+    ascending: bool,
+    cursor: Option<Cursor>,
+    mut visit: impl FnMut(Cursor) -> bool,
+) {
+    let first = key(index, filter.from.unwrap_or(0), 0);
+    let last = key(index, filter.to.unwrap_or(u64::MAX), u64::MAX);
+    let (mut lower, mut upper) = (Bound::Included(&first), Bound::Included(&last));
+    let after = cursor.map(|(at, number)| key(index, at, number));
+    if let Some(after) = &after {
+        if ascending && after >= &first {
+            lower = Bound::Excluded(after);
+        } else if !ascending && after <= &last {
+            upper = Bound::Excluded(after);
+        }
+    }
+    tree().range(lower, upper, ascending, |key, _| visit(position(key)));
+}
+
+pub fn query(query: &JobQuery) -> Result<JobPage, MarketplaceError> {
+    let limit = query
+        .limit
+        .map_or(DEFAULT_PAGE_SIZE, |limit| limit as usize)
+        .clamp(1, MAX_PAGE_SIZE);
+    let sort = query.sort.unwrap_or_default();
+    let ascending = query.ascending.unwrap_or(false);
+    let cursor = query.cursor.as_deref().map(parse_cursor).transpose()?;
+
+    let mut positions: Vec<Cursor> = Vec::new();
+    let mut more = false;
+    if let Some(index) = candidates(&query.filter, sort) {
+        walk(index, &query.filter, ascending, cursor, |(at, number)| {
+            if !matches(number, &query.filter) {
+                return true;
+            }
+            if positions.len() == limit {
+                more = true;
+                return false;
+            }
+            positions.push((at, number));
+            true
+        });
+    }
+
+    let next_cursor = more
+        .then(|| {
+            positions
+                .last()
+                .map(|(at, number)| format!("{}:{}", at, job_id(*number)))
+        })
+        .flatten();
+    let jobs = positions
+        .into_iter()
+        .filter_map(|(_, number)| {
+            let job_id = job_id(number);
+            let job = JOBS.with(|jobs| jobs.borrow().get(&job_id).cloned())?;
+            let entry = read_entry(number)?;
+            Some(JobListing {
+                owner: JOB_OWNERS.with(|owners| owners.borrow().get(&job_id).copied()),
+                job_id,
+                job,
+                status: entry.status,
+                completed_at: entry.completed_at,
+            })
+        })
+        .collect();
+
+    Ok(JobPage { jobs, next_cursor })
+}
+
+/// Number of jobs matching `filter` within its time range, by creation time.
+pub fn count(filter: &JobFilter) -> u64 {
+    let Some(index) = candidates(filter, JobSort::CreatedAt) else {
+        return 0;
+    };
+    // With at most one of owner, agent and status named and no time range,
+    // every key of the chosen index matches.
+    let named = filter.owner.is_some() as u8
+        + filter.agent_id.is_some() as u8
+        + filter.status.is_some() as u8;
+    if named <= 1 && filter.from.is_none() && filter.to.is_none() {
+        return tree().get(&count_key(index)).unwrap_or_default();
+    }
+
+    let mut count = 0;
+    walk(index, filter, true, None, |(_, number)| {
+        if matches(number, filter) {
+            count += 1;
+        }
+        true
+    });
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JobResult, PaymentInfo};
+
+    fn owner(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    /// Record job `number`, created at `created_at`, and index it.
+    fn add(number: u64, created_at: u64, agent_id: &str, owner: Principal) -> String {
+        let job_id = job_id(number);
+        let job = JobRequest {
+            request: String::new(),
+            price: 0.1,
+            created_at,
+            agent_id: agent_id.to_string(),
+            agent_version: "1.0.0".to_string(),
+            prompt_hash: None,
+            cost: None,
+            expires_at: None,
+        };
+        JOBS.with(|jobs| jobs.borrow_mut().insert(job_id.clone(), job));
+        JOB_OWNERS.with(|owners| owners.borrow_mut().insert(job_id.clone(), owner));
+        update(&job_id);
+        job_id
+    }
+
+    fn complete(job_id: &str, completed_at: u64) {
+        let payment = PaymentInfo {
+            job_id: job_id.to_string(),
+            status: PaymentStatus::Completed,
+            transaction_id: None,
+        };
+        PAYMENTS.with(|payments| payments.borrow_mut().insert(job_id.to_string(), payment));
+        let result = JobResult {
+            job_id: job_id.to_string(),
+            output: String::new(),
+            completed_at,
+        };
+        RESULTS.with(|results| results.borrow_mut().insert(job_id.to_string(), result));
+        update(job_id);
+    }
+
+    /// Ids of every job the query matches, following cursors page by page.
+    fn all_pages(mut query: JobQuery) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        loop {
+            let page = super::query(&query).unwrap();
+            pages.push(
+                page.jobs
+                    .into_iter()
+                    .map(|listing| listing.job_id)
+                    .collect(),
+            );
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    fn ids(numbers: &[u64]) -> Vec<String> {
+        numbers.iter().map(|number| job_id(*number)).collect()
+    }
+
+    #[test]
+    fn cursors_page_through_every_job_once() {
+        // Jobs 3 and 4 share a creation time, so the job number breaks the tie.
+        for (number, created_at) in [(1, 10), (2, 20), (3, 30), (4, 30), (5, 50)] {
+            add(number, created_at, "summarizer", owner(1));
+        }
+
+        let query = JobQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let pages = all_pages(query.clone());
+        assert_eq!(pages, vec![ids(&[5, 4]), ids(&[3, 2]), ids(&[1])]);
+
+        let ascending = JobQuery {
+            ascending: Some(true),
+            ..query.clone()
+        };
+        assert_eq!(
+            all_pages(ascending),
+            vec![ids(&[1, 2]), ids(&[3, 4]), ids(&[5])]
+        );
+
+        // A page that ends with the last job still says whether more match.
+        let exact = JobQuery {
+            limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(all_pages(exact), vec![ids(&[5, 4, 3, 2, 1])]);
+
+        let cursor = JobQuery {
+            cursor: Some("not a cursor".to_string()),
+            ..query
+        };
+        assert!(super::query(&cursor).is_err());
+    }
+
+    #[test]
+    fn filters_narrow_the_listing_and_the_count() {
+        add(1, 10, "summarizer", owner(1));
+        add(2, 20, "analyzer", owner(1));
+        add(3, 30, "summarizer", owner(2));
+        add(4, 40, "summarizer", owner(1));
+        complete(&job_id(1), 100);
+        complete(&job_id(3), 60);
+
+        let listed = |filter: JobFilter| -> Vec<String> {
+            let query = JobQuery {
+                filter: filter.clone(),
+                ascending: Some(true),
+                ..Default::default()
+            };
+            let listed = all_pages(query).concat();
+            assert_eq!(count(&filter), listed.len() as u64);
+            listed
+        };
+
+        let by_owner = JobFilter {
+            owner: Some(owner(1)),
+            ..Default::default()
+        };
+        assert_eq!(listed(by_owner.clone()), ids(&[1, 2, 4]));
+        let summaries = JobFilter {
+            agent_id: Some("summarizer".to_string()),
+            ..by_owner.clone()
+        };
+        assert_eq!(listed(summaries.clone()), ids(&[1, 4]));
+        let completed = JobFilter {
+            status: Some(JobStatus::Completed),
+            ..summaries
+        };
+        assert_eq!(listed(completed), ids(&[1]));
+
+        let in_range = JobFilter {
+            from: Some(20),
+            to: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(listed(in_range), ids(&[2, 3]));
+        let quoted = JobFilter {
+            status: Some(JobStatus::Quoted),
+            ..Default::default()
+        };
+        assert_eq!(listed(quoted), ids(&[2, 4]));
+        let unknown = JobFilter {
+            agent_id: Some("translator".to_string()),
+            ..Default::default()
+        };
+        assert!(listed(unknown).is_empty());
+
+        // Sorting by completion leaves out unfinished jobs and orders by
+        // completion time.
+        let by_completion = JobQuery {
+            sort: Some(JobSort::CompletedAt),
+            ascending: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(all_pages(by_completion), vec![ids(&[3, 1])]);
+    }
+}
@@ -0,0 +1,98 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+use crate::error::MarketplaceError;
+
+/// ICP ledger canister, which implements ICRC-2.
+const ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+const E8S_PER_ICP: f64 = 100_000_000.0;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+pub fn icp_to_e8s(amount: f64) -> u64 {
+    (amount * E8S_PER_ICP).round() as u64
+}
+
+pub fn e8s_to_icp(e8s: u64) -> f64 {
+    e8s as f64 / E8S_PER_ICP
+}
+
+/// Pull `amount` ICP from `from` into this canister using an ICRC-2 allowance
+/// the owner granted beforehand. Returns the ledger block index.
+///
+/// The ledger deduplicates transfers with the same memo and creation time
+/// within a day; a duplicate returns the block of the original transfer.
+pub async fn transfer_from(
+    from: Principal,
+    amount: f64,
+    memo: &[u8],
+    created_at_time: u64,
+) -> Result<Nat, MarketplaceError> {
+    let ledger =
+        Principal::from_text(ICP_LEDGER).map_err(|e| MarketplaceError::internal(e.to_string()))?;
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: Nat::from(icp_to_e8s(amount)),
+        fee: None,
+        // ICRC-1 ledgers accept memos of at most 32 bytes.
+        memo: Some(memo.iter().take(32).cloned().collect()),
+        created_at_time: Some(created_at_time),
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, message)| MarketplaceError::TransferFailed {
+                reason: format!("Ledger call failed ({:?}): {}", code, message),
+            })?;
+
+    result.or_else(|err| {
+        let reason = match err {
+            TransferFromError::Duplicate { duplicate_of } => return Ok(duplicate_of),
+            TransferFromError::InsufficientAllowance { allowance } => {
+                format!("Insufficient allowance: {} e8s approved", allowance)
+            }
+            TransferFromError::InsufficientFunds { balance } => {
+                format!("Insufficient funds: balance is {} e8s", balance)
+            }
+            other => format!("Transfer failed: {:?}", other),
+        };
+        Err(MarketplaceError::TransferFailed { reason })
+    })
+}
@@ -1,10 +1,19 @@
-use candid::{CandidType, Deserialize};
-use ic_llm::Model;
+use candid::{CandidType, Deserialize, Principal};
 use std::cell::RefCell;
 use std::collections::HashMap;
 
+mod agents;
+use agents::{AgentVersion, Deprecation, Deprecations};
+
 mod pdf;
-use pdf::PdfCompressor;
+mod pdf_ccitt;
+mod pdf_dedup;
+mod pdf_font_subset;
+mod pdf_fonts;
+mod pdf_image;
+mod pdf_placement;
+mod pdf_writer;
+use pdf::{CompressedPdf, PdfCompressionOptions, PdfCompressor};
 
 mod text_summarizer;
 use text_summarizer::{TextSummarizer, SummarizationOptions};
@@ -12,12 +21,74 @@ use text_summarizer::{TextSummarizer, SummarizationOptions};
 mod csv_analyzer;
 use csv_analyzer::{CsvAnalyzer, AnalysisOptions};
 
+mod pipeline;
+use pipeline::{
+    Pipeline, PipelineQuote, PipelineRequest, PipelineStatus, StepAgent, StepData, StepQuote,
+};
+
+mod audit;
+use audit::{AuditAction, AuditEntry};
+
+mod batch;
+use batch::{Batch, BatchQuote, BatchRequest, BatchStatus, ItemQuote};
+
+mod btree;
+
+mod api_keys;
+use api_keys::{ApiKey, ApiKeyInfo, ApiKeyRequest, KeyUsage, NewApiKey};
+
+mod callbacks;
+use callbacks::{CallbackDelivery, JobCallback, JobCallbacks, JobOutcome};
+
+mod costs;
+use costs::{AgentCostReport, JobCost, Usage};
+
+mod cron;
+mod error;
+use error::MarketplaceError;
+
+mod gateway;
+use gateway::{HttpRequest, HttpResponse};
+
+mod idempotency;
+use idempotency::{IdempotentCall, Scope};
+
+mod job_index;
+use job_index::{JobFilter, JobPage, JobQuery};
+
+mod json;
+mod ledger;
+mod limits;
+mod log;
+use log::{LogEntry, LogFilter};
+
+mod memory;
+mod metrics;
+mod queue;
+
+mod schedule;
+use schedule::{Schedule, ScheduleRequest, ScheduleStatus};
+
+mod timers;
+mod v1;
+
+mod webhooks;
+use webhooks::{Webhook, WebhookDelivery, WebhookInfo, WebhookRegistration};
+
+/// How long a quote is honoured. The job must be paid for and run within it.
+const QUOTE_VALIDITY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 // Types for the API
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Quote {
     pub price: f64,
     pub currency: String,
     pub job_id: String,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub prompt_hash: Option<String>,
+    /// The quote must be paid for and run before this time.
+    pub expires_at: u64,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -46,6 +117,14 @@ pub struct JobRequest {
     pub request: String,
     pub price: f64,
     pub created_at: u64,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub prompt_hash: Option<String>,
+    /// Cycles-relevant resources spent on the job so far.
+    pub cost: Option<JobCost>,
+    /// When the quote stops being honoured. Jobs quoted before quotes
+    /// expired have none.
+    pub expires_at: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -55,53 +134,220 @@ pub struct JobResult {
     pub completed_at: u64,
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RefundInfo {
+    pub job_id: String,
+    pub amount: f64,
+    pub currency: String,
+    pub reason: String,
+    pub issued_at: u64,
+}
+
 // State management
 thread_local! {
     static JOBS: RefCell<HashMap<String, JobRequest>> = RefCell::default();
     static PAYMENTS: RefCell<HashMap<String, PaymentInfo>> = RefCell::default();
     static RESULTS: RefCell<HashMap<String, JobResult>> = RefCell::default();
-    static JOB_COUNTER: RefCell<u64> = RefCell::new(0);
+    static JOB_COUNTER: RefCell<u64> = const { RefCell::new(0) };
     static PDF_UPLOADS: RefCell<HashMap<String, Vec<u8>>> = RefCell::default();
+    static AGENT_DEPRECATIONS: RefCell<Deprecations> = RefCell::default();
+    static PIPELINES: RefCell<HashMap<String, Pipeline>> = RefCell::default();
+    static PIPELINE_INPUTS: RefCell<HashMap<String, StepData>> = RefCell::default();
+    static REFUNDS: RefCell<HashMap<String, RefundInfo>> = RefCell::default();
+    static BATCHES: RefCell<HashMap<String, Batch>> = RefCell::default();
+    static BATCH_INPUTS: RefCell<HashMap<String, Vec<Option<StepData>>>> = RefCell::default();
+    static SCHEDULES: RefCell<HashMap<String, Schedule>> = RefCell::default();
+    static SCHEDULE_INPUTS: RefCell<HashMap<String, StepData>> = RefCell::default();
+    static SCHEDULE_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    /// Prepaid credit balances in e8s.
+    static CREDITS: RefCell<HashMap<Principal, u64>> = RefCell::default();
+    static CALLBACKS: RefCell<HashMap<String, JobCallbacks>> = RefCell::default();
+    static JOB_OWNERS: RefCell<HashMap<String, Principal>> = RefCell::default();
+    static WEBHOOKS: RefCell<HashMap<Principal, Webhook>> = RefCell::default();
+    static WEBHOOK_DELIVERIES: RefCell<HashMap<Principal, Vec<WebhookDelivery>>> = RefCell::default();
+    static WEBHOOK_EVENT_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static API_KEYS: RefCell<HashMap<String, ApiKey>> = RefCell::default();
+    static API_KEY_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static API_KEY_USAGE: RefCell<HashMap<String, KeyUsage>> = RefCell::default();
+    static IDEMPOTENCY_KEYS: RefCell<HashMap<Scope, IdempotentCall>> = RefCell::default();
 }
 
-// Calculate the cost based on request complexity using AI
-async fn calculate_cost(request: &str) -> f64 {
-    let request_lower = request.to_lowercase();
-    
-    // Detect request type
-    let is_summarization = request_lower.contains("summarize");
-    let is_csv_analysis = request_lower.contains("csv") || request_lower.contains("analyze") && (request_lower.contains("dataset") || request_lower.contains("spreadsheet"));
-    
-    let (min_price, max_price, default_price) = if is_summarization {
-        (0.1, 0.5, 0.3) // Text summarization: 0.1 - 0.5 ICP
-    } else if is_csv_analysis {
-        (0.2, 1.0, 0.5) // CSV analysis: 0.2 - 1.0 ICP
+/// Canister state written to stable memory across upgrades.
+///
+/// Fields added after the first release must be `Option`s so that snapshots
+/// taken by older versions still decode.
+#[derive(CandidType, Deserialize, Default)]
+struct StableState {
+    jobs: HashMap<String, JobRequest>,
+    payments: HashMap<String, PaymentInfo>,
+    results: HashMap<String, JobResult>,
+    job_counter: u64,
+    agent_deprecations: Deprecations,
+    pipelines: HashMap<String, Pipeline>,
+    pipeline_inputs: HashMap<String, StepData>,
+    refunds: HashMap<String, RefundInfo>,
+    batches: HashMap<String, Batch>,
+    batch_inputs: HashMap<String, Vec<Option<StepData>>>,
+    schedules: HashMap<String, Schedule>,
+    schedule_inputs: HashMap<String, StepData>,
+    schedule_counter: u64,
+    /// Only read: balances are now kept in e8s in `credit_e8s`.
+    credits: HashMap<Principal, f64>,
+    queue: Vec<queue::Task>,
+    callbacks: Option<HashMap<String, JobCallbacks>>,
+    job_owners: Option<HashMap<String, Principal>>,
+    webhooks: Option<HashMap<Principal, Webhook>>,
+    webhook_deliveries: Option<HashMap<Principal, Vec<WebhookDelivery>>>,
+    webhook_event_counter: Option<u64>,
+    api_keys: Option<HashMap<String, ApiKey>>,
+    api_key_counter: Option<u64>,
+    api_key_usage: Option<HashMap<String, KeyUsage>>,
+    /// Only read: the audit log moved to stable memory.
+    audit_log: Option<Vec<AuditEntry>>,
+    idempotency_keys: Option<HashMap<Scope, IdempotentCall>>,
+    credit_e8s: Option<HashMap<Principal, u64>>,
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        jobs: JOBS.with(|jobs| jobs.take()),
+        payments: PAYMENTS.with(|payments| payments.take()),
+        results: RESULTS.with(|results| results.take()),
+        job_counter: JOB_COUNTER.with(|counter| *counter.borrow()),
+        agent_deprecations: AGENT_DEPRECATIONS.with(|deprecations| deprecations.take()),
+        pipelines: PIPELINES.with(|pipelines| pipelines.take()),
+        pipeline_inputs: PIPELINE_INPUTS.with(|inputs| inputs.take()),
+        refunds: REFUNDS.with(|refunds| refunds.take()),
+        batches: BATCHES.with(|batches| batches.take()),
+        batch_inputs: BATCH_INPUTS.with(|inputs| inputs.take()),
+        schedules: SCHEDULES.with(|schedules| schedules.take()),
+        schedule_inputs: SCHEDULE_INPUTS.with(|inputs| inputs.take()),
+        schedule_counter: SCHEDULE_COUNTER.with(|counter| *counter.borrow()),
+        credits: HashMap::new(),
+        queue: queue::snapshot(),
+        callbacks: Some(CALLBACKS.with(|callbacks| callbacks.take())),
+        job_owners: Some(JOB_OWNERS.with(|owners| owners.take())),
+        webhooks: Some(WEBHOOKS.with(|webhooks| webhooks.take())),
+        webhook_deliveries: Some(WEBHOOK_DELIVERIES.with(|deliveries| deliveries.take())),
+        webhook_event_counter: Some(WEBHOOK_EVENT_COUNTER.with(|counter| *counter.borrow())),
+        api_keys: Some(API_KEYS.with(|keys| keys.take())),
+        api_key_counter: Some(API_KEY_COUNTER.with(|counter| *counter.borrow())),
+        api_key_usage: Some(API_KEY_USAGE.with(|usage| usage.take())),
+        audit_log: None,
+        idempotency_keys: Some(IDEMPOTENCY_KEYS.with(|keys| keys.take())),
+        credit_e8s: Some(CREDITS.with(|credits| credits.take())),
+    };
+
+    let bytes = candid::encode_one(&state).expect("Failed to encode state");
+    memory::save_state(&bytes);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let state = if memory::has_layout() {
+        candid::decode_one::<StableState>(&memory::load_state())
+            .expect("Failed to restore state from stable memory")
+    } else if memory::has_v1_layout() {
+        let state = candid::decode_one::<StableState>(&memory::load_v1_state())
+            .expect("Failed to restore state from stable memory");
+        memory::upgrade_v1_layout();
+        state
     } else {
-        (0.1, 2.0, 0.5) // Other requests: 0.1 - 2.0 ICP
+        // Earlier versions saved the state at the start of stable memory, and
+        // canisters upgraded from versions without persistence have nothing
+        // to restore. The log takes over that space once it is read.
+        let state = ic_cdk::storage::stable_restore::<(StableState,)>()
+            .map(|(state,)| state)
+            .unwrap_or_default();
+        memory::init_layout();
+        state
     };
+    memory::layout_ready();
+
+    JOBS.with(|jobs| *jobs.borrow_mut() = state.jobs);
+    PAYMENTS.with(|payments| *payments.borrow_mut() = state.payments);
+    RESULTS.with(|results| *results.borrow_mut() = state.results);
+    JOB_COUNTER.with(|counter| *counter.borrow_mut() = state.job_counter);
+    AGENT_DEPRECATIONS.with(|deprecations| *deprecations.borrow_mut() = state.agent_deprecations);
+    PIPELINES.with(|pipelines| *pipelines.borrow_mut() = state.pipelines);
+    PIPELINE_INPUTS.with(|inputs| *inputs.borrow_mut() = state.pipeline_inputs);
+    REFUNDS.with(|refunds| *refunds.borrow_mut() = state.refunds);
+    BATCHES.with(|batches| *batches.borrow_mut() = state.batches);
+    BATCH_INPUTS.with(|inputs| *inputs.borrow_mut() = state.batch_inputs);
+    SCHEDULES.with(|schedules| *schedules.borrow_mut() = state.schedules);
+    SCHEDULE_INPUTS.with(|inputs| *inputs.borrow_mut() = state.schedule_inputs);
+    SCHEDULE_COUNTER.with(|counter| *counter.borrow_mut() = state.schedule_counter);
+    let credits = state.credit_e8s.unwrap_or_else(|| {
+        let legacy = state.credits.into_iter();
+        legacy.map(|(owner, icp)| (owner, ledger::icp_to_e8s(icp))).collect()
+    });
+    CREDITS.with(|balances| *balances.borrow_mut() = credits);
+    queue::restore(state.queue);
+    CALLBACKS.with(|callbacks| *callbacks.borrow_mut() = state.callbacks.unwrap_or_default());
+    JOB_OWNERS.with(|owners| *owners.borrow_mut() = state.job_owners.unwrap_or_default());
+    WEBHOOKS.with(|webhooks| *webhooks.borrow_mut() = state.webhooks.unwrap_or_default());
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        *deliveries.borrow_mut() = state.webhook_deliveries.unwrap_or_default()
+    });
+    WEBHOOK_EVENT_COUNTER.with(|counter| {
+        *counter.borrow_mut() = state.webhook_event_counter.unwrap_or_default()
+    });
+    API_KEYS.with(|keys| *keys.borrow_mut() = state.api_keys.unwrap_or_default());
+    API_KEY_COUNTER.with(|counter| *counter.borrow_mut() = state.api_key_counter.unwrap_or_default());
+    API_KEY_USAGE.with(|usage| *usage.borrow_mut() = state.api_key_usage.unwrap_or_default());
+    audit::restore(state.audit_log.unwrap_or_default());
+    IDEMPOTENCY_KEYS.with(|keys| *keys.borrow_mut() = state.idempotency_keys.unwrap_or_default());
+    idempotency::forget_unfinished();
+    job_index::restore();
+
+    // Timers do not survive upgrades: re-arm every active schedule, and the
+    // timer that drains the queue.
+    schedule::rearm_all();
+    callbacks::rearm_all();
+    webhooks::rearm_all();
+
+    // Work that was running during the upgrade was lost with its message.
+    pipeline::recover_interrupted();
+    batch::recover_interrupted();
+    queue::resume();
+}
+
+// Calculate the cost based on request complexity using AI
+async fn calculate_cost(
+    agent_id: &str,
+    request: &str,
+    usage: &mut Usage,
+) -> Result<f64, MarketplaceError> {
+    let _permit = limits::llm_permit()?;
+    let (min_price, max_price, default_price) = agents::price_range(agent_id);
     
     let prompt = format!(
         "Evaluate this request and determine a fair price for processing it. Consider the complexity, length, and computational requirements.\n\nIMPORTANT: Respond with ONLY a single decimal number between {} and {}. Do not include any text, explanation, or other characters. Just the number.\n\nRequest: {}\n\nPrice:",
         min_price, max_price, request
     );
     
-    ic_cdk::println!("Sending request to LLM: {}", prompt);
-    let response = ic_llm::prompt(Model::Qwen3_32B, &prompt).await;
-    ic_cdk::println!("LLM response: {}", response);
+    log::debug(None, format!("Pricing {} request: {}", agent_id, log::content(request)));
+    let response = costs::prompt(usage, &prompt).await;
     
     // Parse the response directly as a float
     let cleaned = response.trim();
-    ic_cdk::println!("Cleaned response: {}", cleaned);
 
-    match cleaned.parse::<f64>() {
+    Ok(match cleaned.parse::<f64>() {
         Ok(price) if price >= min_price && price <= max_price => {
-            ic_cdk::println!("Price {} is within valid range ({}-{})", price, min_price, max_price);
+            log::debug(None, format!("LLM priced {} request at {}", agent_id, price));
             // Round to 2 decimal places
             (price * 100.0).round() / 100.0
         }
         Ok(price) => {
             // Price out of range, clamp to valid range
-            ic_cdk::println!("Price {} out of range, clamping to {}-{}", price, min_price, max_price);
+            log::warn(
+                None,
+                format!(
+                    "LLM price {} for {} out of range, clamping to {}-{}",
+                    price, agent_id, min_price, max_price
+                ),
+            );
             if price < min_price {
                 min_price
             } else {
@@ -110,10 +356,23 @@ async fn calculate_cost(request: &str) -> f64 {
         }
         Err(_) => {
             // Fallback to default price if parsing fails
-            ic_cdk::println!("Failed to parse price from LLM response: {}", response);
+            log::warn(
+                None,
+                format!(
+                    "Unparseable LLM price for {}, using default: {}",
+                    agent_id,
+                    log::content(&response)
+                ),
+            );
             default_price
         }
-    }
+    })
+}
+
+/// Admit a caller for `calls` LLM-backed calls to an agent
+fn admit(caller: Principal, agent_id: &str, calls: u32) -> Result<(), MarketplaceError> {
+    limits::require_caller(caller)?;
+    Ok(limits::check(caller, agent_id, calls, ic_cdk::api::time())?)
 }
 
 fn generate_job_id() -> String {
@@ -125,214 +384,1282 @@ fn generate_job_id() -> String {
     format!("job_{:016}", counter)
 }
 
-/// Compress a PDF with the provided quality (1-100).
-#[ic_cdk::update]
-fn compress_pdf(pdf_bytes: Vec<u8>, quality: u8) -> Result<Vec<u8>, String> {
+fn resolve_agent_version(
+    agent_id: &str,
+    requested: Option<&str>,
+) -> Result<String, MarketplaceError> {
+    AGENT_DEPRECATIONS.with(|deprecations| {
+        agents::resolve_version(
+            agent_id,
+            requested,
+            &deprecations.borrow(),
+            ic_cdk::api::time(),
+        )
+    })
+}
+
+/// Version an agent call should run with: the one pinned on the job when a
+/// job id is given, otherwise the current version.
+///
+/// The job must belong to the caller and be quoted for this agent, since the
+/// call's costs are attributed to it. Jobs of other accounts are reported as
+/// missing.
+fn pinned_version(
+    caller: Principal,
+    job_id: Option<&str>,
+    agent_id: &str,
+) -> Result<String, MarketplaceError> {
+    let Some(job_id) = job_id else {
+        return resolve_agent_version(agent_id, None);
+    };
+
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(job_id).copied());
+    let job = JOBS.with(|jobs| jobs.borrow().get(job_id).cloned());
+    let job = job
+        .filter(|_| owner == Some(caller))
+        .ok_or(MarketplaceError::JobNotFound)?;
+
+    if job.agent_id != agent_id {
+        return Err(MarketplaceError::invalid(
+            "job_id",
+            format!("Job {} was quoted for agent {}", job_id, job.agent_id),
+        ));
+    }
+
+    resolve_agent_version(agent_id, Some(&job.agent_version))
+}
+
+/// Compress a PDF with the provided quality (1-100) or options, and report what changed
+#[ic_cdk::update(name = "v2_compress_pdf")]
+fn compress_pdf(
+    pdf_bytes: Vec<u8>,
+    quality: u8,
+    options: Option<PdfCompressionOptions>,
+) -> Result<CompressedPdf, MarketplaceError> {
     let quality = quality.clamp(1, 100);
-    let compressor = PdfCompressor::new(quality);
-    compressor.compress(pdf_bytes)
+    let compressor = PdfCompressor::with_options(options.unwrap_or_default().resolve(quality));
+    let compressed = compressor.compress(pdf_bytes)?;
+    let report = &compressed.report;
+    metrics::observe_compression(report.original_bytes as usize, report.compressed_bytes as usize);
+    Ok(compressed)
 }
 
 /// Summarize text with the provided tone and options.
-#[ic_cdk::update]
+/// When a job id is given, the prompt version pinned on that job is used.
+#[ic_cdk::update(name = "v2_summarize_text")]
 async fn summarize_text(
     text: String,
     tone: String,
     include_quotes: bool,
-) -> Result<String, String> {
-    let options = SummarizationOptions::new(tone, include_quotes);
-    let summarizer = TextSummarizer::new(options);
-    summarizer.summarize(&text).await
+    job_id: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<String, MarketplaceError> {
+    let keyed = idempotency::keyed(
+        ic_cdk::caller(),
+        idempotency_key,
+        "summarize_text",
+        &(&text, &tone, &include_quotes, &job_id),
+    );
+    idempotency::once(keyed, async move {
+        admit(ic_cdk::caller(), agents::TEXT_SUMMARIZER, 1)?;
+        let version = pinned_version(ic_cdk::caller(), job_id.as_deref(), agents::TEXT_SUMMARIZER)?;
+        let options = SummarizationOptions::new(tone, include_quotes);
+        let summarizer = TextSummarizer::with_version(options, &version)?;
+        let _permit = limits::llm_permit()?;
+
+        let started = costs::instructions();
+        let mut usage = Usage::default();
+        let summary = summarizer.summarize(&text, &mut usage).await;
+        usage.instructions = costs::instructions() - started;
+        if let Some(job_id) = &job_id {
+            costs::charge(job_id, &usage);
+        }
+        summary
+    })
+    .await
 }
 
 /// Analyze CSV data with the provided options.
-#[ic_cdk::update]
+/// When a job id is given, the prompt version pinned on that job is used.
+#[ic_cdk::update(name = "v2_analyze_csv")]
 async fn analyze_csv(
     csv_bytes: Vec<u8>,
     preset: String,
     primary_metric: Option<String>,
     segment_column: Option<String>,
     include_visuals: bool,
-) -> Result<String, String> {
-    let options = AnalysisOptions::new(
-        preset,
-        primary_metric,
-        segment_column,
-        include_visuals,
+    job_id: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<String, MarketplaceError> {
+    let keyed = idempotency::keyed(
+        ic_cdk::caller(),
+        idempotency_key,
+        "analyze_csv",
+        &(&csv_bytes, &preset, &primary_metric, &segment_column, &include_visuals, &job_id),
     );
-    let analyzer = CsvAnalyzer::new(options);
-    analyzer.analyze(&csv_bytes).await
+    idempotency::once(keyed, async move {
+        admit(ic_cdk::caller(), agents::CSV_ANALYZER, 1)?;
+        let version = pinned_version(ic_cdk::caller(), job_id.as_deref(), agents::CSV_ANALYZER)?;
+        let options = AnalysisOptions::new(
+            preset,
+            primary_metric,
+            segment_column,
+            include_visuals,
+        );
+        let analyzer = CsvAnalyzer::with_version(options, &version)?;
+        let _permit = limits::llm_permit()?;
+
+        let started = costs::instructions();
+        let mut usage = Usage::default();
+        let analysis = analyzer.analyze(&csv_bytes, &mut usage).await;
+        usage.instructions = costs::instructions() - started;
+        if let Some(job_id) = &job_id {
+            costs::charge(job_id, &usage);
+        }
+        analysis
+    })
+    .await
 }
 
 /// Get a quote for processing a request
-#[ic_cdk::update]
-async fn get_quote(request: String) -> Result<Quote, String> {
-    ic_cdk::println!("Getting quote for request: {}", request);
+#[ic_cdk::update(name = "v2_get_quote")]
+async fn get_quote(
+    request: String,
+    idempotency_key: Option<String>,
+) -> Result<Quote, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "get_quote", &request);
+    idempotency::once(keyed, async move {
+        let agent_id = agents::classify_request(&request);
+        admit(ic_cdk::caller(), agent_id, 1)?;
+        quote_job(ic_cdk::caller(), agent_id, request, None).await
+    })
+    .await
+}
+
+/// Get a quote for a specific agent, optionally pinned to an older version
+#[ic_cdk::update(name = "v2_get_agent_quote")]
+async fn get_agent_quote(
+    agent_id: String,
+    request: String,
+    version: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<Quote, MarketplaceError> {
+    let keyed = idempotency::keyed(
+        ic_cdk::caller(),
+        idempotency_key,
+        "get_agent_quote",
+        &(&agent_id, &request, &version),
+    );
+    idempotency::once(keyed, async move {
+        admit(ic_cdk::caller(), &agent_id, 1)?;
+        quote_job(ic_cdk::caller(), &agent_id, request, version).await
+    })
+    .await
+}
+
+async fn quote_job(
+    owner: Principal,
+    agent_id: &str,
+    request: String,
+    version: Option<String>,
+) -> Result<Quote, MarketplaceError> {
     if request.trim().is_empty() {
-        ic_cdk::println!("Request cannot be empty");
-        return Err("Request cannot be empty".to_string());
+        return Err(MarketplaceError::invalid("request", "Request cannot be empty"));
     }
 
-    let price = calculate_cost(&request).await;
-    ic_cdk::println!("Price: {}", price);
+    let agent_version = resolve_agent_version(agent_id, version.as_deref())?;
+    let prompt_hash = agents::prompt_hash(agent_id, &agent_version);
+
+    let mut usage = Usage::default();
+    let price = calculate_cost(agent_id, &request, &mut usage).await?;
     let job_id = generate_job_id();
-    ic_cdk::println!("Job ID: {}", job_id);
+    log::info(
+        Some(&job_id),
+        format!("Quoted {} {} at {} ICP", agent_id, agent_version, price),
+    );
     
     // Store the job request
+    let created_at = ic_cdk::api::time();
+    let expires_at = quote_expiry(created_at);
     let job_request = JobRequest {
         request: request.clone(),
         price,
-        created_at: ic_cdk::api::time(),
+        created_at,
+        agent_id: agent_id.to_string(),
+        agent_version: agent_version.clone(),
+        prompt_hash: prompt_hash.clone(),
+        cost: None,
+        expires_at: Some(expires_at),
     };
 
     JOBS.with(|jobs| {
         jobs.borrow_mut().insert(job_id.clone(), job_request);
     });
+    record_job_owner(&job_id, owner);
+    job_index::update(&job_id);
+    costs::charge(&job_id, &usage);
+    costs::track_storage(&job_id);
 
     Ok(Quote {
         price,
         currency: "ICP".to_string(),
         job_id,
+        agent_id: agent_id.to_string(),
+        agent_version,
+        prompt_hash,
+        expires_at,
     })
 }
 
 /// Initiate payment for a job
-#[ic_cdk::update]
-async fn initiate_payment(job_id: String) -> Result<PaymentRequest, String> {
-    // Check if job exists
-    let job = JOBS.with(|jobs| {
-        jobs.borrow().get(&job_id).cloned()
-    });
+#[ic_cdk::update(name = "v2_initiate_payment")]
+async fn initiate_payment(
+    job_id: String,
+    idempotency_key: Option<String>,
+) -> Result<PaymentRequest, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "initiate_payment", &job_id);
+    idempotency::once(keyed, async move {
+        // Check if job exists
+        let job = JOBS.with(|jobs| {
+            jobs.borrow().get(&job_id).cloned()
+        });
 
-    let job = job.ok_or_else(|| "Job not found".to_string())?;
+        let job = job.ok_or(MarketplaceError::JobNotFound)?;
+        if let Some(expires_at) = job.expires_at.filter(|at| *at <= ic_cdk::api::time()) {
+            return Err(MarketplaceError::QuoteExpired { expired_at: expires_at });
+        }
 
-    // Check if payment already exists
-    let payment_exists = PAYMENTS.with(|payments| {
-        payments.borrow().contains_key(&job_id)
-    });
+        // Check if payment already exists
+        let payment_exists = PAYMENTS.with(|payments| {
+            payments.borrow().contains_key(&job_id)
+        });
 
-    if payment_exists {
-        return Err("Payment already initiated for this job".to_string());
-    }
+        if payment_exists {
+            return Err(MarketplaceError::conflict(
+                "Payment already initiated for this job",
+            ));
+        }
 
-    // Create payment request
-    let payment_info = PaymentInfo {
-        job_id: job_id.clone(),
-        status: PaymentStatus::Pending,
-        transaction_id: None,
-    };
+        // Create payment request
+        let payment_info = PaymentInfo {
+            job_id: job_id.clone(),
+            status: PaymentStatus::Pending,
+            transaction_id: None,
+        };
 
-    PAYMENTS.with(|payments| {
-        payments.borrow_mut().insert(job_id.clone(), payment_info);
-    });
+        PAYMENTS.with(|payments| {
+            payments.borrow_mut().insert(job_id.clone(), payment_info);
+        });
+        job_index::update(&job_id);
 
-    Ok(PaymentRequest {
-        job_id,
-        amount: job.price,
-        currency: "ICP".to_string(),
+        Ok(PaymentRequest {
+            job_id,
+            amount: job.price,
+            currency: "ICP".to_string(),
+        })
     })
+    .await
 }
 
 /// Check payment status for a job
-#[ic_cdk::query]
-fn check_payment_status(job_id: String) -> Result<PaymentInfo, String> {
+#[ic_cdk::query(name = "v2_check_payment_status")]
+fn check_payment_status(job_id: String) -> Result<PaymentInfo, MarketplaceError> {
     PAYMENTS.with(|payments| {
         payments.borrow()
             .get(&job_id)
             .cloned()
-            .ok_or_else(|| "Payment not found".to_string())
+            .ok_or_else(|| MarketplaceError::not_found("Payment"))
     })
 }
 
 /// Complete payment (mock function - in production this would be called by ICPAY SDK callback)
-#[ic_cdk::update]
-async fn complete_payment(job_id: String, transaction_id: String) -> Result<(), String> {
-    PAYMENTS.with(|payments| {
+#[ic_cdk::update(name = "v2_complete_payment")]
+async fn complete_payment(job_id: String, transaction_id: String) -> Result<(), MarketplaceError> {
+    let newly_paid = PAYMENTS.with(|payments| {
         let mut payments_mut = payments.borrow_mut();
         if let Some(payment) = payments_mut.get_mut(&job_id) {
+            let newly_paid = !matches!(payment.status, PaymentStatus::Completed);
             payment.status = PaymentStatus::Completed;
-            payment.transaction_id = Some(transaction_id);
-            Ok(())
+            payment.transaction_id = Some(transaction_id.clone());
+            Ok(newly_paid)
         } else {
-            Err("Payment not found".to_string())
+            Err(MarketplaceError::not_found("Payment"))
         }
-    })
+    })?;
+
+    if newly_paid {
+        payment_completed(&job_id, Some(transaction_id));
+    }
+    Ok(())
 }
 
-/// Execute the job after payment is confirmed
-#[ic_cdk::update]
-async fn execute_job(job_id: String) -> Result<JobResult, String> {
-    // Check if payment is completed
+/// Announce that a job has been paid for
+fn payment_completed(job_id: &str, transaction_id: Option<String>) {
+    let amount = JOBS.with(|jobs| jobs.borrow().get(job_id).map(|job| job.price));
+    log::info(Some(job_id), "Payment completed");
+    job_index::update(job_id);
+    audit::record(
+        AuditAction::PaymentCompleted,
+        Some(job_id),
+        format!(
+            "{} ICP, transaction {}",
+            amount.unwrap_or_default(),
+            transaction_id.as_deref().unwrap_or("none")
+        ),
+    );
+    webhooks::job_paid(job_id, amount.unwrap_or_default(), transaction_id);
+}
+
+/// Announce that a job has finished to its callbacks and webhook
+fn job_finished(job_id: &str, outcome: JobOutcome, result: &JobResult) {
+    log::info(Some(job_id), format!("Job finished: {:?}", outcome));
+    job_index::update(job_id);
+    webhooks::job_finished(job_id, &outcome, result);
+    callbacks::notify(job_id, outcome, result);
+}
+
+/// Remember which account requested a job, so its events reach that account
+fn record_job_owner(job_id: &str, owner: Principal) {
+    if owner != Principal::anonymous() {
+        JOB_OWNERS.with(|owners| {
+            owners.borrow_mut().insert(job_id.to_string(), owner);
+        });
+    }
+}
+
+/// Check that the payment for a job has completed
+fn ensure_paid(job_id: &str) -> Result<(), MarketplaceError> {
     let payment = PAYMENTS.with(|payments| {
         payments.borrow()
-            .get(&job_id)
+            .get(job_id)
             .cloned()
     });
 
-    let payment = payment.ok_or_else(|| "Payment not found".to_string())?;
+    let payment = payment.ok_or_else(|| MarketplaceError::not_found("Payment"))?;
 
     match payment.status {
-        PaymentStatus::Completed => {
-            // Payment is confirmed, proceed with execution
-        }
-        PaymentStatus::Pending => {
-            return Err("Payment not yet completed. Please wait for payment confirmation.".to_string());
+        PaymentStatus::Completed => Ok(()),
+        PaymentStatus::Pending => Err(MarketplaceError::PaymentPending),
+        PaymentStatus::Failed => Err(MarketplaceError::PaymentFailed),
+    }
+}
+
+/// When a quote made at `quoted_at` expires
+fn quote_expiry(quoted_at: u64) -> u64 {
+    quoted_at.saturating_add(QUOTE_VALIDITY_NANOS)
+}
+
+fn quoted_job(job_id: &str) -> Result<JobRequest, MarketplaceError> {
+    let job = JOBS.with(|jobs| jobs.borrow().get(job_id).cloned());
+    job.ok_or(MarketplaceError::JobNotFound)
+}
+
+/// Refuse to start a paid job whose quote has expired, refunding its price
+/// the first time
+fn ensure_quote_valid(job_id: &str, job: &JobRequest) -> Result<(), MarketplaceError> {
+    let Some(expires_at) = job.expires_at.filter(|at| *at <= ic_cdk::api::time()) else {
+        return Ok(());
+    };
+    if !REFUNDS.with(|refunds| refunds.borrow().contains_key(job_id)) {
+        record_refund(job_id, job.price, "The quote expired before the job was run");
+    }
+    Err(MarketplaceError::QuoteExpired { expired_at: expires_at })
+}
+
+/// Record that part or all of a job's price is owed back to the payer
+fn record_refund(job_id: &str, amount: f64, reason: &str) {
+    let refund = RefundInfo {
+        job_id: job_id.to_string(),
+        amount,
+        currency: "ICP".to_string(),
+        reason: reason.to_string(),
+        issued_at: ic_cdk::api::time(),
+    };
+
+    log::info(Some(job_id), format!("Refunded {} ICP: {}", amount, reason));
+    audit::record(
+        AuditAction::RefundIssued,
+        Some(job_id),
+        format!("{} ICP: {}", amount, reason),
+    );
+    webhooks::refund_issued(&refund);
+    schedule::return_credit(job_id, amount);
+    REFUNDS.with(|refunds| {
+        refunds.borrow_mut().insert(job_id.to_string(), refund);
+    });
+}
+
+/// Execute the job after payment is confirmed
+#[ic_cdk::update(name = "v2_execute_job")]
+async fn execute_job(
+    job_id: String,
+    idempotency_key: Option<String>,
+) -> Result<JobResult, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "execute_job", &job_id);
+    idempotency::once(keyed, async move {
+        ensure_paid(&job_id)?;
+
+        // Check if job already executed
+        let result_exists = RESULTS.with(|results| {
+            results.borrow().contains_key(&job_id)
+        });
+
+        if result_exists {
+            return Err(MarketplaceError::AlreadyExecuted);
         }
-        PaymentStatus::Failed => {
-            return Err("Payment failed".to_string());
+
+        // Get the job request
+        let job = JOBS.with(|jobs| {
+            jobs.borrow().get(&job_id).cloned()
+        });
+
+        let job = job.ok_or(MarketplaceError::JobNotFound)?;
+
+        match job.agent_id.as_str() {
+            agents::PIPELINE => {
+                return Err(MarketplaceError::invalid(
+                    "job_id",
+                    "Pipeline jobs are started with execute_pipeline",
+                ))
+            }
+            agents::BATCH => {
+                return Err(MarketplaceError::invalid(
+                    "job_id",
+                    "Batch jobs are started with execute_batch",
+                ))
+            }
+            _ => {}
         }
+        ensure_quote_valid(&job_id, &job)?;
+
+        // Refuse to run a job whose pinned version has been retired since quoting
+        resolve_agent_version(&job.agent_id, Some(&job.agent_version))?;
+        let prompt = agents::job_prompt(
+            &job.agent_id,
+            &job.agent_version,
+            job.prompt_hash.as_deref(),
+            &job.request,
+        )?;
+
+        // Execute using LLM canister
+        let permit = limits::llm_permit()?;
+        let started = costs::instructions();
+        let mut usage = Usage::default();
+        let output = costs::prompt(&mut usage, &prompt).await;
+        usage.instructions = costs::instructions() - started;
+        drop(permit);
+
+        // Store the result
+        let result = JobResult {
+            job_id: job_id.clone(),
+            output,
+            completed_at: ic_cdk::api::time(),
+        };
+
+        RESULTS.with(|results| {
+            results.borrow_mut().insert(job_id.clone(), result.clone());
+        });
+        costs::charge(&job_id, &usage);
+        costs::track_storage(&job_id);
+        job_finished(&job_id, JobOutcome::Succeeded, &result);
+
+        Ok(result)
+    })
+    .await
+}
+
+/// Get job result
+#[ic_cdk::query(name = "v2_get_job_result")]
+fn get_job_result(job_id: String) -> Result<JobResult, MarketplaceError> {
+    RESULTS.with(|results| {
+        results.borrow()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| MarketplaceError::not_found("Job result"))
+    })
+}
+
+/// Register a canister method to be called with the result when a job finishes
+#[ic_cdk::update(name = "v2_register_job_callback")]
+fn register_job_callback(
+    job_id: String,
+    callback: JobCallback,
+) -> Result<CallbackDelivery, MarketplaceError> {
+    // Jobs of other accounts are reported as missing.
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(&job_id).copied());
+    if owner != Some(ic_cdk::caller()) || !JOBS.with(|jobs| jobs.borrow().contains_key(&job_id)) {
+        return Err(MarketplaceError::JobNotFound);
     }
+    callbacks::register(&job_id, callback)
+}
 
-    // Check if job already executed
-    let result_exists = RESULTS.with(|results| {
-        results.borrow().contains_key(&job_id)
-    });
+/// Get the delivery log of a job's callbacks
+#[ic_cdk::query(name = "v2_get_callback_deliveries")]
+fn get_callback_deliveries(job_id: String) -> Result<Vec<CallbackDelivery>, MarketplaceError> {
+    // Jobs of other accounts are reported as missing.
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(&job_id).copied());
+    if owner != Some(ic_cdk::caller()) {
+        return Err(MarketplaceError::JobNotFound);
+    }
+    Ok(callbacks::deliveries(&job_id))
+}
+
+/// List jobs a page at a time, filtered and sorted (controllers can list every account's jobs)
+#[ic_cdk::query(name = "v2_list_jobs")]
+fn list_jobs(query: JobQuery) -> Result<JobPage, MarketplaceError> {
+    let mut query = query;
+    query.filter = own_jobs(query.filter)?;
+    job_index::query(&query)
+}
+
+/// Count the jobs matching a filter (controllers can count every account's jobs)
+#[ic_cdk::query(name = "v2_count_jobs")]
+fn count_jobs(filter: JobFilter) -> Result<u64, MarketplaceError> {
+    Ok(job_index::count(&own_jobs(filter)?))
+}
+
+/// Limit a job filter to the caller's own jobs unless the caller is a controller
+fn own_jobs(filter: JobFilter) -> Result<JobFilter, MarketplaceError> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(filter);
+    }
+    if filter.owner.is_some_and(|owner| owner != caller) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can list other accounts' jobs",
+        ));
+    }
+    Ok(JobFilter {
+        owner: Some(caller),
+        ..filter
+    })
+}
+
+/// Quote a multi-step pipeline as a single job with one total price
+#[ic_cdk::update(name = "v2_quote_pipeline")]
+async fn quote_pipeline(
+    request: PipelineRequest,
+    idempotency_key: Option<String>,
+) -> Result<PipelineQuote, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "quote_pipeline", &request);
+    idempotency::once(keyed, async move {
+        let owner = ic_cdk::caller();
+        let mut steps = pipeline::plan(&request, resolve_agent_version)?;
+        admit(owner, agents::PIPELINE, steps.len() as u32)?;
+
+        let mut usage = Usage::default();
+        for step in steps.iter_mut() {
+            step.price = calculate_cost(&step.agent_id, &step.agent.describe(), &mut usage).await?;
+        }
+
+        let total: f64 = steps.iter().map(|step| step.price).sum();
+        let price = (total * 100.0).round() / 100.0;
+        let description = steps
+            .iter()
+            .map(|step| step.agent.describe())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+
+        let job_id = generate_job_id();
+        let now = ic_cdk::api::time();
+
+        JOBS.with(|jobs| {
+            jobs.borrow_mut().insert(
+                job_id.clone(),
+                JobRequest {
+                    request: format!("Pipeline: {}", description),
+                    price,
+                    created_at: now,
+                    agent_id: agents::PIPELINE.to_string(),
+                    agent_version: pipeline::VERSION.to_string(),
+                    prompt_hash: None,
+                    cost: None,
+                    expires_at: Some(quote_expiry(now)),
+                },
+            );
+        });
+        record_job_owner(&job_id, owner);
+        job_index::update(&job_id);
+
+        let step_quotes = steps
+            .iter()
+            .map(|step| StepQuote {
+                step_id: step.id.clone(),
+                agent_id: step.agent_id.clone(),
+                agent_version: step.agent_version.clone(),
+                price: step.price,
+            })
+            .collect();
 
-    if result_exists {
-        return Err("Job already executed".to_string());
+        PIPELINE_INPUTS.with(|inputs| {
+            inputs.borrow_mut().insert(job_id.clone(), request.input);
+        });
+        PIPELINES.with(|pipelines| {
+            pipelines.borrow_mut().insert(
+                job_id.clone(),
+                Pipeline {
+                    job_id: job_id.clone(),
+                    status: PipelineStatus::Quoted,
+                    steps,
+                    refunded: 0.0,
+                    created_at: now,
+                    completed_at: None,
+                },
+            );
+        });
+        costs::charge(&job_id, &usage);
+        costs::track_storage(&job_id);
+
+        Ok(PipelineQuote {
+            quote: Quote {
+                price,
+                currency: "ICP".to_string(),
+                job_id,
+                agent_id: agents::PIPELINE.to_string(),
+                agent_version: pipeline::VERSION.to_string(),
+                prompt_hash: None,
+                expires_at: quote_expiry(now),
+            },
+            steps: step_quotes,
+        })
+    })
+    .await
+}
+
+/// Start a paid pipeline; its steps run in the background on the job queue
+#[ic_cdk::update(name = "v2_execute_pipeline")]
+fn execute_pipeline(job_id: String) -> Result<Pipeline, MarketplaceError> {
+    ensure_paid(&job_id)?;
+
+    let pipeline = PIPELINES.with(|pipelines| pipelines.borrow().get(&job_id).cloned());
+    let pipeline = pipeline.ok_or_else(|| MarketplaceError::not_found("Pipeline"))?;
+
+    if pipeline.status != PipelineStatus::Quoted {
+        return Err(MarketplaceError::AlreadyExecuted);
+    }
+    ensure_quote_valid(&job_id, &quoted_job(&job_id)?)?;
+
+    for step in &pipeline.steps {
+        resolve_agent_version(&step.agent_id, Some(&step.agent_version))?;
     }
 
-    // Get the job request
-    let job = JOBS.with(|jobs| {
-        jobs.borrow().get(&job_id).cloned()
+    PIPELINES.with(|pipelines| {
+        if let Some(pipeline) = pipelines.borrow_mut().get_mut(&job_id) {
+            pipeline.status = PipelineStatus::Running;
+        }
     });
+    job_index::update(&job_id);
+    pipeline::enqueue_ready(&job_id);
+
+    PIPELINES.with(|pipelines| {
+        pipelines
+            .borrow()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| MarketplaceError::not_found("Pipeline"))
+    })
+}
+
+/// Get a pipeline with the status and output of each step
+#[ic_cdk::query(name = "v2_get_pipeline")]
+fn get_pipeline(job_id: String) -> Result<Pipeline, MarketplaceError> {
+    PIPELINES.with(|pipelines| {
+        pipelines
+            .borrow()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| MarketplaceError::not_found("Pipeline"))
+    })
+}
+
+/// Get the refund issued for a job, if any
+#[ic_cdk::query(name = "v2_get_refund")]
+fn get_refund(job_id: String) -> Result<RefundInfo, MarketplaceError> {
+    REFUNDS.with(|refunds| {
+        refunds
+            .borrow()
+            .get(&job_id)
+            .cloned()
+            .ok_or_else(|| MarketplaceError::not_found("Refund"))
+    })
+}
+
+/// Quote one agent over many inputs, with a per-item breakdown and volume discount
+#[ic_cdk::update(name = "v2_submit_batch")]
+async fn submit_batch(
+    request: BatchRequest,
+    idempotency_key: Option<String>,
+) -> Result<BatchQuote, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "submit_batch", &request);
+    idempotency::once(keyed, async move {
+        let owner = ic_cdk::caller();
+        batch::validate(&request)?;
 
-    let job = job.ok_or_else(|| "Job not found".to_string())?;
+        let agent_id = request.agent.agent_id();
+        let agent_version = resolve_agent_version(agent_id, request.version.as_deref())?;
+        let item_count = request.items.len();
+        // Every item is an LLM-backed call; `validate` caps the count.
+        admit(owner, agent_id, item_count as u32)?;
+
+        let mut usage = Usage::default();
+        let unit_price = calculate_cost(agent_id, &request.agent.describe(), &mut usage).await?;
+        let subtotal = (unit_price * item_count as f64 * 100.0).round() / 100.0;
+        let (job_id, quoted) = create_batch_job(
+            owner,
+            request.agent,
+            agent_id,
+            agent_version,
+            request.items,
+            unit_price,
+        );
+        costs::charge(&job_id, &usage);
+
+        let item_quotes = quoted
+            .items
+            .iter()
+            .map(|item| ItemQuote {
+                index: item.index,
+                price: item.price,
+            })
+            .collect();
+
+        Ok(BatchQuote {
+            quote: Quote {
+                price: quoted.price,
+                currency: "ICP".to_string(),
+                job_id,
+                agent_id: agents::BATCH.to_string(),
+                agent_version: batch::VERSION.to_string(),
+                prompt_hash: None,
+                expires_at: quote_expiry(quoted.created_at),
+            },
+            unit_price,
+            subtotal,
+            discount: quoted.discount,
+            items: item_quotes,
+        })
+    })
+    .await
+}
 
-    // Execute using LLM canister
-    let output = ic_llm::prompt(Model::Qwen3_32B, &job.request).await;
+/// Store a quoted batch job and its inputs
+fn create_batch_job(
+    owner: Principal,
+    agent: StepAgent,
+    agent_id: &str,
+    agent_version: String,
+    inputs: Vec<StepData>,
+    unit_price: f64,
+) -> (String, Batch) {
+    let item_count = inputs.len();
+    let (items, discount) = batch::price_items(item_count, unit_price);
+    let price = (items.iter().map(|item| item.price).sum::<f64>() * 100.0).round() / 100.0;
 
-    // Store the result
-    let result = JobResult {
+    let job_id = generate_job_id();
+    let now = ic_cdk::api::time();
+
+    JOBS.with(|jobs| {
+        jobs.borrow_mut().insert(
+            job_id.clone(),
+            JobRequest {
+                request: format!("Batch of {} items: {}", item_count, agent.describe()),
+                price,
+                created_at: now,
+                agent_id: agents::BATCH.to_string(),
+                agent_version: batch::VERSION.to_string(),
+                prompt_hash: None,
+                cost: None,
+                expires_at: Some(quote_expiry(now)),
+            },
+        );
+    });
+    record_job_owner(&job_id, owner);
+    job_index::update(&job_id);
+
+    BATCH_INPUTS.with(|batch_inputs| {
+        batch_inputs
+            .borrow_mut()
+            .insert(job_id.clone(), inputs.into_iter().map(Some).collect());
+    });
+
+    let batch = Batch {
         job_id: job_id.clone(),
-        output,
-        completed_at: ic_cdk::api::time(),
+        agent,
+        agent_id: agent_id.to_string(),
+        agent_version,
+        status: BatchStatus::Quoted,
+        items,
+        unit_price,
+        discount,
+        price,
+        refunded: 0.0,
+        created_at: now,
+        completed_at: None,
     };
 
-    RESULTS.with(|results| {
-        results.borrow_mut().insert(job_id.clone(), result.clone());
+    BATCHES.with(|batches| {
+        batches.borrow_mut().insert(job_id.clone(), batch.clone());
     });
+    costs::track_storage(&job_id);
 
-    Ok(result)
+    (job_id, batch)
 }
 
-/// Get job result
-#[ic_cdk::query]
-fn get_job_result(job_id: String) -> Result<JobResult, String> {
-    RESULTS.with(|results| {
-        results.borrow()
+/// Start a paid batch; its items run in the background on the job queue
+#[ic_cdk::update(name = "v2_execute_batch")]
+fn execute_batch(job_id: String) -> Result<Batch, MarketplaceError> {
+    ensure_paid(&job_id)?;
+
+    let batch = BATCHES.with(|batches| batches.borrow().get(&job_id).cloned());
+    let batch = batch.ok_or_else(|| MarketplaceError::not_found("Batch"))?;
+
+    if batch.status != BatchStatus::Quoted {
+        return Err(MarketplaceError::AlreadyExecuted);
+    }
+    ensure_quote_valid(&job_id, &quoted_job(&job_id)?)?;
+    resolve_agent_version(&batch.agent_id, Some(&batch.agent_version))?;
+
+    batch::enqueue_all(&job_id);
+    get_batch(job_id)
+}
+
+/// Get a batch with the status, result or failure of each item
+#[ic_cdk::query(name = "v2_get_batch")]
+fn get_batch(job_id: String) -> Result<Batch, MarketplaceError> {
+    BATCHES.with(|batches| {
+        batches
+            .borrow()
             .get(&job_id)
             .cloned()
-            .ok_or_else(|| "Job result not found".to_string())
+            .ok_or_else(|| MarketplaceError::not_found("Batch"))
     })
 }
 
-/// Get all jobs (for debugging/admin)
-#[ic_cdk::query]
-fn list_jobs() -> Vec<(String, JobRequest)> {
-    JOBS.with(|jobs| {
-        jobs.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+/// Schedule an agent to run at a future time or on a recurring trigger
+#[ic_cdk::update(name = "v2_create_schedule")]
+async fn create_schedule(
+    request: ScheduleRequest,
+    idempotency_key: Option<String>,
+) -> Result<Schedule, MarketplaceError> {
+    let keyed = idempotency::keyed(ic_cdk::caller(), idempotency_key, "create_schedule", &request);
+    idempotency::once(keyed, async move {
+        let owner = ic_cdk::caller();
+        if owner == Principal::anonymous() {
+            return Err(MarketplaceError::unauthorized(
+                "Anonymous callers cannot create schedules",
+            ));
+        }
+        if !request.agent.accepts_data(&request.input) {
+            return Err(MarketplaceError::invalid(
+                "input",
+                format!("Input is not valid for {}", request.agent.agent_id()),
+            ));
+        }
+
+        let now = ic_cdk::api::time();
+        let next_run_at = schedule::first_run(&request.trigger, now)?;
+        let agent_id = request.agent.agent_id();
+        let agent_version = resolve_agent_version(agent_id, request.version.as_deref())?;
+        limits::check(owner, agent_id, 1, now)?;
+        let price_per_run =
+            calculate_cost(agent_id, &request.agent.describe(), &mut Usage::default()).await?;
+
+        let id = SCHEDULE_COUNTER.with(|counter| {
+            let mut counter = counter.borrow_mut();
+            *counter += 1;
+            format!("schedule_{:016}", *counter)
+        });
+
+        let schedule = Schedule {
+            id: id.clone(),
+            owner,
+            agent: request.agent,
+            agent_id: agent_id.to_string(),
+            agent_version,
+            trigger: request.trigger,
+            charge: request.charge,
+            price_per_run,
+            status: ScheduleStatus::Active,
+            next_run_at: Some(next_run_at),
+            last_error: None,
+            runs: Vec::new(),
+            created_at: now,
+        };
+
+        SCHEDULE_INPUTS.with(|inputs| {
+            inputs.borrow_mut().insert(id.clone(), request.input);
+        });
+        SCHEDULES.with(|schedules| {
+            schedules.borrow_mut().insert(id, schedule.clone());
+        });
+        schedule::arm(&schedule);
+
+        Ok(schedule)
+    })
+    .await
+}
+
+/// List the caller's schedules
+#[ic_cdk::query(name = "v2_list_schedules")]
+fn list_schedules() -> Vec<Schedule> {
+    let caller = ic_cdk::caller();
+    SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .values()
+            .filter(|schedule| schedule.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Apply a change to one of the caller's schedules
+fn update_own_schedule(
+    schedule_id: &str,
+    update: impl FnOnce(&mut Schedule) -> Result<(), MarketplaceError>,
+) -> Result<Schedule, MarketplaceError> {
+    let caller = ic_cdk::caller();
+    SCHEDULES.with(|schedules| {
+        let mut schedules = schedules.borrow_mut();
+        let schedule = schedules
+            .get_mut(schedule_id)
+            .filter(|schedule| schedule.owner == caller)
+            .ok_or_else(|| MarketplaceError::not_found("Schedule"))?;
+        update(schedule)?;
+        Ok(schedule.clone())
+    })
+}
+
+/// Pause one of the caller's schedules
+#[ic_cdk::update(name = "v2_pause_schedule")]
+fn pause_schedule(schedule_id: String) -> Result<Schedule, MarketplaceError> {
+    update_own_schedule(&schedule_id, |schedule| {
+        if schedule.status != ScheduleStatus::Active {
+            return Err(MarketplaceError::conflict("Schedule is not active"));
+        }
+        schedule.status = ScheduleStatus::Paused;
+        Ok(())
     })
 }
 
+/// Resume a paused schedule from its next matching time
+#[ic_cdk::update(name = "v2_resume_schedule")]
+fn resume_schedule(schedule_id: String) -> Result<Schedule, MarketplaceError> {
+    let schedule = update_own_schedule(&schedule_id, |schedule| {
+        if schedule.status != ScheduleStatus::Paused {
+            return Err(MarketplaceError::conflict("Schedule is not paused"));
+        }
+        schedule.next_run_at = Some(schedule::first_run(&schedule.trigger, ic_cdk::api::time())?);
+        schedule.status = ScheduleStatus::Active;
+        schedule.last_error = None;
+        Ok(())
+    })?;
+
+    schedule::arm(&schedule);
+    Ok(schedule)
+}
+
+/// Delete one of the caller's schedules
+#[ic_cdk::update(name = "v2_delete_schedule")]
+fn delete_schedule(schedule_id: String) -> Result<(), MarketplaceError> {
+    update_own_schedule(&schedule_id, |_| Ok(()))?;
+
+    SCHEDULES.with(|schedules| schedules.borrow_mut().remove(&schedule_id));
+    SCHEDULE_INPUTS.with(|inputs| inputs.borrow_mut().remove(&schedule_id));
+    Ok(())
+}
+
+/// Add prepaid credit by pulling ICP from an ICRC-2 allowance granted to this canister
+#[ic_cdk::update(name = "v2_deposit_credit")]
+async fn deposit_credit(
+    amount: f64,
+    idempotency_key: Option<String>,
+) -> Result<f64, MarketplaceError> {
+    let owner = ic_cdk::caller();
+    let keyed = idempotency::keyed(owner, idempotency_key.clone(), "deposit_credit", &amount);
+    idempotency::once(keyed, async move {
+        if owner == Principal::anonymous() {
+            return Err(MarketplaceError::unauthorized(
+                "Anonymous callers cannot hold credit",
+            ));
+        }
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(MarketplaceError::invalid(
+                "amount",
+                "Deposit amount must be positive",
+            ));
+        }
+
+        let (memo, created_at_time) =
+            idempotency::ledger_tag(owner, idempotency_key.as_deref(), "deposit_credit");
+        ledger::transfer_from(owner, amount, &memo, created_at_time).await?;
+        audit::record(AuditAction::CreditDeposited, None, format!("{} ICP", amount));
+
+        Ok(CREDITS.with(|credits| {
+            let mut credits = credits.borrow_mut();
+            let balance = credits.entry(owner).or_insert(0);
+            *balance = balance.saturating_add(ledger::icp_to_e8s(amount));
+            ledger::e8s_to_icp(*balance)
+        }))
+    })
+    .await
+}
+
+/// Get the caller's prepaid credit balance
+#[ic_cdk::query(name = "v2_get_credit_balance")]
+fn get_credit_balance() -> f64 {
+    let owner = ic_cdk::caller();
+    let balance = CREDITS.with(|credits| credits.borrow().get(&owner).copied().unwrap_or(0));
+    ledger::e8s_to_icp(balance)
+}
+
+/// Set the caller's webhook URL; the returned signing secret is only shown once
+#[ic_cdk::update(name = "v2_set_webhook")]
+async fn set_webhook(url: String) -> Result<WebhookRegistration, MarketplaceError> {
+    let owner = ic_cdk::caller();
+    if owner == Principal::anonymous() {
+        return Err(MarketplaceError::unauthorized(
+            "Anonymous callers cannot register webhooks",
+        ));
+    }
+    let registration = webhooks::set(owner, url.clone()).await?;
+    // Query strings can carry tokens, so only the endpoint is recorded.
+    let endpoint = url.split(['?', '#']).next().unwrap_or_default();
+    audit::record(AuditAction::WebhookSet, None, endpoint);
+    Ok(registration)
+}
+
+/// Get the caller's webhook
+#[ic_cdk::query(name = "v2_get_webhook")]
+fn get_webhook() -> Option<WebhookInfo> {
+    webhooks::get(ic_cdk::caller())
+}
+
+/// Remove the caller's webhook
+#[ic_cdk::update(name = "v2_delete_webhook")]
+fn delete_webhook() -> Result<(), MarketplaceError> {
+    webhooks::remove(ic_cdk::caller())?;
+    audit::record(AuditAction::WebhookDeleted, None, "");
+    Ok(())
+}
+
+/// List recent webhook deliveries for the caller, newest first
+#[ic_cdk::query]
+fn list_webhook_deliveries() -> Vec<WebhookDelivery> {
+    webhooks::deliveries(ic_cdk::caller())
+}
+
+/// Strip webhook responses to their status so replicas agree on them
+#[ic_cdk::query]
+fn transform_webhook_response(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    webhooks::transform(args)
+}
+
+/// Create an API key for the caller; the key itself is only shown once
+#[ic_cdk::update(name = "v2_create_api_key")]
+async fn create_api_key(request: ApiKeyRequest) -> Result<NewApiKey, MarketplaceError> {
+    let owner = ic_cdk::caller();
+    if owner == Principal::anonymous() {
+        return Err(MarketplaceError::unauthorized(
+            "Anonymous callers cannot create API keys",
+        ));
+    }
+    let key = api_keys::create(owner, request).await?;
+    audit::record(AuditAction::ApiKeyCreated, None, key.id.clone());
+    Ok(key)
+}
+
+/// List the caller's API keys with their usage
+#[ic_cdk::query(name = "v2_list_api_keys")]
+fn list_api_keys() -> Vec<ApiKeyInfo> {
+    api_keys::list(ic_cdk::caller())
+}
+
+/// Revoke one of the caller's API keys
+#[ic_cdk::update(name = "v2_revoke_api_key")]
+fn revoke_api_key(key_id: String) -> Result<(), MarketplaceError> {
+    api_keys::revoke(ic_cdk::caller(), &key_id)?;
+    audit::record(AuditAction::ApiKeyRevoked, None, key_id);
+    Ok(())
+}
+
+/// Get a quote on behalf of the owner of an API key
+#[ic_cdk::update(name = "v2_quote_with_api_key")]
+async fn quote_with_api_key(
+    api_key: String,
+    agent_id: String,
+    request: String,
+    version: Option<String>,
+) -> Result<Quote, MarketplaceError> {
+    let key = api_keys::authenticate(&api_key, ic_cdk::api::time())?;
+    api_keys::authorize_agent(&key, &agent_id)?;
+    admit(key.owner, &agent_id, 1)?;
+
+    let quote = quote_job(key.owner, &agent_id, request, version).await?;
+    api_keys::record_quote(&key, ic_cdk::api::time());
+    Ok(quote)
+}
+
+/// Execute a paid job on behalf of the owner of an API key, within its spend limit
+#[ic_cdk::update(name = "v2_execute_job_with_api_key")]
+async fn execute_job_with_api_key(
+    api_key: String,
+    job_id: String,
+) -> Result<JobResult, MarketplaceError> {
+    let key = api_keys::authenticate(&api_key, ic_cdk::api::time())?;
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(&job_id).copied());
+    let job = JOBS.with(|jobs| jobs.borrow().get(&job_id).cloned());
+    let job = job
+        .filter(|_| owner == Some(key.owner))
+        .ok_or(MarketplaceError::JobNotFound)?;
+    api_keys::authorize_agent(&key, &job.agent_id)?;
+    ensure_paid(&job_id)?;
+
+    api_keys::charge(&key, job.price, ic_cdk::api::time())?;
+    let result = execute_job(job_id, None).await;
+    if result.is_err() {
+        api_keys::release(&key, job.price, ic_cdk::api::time());
+    }
+    result
+}
+
+/// Drop oversized, malformed or anonymous ingress before it is executed
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    match limits::inspect_ingress() {
+        Ok(()) => ic_cdk::api::call::accept_message(),
+        Err(err) => ic_cdk::trap(&err),
+    }
+}
+
+/// Serve the REST gateway's read-only routes
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    gateway::handle_query(request)
+}
+
+/// Serve the REST gateway's routes that change state
+#[ic_cdk::update]
+async fn http_request_update(request: HttpRequest) -> HttpResponse {
+    gateway::handle_update(request).await
+}
+
+/// Estimated cycle cost against revenue per agent, with cycles converted to
+/// ICP at the given rate (controllers only)
+#[ic_cdk::query(name = "v2_get_cost_report")]
+fn get_cost_report(cycles_per_icp: u64) -> Result<Vec<AgentCostReport>, MarketplaceError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can read cost reports",
+        ));
+    }
+    costs::report(cycles_per_icp)
+}
+
+/// Read the canister log, newest first (controllers only)
+#[ic_cdk::query(name = "v2_get_logs")]
+fn get_logs(filter: LogFilter) -> Result<Vec<LogEntry>, MarketplaceError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can read the log",
+        ));
+    }
+    Ok(log::query(&filter))
+}
+
+/// Choose whether user content is redacted from the log (controllers only)
+#[ic_cdk::update(name = "v2_set_log_redaction")]
+fn set_log_redaction(redact: bool) -> Result<(), MarketplaceError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can change log redaction",
+        ));
+    }
+    log::set_redaction(redact);
+    audit::record(
+        AuditAction::LogRedactionChanged,
+        None,
+        if redact { "enabled" } else { "disabled" },
+    );
+    Ok(())
+}
+
+/// Read the audit log, oldest first (controllers only)
+#[ic_cdk::query(name = "v2_get_audit_log")]
+fn get_audit_log(
+    after_seq: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Vec<AuditEntry>, MarketplaceError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can read the audit log",
+        ));
+    }
+    Ok(audit::entries(after_seq, limit))
+}
+
+/// List the versions of an agent and their deprecation status
+#[ic_cdk::query(name = "v2_list_agent_versions")]
+fn list_agent_versions(agent_id: String) -> Result<Vec<AgentVersion>, MarketplaceError> {
+    let versions = agents::known_versions(&agent_id).ok_or_else(|| {
+        MarketplaceError::UnknownAgent {
+            agent_id: agent_id.clone(),
+        }
+    })?;
+    let now = ic_cdk::api::time();
+
+    Ok(AGENT_DEPRECATIONS.with(|deprecations| {
+        let deprecations = deprecations.borrow();
+        versions
+            .into_iter()
+            .map(|version| agents::describe(&agent_id, version, &deprecations, now))
+            .collect()
+    }))
+}
+
+/// Deprecate an agent version; it keeps running until the window elapses (controllers only)
+#[ic_cdk::update(name = "v2_deprecate_agent_version")]
+fn deprecate_agent_version(
+    agent_id: String,
+    version: String,
+    window_seconds: u64,
+) -> Result<AgentVersion, MarketplaceError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(MarketplaceError::unauthorized(
+            "Only controllers can deprecate agent versions",
+        ));
+    }
+
+    resolve_agent_version(&agent_id, Some(&version))?;
+    if agents::current_version(&agent_id) == Some(version.as_str()) {
+        return Err(MarketplaceError::invalid(
+            "version",
+            "The current version cannot be deprecated",
+        ));
+    }
+
+    let now = ic_cdk::api::time();
+    let deprecation = Deprecation {
+        deprecated_at: now,
+        retires_at: now.saturating_add(window_seconds.saturating_mul(1_000_000_000)),
+    };
+
+    audit::record(
+        AuditAction::AgentVersionDeprecated,
+        None,
+        format!(
+            "{} {} retires in {} seconds",
+            agent_id, version, window_seconds
+        ),
+    );
+
+    Ok(AGENT_DEPRECATIONS.with(|deprecations| {
+        let mut deprecations = deprecations.borrow_mut();
+        deprecations.insert((agent_id.clone(), version.clone()), deprecation);
+        agents::describe(&agent_id, &version, &deprecations, now)
+    }))
+}
+
 ic_cdk::export_candid!();
\ No newline at end of file
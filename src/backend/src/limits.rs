@@ -0,0 +1,313 @@
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::MarketplaceError;
+
+/// Burst of LLM-backed calls a single principal can make.
+const CALLER_CAPACITY: f64 = 20.0;
+/// Calls per second a principal's bucket refills by (10 a minute).
+const CALLER_REFILL: f64 = 1.0 / 6.0;
+
+/// Burst of LLM-backed calls an agent accepts across all callers, so fresh
+/// principals cannot be minted to get around the per-caller limit.
+const AGENT_CAPACITY: f64 = 120.0;
+const AGENT_REFILL: f64 = 2.0;
+
+/// LLM calls made for callers that may be awaiting a response at once. The
+/// job queue runs one task at a time and is not counted.
+const MAX_LLM_CALLS: u32 = 10;
+
+/// Buckets tracked before full ones are dropped.
+const MAX_TRACKED_CALLERS: usize = 10_000;
+
+/// Token bucket; `tokens` is the level at `updated_at` (nanoseconds).
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl Bucket {
+    fn new(tokens: f64, updated_at: u64) -> Self {
+        Self { tokens, updated_at }
+    }
+
+    fn level(&self, capacity: f64, refill: f64, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1_000_000_000.0;
+        (self.tokens + elapsed * refill).min(capacity)
+    }
+}
+
+thread_local! {
+    static CALLER_BUCKETS: RefCell<HashMap<Principal, Bucket>> = RefCell::default();
+    static AGENT_BUCKETS: RefCell<HashMap<String, Bucket>> = RefCell::default();
+    static LLM_CALLS: Cell<u32> = const { Cell::new(0) };
+}
+
+/// A call was refused by a rate limit.
+#[derive(Clone, Debug)]
+pub struct Throttled {
+    pub scope: &'static str,
+    /// Seconds until enough tokens are available.
+    pub retry_after: u64,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rate limit exceeded for this {}, retry in {} seconds",
+            self.scope, self.retry_after
+        )
+    }
+}
+
+impl From<Throttled> for MarketplaceError {
+    fn from(throttled: Throttled) -> Self {
+        MarketplaceError::RateLimited {
+            retry_after: throttled.retry_after,
+        }
+    }
+}
+
+/// Reject the anonymous principal, which anyone can call as.
+pub fn require_caller(caller: Principal) -> Result<(), MarketplaceError> {
+    if caller == Principal::anonymous() {
+        return Err(MarketplaceError::unauthorized(
+            "Anonymous callers are not allowed; sign the call with an identity",
+        ));
+    }
+    Ok(())
+}
+
+/// Take `calls` tokens from both the caller's and the agent's bucket.
+///
+/// Nothing is taken unless both buckets can cover the whole amount. More
+/// calls than a bucket's burst need that bucket full and leave it in debt, so
+/// a large batch is admitted but the next call waits until it is paid off.
+pub fn check(caller: Principal, agent_id: &str, calls: u32, now: u64) -> Result<(), Throttled> {
+    let calls = calls as f64;
+
+    let caller_level = CALLER_BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .get(&caller)
+            .map_or(CALLER_CAPACITY, |bucket| {
+                bucket.level(CALLER_CAPACITY, CALLER_REFILL, now)
+            })
+    });
+    let agent_level = AGENT_BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .get(agent_id)
+            .map_or(AGENT_CAPACITY, |bucket| {
+                bucket.level(AGENT_CAPACITY, AGENT_REFILL, now)
+            })
+    });
+
+    let (caller_needed, agent_needed) = (calls.min(CALLER_CAPACITY), calls.min(AGENT_CAPACITY));
+    if caller_level < caller_needed {
+        return Err(throttled("caller", caller_needed - caller_level, CALLER_REFILL));
+    }
+    if agent_level < agent_needed {
+        return Err(throttled("agent", agent_needed - agent_level, AGENT_REFILL));
+    }
+
+    CALLER_BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        if buckets.len() >= MAX_TRACKED_CALLERS {
+            // A full bucket carries no information, so it can be forgotten.
+            buckets.retain(|_, bucket| {
+                bucket.level(CALLER_CAPACITY, CALLER_REFILL, now) < CALLER_CAPACITY
+            });
+        }
+        buckets.insert(caller, Bucket::new(caller_level - calls, now));
+    });
+    AGENT_BUCKETS.with(|buckets| {
+        buckets
+            .borrow_mut()
+            .insert(agent_id.to_string(), Bucket::new(agent_level - calls, now));
+    });
+    Ok(())
+}
+
+fn throttled(scope: &'static str, missing: f64, refill: f64) -> Throttled {
+    Throttled {
+        scope,
+        retry_after: (missing / refill).ceil().max(1.0) as u64,
+    }
+}
+
+/// Slot for one outstanding LLM call, released when dropped.
+///
+/// The drop also runs when the call's future is cleaned up after a trap, so a
+/// failed call does not hold its slot for good.
+pub struct LlmPermit(());
+
+impl Drop for LlmPermit {
+    fn drop(&mut self) {
+        LLM_CALLS.with(|calls| calls.set(calls.get().saturating_sub(1)));
+    }
+}
+
+/// Reserve a slot for an LLM call, or fail with
+/// [`MarketplaceError::LlmUnavailable`] if all are taken.
+pub fn llm_permit() -> Result<LlmPermit, MarketplaceError> {
+    LLM_CALLS.with(|calls| {
+        if calls.get() >= MAX_LLM_CALLS {
+            return Err(MarketplaceError::LlmUnavailable);
+        }
+        calls.set(calls.get() + 1);
+        Ok(LlmPermit(()))
+    })
+}
+
+/// Largest argument accepted for methods that take documents or data sets;
+/// ingress messages are capped at 2 MiB by the protocol anyway.
+const MAX_DATA_ARG_BYTES: usize = 2 * 1024 * 1024;
+/// Largest argument accepted for methods that take free text.
+const MAX_TEXT_ARG_BYTES: usize = 256 * 1024;
+/// Largest argument accepted for every other method.
+const MAX_ARG_BYTES: usize = 16 * 1024;
+
+/// Decide whether an ingress message should be executed.
+///
+/// Runs on a single replica before consensus, so it only sheds bad traffic
+/// early; the endpoints still enforce their own checks.
+pub fn inspect_ingress() -> Result<(), String> {
+    let exported = ic_cdk::api::call::method_name();
+    // The typed `v2_` methods take the same arguments as the originals.
+    let method = exported.strip_prefix("v2_").unwrap_or(&exported).to_string();
+    let caller = ic_cdk::caller();
+
+    // Gateway requests and API key calls may be anonymous; the key is the
+    // credential.
+    if !matches!(
+        method.as_str(),
+        "http_request_update" | "quote_with_api_key" | "execute_job_with_api_key"
+    ) {
+        require_caller(caller)?;
+    }
+    if matches!(method.as_str(), "deprecate_agent_version" | "set_log_redaction")
+        && !ic_cdk::api::is_controller(&caller)
+    {
+        return Err("Only controllers can call this method".to_string());
+    }
+
+    let max_size = match method.as_str() {
+        "compress_pdf" | "analyze_csv" | "quote_pipeline" | "submit_batch"
+        | "create_schedule" | "http_request_update" => MAX_DATA_ARG_BYTES,
+        "summarize_text" | "get_quote" | "get_agent_quote" | "quote_with_api_key" => {
+            MAX_TEXT_ARG_BYTES
+        }
+        _ => MAX_ARG_BYTES,
+    };
+    let size = ic_cdk::api::call::arg_data_raw_size();
+    if size > max_size {
+        return Err(format!(
+            "Argument of {} bytes is larger than the {} bytes accepted by {}",
+            size, max_size, method
+        ));
+    }
+
+    // Parsing the header and type table catches garbage without paying for
+    // a full decode of the values.
+    candid::de::IDLDeserialize::new(&ic_cdk::api::call::arg_data_raw())
+        .map_err(|_| "Argument is not valid Candid".to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn caller(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    #[test]
+    fn caller_bucket_refills_over_time() {
+        for _ in 0..20 {
+            check(caller(1), "summarizer", 1, 0).unwrap();
+        }
+        let throttled = check(caller(1), "summarizer", 1, 0).unwrap_err();
+        assert_eq!(throttled.scope, "caller");
+        // One token comes back every six seconds.
+        assert_eq!(throttled.retry_after, 6);
+
+        assert!(check(caller(1), "summarizer", 1, 5 * SECOND).is_err());
+        check(caller(1), "summarizer", 1, 6 * SECOND).unwrap();
+        assert!(check(caller(1), "summarizer", 1, 6 * SECOND).is_err());
+
+        // Other callers have their own buckets.
+        check(caller(2), "summarizer", 1, 6 * SECOND).unwrap();
+    }
+
+    #[test]
+    fn refill_stops_at_capacity() {
+        check(caller(1), "summarizer", 20, 0).unwrap();
+        // A day of refill is still only one burst.
+        let later = 86_400 * SECOND;
+        check(caller(1), "summarizer", 20, later).unwrap();
+        assert_eq!(
+            check(caller(1), "summarizer", 1, later)
+                .unwrap_err()
+                .retry_after,
+            6
+        );
+    }
+
+    #[test]
+    fn large_requests_need_a_full_bucket_and_leave_debt() {
+        check(caller(1), "summarizer", 1, 0).unwrap();
+        // Needs the full burst of 20, one token short.
+        assert_eq!(
+            check(caller(1), "summarizer", 50, 0)
+                .unwrap_err()
+                .retry_after,
+            6
+        );
+        check(caller(1), "summarizer", 50, 6 * SECOND).unwrap();
+
+        // 30 tokens of debt plus one token take 31 refills.
+        let throttled = check(caller(1), "summarizer", 1, 6 * SECOND).unwrap_err();
+        assert_eq!(throttled.retry_after, 31 * 6);
+    }
+
+    #[test]
+    fn agent_bucket_is_shared_across_callers() {
+        for n in 0..6 {
+            check(caller(n), "analyzer", 20, 0).unwrap();
+        }
+        let throttled = check(caller(6), "analyzer", 1, 0).unwrap_err();
+        assert_eq!(throttled.scope, "agent");
+        // Two tokens a second; waits are rounded up to whole seconds.
+        assert_eq!(throttled.retry_after, 1);
+        check(caller(6), "analyzer", 1, SECOND / 2).unwrap();
+        check(caller(6), "summarizer", 1, 0).unwrap();
+
+        // A refused call takes nothing from either bucket.
+        assert!(check(caller(7), "analyzer", 2, SECOND / 2).is_err());
+        check(caller(7), "analyzer", 2, 3 * SECOND / 2).unwrap();
+    }
+
+    #[test]
+    fn llm_permits_are_capped_and_released_on_drop() {
+        let mut permits: Vec<_> = (0..MAX_LLM_CALLS).map(|_| llm_permit().unwrap()).collect();
+        assert!(matches!(
+            llm_permit(),
+            Err(MarketplaceError::LlmUnavailable)
+        ));
+
+        permits.pop();
+        let permit = llm_permit().unwrap();
+        assert!(llm_permit().is_err());
+
+        drop(permit);
+        llm_permit().unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use candid::{CandidType, Deserialize};
+use std::cell::Cell;
+
+use crate::memory::{self, LOG_SLOTS, LOG_SLOT_SIZE};
+
+/// Longest message kept in an entry; longer ones are cut to fit a slot.
+const MAX_MESSAGE_LENGTH: usize = 400;
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub at: u64,
+    pub level: Level,
+    /// Job the entry relates to, for following a job through the log.
+    pub job_id: Option<String>,
+    pub message: String,
+}
+
+/// Filters for `get_logs`; entries are returned newest first.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LogFilter {
+    pub min_level: Option<Level>,
+    pub job_id: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Only entries whose message contains this text.
+    pub contains: Option<String>,
+    /// Only entries older than this sequence number, for paging.
+    pub before_seq: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+thread_local! {
+    static REDACT_CONTENT: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn set_redaction(redact: bool) {
+    REDACT_CONTENT.with(|cell| cell.set(redact));
+}
+
+pub fn redaction() -> bool {
+    REDACT_CONTENT.with(Cell::get)
+}
+
+/// Render user content for a log message: redacted unless a controller has
+/// turned redaction off, and shortened either way.
+pub fn content(text: &str) -> String {
+    if redaction() {
+        return format!("[redacted {} bytes]", text.len());
+    }
+    let mut shown: String = text.chars().take(120).collect();
+    if shown.len() < text.len() {
+        shown.push('…');
+    }
+    format!("{:?}", shown)
+}
+
+/// Time to stamp entries with. Outside a canister, e.g. in unit tests, the
+/// clock reads zero.
+fn now() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+fn record(level: Level, job_id: Option<&str>, message: String) {
+    ic_cdk::println!(
+        "[{:?}]{} {}",
+        level,
+        job_id.map(|id| format!(" {}", id)).unwrap_or_default(),
+        message
+    );
+    memory::ensure_layout();
+
+    let seq = memory::next_log_seq();
+    let mut entry = LogEntry {
+        seq,
+        at: now(),
+        level,
+        job_id: job_id.map(str::to_string),
+        message,
+    };
+    truncate(&mut entry.message, MAX_MESSAGE_LENGTH);
+
+    let Ok(bytes) = candid::encode_one(&entry) else {
+        return;
+    };
+    if bytes.len() as u64 > LOG_SLOT_SIZE - 4 {
+        return;
+    }
+    memory::write_log_slot(seq, &bytes);
+    memory::set_next_log_seq(seq + 1);
+}
+
+/// Cut `text` to at most `max` bytes, marking the cut with an ellipsis.
+pub fn truncate(text: &mut String, max: usize) {
+    if text.len() > max {
+        let mut end = max;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+    }
+}
+
+pub fn debug(job_id: Option<&str>, message: impl Into<String>) {
+    record(Level::Debug, job_id, message.into());
+}
+
+pub fn info(job_id: Option<&str>, message: impl Into<String>) {
+    record(Level::Info, job_id, message.into());
+}
+
+pub fn warn(job_id: Option<&str>, message: impl Into<String>) {
+    record(Level::Warn, job_id, message.into());
+}
+
+pub fn error(job_id: Option<&str>, message: impl Into<String>) {
+    record(Level::Error, job_id, message.into());
+}
+
+fn matches(entry: &LogEntry, filter: &LogFilter) -> bool {
+    filter.min_level.is_none_or(|level| entry.level >= level)
+        && filter
+            .job_id
+            .as_ref()
+            .is_none_or(|job_id| entry.job_id.as_ref() == Some(job_id))
+        && filter.since.is_none_or(|since| entry.at >= since)
+        && filter.until.is_none_or(|until| entry.at <= until)
+        && filter
+            .contains
+            .as_ref()
+            .is_none_or(|text| entry.message.contains(text.as_str()))
+}
+
+/// Entries still in the ring buffer that match `filter`, newest first.
+pub fn query(filter: &LogFilter) -> Vec<LogEntry> {
+    if !memory::is_laid_out() {
+        return Vec::new();
+    }
+    let limit = filter
+        .limit
+        .map_or(DEFAULT_QUERY_LIMIT, |limit| limit as usize)
+        .min(MAX_QUERY_LIMIT);
+
+    let next = memory::next_log_seq();
+    let end = filter.before_seq.map_or(next, |before| before.min(next));
+    let start = next.saturating_sub(LOG_SLOTS);
+
+    let mut entries = Vec::new();
+    for seq in (start..end).rev() {
+        if entries.len() >= limit {
+            break;
+        }
+        let bytes = memory::read_log_slot(seq);
+        let Ok(entry) = candid::decode_one::<LogEntry>(&bytes) else {
+            continue;
+        };
+        if entry.seq == seq && matches(&entry, filter) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
@@ -0,0 +1,335 @@
+#[cfg(not(target_arch = "wasm32"))]
+use self::host::{stable_grow, stable_read, stable_size, stable_write};
+#[cfg(target_arch = "wasm32")]
+use ic_cdk::api::stable::{stable_grow, stable_read, stable_size, stable_write};
+use std::cell::{Cell, RefCell};
+
+// Layout of stable memory:
+//
+//   0               magic, identifying this layout
+//   8               sequence number of the next log entry
+//   16              number of buckets handed out
+//   BUCKET_TABLE    region owning each bucket, one byte per bucket
+//   LOG_OFFSET      log ring buffer of LOG_SLOTS slots of LOG_SLOT_SIZE bytes
+//   BUCKETS_OFFSET  buckets of BUCKET_SIZE bytes
+//
+// The log lives at a fixed place so entries can be written as they happen and
+// survive upgrades. Everything else lives in regions: each region is an
+// address space of its own, made of the buckets it was given in order, so
+// regions can grow independently without moving each other.
+//
+// Version 1 of the layout (magic "MKTPLC01") kept the state right after the
+// log ring and had no regions. Earlier versions saved the state at offset 0
+// with `stable_save`.
+
+const MAGIC: &[u8; 8] = b"MKTPLC02";
+const V1_MAGIC: &[u8; 8] = b"MKTPLC01";
+const LOG_SEQ_OFFSET: u64 = 8;
+const BUCKET_COUNT_OFFSET: u64 = 16;
+const BUCKET_TABLE: u64 = 64;
+const MAX_BUCKETS: u64 = PAGE_SIZE - BUCKET_TABLE;
+const LOG_OFFSET: u64 = PAGE_SIZE;
+pub const LOG_SLOTS: u64 = 8192;
+pub const LOG_SLOT_SIZE: u64 = 512;
+const BUCKETS_OFFSET: u64 = LOG_OFFSET + LOG_SLOTS * LOG_SLOT_SIZE;
+const BUCKET_SIZE: u64 = 16 * PAGE_SIZE;
+const V1_STATE_OFFSET: u64 = BUCKETS_OFFSET;
+
+const PAGE_SIZE: u64 = 64 * 1024;
+
+/// Stable storage that can be read and written at any offset. Reads past
+/// what was written return zeros.
+pub trait Memory {
+    fn read(&self, offset: u64, buf: &mut [u8]);
+    fn write(&self, offset: u64, bytes: &[u8]);
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, &value.to_le_bytes());
+    }
+}
+
+/// The regions of stable memory. The numbers are stored in the bucket table
+/// and must not change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    /// Length-prefixed Candid state, written in pre_upgrade.
+    State = 1,
+    /// Tree of the job indexes.
+    JobIndex = 2,
+    /// Indexed fields of each job, by job number.
+    JobEntries = 3,
+    /// Append-only audit log.
+    Audit = 4,
+}
+
+const REGIONS: usize = 5;
+
+thread_local! {
+    static LAYOUT_READY: Cell<bool> = const { Cell::new(false) };
+    /// Buckets of each region in order, read from the bucket table on first
+    /// use.
+    static BUCKETS: RefCell<Option<Vec<Vec<u64>>>> = const { RefCell::new(None) };
+}
+
+/// Stable memory kept on the heap outside a canister, so code built on
+/// regions can run in unit tests.
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    use super::PAGE_SIZE;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn stable_size() -> u64 {
+        MEMORY.with(|memory| memory.borrow().len() as u64 / PAGE_SIZE)
+    }
+
+    pub fn stable_grow(pages: u64) -> Result<u64, ()> {
+        let old = stable_size();
+        MEMORY.with(|memory| {
+            memory
+                .borrow_mut()
+                .resize(((old + pages) * PAGE_SIZE) as usize, 0)
+        });
+        Ok(old)
+    }
+
+    pub fn stable_read(offset: u64, buf: &mut [u8]) {
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            buf.copy_from_slice(&memory.borrow()[offset..offset + buf.len()]);
+        });
+    }
+
+    pub fn stable_write(offset: u64, bytes: &[u8]) {
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            memory.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        });
+    }
+}
+
+fn grow_to(end: u64) {
+    let pages = end.div_ceil(PAGE_SIZE);
+    let current = stable_size();
+    if pages > current {
+        stable_grow(pages - current).expect("Failed to grow stable memory");
+    }
+}
+
+fn read_u64(offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    stable_read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u64(offset: u64, value: u64) {
+    stable_write(offset, &value.to_le_bytes());
+}
+
+fn has_magic(magic: &[u8; 8]) -> bool {
+    if stable_size() == 0 {
+        return false;
+    }
+    let mut found = [0; 8];
+    stable_read(0, &mut found);
+    &found == magic
+}
+
+/// Whether stable memory already uses this layout.
+pub fn has_layout() -> bool {
+    has_magic(MAGIC)
+}
+
+/// Whether stable memory uses version 1 of the layout.
+pub fn has_v1_layout() -> bool {
+    has_magic(V1_MAGIC)
+}
+
+/// Set up the layout, discarding whatever stable memory held before. Callers
+/// must have restored any earlier state first.
+pub fn init_layout() {
+    grow_to(BUCKETS_OFFSET);
+    write_u64(LOG_SEQ_OFFSET, 0);
+    clear_regions();
+}
+
+/// Move from version 1 of the layout, keeping the log. Callers must have
+/// read the state with `load_v1_state` first.
+pub fn upgrade_v1_layout() {
+    clear_regions();
+}
+
+fn clear_regions() {
+    write_u64(BUCKET_COUNT_OFFSET, 0);
+    stable_write(BUCKET_TABLE, &vec![0; MAX_BUCKETS as usize]);
+    stable_write(0, MAGIC);
+    BUCKETS.with(|buckets| *buckets.borrow_mut() = None);
+    layout_ready();
+}
+
+/// Mark stable memory as laid out, e.g. after an upgrade restored it.
+pub fn layout_ready() {
+    LAYOUT_READY.with(|ready| ready.set(true));
+}
+
+/// Lay out stable memory unless it already is.
+pub fn ensure_layout() {
+    if LAYOUT_READY.with(Cell::get) {
+        return;
+    }
+    if !has_layout() {
+        init_layout();
+    }
+    layout_ready();
+}
+
+/// Whether stable memory is laid out, without laying it out, for reads.
+pub fn is_laid_out() -> bool {
+    LAYOUT_READY.with(Cell::get) || has_layout()
+}
+
+fn with_buckets<R>(f: impl FnOnce(&mut Vec<Vec<u64>>) -> R) -> R {
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let buckets = buckets.get_or_insert_with(|| {
+            let count = read_u64(BUCKET_COUNT_OFFSET).min(MAX_BUCKETS);
+            let mut owners = vec![0; count as usize];
+            stable_read(BUCKET_TABLE, &mut owners);
+            let mut regions = vec![Vec::new(); REGIONS];
+            for (bucket, owner) in owners.into_iter().enumerate() {
+                if let Some(region) = regions.get_mut(owner as usize) {
+                    region.push(bucket as u64);
+                }
+            }
+            regions
+        });
+        f(buckets)
+    })
+}
+
+impl Region {
+    /// Stable memory offset of `offset` in the region, if that part of the
+    /// region has a bucket, and how many bytes are left in the bucket.
+    fn locate(self, offset: u64) -> Option<(u64, u64)> {
+        let bucket = with_buckets(|buckets| {
+            buckets[self as usize]
+                .get((offset / BUCKET_SIZE) as usize)
+                .copied()
+        })?;
+        let within = offset % BUCKET_SIZE;
+        Some((
+            BUCKETS_OFFSET + bucket * BUCKET_SIZE + within,
+            BUCKET_SIZE - within,
+        ))
+    }
+
+    /// Give the region buckets until it covers `end`.
+    fn grow_to(self, end: u64) {
+        let needed = end.div_ceil(BUCKET_SIZE);
+        with_buckets(|buckets| {
+            while (buckets[self as usize].len() as u64) < needed {
+                let bucket = read_u64(BUCKET_COUNT_OFFSET);
+                assert!(bucket < MAX_BUCKETS, "Out of stable memory buckets");
+                let start = BUCKETS_OFFSET + bucket * BUCKET_SIZE;
+                let used = stable_size() * PAGE_SIZE;
+                grow_to(start + BUCKET_SIZE);
+                if start < used {
+                    // Memory an earlier layout used may hold old data.
+                    let len = (used - start).min(BUCKET_SIZE);
+                    stable_write(start, &vec![0; len as usize]);
+                }
+                stable_write(BUCKET_TABLE + bucket, &[self as u8]);
+                write_u64(BUCKET_COUNT_OFFSET, bucket + 1);
+                buckets[self as usize].push(bucket);
+            }
+        });
+    }
+}
+
+impl Memory for Region {
+    fn read(&self, mut offset: u64, mut buf: &mut [u8]) {
+        if !is_laid_out() {
+            buf.fill(0);
+            return;
+        }
+        while !buf.is_empty() {
+            let Some((at, left)) = self.locate(offset) else {
+                buf.fill(0);
+                return;
+            };
+            let len = left.min(buf.len() as u64) as usize;
+            stable_read(at, &mut buf[..len]);
+            buf = &mut buf[len..];
+            offset += len as u64;
+        }
+    }
+
+    fn write(&self, mut offset: u64, mut bytes: &[u8]) {
+        ensure_layout();
+        self.grow_to(offset + bytes.len() as u64);
+        while !bytes.is_empty() {
+            let (at, left) = self
+                .locate(offset)
+                .expect("Region was grown to cover the write");
+            let len = left.min(bytes.len() as u64) as usize;
+            stable_write(at, &bytes[..len]);
+            bytes = &bytes[len..];
+            offset += len as u64;
+        }
+    }
+}
+
+pub fn save_state(bytes: &[u8]) {
+    Region::State.write_u64(0, bytes.len() as u64);
+    Region::State.write(8, bytes);
+}
+
+pub fn load_state() -> Vec<u8> {
+    let len = Region::State.read_u64(0);
+    let mut bytes = vec![0; len as usize];
+    Region::State.read(8, &mut bytes);
+    bytes
+}
+
+/// Read the state saved with version 1 of the layout.
+pub fn load_v1_state() -> Vec<u8> {
+    let len = read_u64(V1_STATE_OFFSET);
+    let mut bytes = vec![0; len as usize];
+    stable_read(V1_STATE_OFFSET + 8, &mut bytes);
+    bytes
+}
+
+pub fn next_log_seq() -> u64 {
+    read_u64(LOG_SEQ_OFFSET)
+}
+
+pub fn set_next_log_seq(seq: u64) {
+    write_u64(LOG_SEQ_OFFSET, seq);
+}
+
+/// Read a log slot; an empty slot reads as no bytes.
+pub fn read_log_slot(index: u64) -> Vec<u8> {
+    let offset = LOG_OFFSET + (index % LOG_SLOTS) * LOG_SLOT_SIZE;
+    let mut len = [0; 4];
+    stable_read(offset, &mut len);
+    let len = (u32::from_le_bytes(len) as u64).min(LOG_SLOT_SIZE - 4);
+    let mut bytes = vec![0; len as usize];
+    stable_read(offset + 4, &mut bytes);
+    bytes
+}
+
+/// Write a log slot. `bytes` must fit in `LOG_SLOT_SIZE - 4`.
+pub fn write_log_slot(index: u64, bytes: &[u8]) {
+    let offset = LOG_OFFSET + (index % LOG_SLOTS) * LOG_SLOT_SIZE;
+    stable_write(offset, &(bytes.len() as u32).to_le_bytes());
+    stable_write(offset + 4, bytes);
+}
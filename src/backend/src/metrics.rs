@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::{gateway, queue, PaymentStatus, JOBS, PAYMENTS};
+
+/// Upper bounds of the LLM call latency buckets, in seconds.
+const LLM_LATENCY_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// Upper bounds of the PDF compression ratio buckets (output size over input size).
+const COMPRESSION_RATIO_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 0.75, 0.9, 1.0, 1.25];
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative; the last entry is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.counts[self.bounds.len()];
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Counters and histograms observed as calls happen. They are not persisted,
+/// so they reset on upgrade like any restarted Prometheus target.
+struct Observed {
+    llm_latency: Histogram,
+    llm_failures: u64,
+    compression_ratio: Histogram,
+}
+
+thread_local! {
+    static OBSERVED: RefCell<Observed> = RefCell::new(Observed {
+        llm_latency: Histogram::new(LLM_LATENCY_BUCKETS),
+        llm_failures: 0,
+        compression_ratio: Histogram::new(COMPRESSION_RATIO_BUCKETS),
+    });
+}
+
+/// Times an LLM call; dropping it without calling `succeeded` counts a
+/// failure, which covers calls that trap.
+pub struct LlmCall {
+    started_at: u64,
+    finished: bool,
+}
+
+impl LlmCall {
+    pub fn start() -> Self {
+        Self {
+            started_at: ic_cdk::api::time(),
+            finished: false,
+        }
+    }
+
+    pub fn succeeded(mut self) {
+        self.finished = true;
+        let seconds = ic_cdk::api::time().saturating_sub(self.started_at) as f64 / 1e9;
+        OBSERVED.with(|observed| observed.borrow_mut().llm_latency.observe(seconds));
+    }
+}
+
+impl Drop for LlmCall {
+    fn drop(&mut self) {
+        if !self.finished {
+            OBSERVED.with(|observed| observed.borrow_mut().llm_failures += 1);
+        }
+    }
+}
+
+/// Record the size of a PDF before and after compression.
+pub fn observe_compression(input_bytes: usize, output_bytes: usize) {
+    if input_bytes == 0 {
+        return;
+    }
+    let ratio = output_bytes as f64 / input_bytes as f64;
+    OBSERVED.with(|observed| observed.borrow_mut().compression_ratio.observe(ratio));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Escape a label value for the Prometheus text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Render every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut jobs: BTreeMap<(String, &'static str), u64> = BTreeMap::new();
+    JOBS.with(|all| {
+        for (job_id, job) in all.borrow().iter() {
+            *jobs
+                .entry((job.agent_id.clone(), gateway::job_status(job_id)))
+                .or_default() += 1;
+        }
+    });
+    out.push_str("# HELP marketplace_jobs Jobs by agent and status.\n");
+    out.push_str("# TYPE marketplace_jobs gauge\n");
+    for ((agent_id, status), count) in &jobs {
+        let _ = writeln!(
+            out,
+            "marketplace_jobs{{agent=\"{}\",status=\"{}\"}} {}",
+            label(agent_id),
+            status,
+            count
+        );
+    }
+
+    let mut payments: BTreeMap<&'static str, u64> = BTreeMap::new();
+    PAYMENTS.with(|all| {
+        for payment in all.borrow().values() {
+            let status = match payment.status {
+                PaymentStatus::Pending => "pending",
+                PaymentStatus::Completed => "completed",
+                PaymentStatus::Failed => "failed",
+            };
+            *payments.entry(status).or_default() += 1;
+        }
+    });
+    out.push_str("# HELP marketplace_payments Payments by status.\n");
+    out.push_str("# TYPE marketplace_payments gauge\n");
+    for (status, count) in &payments {
+        let _ = writeln!(
+            out,
+            "marketplace_payments{{status=\"{}\"}} {}",
+            status, count
+        );
+    }
+
+    gauge(
+        &mut out,
+        "marketplace_queue_depth",
+        "Tasks waiting on the job queue.",
+        queue::len(),
+    );
+
+    OBSERVED.with(|observed| {
+        let observed = observed.borrow();
+        observed.llm_latency.render(
+            &mut out,
+            "marketplace_llm_call_duration_seconds",
+            "Latency of successful LLM calls.",
+        );
+        let _ = writeln!(
+            out,
+            "# HELP marketplace_llm_failures_total LLM calls that failed.\n\
+             # TYPE marketplace_llm_failures_total counter\n\
+             marketplace_llm_failures_total {}",
+            observed.llm_failures
+        );
+        observed.compression_ratio.render(
+            &mut out,
+            "marketplace_pdf_compression_ratio",
+            "Compressed PDF size as a fraction of the input size.",
+        );
+    });
+
+    gauge(
+        &mut out,
+        "marketplace_heap_memory_bytes",
+        "Size of the canister's heap memory.",
+        heap_bytes(),
+    );
+    gauge(
+        &mut out,
+        "marketplace_stable_memory_bytes",
+        "Size of the canister's stable memory.",
+        ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE,
+    );
+    gauge(
+        &mut out,
+        "marketplace_cycle_balance",
+        "Cycles held by the canister.",
+        ic_cdk::api::canister_balance128(),
+    );
+
+    out
+}
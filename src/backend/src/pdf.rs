@@ -1,8 +1,246 @@
-use ic_cdk::println;
+use crate::error::MarketplaceError;
+use crate::log;
+use crate::pdf_ccitt;
+use crate::pdf_dedup;
+use crate::pdf_fonts::{self, FontReport};
+use crate::pdf_image::{self, ImageKind};
+use crate::pdf_placement::{self, effective_dpi, POINTS_PER_INCH};
+use crate::pdf_writer;
+use candid::{CandidType, Deserialize};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use image::imageops::FilterType;
 use image::{DynamicImage, GenericImageView};
 use jpeg_encoder::{ColorType as JpegColorType, Encoder};
-use lopdf::{Document, Object, ObjectId, Stream};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Version of the compression pipeline, pinned on quoted jobs.
+pub const VERSION: &str = "1.0.0";
+
+/// Version of the text extraction routine, pinned on quoted jobs.
+pub const TEXT_EXTRACTION_VERSION: &str = "1.0.0";
+
+/// Extract the text of every page of an in-memory PDF.
+pub fn extract_text(input_pdf: &[u8]) -> Result<String, MarketplaceError> {
+    let doc = Document::load_mem(input_pdf)
+        .map_err(|e| MarketplaceError::invalid("pdf", format!("Failed to load PDF: {}", e)))?;
+
+    let page_numbers: Vec<u32> = doc.get_pages().keys().cloned().collect();
+    let text = doc.extract_text(&page_numbers).map_err(|e| {
+        MarketplaceError::invalid("pdf", format!("Failed to extract text: {}", e))
+    })?;
+
+    if text.trim().is_empty() {
+        return Err(MarketplaceError::invalid(
+            "pdf",
+            "PDF does not contain any extractable text",
+        ));
+    }
+
+    Ok(text)
+}
+
+/// A re-encoded image must be at least this much smaller than the original
+/// to replace it; smaller gains are not worth another lossy generation.
+const MIN_IMAGE_SAVING: f64 = 0.05;
+
+/// Images are only downsampled once they exceed the target DPI by this
+/// factor, as with Ghostscript's default `ImageDownsampleThreshold`.
+const DOWNSAMPLE_THRESHOLD: f32 = 1.5;
+
+/// Lowest JPEG quality a size target may bring images down to.
+const MIN_TARGET_QUALITY: u8 = 10;
+
+/// Resolutions a size target may downsample images to, from the finest.
+const TARGET_DPI_STEPS: [u32; 6] = [300, 200, 150, 100, 72, 50];
+
+/// Instructions a size target may spend on attempts, leaving room under the
+/// 40 billion an update call may execute. The search stops with the best
+/// output so far once another attempt might not fit.
+const TARGET_INSTRUCTION_BUDGET: u64 = 30_000_000_000;
+
+/// Named option sets, after Ghostscript's `-dPDFSETTINGS`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionPreset {
+    /// Smallest output for on-screen viewing, 72 DPI.
+    Screen,
+    /// Readable on e-readers and tablets, 150 DPI.
+    Ebook,
+    /// Office printing, 300 DPI.
+    Printer,
+    /// Print production: 300 DPI, high quality, metadata kept.
+    Prepress,
+}
+
+impl CompressionPreset {
+    pub fn options(self) -> CompressionOptions {
+        let defaults = CompressionOptions::default();
+        match self {
+            Self::Screen => CompressionOptions {
+                jpeg_quality: 40,
+                max_image_dimension: Some(1_024),
+                target_dpi: Some(72),
+                object_streams: true,
+                ..defaults
+            },
+            Self::Ebook => CompressionOptions {
+                jpeg_quality: 60,
+                max_image_dimension: Some(2_048),
+                target_dpi: Some(150),
+                object_streams: true,
+                ..defaults
+            },
+            Self::Printer => CompressionOptions {
+                jpeg_quality: 80,
+                max_image_dimension: None,
+                target_dpi: Some(300),
+                ..defaults
+            },
+            Self::Prepress => CompressionOptions {
+                jpeg_quality: 90,
+                remove_metadata: false,
+                max_image_dimension: None,
+                target_dpi: Some(300),
+                ..defaults
+            },
+        }
+    }
+}
+
+/// Options accepted by `compress_pdf`. Fields left out keep the value of the
+/// preset, or of the defaults for the given quality when there is no preset.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PdfCompressionOptions {
+    pub preset: Option<CompressionPreset>,
+    pub jpeg_quality: Option<u8>,
+    pub compress_images: Option<bool>,
+    pub remove_metadata: Option<bool>,
+    /// Longest side in pixels; 0 removes the limit.
+    pub max_image_dimension: Option<u32>,
+    /// Resolution images are downsampled to; 0 removes the limit.
+    pub target_dpi: Option<u32>,
+    pub grayscale: Option<bool>,
+    /// Return the output even when it is larger than the input. Otherwise
+    /// the input comes back unchanged, annotations and metadata included.
+    pub keep_if_larger: Option<bool>,
+    pub strip_annotations: Option<bool>,
+    /// Pack objects into compressed object streams, which needs PDF 1.5.
+    /// Off unless asked for here or by the `Screen` and `Ebook` presets, since
+    /// older readers cannot open the result.
+    pub object_streams: Option<bool>,
+    /// Subset embedded fonts to the glyphs used and drop unused fonts. Off
+    /// unless asked for, since text added later, e.g. in form fields, may
+    /// need glyphs a subset lacks.
+    pub optimize_fonts: Option<bool>,
+    /// Largest acceptable output in bytes. JPEG quality and image resolution
+    /// are lowered as little as needed to get there; when no settings do,
+    /// the smallest output found is returned and the report says so.
+    pub target_bytes: Option<u64>,
+}
+
+impl PdfCompressionOptions {
+    /// Options to compress with; `quality` applies unless a preset or an
+    /// explicit JPEG quality overrides it.
+    pub fn resolve(self, quality: u8) -> CompressionOptions {
+        let base = match self.preset {
+            Some(preset) => preset.options(),
+            None => CompressionOptions {
+                jpeg_quality: quality,
+                ..CompressionOptions::default()
+            },
+        };
+        let limit = |value: u32| (value > 0).then_some(value);
+        CompressionOptions {
+            jpeg_quality: self.jpeg_quality.unwrap_or(base.jpeg_quality),
+            compress_images: self.compress_images.unwrap_or(base.compress_images),
+            remove_metadata: self.remove_metadata.unwrap_or(base.remove_metadata),
+            max_image_dimension: self
+                .max_image_dimension
+                .map_or(base.max_image_dimension, limit),
+            target_dpi: self.target_dpi.map_or(base.target_dpi, limit),
+            grayscale: self.grayscale.unwrap_or(base.grayscale),
+            keep_if_larger: self.keep_if_larger.unwrap_or(base.keep_if_larger),
+            strip_annotations: self.strip_annotations.unwrap_or(base.strip_annotations),
+            object_streams: self.object_streams.unwrap_or(base.object_streams),
+            optimize_fonts: self.optimize_fonts.unwrap_or(base.optimize_fonts),
+            target_bytes: self.target_bytes.or(base.target_bytes),
+        }
+    }
+}
+
+/// What compression did to one image.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ImageReport {
+    pub object_number: u32,
+    pub generation: u16,
+    pub kind: ImageKind,
+    /// Filters the image was encoded with, e.g. `DCTDecode`.
+    pub original_filters: Vec<String>,
+    pub original_width: u32,
+    pub original_height: u32,
+    pub width: u32,
+    pub height: u32,
+    pub original_bytes: u64,
+    pub bytes: u64,
+    /// Resolution the image is drawn at, when it was found on a page and a
+    /// target DPI was set.
+    pub effective_dpi: Option<u32>,
+    /// Why the image was left unchanged, if it was.
+    pub skipped: Option<String>,
+}
+
+/// Summary of a compression run.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompressionReport {
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    /// Compressed size over original size.
+    pub ratio: f64,
+    pub images: Vec<ImageReport>,
+    pub fonts: Vec<FontReport>,
+    /// Fonts no page used, removed from the document.
+    pub fonts_removed: u32,
+    /// Streams that were not compressed before and now use Flate.
+    pub streams_compressed: u32,
+    pub metadata_removed: bool,
+    /// Duplicate objects merged into one copy.
+    pub objects_merged: u32,
+    /// Objects no longer referenced, such as replaced images.
+    pub objects_pruned: u32,
+    /// Stream data dropped by merging and pruning.
+    pub stream_bytes_removed: u64,
+    /// Objects were packed into object streams with a cross-reference stream.
+    pub object_streams: bool,
+    /// The output was larger than the input, so the input was returned.
+    pub kept_original: bool,
+    /// Settings chosen to meet a size target, when one was given.
+    pub target: Option<TargetSettings>,
+}
+
+/// Settings chosen for a size target.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TargetSettings {
+    pub target_bytes: u64,
+    /// Whether the output fits the target. When no settings got there, the
+    /// output is the smallest one found.
+    pub met: bool,
+    pub jpeg_quality: u8,
+    /// Resolution images were downsampled to, if any.
+    pub target_dpi: Option<u32>,
+    /// Compression runs it took to find them.
+    pub attempts: u32,
+}
+
+/// A compressed PDF with its report.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CompressedPdf {
+    #[serde(with = "serde_bytes")]
+    pub pdf: Vec<u8>,
+    pub report: CompressionReport,
+}
 
 /// Configuration for PDF compression.
 #[derive(Clone, Debug)]
@@ -15,6 +253,21 @@ pub struct CompressionOptions {
     pub remove_metadata: bool,
     /// Optional maximum dimension applied when downscaling images.
     pub max_image_dimension: Option<u32>,
+    /// Optional resolution images are downsampled to.
+    pub target_dpi: Option<u32>,
+    /// Whether photos are converted to grayscale when recompressed.
+    pub grayscale: bool,
+    /// Whether to return the output even when it is larger than the input.
+    pub keep_if_larger: bool,
+    /// Whether page annotations (links, comments, form widgets) are removed.
+    pub strip_annotations: bool,
+    /// Whether objects are saved in object streams (PDF 1.5) rather than
+    /// uncompressed behind a classic cross-reference table.
+    pub object_streams: bool,
+    /// Whether embedded fonts are subset and unused fonts removed.
+    pub optimize_fonts: bool,
+    /// Optional output size in bytes to search quality and resolution for.
+    pub target_bytes: Option<u64>,
 }
 
 impl Default for CompressionOptions {
@@ -24,6 +277,13 @@ impl Default for CompressionOptions {
             compress_images: true,
             remove_metadata: true,
             max_image_dimension: Some(2_048),
+            target_dpi: None,
+            grayscale: false,
+            keep_if_larger: false,
+            strip_annotations: false,
+            object_streams: false,
+            optimize_fonts: false,
+            target_bytes: None,
         }
     }
 }
@@ -36,69 +296,368 @@ pub struct PdfCompressor {
 impl PdfCompressor {
     /// Create a compressor with default options for a given quality.
     pub fn new(quality: u8) -> Self {
-        let mut options = CompressionOptions::default();
-        options.jpeg_quality = quality.clamp(1, 100);
+        let options = CompressionOptions {
+            jpeg_quality: quality.clamp(1, 100),
+            ..CompressionOptions::default()
+        };
         Self { options }
     }
 
     /// Create a compressor with custom options.
-    #[allow(dead_code)]
     pub fn with_options(options: CompressionOptions) -> Self {
         let mut options = options;
         options.jpeg_quality = options.jpeg_quality.clamp(1, 100);
         Self { options }
     }
 
-    /// Compress an in-memory PDF and return the resulting bytes.
-    pub fn compress(&self, input_pdf: Vec<u8>) -> Result<Vec<u8>, String> {
-        ic_cdk::println!("Compressing PDF...");
+    /// Compress an in-memory PDF and report what was done.
+    pub fn compress(&self, input_pdf: Vec<u8>) -> Result<CompressedPdf, MarketplaceError> {
+        match self.options.target_bytes {
+            Some(target) => self.compress_to_target(input_pdf, target),
+            None => self.compress_once(input_pdf),
+        }
+    }
+
+    /// Compress to at most `target` bytes with the best settings that get
+    /// there: the finest downsampling level that fits at the lowest quality,
+    /// then the highest quality that fits at that level. Both are binary
+    /// searches, on the assumption that lower settings give smaller output.
+    /// When no settings fit, the smallest output found is returned.
+    fn compress_to_target(
+        &self,
+        input_pdf: Vec<u8>,
+        target: u64,
+    ) -> Result<CompressedPdf, MarketplaceError> {
+        let quality = self.options.jpeg_quality;
+        let min_quality = MIN_TARGET_QUALITY.min(quality);
+        let mut levels = vec![self.options.target_dpi];
+        levels.extend(
+            TARGET_DPI_STEPS
+                .iter()
+                .filter(|&&dpi| self.options.target_dpi.is_none_or(|limit| dpi < limit))
+                .map(|&dpi| Some(dpi)),
+        );
+
+        let attempts = Cell::new(0);
+        let costliest = Cell::new(0);
+        let attempt = |jpeg_quality: u8, target_dpi: Option<u32>| {
+            attempts.set(attempts.get() + 1);
+            let start = instructions();
+            let options = CompressionOptions {
+                jpeg_quality,
+                target_dpi,
+                target_bytes: None,
+                ..self.options.clone()
+            };
+            let compressed = Self::with_options(options).compress_once(input_pdf.clone())?;
+            let cost = instructions() - start;
+            costliest.set(costliest.get().max(cost));
+            let fits = compressed.report.compressed_bytes <= target;
+            Ok::<_, MarketplaceError>((fits, compressed))
+        };
+        let stopped = Cell::new(false);
+        let within_budget = || {
+            if stopped.get() {
+                return false;
+            }
+            let used = instructions();
+            let within = used + costliest.get() <= TARGET_INSTRUCTION_BUDGET;
+            if !within {
+                stopped.set(true);
+                log::info(
+                    None,
+                    format!(
+                        "Stopped after {} attempts to stay within the instruction limit",
+                        attempts.get()
+                    ),
+                );
+            }
+            within
+        };
+
+        let (fits, mut best) = attempt(quality, levels[0])?;
+        let (mut level, mut best_quality) = (0, quality);
+        // Only images respond to quality and resolution.
+        if !fits && self.options.compress_images && within_budget() {
+            let last = levels.len() - 1;
+            let (fits, smallest) = attempt(min_quality, levels[last])?;
+            if smallest.report.compressed_bytes < best.report.compressed_bytes {
+                (best, level, best_quality) = (smallest, last, min_quality);
+            }
+
+            let mut low = 0;
+            while fits && low < level && within_budget() {
+                let middle = (low + level) / 2;
+                match attempt(min_quality, levels[middle])? {
+                    (true, compressed) => (best, level) = (compressed, middle),
+                    (false, _) => low = middle + 1,
+                }
+            }
+            // The caller's quality is already known not to fit at level 0.
+            let mut high = if level == 0 { quality - 1 } else { quality };
+            while fits && best_quality < high && within_budget() {
+                let middle = best_quality + (high - best_quality).div_ceil(2);
+                match attempt(middle, levels[level])? {
+                    (true, compressed) => (best, best_quality) = (compressed, middle),
+                    (false, _) => high = middle - 1,
+                }
+            }
+        }
+
+        let met = best.report.compressed_bytes <= target;
+        log::debug(
+            None,
+            format!(
+                "{} a {} byte target at quality {} and {:?} DPI in {} attempts",
+                if met { "Met" } else { "Missed" },
+                target,
+                best_quality,
+                levels[level],
+                attempts.get()
+            ),
+        );
+        best.report.target = Some(TargetSettings {
+            target_bytes: target,
+            met,
+            jpeg_quality: best_quality,
+            target_dpi: levels[level],
+            attempts: attempts.get(),
+        });
+        Ok(best)
+    }
+
+    /// Run the pipeline once with the configured options.
+    fn compress_once(&self, input_pdf: Vec<u8>) -> Result<CompressedPdf, MarketplaceError> {
+        log::debug(None, format!("Compressing PDF of {} bytes", input_pdf.len()));
         let mut doc = Document::load_mem(&input_pdf)
-            .map_err(|e| format!("Failed to load PDF: {}", e))?;
+            .map_err(|e| MarketplaceError::invalid("pdf", format!("Failed to load PDF: {}", e)))?;
+
+        if self.options.strip_annotations {
+            self.strip_annotations(&mut doc);
+        }
+
+        let images = if self.options.compress_images {
+            let layout = self.layout(&doc);
+            self.compress_pdf_images(&mut doc, &layout)
+        } else {
+            Vec::new()
+        };
+
+        let fonts = if self.options.optimize_fonts {
+            pdf_fonts::optimize(&mut doc).unwrap_or_else(|err| {
+                log::warn(None, format!("Leaving fonts unchanged: {}", err));
+                pdf_fonts::FontsOptimized::default()
+            })
+        } else {
+            pdf_fonts::FontsOptimized::default()
+        };
+
+        let streams_compressed = self.optimize_streams(&mut doc)?;
+
+        let metadata_removed = self.options.remove_metadata && self.remove_metadata(&mut doc);
 
-        if self.options.compress_images {
-            self.compress_pdf_images(&mut doc)?;
+        let merged = pdf_dedup::deduplicate(&mut doc);
+        let pruned = pdf_dedup::prune(&mut doc);
+
+        let object_streams =
+            self.options.object_streams && pdf_writer::supports_object_streams(&doc);
+        let buffer = if object_streams {
+            pdf_writer::save_with_object_streams(&doc)
+        } else {
+            let mut buffer = Vec::new();
+            doc.save_to(&mut buffer).map(|_| buffer)
         }
+        .map_err(|e| MarketplaceError::internal(format!("Failed to save PDF: {}", e)))?;
+
+        let original_bytes = input_pdf.len() as u64;
+        let kept_original = buffer.len() > input_pdf.len() && !self.options.keep_if_larger;
+        let pdf = if kept_original {
+            log::debug(
+                None,
+                format!(
+                    "Compressed PDF is {} bytes, keeping the {} byte original",
+                    buffer.len(),
+                    input_pdf.len()
+                ),
+            );
+            input_pdf
+        } else {
+            log::debug(None, format!("PDF compressed to {} bytes", buffer.len()));
+            buffer
+        };
 
-        self.optimize_streams(&mut doc)?;
+        let compressed_bytes = pdf.len() as u64;
+        Ok(CompressedPdf {
+            pdf,
+            report: CompressionReport {
+                original_bytes,
+                compressed_bytes,
+                ratio: if original_bytes == 0 {
+                    1.0
+                } else {
+                    compressed_bytes as f64 / original_bytes as f64
+                },
+                images,
+                fonts: fonts.fonts,
+                fonts_removed: fonts.removed,
+                streams_compressed,
+                metadata_removed,
+                objects_merged: merged.objects,
+                objects_pruned: pruned.objects,
+                stream_bytes_removed: merged.stream_bytes + pruned.stream_bytes,
+                object_streams: object_streams && !kept_original,
+                kept_original,
+                target: None,
+            },
+        })
+    }
 
-        if self.options.remove_metadata {
-            self.remove_metadata(&mut doc)?;
+    /// Where images are drawn, needed only when downsampling to a DPI.
+    fn layout(&self, doc: &Document) -> Layout {
+        let Some(dpi) = self.options.target_dpi else {
+            return Layout::default();
+        };
+        let longest_side = doc
+            .page_iter()
+            .filter_map(|page_id| page_size(doc, page_id))
+            .map(|(width, height)| width.max(height))
+            .fold(0.0_f32, f32::max);
+        Layout {
+            placements: pdf_placement::image_placements(doc),
+            page_limit: (longest_side > 0.0)
+                .then(|| (longest_side / POINTS_PER_INCH * dpi as f32).ceil() as u32),
         }
+    }
 
-        let mut buffer = Vec::new();
-        doc.save_to(&mut buffer)
-            .map_err(|e| format!("Failed to save PDF: {}", e))?;
+    /// The pixel limit for an image: the configured maximum dimension,
+    /// lowered to bring the image down to the target DPI where it is drawn
+    /// at more than `DOWNSAMPLE_THRESHOLD` times that.
+    fn dimension_limit(
+        &self,
+        layout: &Layout,
+        id: ObjectId,
+        width: u32,
+        height: u32,
+    ) -> Option<u32> {
+        let from_dpi = self.options.target_dpi.and_then(|target| {
+            let Some(&(drawn_width, drawn_height)) = layout.placements.get(&id) else {
+                return layout.page_limit;
+            };
+            let dpi = effective_dpi(width, height, drawn_width, drawn_height)?;
+            let target = target as f32;
+            (dpi > target * DOWNSAMPLE_THRESHOLD)
+                .then(|| (width.max(height) as f32 * target / dpi).ceil() as u32)
+        });
+        match (self.options.max_image_dimension, from_dpi) {
+            (Some(max), Some(dpi)) => Some(max.min(dpi)),
+            (max, dpi) => max.or(dpi),
+        }
+    }
 
-        ic_cdk::println!("PDF compressed successfully");
-        Ok(buffer)
+    fn strip_annotations(&self, doc: &mut Document) {
+        let page_ids: Vec<ObjectId> = doc.page_iter().collect();
+        for page_id in page_ids {
+            if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                page.remove(b"Annots");
+            }
+        }
+        // Form fields are widget annotations; without them the form is empty.
+        if let Ok(catalog) = doc.catalog_mut() {
+            catalog.remove(b"AcroForm");
+        }
     }
 
-    fn compress_pdf_images(&self, doc: &mut Document) -> Result<(), String> {
-        ic_cdk::println!("Compressing PDF images...");
+    fn compress_pdf_images(
+        &self,
+        doc: &mut Document,
+        layout: &Layout,
+    ) -> Vec<ImageReport> {
         let object_ids: Vec<ObjectId> = doc.objects.keys().cloned().collect();
+        let soft_masks: HashSet<ObjectId> = doc
+            .objects
+            .values()
+            .filter_map(|object| object.as_stream().ok())
+            .filter(|stream| self.is_image_stream(stream))
+            .filter_map(|stream| stream.dict.get(b"SMask").and_then(Object::as_reference).ok())
+            .collect();
+        let mut reports = Vec::new();
 
         for object_id in object_ids {
-            let stream_result = doc
-                .get_object_mut(object_id)
-                .and_then(Object::as_stream_mut);
+            let stream = match doc.get_object(object_id).and_then(Object::as_stream) {
+                Ok(stream) if self.is_image_stream(stream) => stream,
+                _ => continue,
+            };
 
-            let stream = match stream_result {
-                Ok(stream) => stream,
-                Err(_) => continue,
+            let dimension = |key: &[u8]| {
+                stream
+                    .dict
+                    .get(key)
+                    .and_then(Object::as_i64)
+                    .map_or(0, |value| value.clamp(0, u32::MAX as i64) as u32)
+            };
+            let kind = pdf_image::classify(doc, stream, soft_masks.contains(&object_id));
+            let mut report = ImageReport {
+                object_number: object_id.0,
+                generation: object_id.1,
+                kind,
+                original_filters: stream.filters().unwrap_or_default(),
+                original_width: dimension(b"Width"),
+                original_height: dimension(b"Height"),
+                width: 0,
+                height: 0,
+                original_bytes: stream.content.len() as u64,
+                bytes: stream.content.len() as u64,
+                effective_dpi: None,
+                skipped: None,
+            };
+            report.width = report.original_width;
+            report.height = report.original_height;
+            report.effective_dpi = layout.placements.get(&object_id).and_then(|drawn| {
+                effective_dpi(report.width, report.height, drawn.0, drawn.1)
+                    .map(|dpi| dpi.round() as u32)
+            });
+            let max_dimension =
+                self.dimension_limit(layout, object_id, report.width, report.height);
+
+            let encoded = match kind {
+                ImageKind::StencilMask => Err("Stencil masks are kept as they are".to_string()),
+                // A soft mask with a Matte entry must stay the size of its image.
+                _ if kind.lossy() && has_matte(doc, stream) => {
+                    self.recompress_image(doc, stream, None)
+                }
+                _ if kind.lossy() => self.recompress_image(doc, stream, max_dimension),
+                _ => self.repack_image(doc, stream),
             };
 
-            if self.is_image_stream(stream) {
-                if let Err(err) = self.compress_image_stream(stream) {
-                    println!(
-                        "pdf_compressor: failed to compress image stream {:?}: {}",
-                        object_id, err
+            match encoded {
+                Ok(Some(encoded)) => {
+                    if let EncodedImage::Jpeg { width, height, .. } = encoded {
+                        report.width = width;
+                        report.height = height;
+                    }
+                    report.bytes = encoded.len() as u64;
+                    if let Ok(stream) = doc
+                        .get_object_mut(object_id)
+                        .and_then(Object::as_stream_mut)
+                    {
+                        encoded.replace(stream);
+                    }
+                }
+                Ok(None) => {
+                    report.skipped = Some("Re-encoding did not make the image smaller".to_string());
+                }
+                Err(err) => {
+                    log::warn(
+                        None,
+                        format!("Failed to compress image stream {:?}: {}", object_id, err),
                     );
+                    report.skipped = Some(err);
                 }
             }
+            reports.push(report);
         }
-        
-        ic_cdk::println!("PDF images compressed successfully");
-        Ok(())
+
+        reports
     }
 
     fn is_image_stream(&self, stream: &Stream) -> bool {
@@ -110,55 +669,106 @@ impl PdfCompressor {
             .unwrap_or(false)
     }
 
-    fn compress_image_stream(&self, stream: &mut Stream) -> Result<(), String> {
-        let image_data = stream
-            .decompressed_content()
-            .map_err(|e| format!("Failed to decompress stream: {}", e))?;
-
-        let mut image = image::load_from_memory(&image_data)
-            .map_err(|e| format!("Failed to decode embedded image: {}", e))?;
+    /// Re-encode a photo or grayscale image as JPEG, or return `None` when
+    /// the original should be kept because re-encoding did not save enough.
+    fn recompress_image(
+        &self,
+        doc: &Document,
+        stream: &Stream,
+        max_dimension: Option<u32>,
+    ) -> Result<Option<EncodedImage>, String> {
+        let mut image = pdf_image::decode(doc, stream)?;
 
-        if let Some(max_dimension) = self.options.max_image_dimension {
+        if let Some(max_dimension) = max_dimension {
             self.downscale_if_needed(&mut image, max_dimension);
         }
 
-        let rgb_image = image.to_rgb8();
-        let (width, height) = rgb_image.dimensions();
+        let (width, height) = image.dimensions();
 
         if width == 0 || height == 0 {
-            return Ok(());
+            return Err("Image has no pixels".to_string());
         }
+        // JPEG stores each dimension in 16 bits.
+        let (Ok(jpeg_width), Ok(jpeg_height)) = (u16::try_from(width), u16::try_from(height))
+        else {
+            return Err(format!(
+                "Image of {}x{} pixels is larger than JPEG allows",
+                width, height
+            ));
+        };
+
+        // Samples keep their values, so the original color space (an ICC
+        // profile, say) still describes them unless they become gray.
+        let single_channel = image.color().channel_count() == 1;
+        let to_gray = self.options.grayscale && !single_channel;
+        let (pixel_bytes, color_type) = if to_gray || single_channel {
+            (image.to_luma8().into_raw(), JpegColorType::Luma)
+        } else {
+            (image.to_rgb8().into_raw(), JpegColorType::Rgb)
+        };
 
         let mut buffer = Vec::new();
         let jpeg_quality = self.scale_quality_for_encoder(self.options.jpeg_quality);
-        let pixel_bytes = rgb_image.as_raw();
 
         Encoder::new(&mut buffer, jpeg_quality)
             .encode(
                 pixel_bytes.as_slice(),
-                width.min(u16::MAX as u32) as u16,
-                height.min(u16::MAX as u32) as u16,
-                JpegColorType::Rgb,
+                jpeg_width,
+                jpeg_height,
+                color_type,
             )
             .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
 
-        stream.set_plain_content(buffer);
-        stream
-            .dict
-            .set(b"Filter", Object::Name(b"DCTDecode".to_vec()));
-        stream
-            .dict
-            .set(b"ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
-        stream.dict.set(b"BitsPerComponent", Object::Integer(8));
-        stream
-            .dict
-            .set(b"Width", Object::Integer(width as i64));
-        stream
-            .dict
-            .set(b"Height", Object::Integer(height as i64));
-        stream.dict.remove(b"DecodeParms");
+        let original_len = stream.content.len() as f64;
+        if buffer.len() as f64 > original_len * (1.0 - MIN_IMAGE_SAVING) {
+            return Ok(None);
+        }
+
+        Ok(Some(EncodedImage::Jpeg {
+            content: buffer,
+            width,
+            height,
+            color_space: to_gray.then_some("DeviceGray"),
+        }))
+    }
+
+    /// Losslessly recompress an image's original samples, for images that
+    /// must not change: bilevel, indexed, CMYK, color-keyed and soft masks.
+    /// Samples of one bit per pixel are CCITT G4-encoded when that is
+    /// smaller than Flate, as it usually is for scans and line art.
+    fn repack_image(
+        &self,
+        doc: &Document,
+        stream: &Stream,
+    ) -> Result<Option<EncodedImage>, String> {
+        let samples = pdf_image::samples(doc, stream)?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder
+            .write_all(&samples)
+            .map_err(|e| format!("Failed to compress image samples: {}", e))?;
+        let content = encoder
+            .finish()
+            .map_err(|e| format!("Failed to compress image samples: {}", e))?;
+        let mut encoded = EncodedImage::Flate { content };
+
+        if let Some((width, height)) = pdf_image::fax_size(doc, stream) {
+            let (columns, rows) = (width as usize, height as usize);
+            if samples.len() >= columns.div_ceil(8) * rows {
+                let content = pdf_ccitt::encode_g4(&samples, columns, rows);
+                if content.len() < encoded.len() {
+                    encoded = EncodedImage::Fax {
+                        content,
+                        columns: width,
+                        rows: height,
+                    };
+                }
+            }
+        }
 
-        Ok(())
+        if encoded.len() >= stream.content.len() {
+            return Ok(None);
+        }
+        Ok(Some(encoded))
     }
 
     fn downscale_if_needed(&self, image: &mut DynamicImage, max_dimension: u32) {
@@ -179,38 +789,305 @@ impl PdfCompressor {
     fn scale_quality_for_encoder(&self, quality: u8) -> u8 {
         // jpeg-encoder expects a 1-255 quality value. Map 1-100 to that range.
         let clamped = quality.clamp(1, 100) as u16;
-        ((clamped * 255) / 100).max(1).min(255) as u8
+        ((clamped * 255) / 100).clamp(1, 255) as u8
     }
 
-    fn optimize_streams(&self, doc: &mut Document) -> Result<(), String> {
+    /// Flate-compress streams that have no filter; returns how many were.
+    /// `Stream::compress` only keeps the result when it is smaller.
+    fn optimize_streams(&self, doc: &mut Document) -> Result<u32, MarketplaceError> {
+        let mut compressed = 0;
         for (_id, object) in doc.objects.iter_mut() {
             if let Ok(stream) = object.as_stream_mut() {
-                if self.is_image_stream(stream) {
+                if self.is_image_stream(stream) || stream.dict.has(b"Filter") {
                     continue;
                 }
 
-                stream
-                    .compress()
-                    .map_err(|e| format!("Failed to apply Flate compression: {}", e))?;
+                stream.compress().map_err(|e| {
+                    MarketplaceError::internal(format!("Failed to apply Flate compression: {}", e))
+                })?;
+                if stream.dict.has(b"Filter") {
+                    compressed += 1;
+                }
             }
         }
 
-        Ok(())
+        Ok(compressed)
     }
 
-    fn remove_metadata(&self, doc: &mut Document) -> Result<(), String> {
-        doc.trailer.remove(b"Info");
-        doc.trailer.remove(b"ID");
+    /// Remove the document info, ID and XMP metadata; returns whether there
+    /// was any. The objects themselves are pruned once unreferenced.
+    fn remove_metadata(&self, doc: &mut Document) -> bool {
+        let mut removed = doc.trailer.remove(b"Info").is_some();
+        removed |= doc.trailer.remove(b"ID").is_some();
 
         if let Ok(catalog) = doc.catalog_mut() {
-            if let Some(metadata) = catalog.remove(b"Metadata") {
-                if let Ok(reference) = metadata.as_reference() {
-                    doc.delete_object(reference);
+            removed |= catalog.remove(b"Metadata").is_some();
+        }
+
+        removed
+    }
+}
+
+/// A replacement for an image stream.
+enum EncodedImage {
+    /// Decoded pixels as JPEG; `color_space` replaces the original color
+    /// space when the pixels were converted to it.
+    Jpeg {
+        content: Vec<u8>,
+        width: u32,
+        height: u32,
+        color_space: Option<&'static str>,
+    },
+    /// The original samples, Flate-compressed.
+    Flate { content: Vec<u8> },
+    /// The original samples of a 1-bit image, CCITT G4-encoded.
+    Fax {
+        content: Vec<u8>,
+        columns: u32,
+        rows: u32,
+    },
+}
+
+impl EncodedImage {
+    fn len(&self) -> usize {
+        match self {
+            Self::Jpeg { content, .. } | Self::Flate { content } | Self::Fax { content, .. } => {
+                content.len()
+            }
+        }
+    }
+
+    /// Put the new encoding in place of the stream's image. JPEG samples
+    /// were decoded into plain colors, so `Decode` no longer applies to them.
+    fn replace(self, stream: &mut Stream) {
+        stream.dict.remove(b"DecodeParms");
+        match self {
+            Self::Jpeg {
+                content,
+                width,
+                height,
+                color_space,
+            } => {
+                stream.set_plain_content(content);
+                stream.dict.set("Filter", "DCTDecode");
+                if let Some(color_space) = color_space {
+                    stream.dict.set("ColorSpace", color_space);
                 }
+                stream.dict.set("BitsPerComponent", 8);
+                stream.dict.set("Width", width as i64);
+                stream.dict.set("Height", height as i64);
+                stream.dict.remove(b"Decode");
+            }
+            Self::Flate { content } => {
+                stream.set_plain_content(content);
+                stream.dict.set("Filter", "FlateDecode");
+            }
+            // BlackIs1 false: a 0 bit decodes as black, as the samples had it.
+            Self::Fax {
+                content,
+                columns,
+                rows,
+            } => {
+                stream.set_plain_content(content);
+                stream.dict.set("Filter", "CCITTFaxDecode");
+                let mut params = Dictionary::new();
+                params.set("K", -1);
+                params.set("Columns", columns as i64);
+                params.set("Rows", rows as i64);
+                stream.dict.set("DecodeParms", params);
             }
         }
+    }
+}
+
+/// Instructions executed so far in this message. Outside a canister, e.g.
+/// in unit tests, none are counted.
+fn instructions() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::instruction_counter()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// Where images are drawn, for downsampling to a target DPI.
+#[derive(Default)]
+struct Layout {
+    /// Largest size each image is drawn at, in points.
+    placements: HashMap<ObjectId, (f32, f32)>,
+    /// Limit for images not found on a page: what the target DPI allows on
+    /// the largest page, since no image is drawn bigger than its page.
+    page_limit: Option<u32>,
+}
+
+/// Whether an image's soft mask has a `Matte` entry, which requires the
+/// image and its mask to keep the same dimensions.
+fn has_matte(doc: &Document, stream: &Stream) -> bool {
+    stream
+        .dict
+        .get_deref(b"SMask", doc)
+        .and_then(Object::as_stream)
+        .is_ok_and(|mask| mask.dict.has(b"Matte"))
+}
 
-        Ok(())
+/// Width and height of a page in points, from its own or an inherited
+/// `MediaBox`.
+fn page_size(doc: &Document, page_id: ObjectId) -> Option<(f32, f32)> {
+    let mut node = doc.get_dictionary(page_id).ok()?;
+    // Bounded, in case a malformed page tree has a cycle.
+    for _ in 0..32 {
+        if let Ok(media_box) = node.get_deref(b"MediaBox", doc).and_then(Object::as_array) {
+            let corners: Vec<f32> = media_box
+                .iter()
+                .filter_map(|value| doc.dereference(value).ok()?.1.as_float().ok())
+                .collect();
+            if let [x0, y0, x1, y1] = corners[..] {
+                return Some(((x1 - x0).abs(), (y1 - y0).abs()));
+            }
+            return None;
+        }
+        node = node
+            .get_deref(b"Parent", doc)
+            .and_then(Object::as_dict)
+            .ok()?;
     }
+    None
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// A page with a noisy 300x300 photo drawn on 100x100 points, 216 DPI.
+    fn photo_pdf() -> Vec<u8> {
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let pixels: Vec<u8> = (0..300 * 300 * 3)
+            .map(|i| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                ((i / 3 % 300) as u8 / 2).wrapping_add(seed as u8 % 64)
+            })
+            .collect();
+
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let image = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => 300,
+                "Height" => 300,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+            },
+            pixels,
+        ));
+        let content = doc.add_object(Stream::new(
+            dictionary! {},
+            b"q 100 0 0 100 0 0 cm /Im0 Do Q".to_vec(),
+        ));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image } },
+            "Contents" => content,
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn compress(options: CompressionOptions) -> CompressedPdf {
+        PdfCompressor::with_options(options)
+            .compress(photo_pdf())
+            .unwrap()
+    }
+
+    /// Output size at the given quality and resolution, without a target.
+    fn size_at(jpeg_quality: u8, target_dpi: Option<u32>) -> u64 {
+        let options = CompressionOptions {
+            jpeg_quality,
+            target_dpi,
+            ..CompressionOptions::default()
+        };
+        compress(options).report.compressed_bytes
+    }
+
+    #[test]
+    fn reachable_target_is_met() {
+        let (full, smallest) = (size_at(70, None), size_at(MIN_TARGET_QUALITY, Some(50)));
+        assert!(smallest < full);
+        let target = (full + smallest) / 2;
+
+        let compressed = compress(CompressionOptions {
+            target_bytes: Some(target),
+            ..CompressionOptions::default()
+        });
+        let report = compressed.report;
+        assert!(report.compressed_bytes <= target);
+        assert_eq!(report.compressed_bytes, compressed.pdf.len() as u64);
+        let settings = report.target.unwrap();
+        assert!(settings.met);
+        assert_eq!(settings.target_bytes, target);
+        assert!(settings.attempts > 2);
+        // The chosen settings give the same output without a target.
+        assert_eq!(
+            size_at(settings.jpeg_quality, settings.target_dpi),
+            report.compressed_bytes
+        );
+
+        // A target the caller's settings already meet takes one attempt.
+        let compressed = compress(CompressionOptions {
+            target_bytes: Some(full),
+            ..CompressionOptions::default()
+        });
+        let settings = compressed.report.target.unwrap();
+        assert!(settings.met);
+        assert_eq!((settings.jpeg_quality, settings.target_dpi), (70, None));
+        assert_eq!(settings.attempts, 1);
+    }
+
+    #[test]
+    fn unreachable_target_falls_back_to_the_smallest_result() {
+        let smallest = size_at(MIN_TARGET_QUALITY, Some(50));
+        let compressed = compress(CompressionOptions {
+            target_bytes: Some(1_000),
+            ..CompressionOptions::default()
+        });
+        let report = compressed.report;
+        assert_eq!(report.compressed_bytes, smallest);
+        let settings = report.target.unwrap();
+        assert!(!settings.met);
+        assert_eq!(
+            (settings.jpeg_quality, settings.target_dpi),
+            (MIN_TARGET_QUALITY, Some(50))
+        );
+        assert_eq!(settings.attempts, 2);
+
+        // Without images to lower, the first output is the only one.
+        let compressed = compress(CompressionOptions {
+            compress_images: false,
+            target_bytes: Some(1_000),
+            ..CompressionOptions::default()
+        });
+        let settings = compressed.report.target.unwrap();
+        assert!(!settings.met);
+        assert_eq!(settings.attempts, 1);
+    }
+}
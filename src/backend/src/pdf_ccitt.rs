@@ -0,0 +1,565 @@
+// CCITT Group 4 (T.6) encoding of 1-bit images, for the CCITTFaxDecode
+// filter with K -1.
+//
+// Each row is coded against the row above it, the first against an
+// all-white row. Samples are read as the filter outputs them with BlackIs1
+// false: a 0 bit is black and a 1 bit white, so decoding reproduces them
+// bit for bit whatever the image's Decode array says they mean.
+
+/// Pass mode, `0001`.
+const PASS: (u16, u8) = (0b0001, 4);
+
+/// Horizontal mode, `001`, followed by two runs.
+const HORIZONTAL: (u16, u8) = (0b001, 3);
+
+/// Vertical modes for `a1 - b1` from -3 to 3.
+const VERTICAL: [(u16, u8); 7] = [
+    (0b0000010, 7),
+    (0b000010, 6),
+    (0b010, 3),
+    (0b1, 1),
+    (0b011, 3),
+    (0b000011, 6),
+    (0b0000011, 7),
+];
+
+/// End of line; two of them end the data.
+const EOL: (u16, u8) = (0b0000_0000_0001, 12);
+
+// Run length codes, from tables 2 and 3 of T.4.
+
+/// White run lengths 0 to 63.
+const WHITE_TERMINATING: [(u16, u8); 64] = [
+    (0b00110101, 8),
+    (0b000111, 6),
+    (0b0111, 4),
+    (0b1000, 4),
+    (0b1011, 4),
+    (0b1100, 4),
+    (0b1110, 4),
+    (0b1111, 4),
+    (0b10011, 5),
+    (0b10100, 5),
+    (0b00111, 5),
+    (0b01000, 5),
+    (0b001000, 6),
+    (0b000011, 6),
+    (0b110100, 6),
+    (0b110101, 6),
+    (0b101010, 6),
+    (0b101011, 6),
+    (0b0100111, 7),
+    (0b0001100, 7),
+    (0b0001000, 7),
+    (0b0010111, 7),
+    (0b0000011, 7),
+    (0b0000100, 7),
+    (0b0101000, 7),
+    (0b0101011, 7),
+    (0b0010011, 7),
+    (0b0100100, 7),
+    (0b0011000, 7),
+    (0b00000010, 8),
+    (0b00000011, 8),
+    (0b00011010, 8),
+    (0b00011011, 8),
+    (0b00010010, 8),
+    (0b00010011, 8),
+    (0b00010100, 8),
+    (0b00010101, 8),
+    (0b00010110, 8),
+    (0b00010111, 8),
+    (0b00101000, 8),
+    (0b00101001, 8),
+    (0b00101010, 8),
+    (0b00101011, 8),
+    (0b00101100, 8),
+    (0b00101101, 8),
+    (0b00000100, 8),
+    (0b00000101, 8),
+    (0b00001010, 8),
+    (0b00001011, 8),
+    (0b01010010, 8),
+    (0b01010011, 8),
+    (0b01010100, 8),
+    (0b01010101, 8),
+    (0b00100100, 8),
+    (0b00100101, 8),
+    (0b01011000, 8),
+    (0b01011001, 8),
+    (0b01011010, 8),
+    (0b01011011, 8),
+    (0b01001010, 8),
+    (0b01001011, 8),
+    (0b00110010, 8),
+    (0b00110011, 8),
+    (0b00110100, 8),
+];
+/// White run lengths 64 to 1728, in steps of 64.
+const WHITE_MAKEUP: [(u16, u8); 27] = [
+    (0b11011, 5),
+    (0b10010, 5),
+    (0b010111, 6),
+    (0b0110111, 7),
+    (0b00110110, 8),
+    (0b00110111, 8),
+    (0b01100100, 8),
+    (0b01100101, 8),
+    (0b01101000, 8),
+    (0b01100111, 8),
+    (0b011001100, 9),
+    (0b011001101, 9),
+    (0b011010010, 9),
+    (0b011010011, 9),
+    (0b011010100, 9),
+    (0b011010101, 9),
+    (0b011010110, 9),
+    (0b011010111, 9),
+    (0b011011000, 9),
+    (0b011011001, 9),
+    (0b011011010, 9),
+    (0b011011011, 9),
+    (0b010011000, 9),
+    (0b010011001, 9),
+    (0b010011010, 9),
+    (0b011000, 6),
+    (0b010011011, 9),
+];
+/// Black run lengths 0 to 63.
+const BLACK_TERMINATING: [(u16, u8); 64] = [
+    (0b0000110111, 10),
+    (0b010, 3),
+    (0b11, 2),
+    (0b10, 2),
+    (0b011, 3),
+    (0b0011, 4),
+    (0b0010, 4),
+    (0b00011, 5),
+    (0b000101, 6),
+    (0b000100, 6),
+    (0b0000100, 7),
+    (0b0000101, 7),
+    (0b0000111, 7),
+    (0b00000100, 8),
+    (0b00000111, 8),
+    (0b000011000, 9),
+    (0b0000010111, 10),
+    (0b0000011000, 10),
+    (0b0000001000, 10),
+    (0b00001100111, 11),
+    (0b00001101000, 11),
+    (0b00001101100, 11),
+    (0b00000110111, 11),
+    (0b00000101000, 11),
+    (0b00000010111, 11),
+    (0b00000011000, 11),
+    (0b000011001010, 12),
+    (0b000011001011, 12),
+    (0b000011001100, 12),
+    (0b000011001101, 12),
+    (0b000001101000, 12),
+    (0b000001101001, 12),
+    (0b000001101010, 12),
+    (0b000001101011, 12),
+    (0b000011010010, 12),
+    (0b000011010011, 12),
+    (0b000011010100, 12),
+    (0b000011010101, 12),
+    (0b000011010110, 12),
+    (0b000011010111, 12),
+    (0b000001101100, 12),
+    (0b000001101101, 12),
+    (0b000011011010, 12),
+    (0b000011011011, 12),
+    (0b000001010100, 12),
+    (0b000001010101, 12),
+    (0b000001010110, 12),
+    (0b000001010111, 12),
+    (0b000001100100, 12),
+    (0b000001100101, 12),
+    (0b000001010010, 12),
+    (0b000001010011, 12),
+    (0b000000100100, 12),
+    (0b000000110111, 12),
+    (0b000000111000, 12),
+    (0b000000100111, 12),
+    (0b000000101000, 12),
+    (0b000001011000, 12),
+    (0b000001011001, 12),
+    (0b000000101011, 12),
+    (0b000000101100, 12),
+    (0b000001011010, 12),
+    (0b000001100110, 12),
+    (0b000001100111, 12),
+];
+/// Black run lengths 64 to 1728, in steps of 64.
+const BLACK_MAKEUP: [(u16, u8); 27] = [
+    (0b0000001111, 10),
+    (0b000011001000, 12),
+    (0b000011001001, 12),
+    (0b000001011011, 12),
+    (0b000000110011, 12),
+    (0b000000110100, 12),
+    (0b000000110101, 12),
+    (0b0000001101100, 13),
+    (0b0000001101101, 13),
+    (0b0000001001010, 13),
+    (0b0000001001011, 13),
+    (0b0000001001100, 13),
+    (0b0000001001101, 13),
+    (0b0000001110010, 13),
+    (0b0000001110011, 13),
+    (0b0000001110100, 13),
+    (0b0000001110101, 13),
+    (0b0000001110110, 13),
+    (0b0000001110111, 13),
+    (0b0000001010010, 13),
+    (0b0000001010011, 13),
+    (0b0000001010100, 13),
+    (0b0000001010101, 13),
+    (0b0000001011010, 13),
+    (0b0000001011011, 13),
+    (0b0000001100100, 13),
+    (0b0000001100101, 13),
+];
+/// Run lengths 1792 to 2560 of either color, in steps of 64.
+const EXTENDED_MAKEUP: [(u16, u8); 13] = [
+    (0b00000001000, 11),
+    (0b00000001100, 11),
+    (0b00000001101, 11),
+    (0b000000010010, 12),
+    (0b000000010011, 12),
+    (0b000000010100, 12),
+    (0b000000010101, 12),
+    (0b000000010110, 12),
+    (0b000000010111, 12),
+    (0b000000011100, 12),
+    (0b000000011101, 12),
+    (0b000000011110, 12),
+    (0b000000011111, 12),
+];
+
+/// Packs bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    len: u8,
+}
+
+impl BitWriter {
+    fn push(&mut self, (code, len): (u16, u8)) {
+        self.bits = self.bits << len | code as u32;
+        self.len += len;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.bits >> self.len) as u8);
+        }
+        self.bits &= (1 << self.len) - 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push((self.bits << (8 - self.len)) as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Write a run of `length` pixels: makeup codes for its multiples of 64,
+/// then a terminating code for the rest.
+fn push_run(writer: &mut BitWriter, mut length: usize, black: bool) {
+    let (terminating, makeup) = if black {
+        (&BLACK_TERMINATING, &BLACK_MAKEUP)
+    } else {
+        (&WHITE_TERMINATING, &WHITE_MAKEUP)
+    };
+    while length >= 2560 {
+        writer.push(EXTENDED_MAKEUP[EXTENDED_MAKEUP.len() - 1]);
+        length -= 2560;
+    }
+    if length >= 1792 {
+        writer.push(EXTENDED_MAKEUP[length / 64 - 28]);
+    } else if length >= 64 {
+        writer.push(makeup[length / 64 - 1]);
+    }
+    writer.push(terminating[length % 64]);
+}
+
+/// Positions where a row changes color, starting from white, followed by
+/// `width` twice so lookups past the last change find the row's end.
+fn changes(row: &[u8], width: usize, changes: &mut Vec<usize>) {
+    changes.clear();
+    let mut black = false;
+    for x in 0..width {
+        let pixel = row[x / 8] >> (7 - x % 8) & 1 == 0;
+        if pixel != black {
+            changes.push(x);
+            black = pixel;
+        }
+    }
+    changes.extend([width, width]);
+}
+
+/// Encode `height` rows of `width` 1-bit samples, each row padded to a
+/// whole byte.
+pub fn encode_g4(samples: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let row_bytes = width.div_ceil(8);
+    let mut writer = BitWriter::default();
+    let mut reference = vec![width, width];
+    let mut coding = Vec::new();
+
+    for row in samples.chunks_exact(row_bytes).take(height) {
+        changes(row, width, &mut coding);
+        // `a0` starts on an imaginary white pixel before the row.
+        let mut a0: Option<usize> = None;
+        let mut black = false;
+        // Changes at even indexes turn the row black.
+        let (mut i, mut j) = (0, 0usize);
+        loop {
+            let after = |position: usize| a0.is_none_or(|a0| position > a0);
+            while !after(coding[i]) {
+                i += 1;
+            }
+            let a1 = coding[i];
+            // b1 is the first change on the reference row after a0 to the
+            // color opposite a0's. After a vertical mode flips the color it
+            // can be the change just before the previous b1.
+            j = j.saturating_sub(1);
+            while reference[j] < width && (!after(reference[j]) || (j % 2 == 1) != black) {
+                j += 1;
+            }
+            let b1 = reference[j];
+            let b2 = reference[j + 1];
+
+            if b2 < a1 {
+                writer.push(PASS);
+                a0 = Some(b2);
+            } else if a1.abs_diff(b1) <= 3 {
+                writer.push(VERTICAL[(a1 as isize - b1 as isize + 3) as usize]);
+                a0 = Some(a1);
+                black = !black;
+            } else {
+                let a2 = coding[i + 1];
+                writer.push(HORIZONTAL);
+                push_run(&mut writer, a1 - a0.unwrap_or(0), black);
+                push_run(&mut writer, a2 - a1, !black);
+                a0 = Some(a2);
+            }
+            if a0.is_some_and(|a0| a0 >= width) {
+                break;
+            }
+        }
+        std::mem::swap(&mut reference, &mut coding);
+    }
+
+    writer.push(EOL);
+    writer.push(EOL);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits most significant first.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u16 {
+            let bit = self.data[self.position / 8] >> (7 - self.position % 8) & 1;
+            self.position += 1;
+            bit as u16
+        }
+
+        /// Read whichever of `codes` comes next, by its index.
+        fn code(&mut self, codes: &[(u16, u8)]) -> usize {
+            let (mut value, mut len) = (0, 0);
+            loop {
+                value = value << 1 | self.bit();
+                len += 1;
+                if let Some(i) = codes.iter().position(|&code| code == (value, len)) {
+                    return i;
+                }
+                assert!(len < 13, "No code matches");
+            }
+        }
+
+        fn run(&mut self, black: bool) -> usize {
+            let codes: Vec<(u16, u8)> = if black {
+                [&BLACK_TERMINATING[..], &BLACK_MAKEUP, &EXTENDED_MAKEUP].concat()
+            } else {
+                [&WHITE_TERMINATING[..], &WHITE_MAKEUP, &EXTENDED_MAKEUP].concat()
+            };
+            let mut length = 0;
+            loop {
+                match self.code(&codes) {
+                    i @ 0..64 => return length + i,
+                    i @ 64..91 => length += (i - 63) * 64,
+                    i => length += (i - 91) * 64 + 1792,
+                }
+            }
+        }
+    }
+
+    /// A straightforward decoder, finding b1 and b2 afresh for every code.
+    fn decode_g4(data: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let mut reader = BitReader { data, position: 0 };
+        let modes = [&[PASS, HORIZONTAL][..], &VERTICAL].concat();
+        let mut output = Vec::new();
+        let mut reference: Vec<usize> = Vec::new();
+        for _ in 0..height {
+            let mut coding = Vec::new();
+            let mut a0: Option<usize> = None;
+            let mut black = false;
+            while a0.is_none_or(|a0| a0 < width) {
+                let after = |position: usize| a0.is_none_or(|a0| position > a0);
+                let b1_index = (0..reference.len())
+                    .find(|&k| after(reference[k]) && (k % 2 == 0) != black)
+                    .unwrap_or(reference.len());
+                let b1 = reference.get(b1_index).copied().unwrap_or(width);
+                let b2 = reference.get(b1_index + 1).copied().unwrap_or(width);
+                match reader.code(&modes) {
+                    0 => a0 = Some(b2),
+                    1 => {
+                        let a1 = a0.unwrap_or(0) + reader.run(black);
+                        let a2 = a1 + reader.run(!black);
+                        coding.extend([a1, a2]);
+                        a0 = Some(a2);
+                    }
+                    i => {
+                        let a1 = (b1 as isize + i as isize - 5) as usize;
+                        coding.push(a1);
+                        a0 = Some(a1);
+                        black = !black;
+                    }
+                }
+            }
+            coding.retain(|&x| x < width);
+
+            let mut row = vec![0xff; width.div_ceil(8)];
+            for x in 0..width {
+                let changes = coding.iter().filter(|&&change| change <= x).count();
+                if changes % 2 == 1 {
+                    row[x / 8] &= !(0x80 >> (x % 8));
+                }
+            }
+            output.extend(row);
+            reference = coding;
+        }
+        assert_eq!(reader.code(&[EOL]), 0);
+        assert_eq!(reader.code(&[EOL]), 0);
+        output
+    }
+
+    /// Rows of `width` pixels from `pixel(x, y)`, true for black, with the
+    /// padding bits set.
+    fn image(width: usize, height: usize, pixel: impl Fn(usize, usize) -> bool) -> Vec<u8> {
+        let mut samples = vec![0xff; width.div_ceil(8) * height];
+        for y in 0..height {
+            for x in 0..width {
+                if pixel(x, y) {
+                    samples[y * width.div_ceil(8) + x / 8] &= !(0x80 >> (x % 8));
+                }
+            }
+        }
+        samples
+    }
+
+    fn assert_round_trip(samples: &[u8], width: usize, height: usize) {
+        let encoded = encode_g4(samples, width, height);
+        assert_eq!(
+            decode_g4(&encoded, width, height),
+            samples,
+            "{}x{}",
+            width,
+            height
+        );
+    }
+
+    #[test]
+    fn white_row_is_a_single_vertical_code() {
+        // V0, then two EOLs, padded: 1 000000000001 000000000001 0000000.
+        assert_eq!(encode_g4(&[0xff], 8, 1), [0x80, 0x08, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn matches_codes_worked_out_from_the_standard() {
+        // Coded by hand from T.6 table 1 and T.4 tables 2 and 3, not from
+        // the tables above:
+        //   ..###...  H W2 0111 B3 10, V0                001 0111 10 1
+        //   ......##  P (b2 5 < a1 6), VL2 (6 - 8), V0    0001 000010 1
+        //   .......#  VR1 (7 - 6), V0                    011 1
+        //   ########  H W0 00110101 B8 000101            001 00110101 000101
+        // then EOFB and zero padding.
+        assert_eq!(
+            encode_g4(&[0xc7, 0xfc, 0xfe, 0x00], 8, 4),
+            [0x2f, 0x44, 0x2b, 0x93, 0x51, 0x40, 0x04, 0x00, 0x40]
+        );
+
+        // A white run of 70 needs makeup code 64 (11011) and 6 (1110); the
+        // black run of 10 is 0000100: H 001 11011 1110 0000100, EOFB.
+        let samples = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfc, 0x00];
+        assert_eq!(
+            encode_g4(&samples, 80, 1),
+            [0x3b, 0xe0, 0x80, 0x02, 0x00, 0x20]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_a_decoder() {
+        let mut state: u32 = 1;
+        let mut random = move || {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            state >> 16
+        };
+        for width in [1, 7, 8, 13, 64, 100] {
+            let noise: Vec<bool> = (0..width * 20).map(|_| random() % 3 == 0).collect();
+            assert_round_trip(&image(width, 20, |x, y| noise[y * width + x]), width, 20);
+            assert_round_trip(&image(width, 3, |_, _| true), width, 3);
+            assert_round_trip(&image(width, 3, |_, _| false), width, 3);
+        }
+
+        // Text-like shapes, where rows mostly follow the row above.
+        let shapes = image(200, 60, |x, y| {
+            let (cx, cy) = (x % 40, y % 30);
+            (cx as isize - 20).pow(2) + (cy as isize - 15).pow(2) < 100 || x % 57 == y % 13
+        });
+        assert_round_trip(&shapes, 200, 60);
+
+        // Runs longer than the longest makeup code.
+        let wide = image(6000, 4, |x, y| x == 5990 - y || (y == 2 && x > 2));
+        assert_round_trip(&wide, 6000, 4);
+    }
+
+    #[test]
+    fn run_codes_are_prefix_free() {
+        for codes in [
+            [
+                &WHITE_TERMINATING[..],
+                &WHITE_MAKEUP,
+                &EXTENDED_MAKEUP,
+                &[EOL],
+            ]
+            .concat(),
+            [
+                &BLACK_TERMINATING[..],
+                &BLACK_MAKEUP,
+                &EXTENDED_MAKEUP,
+                &[EOL],
+            ]
+            .concat(),
+        ] {
+            for (i, &(code, len)) in codes.iter().enumerate() {
+                for (j, &(other, other_len)) in codes.iter().enumerate() {
+                    if i != j && other_len >= len {
+                        assert_ne!(other >> (other_len - len), code, "{} and {}", i, j);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Merging objects can make the objects that refer to them identical, e.g.
+/// two font descriptors once their font files are merged; this many rounds
+/// catch all but the deepest chains.
+const MAX_ROUNDS: usize = 8;
+
+/// Objects removed from a document and the stream data they held.
+#[derive(Default)]
+pub struct Removed {
+    pub objects: u32,
+    pub stream_bytes: u64,
+}
+
+impl Removed {
+    fn add(&mut self, object: &Object) {
+        self.objects += 1;
+        if let Object::Stream(stream) = object {
+            self.stream_bytes += stream.content.len() as u64;
+        }
+    }
+}
+
+/// Whether an object may be shared by whatever referred to its duplicates.
+/// Pages, annotations, form fields and the like are not: they are
+/// identified by their object, even when identical to another.
+fn mergeable(object: &Object) -> bool {
+    match object {
+        Object::Stream(_) | Object::Array(_) => true,
+        Object::Dictionary(dict) => matches!(
+            dict.get(b"Type").and_then(Object::as_name),
+            Ok(b"Font" | b"FontDescriptor" | b"ExtGState" | b"Encoding")
+        ),
+        _ => false,
+    }
+}
+
+fn fingerprint(object: &Object) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    feed(&mut hasher, object);
+    hasher.finalize().into()
+}
+
+fn feed(hasher: &mut Sha256, object: &Object) {
+    match object {
+        Object::Null => hasher.update(b"n"),
+        Object::Boolean(value) => hasher.update([b'b', *value as u8]),
+        Object::Integer(value) => {
+            hasher.update(b"i");
+            hasher.update(value.to_be_bytes());
+        }
+        Object::Real(value) => {
+            hasher.update(b"r");
+            hasher.update(value.to_bits().to_be_bytes());
+        }
+        Object::Name(name) => feed_bytes(hasher, b"/", name),
+        // Literal and hexadecimal strings with the same bytes are equal.
+        Object::String(bytes, _) => feed_bytes(hasher, b"s", bytes),
+        Object::Array(items) => {
+            hasher.update(b"[");
+            hasher.update((items.len() as u64).to_be_bytes());
+            for item in items {
+                feed(hasher, item);
+            }
+        }
+        Object::Dictionary(dict) => feed_dictionary(hasher, dict),
+        Object::Stream(stream) => {
+            hasher.update(b"S");
+            feed_dictionary(hasher, &stream.dict);
+            feed_bytes(hasher, b"c", &stream.content);
+        }
+        Object::Reference((number, generation)) => {
+            hasher.update(b"R");
+            hasher.update(number.to_be_bytes());
+            hasher.update(generation.to_be_bytes());
+        }
+    }
+}
+
+fn feed_bytes(hasher: &mut Sha256, tag: &[u8], bytes: &[u8]) {
+    hasher.update(tag);
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+/// Keys are fed in sorted order, so key order does not matter. A stream's
+/// `Length` is left out: it may be indirect, and the content is fed anyway.
+fn feed_dictionary(hasher: &mut Sha256, dict: &Dictionary) {
+    let mut entries: Vec<_> = dict
+        .iter()
+        .filter(|(key, _)| key.as_slice() != b"Length")
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    hasher.update(b"<");
+    hasher.update((entries.len() as u64).to_be_bytes());
+    for (key, value) in entries {
+        feed_bytes(hasher, b"/", key);
+        feed(hasher, value);
+    }
+}
+
+fn rewrite(object: &mut Object, replacements: &HashMap<ObjectId, ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            if let Some(canonical) = replacements.get(id) {
+                *id = *canonical;
+            }
+        }
+        Object::Array(items) => {
+            for item in items {
+                rewrite(item, replacements);
+            }
+        }
+        Object::Dictionary(dict) => rewrite_dictionary(dict, replacements),
+        Object::Stream(stream) => rewrite_dictionary(&mut stream.dict, replacements),
+        _ => {}
+    }
+}
+
+fn rewrite_dictionary(dict: &mut Dictionary, replacements: &HashMap<ObjectId, ObjectId>) {
+    for (_, value) in dict.iter_mut() {
+        rewrite(value, replacements);
+    }
+}
+
+/// Merge identical objects into the one with the lowest id and point every
+/// reference at it.
+pub fn deduplicate(doc: &mut Document) -> Removed {
+    let mut removed = Removed::default();
+    for _ in 0..MAX_ROUNDS {
+        let mut canonical: HashMap<[u8; 32], ObjectId> = HashMap::new();
+        let mut replacements = HashMap::new();
+        for (id, object) in &doc.objects {
+            if !mergeable(object) {
+                continue;
+            }
+            match canonical.entry(fingerprint(object)) {
+                Entry::Occupied(first) => {
+                    replacements.insert(*id, *first.get());
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(*id);
+                }
+            }
+        }
+        if replacements.is_empty() {
+            break;
+        }
+
+        for id in replacements.keys() {
+            if let Some(object) = doc.objects.remove(id) {
+                removed.add(&object);
+            }
+        }
+        for object in doc.objects.values_mut() {
+            rewrite(object, &replacements);
+        }
+        rewrite_dictionary(&mut doc.trailer, &replacements);
+    }
+    removed
+}
+
+fn references(object: &Object, found: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => found.push(*id),
+        Object::Array(items) => {
+            for item in items {
+                references(item, found);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter() {
+                references(value, found);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter() {
+                references(value, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Remove objects that cannot be reached from the trailer, such as the
+/// originals of replaced images and dropped metadata.
+pub fn prune(doc: &mut Document) -> Removed {
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    for (_, value) in doc.trailer.iter() {
+        references(value, &mut pending);
+    }
+    while let Some(id) = pending.pop() {
+        if reachable.insert(id) {
+            if let Some(object) = doc.objects.get(&id) {
+                references(object, &mut pending);
+            }
+        }
+    }
+
+    let mut removed = Removed::default();
+    doc.objects.retain(|id, object| {
+        let keep = reachable.contains(id);
+        if !keep {
+            removed.add(object);
+        }
+        keep
+    });
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    fn image(doc: &mut Document, pixels: &[u8], length: i64) -> ObjectId {
+        doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => pixels.len() as i64,
+                "Height" => 1,
+                "Length" => length,
+            },
+            pixels.to_vec(),
+        ))
+    }
+
+    /// A font whose descriptor embeds a copy of `program`.
+    fn font(doc: &mut Document, program: &[u8]) -> ObjectId {
+        let file = doc.add_object(Stream::new(dictionary! {}, program.to_vec()));
+        let descriptor = doc.add_object(dictionary! {
+            "Type" => "FontDescriptor",
+            "FontName" => "ABCDEF+Body",
+            "FontFile2" => file,
+        });
+        doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "TrueType",
+            "BaseFont" => "ABCDEF+Body",
+            "FontDescriptor" => descriptor,
+        })
+    }
+
+    fn page(doc: &mut Document, resources: Dictionary) -> ObjectId {
+        doc.add_object(dictionary! { "Type" => "Page", "Resources" => resources })
+    }
+
+    fn resource(doc: &Document, page: ObjectId, kind: &[u8], name: &[u8]) -> ObjectId {
+        doc.get_dictionary(page)
+            .and_then(|page| page.get(b"Resources"))
+            .and_then(Object::as_dict)
+            .and_then(|resources| resources.get(kind))
+            .and_then(Object::as_dict)
+            .and_then(|entries| entries.get(name))
+            .and_then(Object::as_reference)
+            .unwrap()
+    }
+
+    #[test]
+    fn merges_identical_images_and_fonts() {
+        let mut doc = Document::with_version("1.5");
+        let first_image = image(&mut doc, &[1, 2, 3], 3);
+        // Only the Length differs, which is left out of the comparison.
+        let second_image = image(&mut doc, &[1, 2, 3], 4);
+        let first_font = font(&mut doc, b"glyphs");
+        let second_font = font(&mut doc, b"glyphs");
+        let first_page = page(
+            &mut doc,
+            dictionary! {
+                "XObject" => dictionary! { "Im0" => first_image },
+                "Font" => dictionary! { "F0" => first_font },
+            },
+        );
+        let second_page = page(
+            &mut doc,
+            dictionary! {
+                "XObject" => dictionary! { "Im0" => second_image },
+                "Font" => dictionary! { "F0" => second_font },
+            },
+        );
+        let objects = doc.objects.len();
+
+        let removed = deduplicate(&mut doc);
+        // The image and the font file, then the descriptors once they refer
+        // to the same file, and then the fonts.
+        assert_eq!(removed.objects, 4);
+        assert_eq!(removed.stream_bytes, 3 + 6);
+        assert_eq!(doc.objects.len(), objects - 4);
+
+        for page in [first_page, second_page] {
+            assert_eq!(resource(&doc, page, b"XObject", b"Im0"), first_image);
+            assert_eq!(resource(&doc, page, b"Font", b"F0"), first_font);
+        }
+        // Identical pages are still two pages.
+        assert!(doc.objects.contains_key(&second_page));
+    }
+
+    #[test]
+    fn keeps_near_duplicates_apart() {
+        let mut doc = Document::with_version("1.5");
+        let first_image = image(&mut doc, &[1, 2, 3], 3);
+        let second_image = image(&mut doc, &[1, 2, 4], 3);
+        let first_font = font(&mut doc, b"glyphs");
+        let second_font = font(&mut doc, b"glyphS");
+        let mut named =
+            dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" };
+        let helvetica = doc.add_object(named.clone());
+        named.set("BaseFont", "Helvetica-Bold");
+        let bold = doc.add_object(named);
+        let objects = doc.objects.len();
+
+        let removed = deduplicate(&mut doc);
+        assert_eq!(removed.objects, 0);
+        assert_eq!(doc.objects.len(), objects);
+        for id in [
+            first_image,
+            second_image,
+            first_font,
+            second_font,
+            helvetica,
+            bold,
+        ] {
+            assert!(doc.objects.contains_key(&id));
+        }
+    }
+}
@@ -0,0 +1,1239 @@
+use std::collections::{HashMap, HashSet};
+
+/// Tables left out of subset TrueType fonts. PDF viewers take text layout
+/// from the content stream, so layout features, kerning and device metrics
+/// go unused.
+const DROPPED_TABLES: [&[u8; 4]; 13] = [
+    b"BASE", b"DSIG", b"GDEF", b"GPOS", b"GSUB", b"JSTF", b"LTSH", b"MATH", b"PCLT", b"VDMX",
+    b"hdmx", b"kern", b"morx",
+];
+
+/// The `sfnt` versions of fonts with TrueType outlines.
+const TRUETYPE_VERSIONS: [&[u8; 4]; 2] = [b"\0\x01\0\0", b"true"];
+
+/// A Type 2 charstring that draws nothing, for glyphs no page uses.
+const ENDCHAR: &[u8] = &[14];
+
+/// Type 2 charstrings call subroutines at most this deep.
+const MAX_SUBR_DEPTH: usize = 10;
+
+/// Standard Encoding codes above 127, of the glyphs with SIDs 96 to 149
+/// in order; `seac` accents may name them. Codes 32 to 126 are SIDs 1 to 95.
+const STANDARD_HIGH: [u8; 54] = [
+    161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175, 177, 178, 179, 180,
+    182, 183, 184, 185, 186, 187, 188, 189, 191, 193, 194, 195, 196, 197, 198, 199, 200, 202, 203,
+    205, 206, 207, 208, 225, 227, 232, 233, 234, 235, 241, 245, 248, 249, 250, 251,
+];
+
+fn truncated() -> String {
+    "Font program is truncated".to_string()
+}
+
+fn slice(data: &[u8], at: usize, len: usize) -> Result<&[u8], String> {
+    at.checked_add(len)
+        .and_then(|end| data.get(at..end))
+        .ok_or_else(truncated)
+}
+
+fn read_u8(data: &[u8], at: usize) -> Result<u8, String> {
+    data.get(at).copied().ok_or_else(truncated)
+}
+
+fn read_tag(data: &[u8], at: usize) -> Result<[u8; 4], String> {
+    let bytes = slice(data, at, 4)?;
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, String> {
+    let bytes = slice(data, at, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, String> {
+    Ok(u32::from_be_bytes(read_tag(data, at)?))
+}
+
+/// A TrueType font program (`FontFile2`).
+pub struct TrueType<'a> {
+    version: [u8; 4],
+    tables: Vec<([u8; 4], &'a [u8])>,
+    num_glyphs: u16,
+    long_loca: bool,
+}
+
+impl<'a> TrueType<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        let version = read_tag(data, 0)?;
+        if !TRUETYPE_VERSIONS.contains(&&version) {
+            return Err("Font program has no TrueType outlines".to_string());
+        }
+        let count = read_u16(data, 4)? as usize;
+        let mut tables = Vec::with_capacity(count);
+        for index in 0..count {
+            let record = 12 + 16 * index;
+            let tag = read_tag(data, record)?;
+            let offset = read_u32(data, record + 8)? as usize;
+            let length = read_u32(data, record + 12)? as usize;
+            tables.push((tag, slice(data, offset, length)?));
+        }
+
+        let mut font = Self {
+            version,
+            tables,
+            num_glyphs: 0,
+            long_loca: false,
+        };
+        font.num_glyphs = read_u16(font.table(b"maxp")?, 4)?;
+        font.long_loca = read_u16(font.table(b"head")?, 50)? != 0;
+        Ok(font)
+    }
+
+    pub fn num_glyphs(&self) -> u16 {
+        self.num_glyphs
+    }
+
+    fn find(&self, tag: &[u8; 4]) -> Option<&'a [u8]> {
+        self.tables
+            .iter()
+            .find(|(found, _)| found == tag)
+            .map(|(_, data)| *data)
+    }
+
+    fn table(&self, tag: &[u8; 4]) -> Result<&'a [u8], String> {
+        self.find(tag).ok_or_else(|| {
+            format!(
+                "TrueType font has no {} table",
+                String::from_utf8_lossy(tag)
+            )
+        })
+    }
+
+    /// The glyph `code` maps to in the `cmap` subtable for `platform` and
+    /// `encoding`, if the font has one.
+    pub fn lookup(&self, platform: u16, encoding: u16, code: u32) -> Option<u16> {
+        let cmap = self.find(b"cmap")?;
+        let count = read_u16(cmap, 2).ok()?;
+        let offset = (0..count as usize).find_map(|index| {
+            let record = 4 + 8 * index;
+            let matches = read_u16(cmap, record).ok()? == platform
+                && read_u16(cmap, record + 2).ok()? == encoding;
+            matches.then(|| read_u32(cmap, record + 4).ok()).flatten()
+        })?;
+        let table = cmap.get(offset as usize..)?;
+        let glyph = match read_u16(table, 0).ok()? {
+            0 if code < 256 => table.get(6 + code as usize).map(|glyph| *glyph as u16),
+            4 => cmap_format4(table, code),
+            6 => cmap_format6(table, code),
+            12 => cmap_format12(table, code),
+            _ => None,
+        }?;
+        (glyph != 0).then_some(glyph)
+    }
+
+    /// A copy of the font in which only `glyphs` and the glyphs they are
+    /// composed of keep their outlines. Glyph ids are unchanged, so the
+    /// content streams and metrics that refer to them stay valid.
+    pub fn subset(&self, glyphs: &HashSet<u16>) -> Result<Vec<u8>, String> {
+        let glyf = self.table(b"glyf")?;
+        let loca = self.table(b"loca")?;
+        let count = self.num_glyphs as usize;
+        let offsets = (0..=count)
+            .map(|index| match self.long_loca {
+                true => read_u32(loca, 4 * index).map(|offset| offset as usize),
+                false => read_u16(loca, 2 * index).map(|offset| offset as usize * 2),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let glyph = |gid: u16| -> Result<&[u8], String> {
+            let (start, end) = (offsets[gid as usize], offsets[gid as usize + 1]);
+            glyf.get(start..end.max(start)).ok_or_else(truncated)
+        };
+
+        let mut keep = HashSet::new();
+        let mut pending: Vec<u16> = glyphs.iter().copied().chain([0]).collect();
+        while let Some(gid) = pending.pop() {
+            if gid >= self.num_glyphs || !keep.insert(gid) {
+                continue;
+            }
+            let data = glyph(gid)?;
+            if !data.is_empty() && (read_u16(data, 0)? as i16) < 0 {
+                pending.extend(components(data)?);
+            }
+        }
+
+        let mut new_glyf = Vec::new();
+        let mut new_offsets = Vec::with_capacity(count + 1);
+        for gid in 0..self.num_glyphs {
+            new_offsets.push(new_glyf.len());
+            if keep.contains(&gid) {
+                new_glyf.extend_from_slice(glyph(gid)?);
+                new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+            }
+        }
+        new_offsets.push(new_glyf.len());
+
+        // Short offsets are stored halved in 16 bits.
+        let long_loca = self.long_loca || new_glyf.len() > 0x1_FFFE;
+        let new_loca: Vec<u8> = new_offsets
+            .iter()
+            .flat_map(|offset| match long_loca {
+                true => (*offset as u32).to_be_bytes().to_vec(),
+                false => ((*offset / 2) as u16).to_be_bytes().to_vec(),
+            })
+            .collect();
+        let mut head = self.table(b"head")?.to_vec();
+        if head.len() < 54 {
+            return Err(truncated());
+        }
+        head[8..12].fill(0);
+        head[50..52].copy_from_slice(&(long_loca as u16).to_be_bytes());
+
+        let mut tables: Vec<([u8; 4], &[u8])> = self
+            .tables
+            .iter()
+            .filter(|(tag, _)| !DROPPED_TABLES.contains(&tag))
+            .map(|(tag, data)| {
+                let data = match tag {
+                    b"glyf" => new_glyf.as_slice(),
+                    b"loca" => new_loca.as_slice(),
+                    b"head" => head.as_slice(),
+                    _ => data,
+                };
+                (*tag, data)
+            })
+            .collect();
+        tables.sort_by_key(|(tag, _)| *tag);
+        Ok(write_sfnt(self.version, &tables))
+    }
+}
+
+/// Glyphs a composite glyph is built from.
+fn components(glyph: &[u8]) -> Result<Vec<u16>, String> {
+    const ARGS_ARE_WORDS: u16 = 0x0001;
+    const HAVE_SCALE: u16 = 0x0008;
+    const MORE_COMPONENTS: u16 = 0x0020;
+    const HAVE_XY_SCALE: u16 = 0x0040;
+    const HAVE_TWO_BY_TWO: u16 = 0x0080;
+
+    let mut found = Vec::new();
+    let mut at = 10;
+    loop {
+        let flags = read_u16(glyph, at)?;
+        found.push(read_u16(glyph, at + 2)?);
+        at += 4;
+        at += if flags & ARGS_ARE_WORDS != 0 { 4 } else { 2 };
+        at += match flags {
+            _ if flags & HAVE_SCALE != 0 => 2,
+            _ if flags & HAVE_XY_SCALE != 0 => 4,
+            _ if flags & HAVE_TWO_BY_TWO != 0 => 8,
+            _ => 0,
+        };
+        if flags & MORE_COMPONENTS == 0 {
+            return Ok(found);
+        }
+    }
+}
+
+fn cmap_format4(table: &[u8], code: u32) -> Option<u16> {
+    let code = u16::try_from(code).ok()?;
+    let segments = read_u16(table, 6).ok()? as usize;
+    for segment in (0..segments).step_by(2) {
+        if read_u16(table, 14 + segment).ok()? < code {
+            continue;
+        }
+        let start = read_u16(table, 16 + segments + segment).ok()?;
+        if start > code {
+            return None;
+        }
+        let delta = read_u16(table, 16 + 2 * segments + segment).ok()?;
+        let range_at = 16 + 3 * segments + segment;
+        let range = read_u16(table, range_at).ok()? as usize;
+        if range == 0 {
+            return Some(code.wrapping_add(delta));
+        }
+        let at = range_at + range + 2 * (code - start) as usize;
+        let glyph = read_u16(table, at).ok()?;
+        return (glyph != 0).then(|| glyph.wrapping_add(delta));
+    }
+    None
+}
+
+fn cmap_format6(table: &[u8], code: u32) -> Option<u16> {
+    let first = read_u16(table, 6).ok()? as u32;
+    let count = read_u16(table, 8).ok()? as u32;
+    let index = code.checked_sub(first).filter(|index| *index < count)?;
+    read_u16(table, 10 + 2 * index as usize).ok()
+}
+
+fn cmap_format12(table: &[u8], code: u32) -> Option<u16> {
+    let groups = read_u32(table, 12).ok()? as usize;
+    (0..groups).find_map(|group| {
+        let at = 16 + 12 * group;
+        let start = read_u32(table, at).ok()?;
+        let end = read_u32(table, at + 4).ok()?;
+        let glyph = read_u32(table, at + 8).ok()?;
+        (start..=end)
+            .contains(&code)
+            .then(|| u16::try_from(glyph.checked_add(code - start)?).ok())
+            .flatten()
+    })
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/// Assemble a font file from tables sorted by tag.
+fn write_sfnt(version: [u8; 4], tables: &[([u8; 4], &[u8])]) -> Vec<u8> {
+    let count = tables.len() as u16;
+    let entry_selector = 15 - count.max(1).leading_zeros() as u16;
+    let search_range = 16 << entry_selector;
+    let mut out = Vec::new();
+    out.extend_from_slice(&version);
+    for value in [
+        count,
+        search_range,
+        entry_selector,
+        count * 16 - search_range,
+    ] {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let mut offset = 12 + 16 * tables.len();
+    let mut head_at = None;
+    for (tag, data) in tables {
+        if tag == b"head" {
+            head_at = Some(offset);
+        }
+        out.extend_from_slice(tag);
+        out.extend_from_slice(&checksum(data).to_be_bytes());
+        out.extend_from_slice(&(offset as u32).to_be_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += data.len().next_multiple_of(4);
+    }
+    for (_, data) in tables {
+        out.extend_from_slice(data);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    if let Some(head_at) = head_at {
+        let adjustment = 0xB1B0_AFBA_u32.wrapping_sub(checksum(&out));
+        out[head_at + 8..head_at + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+    out
+}
+
+/// Top DICT operators.
+const CHARSET: u16 = 15;
+const ENCODING: u16 = 16;
+const CHAR_STRINGS: u16 = 17;
+const PRIVATE: u16 = 18;
+const CHARSTRING_TYPE: u16 = 1206;
+const ROS: u16 = 1230;
+const FD_ARRAY: u16 = 1236;
+const FD_SELECT: u16 = 1237;
+/// Private DICT operator.
+const SUBRS: u16 = 19;
+
+/// A bare CFF font program (`FontFile3` of subtype `Type1C` or
+/// `CIDFontType0C`).
+pub struct Cff<'a> {
+    data: &'a [u8],
+    /// Header and Name INDEX, copied as they are.
+    header: &'a [u8],
+    top: Vec<DictEntry<'a>>,
+    /// String and Global Subr INDEXes, copied as they are.
+    shared: &'a [u8],
+    global_subrs: Vec<&'a [u8]>,
+    char_strings: Vec<&'a [u8]>,
+}
+
+/// A DICT operator with its operands. Real operands are read as 0: they
+/// are kept as written, and only integer offsets are ever rewritten.
+struct DictEntry<'a> {
+    operator: u16,
+    operands: Vec<i32>,
+    raw: &'a [u8],
+}
+
+impl<'a> Cff<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if read_u8(data, 0)? != 1 {
+            return Err("Only CFF version 1 is supported".to_string());
+        }
+        let header_size = read_u8(data, 2)? as usize;
+        let (names, top_at) = index(data, header_size)?;
+        let (tops, strings_at) = index(data, top_at)?;
+        if names.len() != 1 || tops.len() != 1 {
+            return Err("CFF font sets are not supported".to_string());
+        }
+        let (_, subrs_at) = index(data, strings_at)?;
+        let (global_subrs, shared_end) = index(data, subrs_at)?;
+        let top = dict(tops[0])?;
+        if operand(&top, CHARSTRING_TYPE, 0).is_some_and(|kind| kind != 2) {
+            return Err("Only Type 2 charstrings are supported".to_string());
+        }
+        let char_strings_at = operand(&top, CHAR_STRINGS, 0)
+            .ok_or_else(|| "CFF font has no CharStrings".to_string())?;
+        let (char_strings, _) = index(data, offset(char_strings_at)?)?;
+        if char_strings.is_empty() || char_strings.len() > u16::MAX as usize {
+            return Err("CFF font has an invalid number of glyphs".to_string());
+        }
+        Ok(Self {
+            data,
+            header: &data[..top_at],
+            top,
+            shared: &data[strings_at..shared_end],
+            global_subrs,
+            char_strings,
+        })
+    }
+
+    pub fn num_glyphs(&self) -> u16 {
+        self.char_strings.len() as u16
+    }
+
+    /// Whether glyphs are selected by CID rather than by glyph id.
+    pub fn is_cid_keyed(&self) -> bool {
+        self.top.iter().any(|entry| entry.operator == ROS)
+    }
+
+    /// The SID, or CID in a CID-keyed font, of every glyph.
+    fn charset(&self) -> Result<Vec<u16>, String> {
+        let count = self.char_strings.len();
+        let at = match operand(&self.top, CHARSET, 0).unwrap_or(0) {
+            // ISOAdobe: glyph ids are SIDs.
+            0 => return Ok((0..count as u16).collect()),
+            1 | 2 => return Err("Expert charsets are not supported".to_string()),
+            at => offset(at)?,
+        };
+        let mut names = vec![0];
+        let format = read_u8(self.data, at)?;
+        let mut cursor = at + 1;
+        while names.len() < count {
+            match format {
+                0 => names.push(read_u16(self.data, cursor)?),
+                1 | 2 => {
+                    let first = read_u16(self.data, cursor)?;
+                    let left = match format {
+                        1 => read_u8(self.data, cursor + 2)? as u16,
+                        _ => read_u16(self.data, cursor + 2)?,
+                    };
+                    names.extend((0..=left).map(|step| first.wrapping_add(step)));
+                    cursor += 1 + format as usize;
+                }
+                _ => return Err(format!("Unknown CFF charset format {}", format)),
+            }
+            cursor += 2;
+        }
+        names.truncate(count);
+        Ok(names)
+    }
+
+    /// Glyph ids by CID, for a CID-keyed font.
+    pub fn cid_to_gid(&self) -> Result<HashMap<u16, u16>, String> {
+        Ok(self
+            .charset()?
+            .into_iter()
+            .enumerate()
+            .map(|(gid, cid)| (cid, gid as u16))
+            .collect())
+    }
+
+    /// Glyph ids by character code, from the font's own encoding.
+    /// Predefined encodings map codes to glyph names and are not supported.
+    pub fn encoding(&self) -> Result<HashMap<u8, u16>, String> {
+        let at = match operand(&self.top, ENCODING, 0).unwrap_or(0) {
+            0 | 1 => return Err("Predefined CFF encodings are not supported".to_string()),
+            at => offset(at)?,
+        };
+        let data = self.data;
+        let format = read_u8(data, at)?;
+        let count = read_u8(data, at + 1)? as usize;
+        let mut codes = HashMap::new();
+        let mut gid = 1u16;
+        let mut cursor = at + 2;
+        for _ in 0..count {
+            match format & 0x7F {
+                0 => {
+                    codes.insert(read_u8(data, cursor)?, gid);
+                    gid += 1;
+                    cursor += 1;
+                }
+                1 => {
+                    let first = read_u8(data, cursor)?;
+                    for code in first..=first.saturating_add(read_u8(data, cursor + 1)?) {
+                        codes.insert(code, gid);
+                        gid += 1;
+                    }
+                    cursor += 2;
+                }
+                format => return Err(format!("Unknown CFF encoding format {}", format)),
+            }
+        }
+        if format & 0x80 != 0 {
+            let charset = self.charset()?;
+            for _ in 0..read_u8(data, cursor)? {
+                let code = read_u8(data, cursor + 1)?;
+                let sid = read_u16(data, cursor + 2)?;
+                if let Some(gid) = charset.iter().position(|found| *found == sid) {
+                    codes.insert(code, gid as u16);
+                }
+                cursor += 3;
+            }
+        }
+        Ok(codes)
+    }
+
+    /// Glyphs that kept glyphs built with the `seac` form of `endchar`
+    /// draw as their base and accent. CID-keyed fonts cannot use `seac`.
+    fn accent_parts(&self, glyphs: &HashSet<u16>) -> Result<HashSet<u16>, String> {
+        if self.is_cid_keyed() {
+            return Ok(HashSet::new());
+        }
+        let (_, subrs) = self.private(&self.top)?;
+        let local_subrs = match subrs.is_empty() {
+            true => Vec::new(),
+            false => index(subrs, 0)?.0,
+        };
+        let mut charset = None;
+        let mut parts = HashSet::new();
+        for &gid in glyphs {
+            let Some(charstring) = self.char_strings.get(gid as usize) else {
+                continue;
+            };
+            let mut interpreter = Interpreter {
+                global_subrs: &self.global_subrs,
+                local_subrs: &local_subrs,
+                stack: Vec::new(),
+                stems: 0,
+            };
+            let Flow::End(Some(codes)) = interpreter.run(charstring, 0)? else {
+                continue;
+            };
+            if charset.is_none() {
+                charset = Some(self.charset()?);
+            }
+            let names = charset.as_deref().unwrap_or_default();
+            for code in codes {
+                let part = standard_sid(code)
+                    .and_then(|sid| names.iter().position(|found| *found == sid))
+                    .ok_or_else(|| format!("Glyph {} is built from a missing glyph", gid))?;
+                parts.insert(part as u16);
+            }
+        }
+        Ok(parts)
+    }
+
+    /// A copy of the font in which glyphs other than `glyphs`, the parts of
+    /// accented glyphs among them, and `.notdef` draw nothing. Glyph ids
+    /// are unchanged.
+    pub fn subset(&self, glyphs: &HashSet<u16>) -> Result<Vec<u8>, String> {
+        let data = self.data;
+        let count = self.char_strings.len();
+        let parts = self.accent_parts(glyphs)?;
+        let glyphs: HashSet<u16> = glyphs.union(&parts).copied().collect();
+        let char_strings: Vec<&[u8]> = self
+            .char_strings
+            .iter()
+            .enumerate()
+            .map(
+                |(gid, charstring)| match gid == 0 || glyphs.contains(&(gid as u16)) {
+                    true => *charstring,
+                    false => ENDCHAR,
+                },
+            )
+            .collect();
+
+        // Sections other than the header, Top DICT and shared INDEXes are
+        // laid out after them in this order, each followed by the next.
+        let charset = match operand(&self.top, CHARSET, 0) {
+            Some(at) if at > 2 => {
+                let at = offset(at)?;
+                Some(slice(data, at, charset_len(data, at, count)?)?)
+            }
+            _ => None,
+        };
+        let encoding = match operand(&self.top, ENCODING, 0) {
+            Some(at) if at > 1 => {
+                let at = offset(at)?;
+                Some(slice(data, at, encoding_len(data, at)?)?)
+            }
+            _ => None,
+        };
+        let fd_select = match operand(&self.top, FD_SELECT, 0) {
+            Some(at) => {
+                let at = offset(at)?;
+                Some(slice(data, at, fd_select_len(data, at, count)?)?)
+            }
+            None => None,
+        };
+        let font_dicts = match operand(&self.top, FD_ARRAY, 0) {
+            Some(at) => index(data, offset(at)?)?
+                .0
+                .into_iter()
+                .map(dict)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        let privates = match font_dicts.is_empty() {
+            true => vec![self.private(&self.top)?],
+            false => font_dicts
+                .iter()
+                .map(|font_dict| self.private(font_dict))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        let top_offsets = |values: [Option<usize>; 6]| -> HashMap<u16, Vec<i32>> {
+            let [charset_at, encoding_at, char_strings_at, private, fd_array_at, fd_select_at] =
+                values;
+            let mut offsets = HashMap::new();
+            let mut set = |operator: u16, value: Option<usize>| {
+                if let Some(value) = value {
+                    offsets.insert(operator, vec![value as i32]);
+                }
+            };
+            set(CHARSET, charset_at);
+            set(ENCODING, encoding_at);
+            set(CHAR_STRINGS, char_strings_at);
+            set(FD_ARRAY, fd_array_at);
+            set(FD_SELECT, fd_select_at);
+            if let (Some(at), true) = (private, font_dicts.is_empty()) {
+                offsets.insert(PRIVATE, vec![privates[0].0.len() as i32, at as i32]);
+            }
+            offsets
+        };
+        let placeholders = [
+            charset.map(|_| 0),
+            encoding.map(|_| 0),
+            Some(0),
+            Some(0),
+            (!font_dicts.is_empty()).then_some(0),
+            fd_select.map(|_| 0),
+        ];
+        let top_len = write_dict(&self.top, &top_offsets(placeholders)).len();
+
+        let private_offsets =
+            |size: usize, at: usize| HashMap::from([(PRIVATE, vec![size as i32, at as i32])]);
+        let font_dict_lens: Vec<usize> = font_dicts
+            .iter()
+            .map(|font_dict| write_dict(font_dict, &private_offsets(0, 0)).len())
+            .collect();
+        let char_strings_len = index_len(char_strings.iter().map(|item| item.len()));
+
+        let mut at = self.header.len() + index_len([top_len]) + self.shared.len();
+        let mut place = |len: usize| {
+            let start = at;
+            at += len;
+            start
+        };
+        let charset_at = charset.map(|section| place(section.len()));
+        let encoding_at = encoding.map(|section| place(section.len()));
+        let char_strings_at = place(char_strings_len);
+        let fd_select_at = fd_select.map(|section| place(section.len()));
+        let fd_array_at =
+            (!font_dicts.is_empty()).then(|| place(index_len(font_dict_lens.iter().copied())));
+        let private_ats: Vec<usize> = privates
+            .iter()
+            .map(|(private, subrs)| place(private.len() + subrs.len()))
+            .collect();
+
+        let top = write_dict(
+            &self.top,
+            &top_offsets([
+                charset_at,
+                encoding_at,
+                Some(char_strings_at),
+                private_ats.first().copied(),
+                fd_array_at,
+                fd_select_at,
+            ]),
+        );
+        let mut out = self.header.to_vec();
+        write_index(&mut out, &[&top]);
+        out.extend_from_slice(self.shared);
+        for section in [charset, encoding].into_iter().flatten() {
+            out.extend_from_slice(section);
+        }
+        write_index(&mut out, &char_strings);
+        if let Some(section) = fd_select {
+            out.extend_from_slice(section);
+        }
+        if !font_dicts.is_empty() {
+            let written: Vec<Vec<u8>> = font_dicts
+                .iter()
+                .zip(&privates)
+                .zip(&private_ats)
+                .map(|((font_dict, (private, _)), at)| {
+                    write_dict(font_dict, &private_offsets(private.len(), *at))
+                })
+                .collect();
+            let items: Vec<&[u8]> = written.iter().map(Vec::as_slice).collect();
+            write_index(&mut out, &items);
+        }
+        for (private, subrs) in &privates {
+            out.extend_from_slice(private);
+            out.extend_from_slice(subrs);
+        }
+        Ok(out)
+    }
+
+    /// The Private DICT a Top or Font DICT points at, rewritten to find its
+    /// local subroutines right after it, and those subroutines.
+    fn private(&self, owner: &[DictEntry]) -> Result<(Vec<u8>, &'a [u8]), String> {
+        let (Some(size), Some(at)) = (operand(owner, PRIVATE, 0), operand(owner, PRIVATE, 1))
+        else {
+            return Err("CFF font has no Private DICT".to_string());
+        };
+        let at = offset(at)?;
+        let private = dict(slice(self.data, at, offset(size)?)?)?;
+        let Some(subrs_at) = operand(&private, SUBRS, 0) else {
+            return Ok((write_dict(&private, &HashMap::new()), &[]));
+        };
+        let subrs_at = at + offset(subrs_at)?;
+        let (_, subrs_end) = index(self.data, subrs_at)?;
+        let len = write_dict(&private, &HashMap::from([(SUBRS, vec![0])])).len();
+        Ok((
+            write_dict(&private, &HashMap::from([(SUBRS, vec![len as i32])])),
+            &self.data[subrs_at..subrs_end],
+        ))
+    }
+}
+
+/// Where running a charstring, or a subroutine it calls, stopped.
+enum Flow {
+    Return,
+    /// `endchar`, with the base and accent codes of a `seac`.
+    End(Option<[i32; 2]>),
+}
+
+/// Runs Type 2 charstrings only as far as needed to find `seac`: operands
+/// are tracked, subroutines followed and hint masks skipped.
+struct Interpreter<'a, 'b> {
+    global_subrs: &'b [&'a [u8]],
+    local_subrs: &'b [&'a [u8]],
+    stack: Vec<i32>,
+    /// Stem hints declared so far, which set the size of hint masks.
+    stems: usize,
+}
+
+impl Interpreter<'_, '_> {
+    fn run(&mut self, code: &[u8], depth: usize) -> Result<Flow, String> {
+        let invalid = || "CFF charstring is invalid".to_string();
+        if depth > MAX_SUBR_DEPTH {
+            return Err("CFF charstring subroutines nest too deeply".to_string());
+        }
+        let mut at = 0;
+        while at < code.len() {
+            let b0 = code[at];
+            at += 1;
+            match b0 {
+                // hstem, vstem, hstemhm, vstemhm
+                1 | 3 | 18 | 23 => {
+                    self.stems += self.stack.len() / 2;
+                    self.stack.clear();
+                }
+                // hintmask, cntrmask; operands left are an implied vstem.
+                19 | 20 => {
+                    self.stems += self.stack.len() / 2;
+                    self.stack.clear();
+                    at += self.stems.div_ceil(8);
+                }
+                // callsubr, callgsubr
+                10 | 29 => {
+                    let subrs = match b0 {
+                        10 => self.local_subrs,
+                        _ => self.global_subrs,
+                    };
+                    let number = self.stack.pop().ok_or_else(invalid)? + subr_bias(subrs.len());
+                    let subr = usize::try_from(number)
+                        .ok()
+                        .and_then(|number| subrs.get(number))
+                        .ok_or_else(invalid)?;
+                    if let Flow::End(seac) = self.run(subr, depth + 1)? {
+                        return Ok(Flow::End(seac));
+                    }
+                }
+                11 => return Ok(Flow::Return),
+                14 => {
+                    let seac = match self.stack[..] {
+                        [.., _, _, base, accent] => Some([base, accent]),
+                        _ => None,
+                    };
+                    return Ok(Flow::End(seac));
+                }
+                12 => {
+                    // Arithmetic and storage operators compute operands,
+                    // which this does not follow.
+                    if !(34..=37).contains(&read_u8(code, at)?) {
+                        return Err("CFF charstring arithmetic is not supported".to_string());
+                    }
+                    at += 1;
+                    self.stack.clear();
+                }
+                28 => {
+                    self.stack.push(read_u16(code, at)? as i16 as i32);
+                    at += 2;
+                }
+                32..=246 => self.stack.push(b0 as i32 - 139),
+                247..=250 => {
+                    self.stack
+                        .push((b0 as i32 - 247) * 256 + read_u8(code, at)? as i32 + 108);
+                    at += 1;
+                }
+                251..=254 => {
+                    self.stack
+                        .push(-(b0 as i32 - 251) * 256 - read_u8(code, at)? as i32 - 108);
+                    at += 1;
+                }
+                // A 16.16 fixed-point number, of which the integer part.
+                255 => {
+                    self.stack.push(read_u32(code, at)? as i32 >> 16);
+                    at += 4;
+                }
+                _ => self.stack.clear(),
+            }
+        }
+        Ok(Flow::Return)
+    }
+}
+
+/// Subroutine numbers in charstrings are offset by this for `count` subrs.
+fn subr_bias(count: usize) -> i32 {
+    match count {
+        0..1240 => 107,
+        1240..33900 => 1131,
+        _ => 32768,
+    }
+}
+
+/// The SID Standard Encoding gives a code.
+fn standard_sid(code: i32) -> Option<u16> {
+    match code {
+        32..=126 => Some(code as u16 - 31),
+        _ => STANDARD_HIGH
+            .iter()
+            .position(|standard| *standard as i32 == code)
+            .map(|position| 96 + position as u16),
+    }
+}
+
+fn offset(value: i32) -> Result<usize, String> {
+    usize::try_from(value).map_err(|_| "CFF font has a negative offset".to_string())
+}
+
+fn operand(entries: &[DictEntry], operator: u16, position: usize) -> Option<i32> {
+    entries
+        .iter()
+        .find(|entry| entry.operator == operator)
+        .and_then(|entry| entry.operands.get(position).copied())
+}
+
+/// The items of the INDEX at `at`, and where it ends.
+fn index(data: &[u8], at: usize) -> Result<(Vec<&[u8]>, usize), String> {
+    let count = read_u16(data, at)? as usize;
+    if count == 0 {
+        return Ok((Vec::new(), at + 2));
+    }
+    let offset_size = read_u8(data, at + 2)? as usize;
+    if !(1..=4).contains(&offset_size) {
+        return Err("CFF INDEX has an invalid offset size".to_string());
+    }
+    let offset = |index: usize| -> Result<usize, String> {
+        let bytes = slice(data, at + 3 + index * offset_size, offset_size)?;
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as usize))
+    };
+    // Offsets count from 1, at the byte before the data.
+    let base = at + 2 + (count + 1) * offset_size;
+    let mut items = Vec::with_capacity(count);
+    let mut start = offset(0)?;
+    for index in 1..=count {
+        let end = offset(index)?;
+        if end < start {
+            return Err("CFF INDEX offsets are out of order".to_string());
+        }
+        items.push(slice(data, base + start, end - start)?);
+        start = end;
+    }
+    Ok((items, base + start))
+}
+
+fn offset_size(largest: usize) -> usize {
+    match largest {
+        0..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        0x1_0000..=0xFF_FFFF => 3,
+        _ => 4,
+    }
+}
+
+fn index_len(lens: impl IntoIterator<Item = usize>) -> usize {
+    let (count, total) = lens
+        .into_iter()
+        .fold((0, 0), |(count, total), len| (count + 1, total + len));
+    match count {
+        0 => 2,
+        _ => 3 + (count + 1) * offset_size(total + 1) + total,
+    }
+}
+
+fn write_index(out: &mut Vec<u8>, items: &[&[u8]]) {
+    out.extend_from_slice(&(items.len() as u16).to_be_bytes());
+    if items.is_empty() {
+        return;
+    }
+    let total: usize = items.iter().map(|item| item.len()).sum();
+    let size = offset_size(total + 1);
+    out.push(size as u8);
+    let mut offset = 1usize;
+    for len in [0].into_iter().chain(items.iter().map(|item| item.len())) {
+        offset += len;
+        out.extend_from_slice(&offset.to_be_bytes()[usize::BITS as usize / 8 - size..]);
+    }
+    for item in items {
+        out.extend_from_slice(item);
+    }
+}
+
+fn dict(data: &[u8]) -> Result<Vec<DictEntry<'_>>, String> {
+    let invalid = || "CFF DICT is invalid".to_string();
+    let mut entries = Vec::new();
+    let mut operands = Vec::new();
+    let mut start = 0;
+    let mut at = 0;
+    while at < data.len() {
+        let b0 = data[at];
+        let next = || read_u8(data, at + 1).map(|byte| byte as i32);
+        match b0 {
+            0..=21 => {
+                let operator = match b0 {
+                    12 => 1200 + next()? as u16,
+                    _ => b0 as u16,
+                };
+                at += if b0 == 12 { 2 } else { 1 };
+                entries.push(DictEntry {
+                    operator,
+                    operands: std::mem::take(&mut operands),
+                    raw: &data[start..at],
+                });
+                start = at;
+            }
+            28 => {
+                operands.push(read_u16(data, at + 1)? as i16 as i32);
+                at += 3;
+            }
+            29 => {
+                operands.push(read_u32(data, at + 1)? as i32);
+                at += 5;
+            }
+            30 => {
+                at += 1;
+                loop {
+                    let byte = read_u8(data, at).map_err(|_| invalid())?;
+                    at += 1;
+                    if byte >> 4 == 0xF || byte & 0xF == 0xF {
+                        break;
+                    }
+                }
+                operands.push(0);
+            }
+            32..=246 => {
+                operands.push(b0 as i32 - 139);
+                at += 1;
+            }
+            247..=250 => {
+                operands.push((b0 as i32 - 247) * 256 + next()? + 108);
+                at += 2;
+            }
+            251..=254 => {
+                operands.push(-(b0 as i32 - 251) * 256 - next()? - 108);
+                at += 2;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(entries)
+}
+
+/// Serialize a DICT, replacing the operands of the operators in `offsets`.
+/// Replacements are written as 5-byte integers, so the size of the result
+/// does not depend on their values.
+fn write_dict(entries: &[DictEntry], offsets: &HashMap<u16, Vec<i32>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for entry in entries {
+        let Some(values) = offsets.get(&entry.operator) else {
+            out.extend_from_slice(entry.raw);
+            continue;
+        };
+        for value in values {
+            out.push(29);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        match entry.operator {
+            operator @ 1200.. => out.extend_from_slice(&[12, (operator - 1200) as u8]),
+            operator => out.push(operator as u8),
+        }
+    }
+    out
+}
+
+fn charset_len(data: &[u8], at: usize, count: usize) -> Result<usize, String> {
+    let format = read_u8(data, at)?;
+    if format == 0 {
+        return Ok(1 + 2 * (count - 1));
+    }
+    let left_size = match format {
+        1 => 1,
+        2 => 2,
+        _ => return Err(format!("Unknown CFF charset format {}", format)),
+    };
+    let mut covered = 1;
+    let mut cursor = at + 1;
+    while covered < count {
+        let left = match left_size {
+            1 => read_u8(data, cursor + 2)? as usize,
+            _ => read_u16(data, cursor + 2)? as usize,
+        };
+        covered += left + 1;
+        cursor += 2 + left_size;
+    }
+    Ok(cursor - at)
+}
+
+fn encoding_len(data: &[u8], at: usize) -> Result<usize, String> {
+    let format = read_u8(data, at)?;
+    let count = read_u8(data, at + 1)? as usize;
+    let mut len = match format & 0x7F {
+        0 => 2 + count,
+        1 => 2 + 2 * count,
+        format => return Err(format!("Unknown CFF encoding format {}", format)),
+    };
+    if format & 0x80 != 0 {
+        len += 1 + 3 * read_u8(data, at + len)? as usize;
+    }
+    Ok(len)
+}
+
+fn fd_select_len(data: &[u8], at: usize, count: usize) -> Result<usize, String> {
+    match read_u8(data, at)? {
+        0 => Ok(1 + count),
+        3 => Ok(5 + 3 * read_u16(data, at + 1)? as usize),
+        format => Err(format!("Unknown CFF FDSelect format {}", format)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(value: i32) -> Vec<u8> {
+        [&[29][..], &value.to_be_bytes()].concat()
+    }
+
+    /// A Type1C font with an empty Private DICT, whose glyphs have the
+    /// given SIDs and charstrings.
+    fn type1c(sids: &[u16], char_strings: &[&[u8]], global_subrs: &[&[u8]]) -> Vec<u8> {
+        let top = |charset: usize, char_strings: usize, private: usize| {
+            [
+                int(charset as i32),
+                vec![15],
+                int(char_strings as i32),
+                vec![17],
+                int(0),
+                int(private as i32),
+                vec![18],
+            ]
+            .concat()
+        };
+        let mut out = vec![1, 0, 4, 4];
+        write_index(&mut out, &[b"Test"]);
+        let mut shared = Vec::new();
+        write_index(&mut shared, &[]);
+        write_index(&mut shared, global_subrs);
+        let charset_at = out.len() + index_len([top(0, 0, 0).len()]) + shared.len();
+        let mut charset = vec![0];
+        for sid in &sids[1..] {
+            charset.extend_from_slice(&sid.to_be_bytes());
+        }
+        let char_strings_at = charset_at + charset.len();
+        let private_at = char_strings_at + index_len(char_strings.iter().map(|item| item.len()));
+        write_index(&mut out, &[&top(charset_at, char_strings_at, private_at)]);
+        out.extend_from_slice(&shared);
+        out.extend_from_slice(&charset);
+        write_index(&mut out, char_strings);
+        out
+    }
+
+    #[test]
+    fn subset_keeps_seac_parts() {
+        // `0 0 65 194 endchar` builds Aacute from A and acute; 194 is
+        // written as the two bytes 247 86.
+        let seac: &[u8] = &[139, 139, 204, 247, 86, 14];
+        let seac_in_subr: &[u8] = &[139, 139, 205, 247, 86, 14];
+        let char_strings: [&[u8]; 9] = [
+            ENDCHAR,
+            &[139, 139, 21, 14],
+            &[140, 139, 21, 14],
+            &[141, 139, 21, 14],
+            &[142, 139, 21, 14],
+            seac,
+            // Global subr 0, biased by 107.
+            &[32, 29],
+            // Three stems take one mask byte, which reads as endchar if
+            // it is not skipped.
+            &[
+                139, 140, 141, 142, 18, 143, 144, 19, 14, 139, 139, 206, 247, 86, 14,
+            ],
+            &[143, 139, 21, 14],
+        ];
+        // .notdef, A, B, C, acute, then custom glyphs.
+        let sids = [0, 34, 35, 36, 125, 391, 392, 393, 394];
+        let font = type1c(&sids, &char_strings, &[seac_in_subr]);
+
+        let cff = Cff::parse(&font).unwrap();
+        let subset = cff.subset(&HashSet::from([5, 6, 7])).unwrap();
+        let subset = Cff::parse(&subset).unwrap();
+        for (gid, char_string) in char_strings.iter().enumerate() {
+            let expected = if gid == 8 { ENDCHAR } else { char_string };
+            assert_eq!(subset.char_strings[gid], expected, "glyph {}", gid);
+        }
+    }
+
+    #[test]
+    fn seac_with_a_code_outside_standard_encoding_is_an_error() {
+        let char_strings: [&[u8]; 2] = [ENDCHAR, &[139, 139, 139, 139, 14]];
+        let font = type1c(&[0, 391], &char_strings, &[]);
+        let cff = Cff::parse(&font).unwrap();
+        assert!(cff.subset(&HashSet::from([1])).is_err());
+    }
+
+    #[test]
+    fn cff_index_round_trips() {
+        let long = vec![7; 300];
+        for items in [&[][..], &[&b"a"[..], b"", b"bc"], &[&long[..], b"d"]] {
+            let mut out = vec![0xAA];
+            write_index(&mut out, items);
+            assert_eq!(
+                out.len(),
+                1 + index_len(items.iter().map(|item| item.len()))
+            );
+            let (read, end) = index(&out, 1).unwrap();
+            assert_eq!(read, items);
+            assert_eq!(end, out.len());
+        }
+    }
+
+    #[test]
+    fn cff_dict_round_trips() {
+        let data = [
+            // 0 108 -108 -1000 100000 0.5 CharStrings
+            &[139, 247, 0, 251, 0, 28, 0xFC, 0x18][..],
+            &[29, 0, 1, 0x86, 0xA0, 30, 0xA5, 0xFF, 17],
+            // 1 2 3 ROS, then Private with 1131 and -1131.
+            &[140, 141, 142, 12, 30, 254, 255, 250, 255, 18],
+        ]
+        .concat();
+        let entries = dict(&data).unwrap();
+        let found: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.operator, entry.operands.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (CHAR_STRINGS, vec![0, 108, -108, -1000, 100000, 0]),
+                (ROS, vec![1, 2, 3]),
+                (PRIVATE, vec![-1131, 1131]),
+            ]
+        );
+        assert_eq!(write_dict(&entries, &HashMap::new()), data);
+
+        let offsets = HashMap::from([(PRIVATE, vec![4, 70000])]);
+        let written = write_dict(&entries, &offsets);
+        assert_eq!(written.len(), data.len() - 5 + 10 + 1);
+        let rewritten = dict(&written).unwrap();
+        assert_eq!(rewritten[1].raw, entries[1].raw);
+        assert_eq!(rewritten[2].operator, PRIVATE);
+        assert_eq!(rewritten[2].operands, [4, 70000]);
+    }
+
+    /// A TrueType font of four glyphs, the third a composite of the
+    /// fourth, with a GPOS table for the subset to drop.
+    fn truetype(long_loca: bool) -> Vec<u8> {
+        let simple = |fill: u8| [&[0, 1][..], &[fill; 10]].concat();
+        let composite = [&[0xFF, 0xFF][..], &[0; 8], &[0, 0, 0, 3, 5, 6]].concat();
+        let glyphs = [simple(1), simple(2), composite, simple(4)];
+        let mut glyf = Vec::new();
+        let mut loca = Vec::new();
+        for glyph in glyphs.iter().map(Vec::as_slice).chain([&[][..]]) {
+            match long_loca {
+                true => loca.extend_from_slice(&(glyf.len() as u32).to_be_bytes()),
+                false => loca.extend_from_slice(&(glyf.len() as u16 / 2).to_be_bytes()),
+            }
+            glyf.extend_from_slice(glyph);
+        }
+        let mut head = vec![0; 54];
+        head[12..16].copy_from_slice(&0x5F0F_3CF5_u32.to_be_bytes());
+        head[51] = long_loca as u8;
+        let maxp = [0, 0, 0x50, 0, 0, 4];
+        write_sfnt(
+            *b"\0\x01\0\0",
+            &[
+                (*b"GPOS", b"gpos"),
+                (*b"glyf", &glyf),
+                (*b"head", &head),
+                (*b"loca", &loca),
+                (*b"maxp", &maxp),
+            ],
+        )
+    }
+
+    #[test]
+    fn truetype_subset_rewrites_loca_and_checksums() {
+        for long_loca in [false, true] {
+            let font = truetype(long_loca);
+            let subset = TrueType::parse(&font)
+                .unwrap()
+                .subset(&HashSet::from([2]))
+                .unwrap();
+
+            let parsed = TrueType::parse(&subset).unwrap();
+            assert_eq!(parsed.num_glyphs(), 4);
+            assert_eq!(parsed.long_loca, long_loca);
+            let tags: Vec<_> = parsed.tables.iter().map(|(tag, _)| tag).collect();
+            assert_eq!(tags, [b"glyf", b"head", b"loca", b"maxp"]);
+
+            // Glyph 1 is dropped; 0 is always kept and 3 is a component of 2.
+            let glyf = parsed.table(b"glyf").unwrap();
+            let original = TrueType::parse(&font).unwrap();
+            let original_glyf = original.table(b"glyf").unwrap();
+            assert_eq!(glyf, [&original_glyf[..12], &original_glyf[24..]].concat());
+            let offsets: Vec<u32> = (0..5)
+                .map(|index| match long_loca {
+                    true => read_u32(parsed.table(b"loca").unwrap(), 4 * index).unwrap(),
+                    false => {
+                        read_u16(parsed.table(b"loca").unwrap(), 2 * index).unwrap() as u32 * 2
+                    }
+                })
+                .collect();
+            assert_eq!(offsets, [0, 12, 12, 28, 40]);
+
+            for index in 0..parsed.tables.len() {
+                let record = 12 + 16 * index;
+                let (tag, data) = parsed.tables[index];
+                let mut data = data.to_vec();
+                if &tag == b"head" {
+                    // The head checksum is taken with checkSumAdjustment as 0.
+                    data[8..12].fill(0);
+                }
+                assert_eq!(read_u32(&subset, record + 4).unwrap(), checksum(&data));
+            }
+            assert_eq!(checksum(&subset), 0xB1B0_AFBA);
+        }
+    }
+}
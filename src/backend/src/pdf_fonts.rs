@@ -0,0 +1,1024 @@
+use crate::pdf_font_subset::{Cff, TrueType};
+use candid::{CandidType, Deserialize};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Forms, patterns and Type 3 glyphs nested deeper than this are not
+/// followed, and fonts are then left as they are.
+const MAX_DEPTH: usize = 8;
+
+/// Content streams parsed per document, to bound the work for forms drawn
+/// many times over.
+const MAX_STREAMS: usize = 10_000;
+
+/// Codes 0x80 to 0x9F of `WinAnsiEncoding`, where it differs from Latin-1.
+/// Unassigned codes show a bullet.
+const WIN_ANSI_HIGH: [u32; 32] = [
+    0x20AC, 0x2022, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160, 0x2039,
+    0x0152, 0x2022, 0x017D, 0x2022, 0x2022, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x2022, 0x017E, 0x0178,
+];
+
+/// What font optimization did to one embedded font program.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FontReport {
+    pub object_number: u32,
+    pub generation: u16,
+    /// `BaseFont` of a font using the program.
+    pub name: String,
+    pub original_bytes: u64,
+    pub bytes: u64,
+    /// Glyphs shown on any page, out of the glyphs in the font.
+    pub glyphs_used: u32,
+    pub glyphs: u32,
+    /// Why the program was left unchanged, if it was.
+    pub skipped: Option<String>,
+}
+
+/// What font optimization did to a document.
+#[derive(Default)]
+pub struct FontsOptimized {
+    pub fonts: Vec<FontReport>,
+    /// Fonts no page used, removed from the resources listing them.
+    pub removed: u32,
+}
+
+/// Subset embedded TrueType and CFF font programs to the glyphs the pages
+/// show, and remove fonts no page uses. Fails without changing anything
+/// when some content cannot be read, as the glyphs used are then unknown.
+pub fn optimize(doc: &mut Document) -> Result<FontsOptimized, String> {
+    let usage = Usage::collect(doc)?;
+    let fonts = subset_programs(doc, &usage);
+    let removed = remove_unused(doc, &usage);
+    Ok(FontsOptimized { fonts, removed })
+}
+
+/// Fonts the content of a document uses.
+#[derive(Default)]
+struct Usage {
+    /// Character codes shown with each font dictionary. Fonts selected but
+    /// never shown with are present with no codes.
+    codes: HashMap<ObjectId, HashSet<u16>>,
+    /// Fonts used where their glyphs cannot be followed: by form fields,
+    /// whose text viewers may regenerate, and by graphics states.
+    pinned: HashSet<ObjectId>,
+}
+
+impl Usage {
+    fn collect(doc: &Document) -> Result<Self, String> {
+        let mut walker = Walker {
+            doc,
+            usage: Usage::default(),
+            walked: HashSet::new(),
+            streams_left: MAX_STREAMS,
+        };
+        for page_id in doc.page_iter() {
+            let (own, inherited) = doc.get_page_resources(page_id);
+            let mut resources: Vec<&Dictionary> = own.into_iter().collect();
+            resources.extend(
+                inherited
+                    .iter()
+                    .filter_map(|id| doc.get_dictionary(*id).ok()),
+            );
+            let mut content = Vec::new();
+            for id in doc.get_page_contents(page_id) {
+                let stream = doc
+                    .get_object(id)
+                    .and_then(Object::as_stream)
+                    .map_err(|_| "Page content is not a stream".to_string())?;
+                content.extend(decoded(stream)?);
+                content.push(b'\n');
+            }
+            walker.walk(&content, &resources, 0)?;
+
+            let Ok(page) = doc.get_dictionary(page_id) else {
+                continue;
+            };
+            let annotations = page
+                .get_deref(b"Annots", doc)
+                .and_then(Object::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            for annotation in annotations.iter().filter_map(|a| dictionary(doc, a)) {
+                walker.appearances(annotation)?;
+            }
+        }
+
+        let mut usage = walker.usage;
+        let form_fonts = doc
+            .catalog()
+            .and_then(|catalog| catalog.get_deref(b"AcroForm", doc))
+            .and_then(Object::as_dict)
+            .and_then(|form| form.get_deref(b"DR", doc))
+            .and_then(Object::as_dict)
+            .and_then(|resources| resources.get_deref(b"Font", doc))
+            .and_then(Object::as_dict);
+        if let Ok(fonts) = form_fonts {
+            usage.pinned.extend(
+                fonts
+                    .iter()
+                    .filter_map(|(_, font)| font.as_reference().ok()),
+            );
+        }
+        Ok(usage)
+    }
+}
+
+struct Walker<'a> {
+    doc: &'a Document,
+    usage: Usage,
+    /// Streams with resources of their own, and Type 3 fonts, already
+    /// walked: they show the same glyphs wherever they are drawn.
+    walked: HashSet<ObjectId>,
+    streams_left: usize,
+}
+
+impl<'a> Walker<'a> {
+    fn walk(
+        &mut self,
+        content: &[u8],
+        resources: &[&'a Dictionary],
+        depth: usize,
+    ) -> Result<(), String> {
+        let doc = self.doc;
+        // The font selected, and whether it uses two-byte codes.
+        let mut font: Option<(ObjectId, bool)> = None;
+        for Operation { operator, operands } in operations(content)? {
+            let name = match operands.last() {
+                Some(Operand::Name(name)) => Some(name.as_slice()),
+                _ => None,
+            };
+            match operator.as_slice() {
+                b"Tf" => {
+                    font = None;
+                    let Some(Operand::Name(name)) = operands.first() else {
+                        continue;
+                    };
+                    match resource(doc, resources, b"Font", name) {
+                        Some(Object::Reference(id)) => {
+                            font = Some(self.select(*id, resources, depth)?)
+                        }
+                        Some(_) => {
+                            return Err("Fonts stored inline in resources are not supported".into())
+                        }
+                        None => {}
+                    }
+                }
+                b"Tj" | b"'" | b"\"" | b"TJ" => {
+                    let Some((id, two_byte)) = font else {
+                        continue;
+                    };
+                    let strings: Vec<&[u8]> = match operands.last() {
+                        Some(Operand::String(text)) => vec![text],
+                        Some(Operand::Array(items)) => items
+                            .iter()
+                            .filter_map(|item| match item {
+                                Operand::String(text) => Some(text.as_slice()),
+                                _ => None,
+                            })
+                            .collect(),
+                        _ => continue,
+                    };
+                    let codes = self.usage.codes.entry(id).or_default();
+                    for text in strings {
+                        match two_byte {
+                            true => codes.extend(
+                                text.chunks_exact(2)
+                                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+                            ),
+                            false => codes.extend(text.iter().map(|code| *code as u16)),
+                        }
+                    }
+                }
+                b"Do" | b"scn" | b"SCN" => {
+                    let category: &[u8] = match operator.as_slice() {
+                        b"Do" => b"XObject",
+                        _ => b"Pattern",
+                    };
+                    let Some(Object::Reference(id)) =
+                        name.and_then(|name| resource(doc, resources, category, name))
+                    else {
+                        continue;
+                    };
+                    // Images and shading patterns show no text.
+                    if let Ok(stream) = doc.get_object(*id).and_then(Object::as_stream) {
+                        if stream.dict.get(b"Subtype").and_then(Object::as_name).ok()
+                            != Some(b"Image")
+                        {
+                            self.form(*id, stream, resources, depth + 1)?;
+                        }
+                    }
+                }
+                b"gs" => {
+                    let state = name
+                        .and_then(|name| resource(doc, resources, b"ExtGState", name))
+                        .and_then(|state| dictionary(doc, state));
+                    let state_font = state
+                        .and_then(|state| state.get_deref(b"Font", doc).ok())
+                        .and_then(|font| font.as_array().ok())
+                        .and_then(|font| font.first())
+                        .and_then(|font| font.as_reference().ok());
+                    if let Some(id) = state_font {
+                        self.usage.pinned.insert(id);
+                        font = Some(self.select(id, resources, depth)?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a font as used, walking its glyph procedures if it is a
+    /// Type 3 font; returns it with whether it uses two-byte codes.
+    fn select(
+        &mut self,
+        id: ObjectId,
+        resources: &[&'a Dictionary],
+        depth: usize,
+    ) -> Result<(ObjectId, bool), String> {
+        let doc = self.doc;
+        self.usage.codes.entry(id).or_default();
+        let Ok(font) = doc.get_dictionary(id) else {
+            return Ok((id, false));
+        };
+        match font.get(b"Subtype").and_then(Object::as_name) {
+            Ok(b"Type0") => return Ok((id, true)),
+            Ok(b"Type3") if self.walked.insert(id) => {
+                let own = font.get_deref(b"Resources", doc).and_then(Object::as_dict);
+                let resources = match own {
+                    Ok(own) => vec![own],
+                    Err(_) => resources.to_vec(),
+                };
+                if let Ok(procedures) = font.get_deref(b"CharProcs", doc).and_then(Object::as_dict)
+                {
+                    for (_, procedure) in procedures.iter() {
+                        let Ok(procedure_id) = procedure.as_reference() else {
+                            continue;
+                        };
+                        if let Ok(stream) = doc.get_object(procedure_id).and_then(Object::as_stream)
+                        {
+                            self.form(procedure_id, stream, &resources, depth + 1)?;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok((id, false))
+    }
+
+    /// Walk a form XObject, tiling pattern or glyph procedure drawn with
+    /// `resources` in effect.
+    fn form(
+        &mut self,
+        id: ObjectId,
+        stream: &'a Stream,
+        resources: &[&'a Dictionary],
+        depth: usize,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH || self.streams_left == 0 {
+            return Err("Content is nested too deeply to find the glyphs used".to_string());
+        }
+        let own = stream
+            .dict
+            .get_deref(b"Resources", self.doc)
+            .and_then(Object::as_dict)
+            .ok();
+        if own.is_some() && !self.walked.insert(id) {
+            return Ok(());
+        }
+        self.streams_left -= 1;
+        let resources = match own {
+            Some(own) => vec![own],
+            None => resources.to_vec(),
+        };
+        self.walk(&decoded(stream)?, &resources, depth)
+    }
+
+    /// Walk every appearance stream of an annotation.
+    fn appearances(&mut self, annotation: &'a Dictionary) -> Result<(), String> {
+        let doc = self.doc;
+        let Ok(appearances) = annotation.get_deref(b"AP", doc).and_then(Object::as_dict) else {
+            return Ok(());
+        };
+        for (_, appearance) in appearances.iter() {
+            // An appearance is a stream, or a stream for each state.
+            let states = match dictionary(doc, appearance) {
+                Some(states) => states.iter().map(|(_, state)| state).collect(),
+                None => vec![appearance],
+            };
+            for state in states {
+                let Ok(id) = state.as_reference() else {
+                    continue;
+                };
+                if let Ok(stream) = doc.get_object(id).and_then(Object::as_stream) {
+                    self.form(id, stream, &[], 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dictionary<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Dictionary> {
+    match object {
+        Object::Reference(id) => doc.get_dictionary(*id).ok(),
+        Object::Dictionary(dict) => Some(dict),
+        _ => None,
+    }
+}
+
+fn resource<'a>(
+    doc: &'a Document,
+    resources: &[&'a Dictionary],
+    category: &[u8],
+    name: &[u8],
+) -> Option<&'a Object> {
+    resources.iter().find_map(|resources| {
+        resources
+            .get_deref(category, doc)
+            .and_then(Object::as_dict)
+            .and_then(|entries| entries.get(name))
+            .ok()
+    })
+}
+
+fn decoded(stream: &Stream) -> Result<Vec<u8>, String> {
+    match stream.dict.has(b"Filter") {
+        true => stream
+            .decompressed_content()
+            .map_err(|e| format!("Failed to decode stream: {}", e)),
+        false => Ok(stream.content.clone()),
+    }
+}
+
+/// A content stream operand, reduced to what finding glyphs needs.
+enum Operand {
+    Name(Vec<u8>),
+    String(Vec<u8>),
+    Array(Vec<Operand>),
+    Other,
+}
+
+struct Operation {
+    operator: Vec<u8>,
+    operands: Vec<Operand>,
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte) && !b"()<>[]{}/%".contains(&byte)
+}
+
+/// Split a content stream into operations. Unlike a parser that stops at
+/// the first token it does not understand, this fails instead, so no text
+/// goes unseen.
+fn operations(content: &[u8]) -> Result<Vec<Operation>, String> {
+    let invalid = || "A content stream could not be parsed".to_string();
+    let mut operations = Vec::new();
+    // Operands of the operation, then of each open array or dictionary.
+    let mut open: Vec<(u8, Vec<Operand>)> = vec![(0, Vec::new())];
+    let mut at = 0;
+    while at < content.len() {
+        let byte = content[at];
+        let operand = match byte {
+            _ if is_whitespace(byte) => {
+                at += 1;
+                continue;
+            }
+            b'%' => {
+                while at < content.len() && !matches!(content[at], b'\r' | b'\n') {
+                    at += 1;
+                }
+                continue;
+            }
+            b'/' => {
+                let start = at + 1;
+                at = start;
+                while at < content.len() && is_regular(content[at]) {
+                    at += 1;
+                }
+                Operand::Name(unescape_name(&content[start..at]))
+            }
+            b'(' => {
+                let (text, end) = literal_string(content, at + 1).ok_or_else(invalid)?;
+                at = end;
+                Operand::String(text)
+            }
+            b'[' => {
+                at += 1;
+                open.push((b'[', Vec::new()));
+                continue;
+            }
+            b'<' if content.get(at + 1) == Some(&b'<') => {
+                at += 2;
+                open.push((b'<', Vec::new()));
+                continue;
+            }
+            b'<' => {
+                let end = content[at..]
+                    .iter()
+                    .position(|byte| *byte == b'>')
+                    .ok_or_else(invalid)?;
+                let text = hex_string(&content[at + 1..at + end]).ok_or_else(invalid)?;
+                at += end + 1;
+                Operand::String(text)
+            }
+            b']' | b'>' => {
+                let opener = match byte {
+                    b']' => b'[',
+                    _ if content.get(at + 1) == Some(&b'>') => b'<',
+                    _ => return Err(invalid()),
+                };
+                at += if opener == b'<' { 2 } else { 1 };
+                match open.pop() {
+                    Some((found, items)) if found == opener && !open.is_empty() => match opener {
+                        b'[' => Operand::Array(items),
+                        _ => Operand::Other,
+                    },
+                    _ => return Err(invalid()),
+                }
+            }
+            b'{' | b'}' => {
+                at += 1;
+                continue;
+            }
+            b')' => return Err(invalid()),
+            _ => {
+                let start = at;
+                while at < content.len() && is_regular(content[at]) {
+                    at += 1;
+                }
+                let token = &content[start..at];
+                if token[0].is_ascii_digit()
+                    || matches!(token[0], b'+' | b'-' | b'.')
+                    || matches!(token, b"true" | b"false" | b"null")
+                {
+                    Operand::Other
+                } else {
+                    if open.len() > 1 {
+                        return Err(invalid());
+                    }
+                    let operands = std::mem::take(&mut open[0].1);
+                    if token == b"BI" {
+                        at = skip_inline_image(content, at).ok_or_else(invalid)?;
+                        continue;
+                    }
+                    operations.push(Operation {
+                        operator: token.to_vec(),
+                        operands,
+                    });
+                    continue;
+                }
+            }
+        };
+        open.last_mut().ok_or_else(invalid)?.1.push(operand);
+    }
+    Ok(operations)
+}
+
+fn unescape_name(name: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len());
+    let mut at = 0;
+    while at < name.len() {
+        let escaped = (name[at] == b'#')
+            .then(|| name.get(at + 1..at + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                at += 3;
+            }
+            None => {
+                out.push(name[at]);
+                at += 1;
+            }
+        }
+    }
+    out
+}
+
+/// A literal string starting after its opening parenthesis, and where it
+/// ends.
+fn literal_string(content: &[u8], mut at: usize) -> Option<(Vec<u8>, usize)> {
+    let mut text = Vec::new();
+    let mut depth = 0;
+    while let Some(&byte) = content.get(at) {
+        at += 1;
+        match byte {
+            b'(' => {
+                depth += 1;
+                text.push(byte);
+            }
+            b')' if depth == 0 => return Some((text, at)),
+            b')' => {
+                depth -= 1;
+                text.push(byte);
+            }
+            b'\\' => {
+                let next = *content.get(at)?;
+                at += 1;
+                match next {
+                    b'n' => text.push(b'\n'),
+                    b'r' => text.push(b'\r'),
+                    b't' => text.push(b'\t'),
+                    b'b' => text.push(0x08),
+                    b'f' => text.push(0x0C),
+                    b'0'..=b'7' => {
+                        let mut value = (next - b'0') as u32;
+                        for _ in 0..2 {
+                            match content.get(at) {
+                                Some(digit @ b'0'..=b'7') => {
+                                    value = value * 8 + (digit - b'0') as u32;
+                                    at += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        text.push(value as u8);
+                    }
+                    // A backslash before a line break continues the line.
+                    b'\r' => {
+                        if content.get(at) == Some(&b'\n') {
+                            at += 1;
+                        }
+                    }
+                    b'\n' => {}
+                    _ => text.push(next),
+                }
+            }
+            _ => text.push(byte),
+        }
+    }
+    None
+}
+
+fn hex_string(hex: &[u8]) -> Option<Vec<u8>> {
+    let mut digits = hex
+        .iter()
+        .filter(|byte| !is_whitespace(**byte))
+        .map(|byte| (*byte as char).to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    if digits.len() % 2 == 1 {
+        digits.push(0);
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+/// Skip an inline image from after its `BI` to after its `EI`.
+fn skip_inline_image(content: &[u8], at: usize) -> Option<usize> {
+    let keyword = |from: usize, keyword: &[u8]| {
+        (from.max(1)..content.len()).find(|&at| {
+            content[at..].starts_with(keyword)
+                && is_whitespace(content[at - 1])
+                && content.get(at + 2).is_none_or(|byte| is_whitespace(*byte))
+        })
+    };
+    // Image data starts after the single whitespace byte that follows `ID`.
+    let data = keyword(at, b"ID")? + 3;
+    Some(keyword(data, b"EI")? + 2)
+}
+
+/// A parsed font program.
+enum Program<'a> {
+    TrueType(TrueType<'a>),
+    Cff(Cff<'a>),
+}
+
+impl Program<'_> {
+    fn num_glyphs(&self) -> u16 {
+        match self {
+            Self::TrueType(font) => font.num_glyphs(),
+            Self::Cff(font) => font.num_glyphs(),
+        }
+    }
+
+    fn subset(&self, glyphs: &HashSet<u16>) -> Result<Vec<u8>, String> {
+        match self {
+            Self::TrueType(font) => font.subset(glyphs),
+            Self::Cff(font) => font.subset(glyphs),
+        }
+    }
+}
+
+fn subtype(font: &Dictionary) -> Option<&[u8]> {
+    font.get(b"Subtype").and_then(Object::as_name).ok()
+}
+
+fn descendant<'a>(doc: &'a Document, font: &'a Dictionary) -> Option<&'a Dictionary> {
+    let fonts = font
+        .get_deref(b"DescendantFonts", doc)
+        .and_then(Object::as_array);
+    dictionary(doc, fonts.ok()?.first()?)
+}
+
+fn descriptor<'a>(doc: &'a Document, font: &'a Dictionary) -> Option<&'a Dictionary> {
+    let font = match subtype(font)? {
+        b"Type0" => descendant(doc, font)?,
+        _ => font,
+    };
+    font.get_deref(b"FontDescriptor", doc)
+        .and_then(Object::as_dict)
+        .ok()
+}
+
+/// The embedded program of a font, and the descriptor key holding it.
+/// Descendants of Type 0 fonts are reached through their parent only.
+fn program_of(doc: &Document, font: &Dictionary) -> Option<(ObjectId, &'static [u8])> {
+    if font.get(b"Type").and_then(Object::as_name).ok() != Some(b"Font")
+        || !matches!(
+            subtype(font)?,
+            b"Type0" | b"Type1" | b"MMType1" | b"TrueType"
+        )
+    {
+        return None;
+    }
+    let descriptor = descriptor(doc, font)?;
+    [&b"FontFile"[..], b"FontFile2", b"FontFile3"]
+        .into_iter()
+        .find_map(|key| {
+            Some((
+                descriptor.get(key).and_then(Object::as_reference).ok()?,
+                key,
+            ))
+        })
+}
+
+/// The glyphs a font shows for `codes`.
+fn glyph_ids(
+    doc: &Document,
+    font: &Dictionary,
+    program: &Program,
+    codes: &HashSet<u16>,
+) -> Result<HashSet<u16>, String> {
+    match (subtype(font), program) {
+        (Some(b"Type0"), _) => {
+            let encoding = font.get_deref(b"Encoding", doc).and_then(Object::as_name);
+            if !matches!(encoding, Ok(b"Identity-H" | b"Identity-V")) {
+                return Err("Only Identity-H and Identity-V encodings are supported".to_string());
+            }
+            let descendant =
+                descendant(doc, font).ok_or_else(|| "Type 0 font has no descendant".to_string())?;
+            // With an identity encoding, codes are CIDs.
+            match program {
+                Program::TrueType(_) => match descendant.get_deref(b"CIDToGIDMap", doc) {
+                    Err(_) | Ok(Object::Name(_)) => Ok(codes.clone()),
+                    Ok(Object::Stream(map)) => {
+                        let map = decoded(map)?;
+                        Ok(codes
+                            .iter()
+                            .filter_map(|cid| map.get(*cid as usize * 2..*cid as usize * 2 + 2))
+                            .map(|gid| u16::from_be_bytes([gid[0], gid[1]]))
+                            .collect())
+                    }
+                    Ok(_) => Err("CIDToGIDMap is invalid".to_string()),
+                },
+                Program::Cff(cff) if cff.is_cid_keyed() => {
+                    let gids = cff.cid_to_gid()?;
+                    Ok(codes
+                        .iter()
+                        .filter_map(|cid| gids.get(cid).copied())
+                        .collect())
+                }
+                Program::Cff(_) => Ok(codes.clone()),
+            }
+        }
+        (Some(b"TrueType"), Program::TrueType(program)) => {
+            truetype_glyph_ids(doc, font, program, codes)
+        }
+        (Some(b"Type1" | b"MMType1"), Program::Cff(cff)) if !font.has(b"Encoding") => {
+            let encoding = cff.encoding()?;
+            Ok(codes
+                .iter()
+                .filter_map(|code| encoding.get(&(*code as u8)).copied())
+                .collect())
+        }
+        (Some(b"Type1" | b"MMType1"), Program::Cff(_)) => {
+            Err("Fonts that select glyphs by name are not supported".to_string())
+        }
+        _ => Err("Font program does not match the font type".to_string()),
+    }
+}
+
+/// Glyphs of a simple TrueType font. Viewers find them through whichever
+/// `cmap` subtable the font has, so every one that could apply is tried.
+fn truetype_glyph_ids(
+    doc: &Document,
+    font: &Dictionary,
+    program: &TrueType,
+    codes: &HashSet<u16>,
+) -> Result<HashSet<u16>, String> {
+    let symbolic = descriptor(doc, font)
+        .and_then(|descriptor| descriptor.get(b"Flags").and_then(Object::as_i64).ok())
+        .is_some_and(|flags| flags & 4 != 0);
+    let encoding = match font.get_deref(b"Encoding", doc) {
+        Err(_) => None,
+        Ok(Object::Name(name)) => Some(name.as_slice()),
+        Ok(Object::Dictionary(dict)) if !dict.has(b"Differences") => {
+            dict.get(b"BaseEncoding").and_then(Object::as_name).ok()
+        }
+        Ok(_) => return Err("Encodings with glyph name differences are not supported".to_string()),
+    };
+
+    let mut glyphs = HashSet::new();
+    for &code in codes {
+        let code = code as u32;
+        let unicode = match encoding {
+            Some(b"WinAnsiEncoding") => match code {
+                0x7F => vec![0x2022],
+                0x80..=0x9F => vec![WIN_ANSI_HIGH[code as usize - 0x80]],
+                0xA0 => vec![0xA0, 0x20],
+                0xAD => vec![0xAD, 0x2D],
+                _ => vec![code],
+            },
+            // Symbolic fonts without an encoding map codes through the font's
+            // own `cmap`.
+            None if symbolic => vec![code],
+            Some(b"MacRomanEncoding" | b"StandardEncoding") | None if code < 0x80 => match code {
+                0x27 => vec![0x27, 0x2019],
+                0x60 => vec![0x60, 0x2018],
+                _ => vec![code],
+            },
+            _ => return Err("Font encoding is not supported".to_string()),
+        };
+        for candidate in [code, 0xF000 | code, 0xF100 | code, 0xF200 | code] {
+            glyphs.extend(program.lookup(3, 0, candidate));
+        }
+        glyphs.extend(program.lookup(1, 0, code));
+        for character in unicode {
+            for (platform, encoding) in [(3, 1), (3, 10), (0, 3), (0, 4)] {
+                glyphs.extend(program.lookup(platform, encoding, character));
+            }
+        }
+    }
+    Ok(glyphs)
+}
+
+/// A font program cut down to the glyphs used.
+struct Subset {
+    content: Vec<u8>,
+    glyphs_used: u32,
+    glyphs: u32,
+    tag: Vec<u8>,
+}
+
+fn subset_programs(doc: &mut Document, usage: &Usage) -> Vec<FontReport> {
+    let mut users: BTreeMap<(ObjectId, &[u8]), Vec<ObjectId>> = BTreeMap::new();
+    for (id, object) in &doc.objects {
+        if let Some(program) = object.as_dict().ok().and_then(|font| program_of(doc, font)) {
+            users.entry(program).or_default().push(*id);
+        }
+    }
+
+    let mut reports = Vec::new();
+    for ((program_id, key), fonts) in users {
+        // Programs only unused fonts refer to go with those fonts.
+        if fonts
+            .iter()
+            .all(|id| !usage.codes.contains_key(id) && !usage.pinned.contains(id))
+        {
+            continue;
+        }
+        let Ok(stream) = doc.get_object(program_id).and_then(Object::as_stream) else {
+            continue;
+        };
+        let original_bytes = stream.content.len() as u64;
+        let mut report = FontReport {
+            object_number: program_id.0,
+            generation: program_id.1,
+            name: fonts
+                .iter()
+                .find_map(|id| {
+                    let font = doc.get_dictionary(*id).ok()?;
+                    font.get(b"BaseFont").and_then(Object::as_name).ok()
+                })
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_default(),
+            original_bytes,
+            bytes: original_bytes,
+            glyphs_used: 0,
+            glyphs: 0,
+            skipped: None,
+        };
+
+        let replacement = subset_program(doc, usage, stream, key, &fonts).and_then(|subset| {
+            report.glyphs_used = subset.glyphs_used;
+            report.glyphs = subset.glyphs;
+            let mut replacement = stream.clone();
+            let length = subset.content.len();
+            replacement.set_plain_content(subset.content);
+            replacement
+                .compress()
+                .map_err(|e| format!("Failed to compress font program: {}", e))?;
+            if key == b"FontFile2" {
+                replacement.dict.set("Length1", length as i64);
+            }
+            if replacement.content.len() as u64 >= original_bytes {
+                return Err("Subsetting did not make the font smaller".to_string());
+            }
+            Ok((replacement, subset.tag))
+        });
+        match replacement {
+            Ok((replacement, tag)) => {
+                report.bytes = replacement.content.len() as u64;
+                doc.objects.insert(program_id, Object::Stream(replacement));
+                tag_names(doc, &fonts, &tag);
+            }
+            Err(err) => report.skipped = Some(err),
+        }
+        reports.push(report);
+    }
+    reports
+}
+
+fn subset_program(
+    doc: &Document,
+    usage: &Usage,
+    stream: &Stream,
+    key: &[u8],
+    fonts: &[ObjectId],
+) -> Result<Subset, String> {
+    if fonts.iter().any(|id| usage.pinned.contains(id)) {
+        return Err("Used by form fields or graphics states".to_string());
+    }
+    let data = decoded(stream)?;
+    let program = match (key, stream.dict.get(b"Subtype").and_then(Object::as_name)) {
+        (b"FontFile2", _) | (b"FontFile3", Ok(b"OpenType")) => {
+            Program::TrueType(TrueType::parse(&data)?)
+        }
+        (b"FontFile3", Ok(b"Type1C" | b"CIDFontType0C")) => Program::Cff(Cff::parse(&data)?),
+        (b"FontFile", _) => return Err("Type 1 font programs are not subset".to_string()),
+        _ => return Err("Unknown font program type".to_string()),
+    };
+
+    let mut glyphs = HashSet::new();
+    for id in fonts {
+        let (Some(codes), Ok(font)) = (usage.codes.get(id), doc.get_dictionary(*id)) else {
+            continue;
+        };
+        glyphs.extend(glyph_ids(doc, font, &program, codes)?);
+    }
+    let total = program.num_glyphs();
+    glyphs.retain(|gid| *gid < total);
+
+    let mut sorted: Vec<u16> = glyphs.iter().copied().collect();
+    sorted.sort_unstable();
+    let mut hasher = Sha256::new();
+    for gid in &sorted {
+        hasher.update(gid.to_be_bytes());
+    }
+    hasher.update(&data);
+    let tag = hasher.finalize()[..6]
+        .iter()
+        .map(|byte| b'A' + byte % 26)
+        .collect();
+
+    Ok(Subset {
+        content: program.subset(&glyphs)?,
+        glyphs_used: glyphs.len() as u32,
+        glyphs: total as u32,
+        tag,
+    })
+}
+
+/// Mark fonts as subset by prefixing their names with a six-letter tag, as
+/// in `ABCDEF+Helvetica`, unless they already are.
+fn tag_names(doc: &mut Document, fonts: &[ObjectId], tag: &[u8]) {
+    let mut names: Vec<(ObjectId, &[u8])> = Vec::new();
+    for id in fonts {
+        names.push((*id, b"BaseFont"));
+        let Ok(font) = doc.get_dictionary(*id) else {
+            continue;
+        };
+        let holder = match subtype(font) {
+            Some(b"Type0") => {
+                let descendant = font
+                    .get(b"DescendantFonts")
+                    .and_then(Object::as_array)
+                    .ok()
+                    .and_then(|fonts| fonts.first())
+                    .and_then(|font| font.as_reference().ok());
+                names.extend(descendant.map(|id| (id, &b"BaseFont"[..])));
+                descendant.and_then(|id| doc.get_dictionary(id).ok())
+            }
+            _ => Some(font),
+        };
+        let descriptor = holder
+            .and_then(|holder| holder.get(b"FontDescriptor").ok())
+            .and_then(|descriptor| descriptor.as_reference().ok());
+        names.extend(descriptor.map(|id| (id, &b"FontName"[..])));
+    }
+
+    for (id, key) in names {
+        let Ok(dict) = doc.get_dictionary_mut(id) else {
+            continue;
+        };
+        let Ok(name) = dict.get(key).and_then(Object::as_name) else {
+            continue;
+        };
+        let tagged =
+            name.len() > 7 && name[6] == b'+' && name[..6].iter().all(u8::is_ascii_uppercase);
+        if !tagged {
+            let name = [tag, b"+", name].concat();
+            dict.set(key, Object::Name(name));
+        }
+    }
+}
+
+/// Remove fonts no content selected from every resource dictionary listing
+/// them; they are pruned once unreferenced. Returns how many were removed.
+fn remove_unused(doc: &mut Document, usage: &Usage) -> u32 {
+    let is_font = |font: &Dictionary| {
+        font.get(b"Type").and_then(Object::as_name).ok() == Some(b"Font")
+            && matches!(
+                subtype(font),
+                Some(b"Type0" | b"Type1" | b"MMType1" | b"TrueType" | b"Type3")
+            )
+    };
+    let unused: HashSet<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, object)| object.as_dict().is_ok_and(is_font))
+        .map(|(id, _)| *id)
+        .filter(|id| !usage.codes.contains_key(id) && !usage.pinned.contains(id))
+        .collect();
+    if unused.is_empty() {
+        return 0;
+    }
+
+    // Resource and font dictionaries stored as objects of their own.
+    let mut resources_ids = Vec::new();
+    let mut font_ids = Vec::new();
+    for object in doc.objects.values() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &stream.dict,
+            _ => continue,
+        };
+        match dict.get(b"Resources") {
+            Ok(Object::Reference(id)) => resources_ids.push(*id),
+            Ok(Object::Dictionary(resources)) => {
+                font_ids.extend(resources.get(b"Font").and_then(Object::as_reference));
+            }
+            _ => {}
+        }
+    }
+    for id in &resources_ids {
+        if let Ok(resources) = doc.get_dictionary(*id) {
+            font_ids.extend(resources.get(b"Font").and_then(Object::as_reference));
+        }
+    }
+
+    let mut removed = HashSet::new();
+    let mut remove = |fonts: &mut Dictionary| {
+        let names: Vec<Vec<u8>> = fonts
+            .iter()
+            .filter_map(|(name, font)| {
+                let id = font.as_reference().ok().filter(|id| unused.contains(id))?;
+                removed.insert(id);
+                Some(name.clone())
+            })
+            .collect();
+        for name in names {
+            fonts.remove(&name);
+        }
+    };
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if let Ok(Object::Dictionary(resources)) = dict.get_mut(b"Resources") {
+            if let Ok(Object::Dictionary(fonts)) = resources.get_mut(b"Font") {
+                remove(fonts);
+            }
+        }
+    }
+    for id in resources_ids {
+        if let Ok(Object::Dictionary(fonts)) = doc
+            .get_dictionary_mut(id)
+            .and_then(|resources| resources.get_mut(b"Font"))
+        {
+            remove(fonts);
+        }
+    }
+    for id in font_ids {
+        if let Ok(fonts) = doc.get_dictionary_mut(id) {
+            remove(fonts);
+        }
+    }
+    removed.len() as u32
+}
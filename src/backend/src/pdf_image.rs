@@ -0,0 +1,795 @@
+use candid::{CandidType, Deserialize};
+use flate2::read::ZlibDecoder;
+use image::{DynamicImage, GrayImage, ImageFormat, RgbImage};
+use lopdf::{Dictionary, Document, Object, Stream};
+use std::io::Read;
+
+/// Largest image decoded, in pixels, to bound memory use.
+const MAX_PIXELS: u64 = 25_000_000;
+
+/// Most bytes of samples unfiltered for one image, whatever its dimensions
+/// say: an 8-bit CMYK image of `MAX_PIXELS`.
+const MAX_SAMPLE_BYTES: u64 = MAX_PIXELS * 4;
+
+/// Longest palette of an indexed color space: 256 8-bit CMYK colors.
+const MAX_PALETTE_BYTES: usize = 256 * 4;
+
+/// How deeply color spaces may nest, e.g. an indexed space over an ICC one.
+const MAX_COLOR_SPACE_DEPTH: usize = 4;
+
+/// Color space of an image's samples.
+enum ColorSpace {
+    Gray,
+    Rgb,
+    Cmyk,
+    /// Colors of the base space, one 8-bit value per component.
+    Indexed {
+        base: Box<ColorSpace>,
+        palette: Vec<u8>,
+    },
+}
+
+impl ColorSpace {
+    fn components(&self) -> usize {
+        match self {
+            Self::Gray | Self::Indexed { .. } => 1,
+            Self::Rgb => 3,
+            Self::Cmyk => 4,
+        }
+    }
+}
+
+/// What an image is, which decides how it may be re-encoded.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// Continuous-tone color.
+    Photo,
+    Grayscale,
+    /// One bit per pixel, such as scans and line art.
+    Bilevel,
+    /// Colors picked from a palette.
+    Indexed,
+    /// Print colors, which only survive lossless re-encoding here.
+    Cmyk,
+    /// Masked by a color key, which depends on exact sample values.
+    ColorKeyMasked,
+    /// Transparency of another image.
+    SoftMask,
+    /// A one-bit stencil painted with the current color.
+    StencilMask,
+}
+
+impl ImageKind {
+    /// Whether the image may be re-encoded as JPEG; otherwise only its
+    /// samples may be recompressed losslessly.
+    pub fn lossy(self) -> bool {
+        matches!(self, Self::Photo | Self::Grayscale)
+    }
+}
+
+/// Classify an image; `soft_mask` tells whether another image uses it as
+/// its `SMask`.
+pub fn classify(doc: &Document, stream: &Stream, soft_mask: bool) -> ImageKind {
+    let dict = &stream.dict;
+    if dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false)
+    {
+        return ImageKind::StencilMask;
+    }
+    if soft_mask {
+        return ImageKind::SoftMask;
+    }
+    if matches!(
+        dict.get(b"Mask").map(|mask| resolve(doc, mask)),
+        Ok(Object::Array(_))
+    ) {
+        return ImageKind::ColorKeyMasked;
+    }
+    let color_space = dict
+        .get(b"ColorSpace")
+        .ok()
+        .and_then(|object| color_space(doc, object, 0).ok());
+    let bits = positive(doc, dict, b"BitsPerComponent").ok();
+    match color_space {
+        Some(ColorSpace::Indexed { .. }) => ImageKind::Indexed,
+        _ if bits == Some(1) => ImageKind::Bilevel,
+        Some(ColorSpace::Gray) => ImageKind::Grayscale,
+        Some(ColorSpace::Cmyk) => ImageKind::Cmyk,
+        _ => ImageKind::Photo,
+    }
+}
+
+/// The raw samples of an image with every filter undone, for lossless
+/// recompression. JPEG data has no raw samples to recover.
+pub fn samples(doc: &Document, stream: &Stream) -> Result<Vec<u8>, String> {
+    let limit = max_sample_bytes(doc, &stream.dict)?;
+    match unfilter(doc, stream, limit)? {
+        Unfiltered::Samples(data) => Ok(data),
+        Unfiltered::Jpeg(_) => Err("JPEG images cannot be recompressed losslessly".to_string()),
+    }
+}
+
+/// Width and height of an image with one 1-bit component per pixel, the
+/// images CCITT fax encoding can hold.
+pub fn fax_size(doc: &Document, stream: &Stream) -> Option<(u32, u32)> {
+    let dict = &stream.dict;
+    let mask = dict.get(b"ImageMask").and_then(Object::as_bool);
+    let bits = dict
+        .get(b"BitsPerComponent")
+        .and_then(|bits| resolve(doc, bits).as_i64())
+        .ok();
+    let space = color_space(doc, dict.get(b"ColorSpace").ok()?, 0).ok()?;
+    if mask.unwrap_or(false) || bits != Some(1) || space.components() != 1 {
+        return None;
+    }
+    Some((
+        positive(doc, dict, b"Width").ok()?,
+        positive(doc, dict, b"Height").ok()?,
+    ))
+}
+
+/// Stream data with every filter applied except a final image codec.
+enum Unfiltered {
+    Samples(Vec<u8>),
+    Jpeg(Vec<u8>),
+}
+
+/// Decode an image XObject into pixels.
+///
+/// Raw sample data is rebuilt from `Width`, `Height`, `BitsPerComponent`,
+/// `ColorSpace` and `Decode`; JPEG data is handed to the JPEG decoder.
+/// Stencil masks and images in color spaces or codecs that cannot be
+/// reproduced are refused, so they are kept as they are.
+pub fn decode(doc: &Document, stream: &Stream) -> Result<DynamicImage, String> {
+    let dict = &stream.dict;
+    if dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false)
+    {
+        return Err("Stencil masks are kept as they are".to_string());
+    }
+
+    let width = positive(doc, dict, b"Width")?;
+    let height = positive(doc, dict, b"Height")?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(format!("Image of {}x{} pixels is too large", width, height));
+    }
+
+    match unfilter(doc, stream, max_sample_bytes(doc, dict)?)? {
+        Unfiltered::Jpeg(data) => decode_jpeg(doc, dict, &data),
+        Unfiltered::Samples(data) => {
+            let color_space = dict
+                .get(b"ColorSpace")
+                .map_err(|_| "Image has no color space".to_string())
+                .and_then(|object| color_space(doc, object, 0))?;
+            let bits = positive(doc, dict, b"BitsPerComponent")?;
+            if ![1, 2, 4, 8, 16].contains(&bits) {
+                return Err(format!("Unsupported {} bits per component", bits));
+            }
+            let decode = decode_array(doc, dict);
+            from_samples(&data, width, height, bits, &color_space, decode)
+        }
+    }
+}
+
+fn decode_jpeg(doc: &Document, dict: &Dictionary, data: &[u8]) -> Result<DynamicImage, String> {
+    if dict.has(b"Decode") {
+        return Err("JPEG images with a Decode array are kept as they are".to_string());
+    }
+    if let Ok(object) = dict.get(b"ColorSpace") {
+        if matches!(color_space(doc, object, 0), Ok(ColorSpace::Cmyk)) {
+            return Err("CMYK JPEG images are kept as they are".to_string());
+        }
+    }
+    image::load_from_memory_with_format(data, ImageFormat::Jpeg)
+        .map_err(|e| format!("Failed to decode JPEG: {}", e))
+}
+
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> &'a Object {
+    doc.dereference(object)
+        .map(|(_, object)| object)
+        .unwrap_or(object)
+}
+
+fn positive(doc: &Document, dict: &Dictionary, key: &[u8]) -> Result<u32, String> {
+    dict.get(key)
+        .map(|object| resolve(doc, object))
+        .and_then(Object::as_i64)
+        .ok()
+        .filter(|value| *value > 0 && *value <= u32::MAX as i64)
+        .map(|value| value as u32)
+        .ok_or_else(|| format!("Image has no valid {}", String::from_utf8_lossy(key)))
+}
+
+fn color_space(doc: &Document, object: &Object, depth: usize) -> Result<ColorSpace, String> {
+    if depth > MAX_COLOR_SPACE_DEPTH {
+        return Err("Color space is nested too deeply".to_string());
+    }
+    let unsupported = |name: &[u8]| {
+        Err(format!(
+            "Unsupported color space {}",
+            String::from_utf8_lossy(name)
+        ))
+    };
+
+    let items = match resolve(doc, object) {
+        Object::Name(name) => {
+            return match name.as_slice() {
+                b"DeviceGray" | b"G" | b"CalGray" => Ok(ColorSpace::Gray),
+                b"DeviceRGB" | b"RGB" | b"CalRGB" => Ok(ColorSpace::Rgb),
+                b"DeviceCMYK" | b"CMYK" => Ok(ColorSpace::Cmyk),
+                other => unsupported(other),
+            }
+        }
+        Object::Array(items) => items,
+        _ => return Err("Malformed color space".to_string()),
+    };
+
+    let family = items
+        .first()
+        .and_then(|family| resolve(doc, family).as_name().ok())
+        .ok_or_else(|| "Malformed color space".to_string())?;
+    match family {
+        b"CalGray" => Ok(ColorSpace::Gray),
+        b"CalRGB" => Ok(ColorSpace::Rgb),
+        b"ICCBased" => {
+            let profile = items
+                .get(1)
+                .and_then(|profile| resolve(doc, profile).as_stream().ok())
+                .ok_or_else(|| "Malformed ICC color space".to_string())?;
+            match profile.dict.get(b"N").and_then(Object::as_i64) {
+                Ok(1) => Ok(ColorSpace::Gray),
+                Ok(3) => Ok(ColorSpace::Rgb),
+                Ok(4) => Ok(ColorSpace::Cmyk),
+                _ => match profile.dict.get(b"Alternate") {
+                    Ok(alternate) => color_space(doc, alternate, depth + 1),
+                    Err(_) => Err("ICC profile has no component count".to_string()),
+                },
+            }
+        }
+        b"Indexed" | b"I" => {
+            let base = items
+                .get(1)
+                .ok_or_else(|| "Indexed color space has no base".to_string())
+                .and_then(|base| color_space(doc, base, depth + 1))?;
+            if matches!(base, ColorSpace::Indexed { .. }) {
+                return Err("Indexed color space over another indexed space".to_string());
+            }
+            let hival = items
+                .get(2)
+                .and_then(|hival| resolve(doc, hival).as_i64().ok())
+                .ok_or_else(|| "Indexed color space has no hival".to_string())?
+                .clamp(0, 255) as usize;
+            let mut palette = match items.get(3).map(|lookup| resolve(doc, lookup)) {
+                Some(Object::String(bytes, _)) => bytes.clone(),
+                Some(Object::Stream(stream)) => match unfilter(doc, stream, MAX_PALETTE_BYTES)? {
+                    Unfiltered::Samples(bytes) => bytes,
+                    Unfiltered::Jpeg(_) => return Err("Malformed indexed palette".to_string()),
+                },
+                _ => return Err("Indexed color space has no palette".to_string()),
+            };
+            let needed = (hival + 1) * base.components();
+            if palette.len() < needed {
+                return Err("Indexed palette is too short".to_string());
+            }
+            palette.truncate(needed);
+            Ok(ColorSpace::Indexed {
+                base: Box::new(base),
+                palette,
+            })
+        }
+        other => unsupported(other),
+    }
+}
+
+/// `[min, max]` pairs mapping samples onto component values, if given.
+fn decode_array(doc: &Document, dict: &Dictionary) -> Option<Vec<(f32, f32)>> {
+    let values: Vec<f32> = dict
+        .get(b"Decode")
+        .map(|object| resolve(doc, object))
+        .and_then(Object::as_array)
+        .ok()?
+        .iter()
+        .filter_map(|value| resolve(doc, value).as_float().ok())
+        .collect();
+    Some(
+        values
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect(),
+    )
+}
+
+/// Most bytes the samples of an image can take, with a byte per row for PNG
+/// predictor tags, to bound what decompressing them may produce. Color
+/// spaces and depths that cannot be read count as 16-bit CMYK.
+fn max_sample_bytes(doc: &Document, dict: &Dictionary) -> Result<usize, String> {
+    let width = positive(doc, dict, b"Width")? as u64;
+    let height = positive(doc, dict, b"Height")? as u64;
+    let components = dict
+        .get(b"ColorSpace")
+        .ok()
+        .and_then(|object| color_space(doc, object, 0).ok())
+        .map_or(4, |space| space.components()) as u64;
+    let bits = dict
+        .get(b"BitsPerComponent")
+        .and_then(|bits| resolve(doc, bits).as_i64())
+        .map_or(16, |bits| bits.clamp(1, 16)) as u64;
+    let row = (width * components * bits).div_ceil(8) + 1;
+    Ok(row.saturating_mul(height).min(MAX_SAMPLE_BYTES) as usize)
+}
+
+/// Undo the stream's filters, producing at most `limit` bytes from each
+/// decompression.
+fn unfilter(doc: &Document, stream: &Stream, limit: usize) -> Result<Unfiltered, String> {
+    let filters = if stream.dict.has(b"Filter") {
+        match resolve(doc, stream.dict.get(b"Filter").map_err(|e| e.to_string())?) {
+            Object::Name(name) => vec![name.clone()],
+            Object::Array(names) => names
+                .iter()
+                .map(|name| resolve(doc, name).as_name().map(<[u8]>::to_vec))
+                .collect::<Result<_, _>>()
+                .map_err(|_| "Malformed filter".to_string())?,
+            _ => return Err("Malformed filter".to_string()),
+        }
+    } else {
+        Vec::new()
+    };
+    let params: Vec<Option<&Dictionary>> = match stream
+        .dict
+        .get(b"DecodeParms")
+        .map(|params| resolve(doc, params))
+    {
+        Ok(Object::Dictionary(params)) => vec![Some(params)],
+        Ok(Object::Array(params)) => params
+            .iter()
+            .map(|params| resolve(doc, params).as_dict().ok())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut data = stream.content.clone();
+    for (i, filter) in filters.iter().enumerate() {
+        let params = params.get(i).copied().flatten();
+        data = match filter.as_slice() {
+            b"FlateDecode" | b"Fl" => predict(inflate(&data, limit)?, params)?,
+            b"LZWDecode" | b"LZW" => predict(lzw(&data, params)?, params)?,
+            b"ASCIIHexDecode" | b"AHx" => ascii_hex(&data)?,
+            b"ASCII85Decode" | b"A85" => ascii85(&data)?,
+            b"RunLengthDecode" | b"RL" => run_length(&data),
+            b"DCTDecode" | b"DCT" if i + 1 == filters.len() => return Ok(Unfiltered::Jpeg(data)),
+            other => {
+                return Err(format!(
+                    "Unsupported filter {}",
+                    String::from_utf8_lossy(other)
+                ))
+            }
+        };
+    }
+    Ok(Unfiltered::Samples(data))
+}
+
+/// Inflate zlib data, keeping at most `limit` bytes: anything past what the
+/// image can use is dropped rather than decompressed.
+fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut output = Vec::with_capacity((data.len() * 2).min(limit));
+    ZlibDecoder::new(data)
+        .take(limit as u64)
+        .read_to_end(&mut output)
+        .map_err(|e| format!("Failed to inflate image data: {}", e))?;
+    Ok(output)
+}
+
+/// LZW as lopdf implements it, without its predictor handling, which only
+/// supports 8-bit samples.
+fn lzw(data: &[u8], params: Option<&Dictionary>) -> Result<Vec<u8>, String> {
+    let mut dict = Dictionary::new();
+    dict.set("Filter", "LZWDecode");
+    if let Some(early_change) = params.and_then(|params| params.get(b"EarlyChange").ok()) {
+        let mut parms = Dictionary::new();
+        parms.set("EarlyChange", early_change.clone());
+        dict.set("DecodeParms", parms);
+    }
+    Stream::new(dict, data.to_vec())
+        .decompressed_content()
+        .map_err(|e| format!("Failed to decode LZW image data: {}", e))
+}
+
+fn ascii_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut digits = Vec::with_capacity(data.len());
+    for &byte in data {
+        match byte {
+            b'>' => break,
+            byte if byte.is_ascii_whitespace() => {}
+            byte => digits.push(
+                (byte as char)
+                    .to_digit(16)
+                    .ok_or_else(|| "Invalid ASCIIHex data".to_string())? as u8,
+            ),
+        }
+    }
+    if digits.len() % 2 == 1 {
+        digits.push(0);
+    }
+    Ok(digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect())
+}
+
+fn ascii85(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = Vec::with_capacity(data.len() * 4 / 5);
+    let mut group = [0u8; 5];
+    let mut len = 0;
+    for &byte in data.strip_prefix(b"<~").unwrap_or(data) {
+        match byte {
+            b'~' => break,
+            b'z' if len == 0 => output.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group[len] = byte - b'!';
+                len += 1;
+                if len == 5 {
+                    output.extend_from_slice(&base85_word(&group)?.to_be_bytes());
+                    len = 0;
+                }
+            }
+            byte if byte.is_ascii_whitespace() => {}
+            _ => return Err("Invalid ASCII85 data".to_string()),
+        }
+    }
+    if len == 1 {
+        return Err("Invalid ASCII85 data".to_string());
+    }
+    if len > 1 {
+        group[len..].fill(84);
+        output.extend_from_slice(&base85_word(&group)?.to_be_bytes()[..len - 1]);
+    }
+    Ok(output)
+}
+
+fn base85_word(group: &[u8; 5]) -> Result<u32, String> {
+    group
+        .iter()
+        .try_fold(0u32, |word, &digit| {
+            word.checked_mul(85)?.checked_add(digit as u32)
+        })
+        .ok_or_else(|| "Invalid ASCII85 data".to_string())
+}
+
+fn run_length(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let length = data[i] as usize;
+        i += 1;
+        match length {
+            128 => break,
+            0..=127 => {
+                let end = (i + length + 1).min(data.len());
+                output.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+            _ => {
+                if let Some(&byte) = data.get(i) {
+                    output.extend(std::iter::repeat_n(byte, 257 - length));
+                }
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+/// Undo a PNG (10-15) or TIFF (2) predictor.
+fn predict(data: Vec<u8>, params: Option<&Dictionary>) -> Result<Vec<u8>, String> {
+    let Some(params) = params else {
+        return Ok(data);
+    };
+    let get =
+        |key: &[u8], default: i64| params.get(key).and_then(Object::as_i64).unwrap_or(default);
+    let predictor = get(b"Predictor", 1);
+    if predictor < 2 {
+        return Ok(data);
+    }
+    let colors = get(b"Colors", 1).clamp(1, 32) as usize;
+    let bits = get(b"BitsPerComponent", 8).clamp(1, 16) as usize;
+    let columns = get(b"Columns", 1).clamp(1, u32::MAX as i64) as u64;
+    // The parameters are not tied to the image's dimensions, so check a row
+    // fits the data before allocating one.
+    let row_bytes = (colors as u64 * bits as u64 * columns).div_ceil(8);
+    if row_bytes > data.len() as u64 {
+        return Err("Predictor rows are longer than the image data".to_string());
+    }
+    let row_bytes = row_bytes as usize;
+    let pixel_bytes = (colors * bits).div_ceil(8);
+
+    if predictor == 2 {
+        return tiff_predictor(data, row_bytes, colors, bits);
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; row_bytes];
+    for chunk in data.chunks_exact(row_bytes + 1) {
+        let mut current = chunk[1..].to_vec();
+        for i in 0..row_bytes {
+            let left = if i >= pixel_bytes {
+                current[i - pixel_bytes]
+            } else {
+                0
+            };
+            let up = previous[i];
+            let up_left = if i >= pixel_bytes {
+                previous[i - pixel_bytes]
+            } else {
+                0
+            };
+            let prediction = match chunk[0] {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                other => return Err(format!("Invalid PNG predictor {}", other)),
+            };
+            current[i] = current[i].wrapping_add(prediction);
+        }
+        output.extend_from_slice(&current);
+        previous = current;
+    }
+    Ok(output)
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance = |value: u8| (estimate - value as i16).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+fn tiff_predictor(
+    mut data: Vec<u8>,
+    row_bytes: usize,
+    colors: usize,
+    bits: usize,
+) -> Result<Vec<u8>, String> {
+    for row in data.chunks_exact_mut(row_bytes) {
+        match bits {
+            8 => {
+                for i in colors..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - colors]);
+                }
+            }
+            16 => {
+                for i in (colors * 2..row.len() - 1).step_by(2) {
+                    let left = u16::from_be_bytes([row[i - colors * 2], row[i - colors * 2 + 1]]);
+                    let value = u16::from_be_bytes([row[i], row[i + 1]]).wrapping_add(left);
+                    row[i..i + 2].copy_from_slice(&value.to_be_bytes());
+                }
+            }
+            _ => return Err(format!("Unsupported TIFF predictor at {} bits", bits)),
+        }
+    }
+    Ok(data)
+}
+
+/// Read sample `index` of a row packed at `bits` per sample.
+fn sample(row: &[u8], index: usize, bits: usize) -> usize {
+    match bits {
+        8 => row[index] as usize,
+        16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]) as usize,
+        _ => {
+            let offset = index * bits;
+            let shift = 8 - bits - offset % 8;
+            (row[offset / 8] as usize >> shift) & ((1 << bits) - 1)
+        }
+    }
+}
+
+fn cmyk_to_rgb(cmyk: &[u8]) -> [u8; 3] {
+    let k = 255 - cmyk[3] as u16;
+    let channel = |value: u8| ((255 - value as u16) * k / 255) as u8;
+    [channel(cmyk[0]), channel(cmyk[1]), channel(cmyk[2])]
+}
+
+fn from_samples(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    bits: u32,
+    color_space: &ColorSpace,
+    decode: Option<Vec<(f32, f32)>>,
+) -> Result<DynamicImage, String> {
+    let components = color_space.components();
+    let bits = bits as usize;
+    let row_bytes = (width as usize * components * bits).div_ceil(8);
+    let rows = data.chunks_exact(row_bytes).take(height as usize);
+    if rows.len() < height as usize {
+        return Err("Image data is truncated".to_string());
+    }
+
+    // One table per component mapping each sample value through `Decode`:
+    // onto 0-255 for colors, onto palette positions for indexed images.
+    let max_sample = (1usize << bits) - 1;
+    let indexed = matches!(color_space, ColorSpace::Indexed { .. });
+    let tables: Vec<Vec<u8>> = (0..components)
+        .map(|component| {
+            let default = if indexed {
+                (0.0, max_sample as f32)
+            } else {
+                (0.0, 1.0)
+            };
+            let (min, max) = decode
+                .as_ref()
+                .and_then(|decode| decode.get(component).copied())
+                .unwrap_or(default);
+            let scale = if indexed { 1.0 } else { 255.0 };
+            (0..=max_sample)
+                .map(|value| {
+                    let mapped = min + value as f32 * (max - min) / max_sample as f32;
+                    (mapped * scale).round().clamp(0.0, 255.0) as u8
+                })
+                .collect()
+        })
+        .collect();
+
+    let pixels = width as usize * height as usize;
+    let mut values = Vec::with_capacity(pixels * components);
+    for row in rows {
+        for index in 0..width as usize * components {
+            values.push(tables[index % components][sample(row, index, bits)]);
+        }
+    }
+
+    let gray =
+        |pixels: Vec<u8>| GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8);
+    let rgb =
+        |pixels: Vec<u8>| RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8);
+    let image = match color_space {
+        ColorSpace::Gray => gray(values),
+        ColorSpace::Rgb => rgb(values),
+        ColorSpace::Cmyk => rgb(values.chunks_exact(4).flat_map(cmyk_to_rgb).collect()),
+        ColorSpace::Indexed { base, palette } => {
+            let size = base.components();
+            let last = palette.len() / size - 1;
+            let entry = |index: &u8| {
+                let start = (*index as usize).min(last) * size;
+                &palette[start..start + size]
+            };
+            match base.as_ref() {
+                ColorSpace::Gray => gray(values.iter().map(|index| entry(index)[0]).collect()),
+                ColorSpace::Rgb => rgb(values.iter().flat_map(entry).copied().collect()),
+                _ => rgb(values.iter().map(entry).flat_map(cmyk_to_rgb).collect()),
+            }
+        }
+    };
+    image.ok_or_else(|| "Image data does not match its dimensions".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// Filter rows with PNG filter types in turn, as an encoder would.
+    fn png_filter(raw: &[u8], row_bytes: usize, pixel_bytes: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut previous = vec![0u8; row_bytes];
+        for (index, row) in raw.chunks_exact(row_bytes).enumerate() {
+            let kind = (index % 5) as u8;
+            output.push(kind);
+            for i in 0..row_bytes {
+                let left = if i >= pixel_bytes {
+                    row[i - pixel_bytes]
+                } else {
+                    0
+                };
+                let up = previous[i];
+                let up_left = if i >= pixel_bytes {
+                    previous[i - pixel_bytes]
+                } else {
+                    0
+                };
+                let prediction = match kind {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, up_left),
+                };
+                output.push(row[i].wrapping_sub(prediction));
+            }
+            previous = row.to_vec();
+        }
+        output
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed = 0x2545f4914f6cdd1du64;
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                // Runs of equal bytes make the predictions matter.
+                if seed.is_multiple_of(3) {
+                    0x80
+                } else {
+                    seed as u8
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn png_predictors_round_trip() {
+        for (colors, bits, columns) in [(1, 8, 17), (3, 8, 10), (4, 16, 5), (1, 1, 21), (2, 4, 9)] {
+            let row_bytes = (colors * bits * columns as usize).div_ceil(8);
+            let pixel_bytes = (colors * bits).div_ceil(8);
+            let raw = noise(row_bytes * 12);
+            let params = dictionary! {
+                "Predictor" => 15,
+                "Colors" => colors as i64,
+                "BitsPerComponent" => bits as i64,
+                "Columns" => columns,
+            };
+            let filtered = png_filter(&raw, row_bytes, pixel_bytes);
+            assert_eq!(predict(filtered, Some(&params)).unwrap(), raw);
+        }
+    }
+
+    #[test]
+    fn png_predictor_rows() {
+        let params = dictionary! { "Predictor" => 12, "Columns" => 3 };
+        let data = vec![0, 1, 2, 3, 1, 1, 1, 1, 2, 1, 1, 1, 3, 0, 2, 4, 4, 1, 0, 1];
+        assert_eq!(
+            predict(data, Some(&params)).unwrap(),
+            vec![1, 2, 3, 1, 2, 3, 2, 3, 4, 1, 4, 8, 2, 4, 9]
+        );
+    }
+
+    #[test]
+    fn predictor_rejects_bad_rows() {
+        let params = dictionary! { "Predictor" => 12, "Columns" => 3 };
+        assert!(predict(vec![5, 0, 0, 0], Some(&params)).is_err());
+        let params = dictionary! { "Predictor" => 12, "Columns" => 1_000_000_000 };
+        assert!(predict(vec![0; 64], Some(&params)).is_err());
+    }
+
+    #[test]
+    fn tiff_predictor_adds_left_samples() {
+        let params = dictionary! { "Predictor" => 2, "Colors" => 2, "Columns" => 3 };
+        let data = vec![1, 10, 1, 10, 1, 10, 5, 5, 0, 0, 255, 1];
+        assert_eq!(
+            predict(data, Some(&params)).unwrap(),
+            vec![1, 10, 2, 20, 3, 30, 5, 5, 5, 5, 4, 6]
+        );
+    }
+
+    #[test]
+    fn ascii85_decodes() {
+        assert_eq!(ascii85(b"<~9jqo^Bla~>").unwrap(), b"Man is");
+        assert_eq!(ascii85(b"9jqo^\n Bla~>").unwrap(), b"Man is");
+        assert_eq!(ascii85(b"z@:B~>").unwrap(), b"\0\0\0\0ab");
+        assert_eq!(ascii85(b"s8W-!~>").unwrap(), [255; 4]);
+        assert_eq!(ascii85(b"~>").unwrap(), b"");
+    }
+
+    #[test]
+    fn ascii85_rejects_invalid_data() {
+        // Past the largest 32-bit value.
+        assert!(ascii85(b"s8W-\"~>").is_err());
+        // A single digit in the last group holds no byte.
+        assert!(ascii85(b"9jqo^B~>").is_err());
+        // Outside the alphabet, or `z` within a group.
+        assert!(ascii85(b"9jqo{~>").is_err());
+        assert!(ascii85(b"9jzqo^~>").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::collections::HashMap;
+
+/// Form XObjects nested deeper than this are not followed.
+const MAX_FORM_DEPTH: usize = 8;
+
+/// Form XObjects followed per document, to bound the work for forms drawn
+/// many times over.
+const MAX_FORMS: usize = 10_000;
+
+/// An affine transformation `[a b c d e f]`, as in the `cm` operator.
+type Matrix = [f32; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// PDF resolution unit: 72 points per inch.
+pub const POINTS_PER_INCH: f32 = 72.0;
+
+/// `first` followed by `then`.
+fn concat(first: &Matrix, then: &Matrix) -> Matrix {
+    [
+        first[0] * then[0] + first[1] * then[2],
+        first[0] * then[1] + first[1] * then[3],
+        first[2] * then[0] + first[3] * then[2],
+        first[2] * then[1] + first[3] * then[3],
+        first[4] * then[0] + first[5] * then[2] + then[4],
+        first[4] * then[1] + first[5] * then[3] + then[5],
+    ]
+}
+
+fn matrix(operands: &[Object]) -> Option<Matrix> {
+    let values: Vec<f32> = operands
+        .iter()
+        .filter_map(|value| value.as_float().ok())
+        .collect();
+    values.try_into().ok()
+}
+
+/// Resolution of an image of `width` by `height` pixels drawn at the given
+/// size in points, along its less detailed axis.
+pub fn effective_dpi(width: u32, height: u32, drawn_width: f32, drawn_height: f32) -> Option<f32> {
+    (drawn_width > 0.01 && drawn_height > 0.01).then(|| {
+        let horizontal = width as f32 * POINTS_PER_INCH / drawn_width;
+        let vertical = height as f32 * POINTS_PER_INCH / drawn_height;
+        horizontal.min(vertical)
+    })
+}
+
+/// The largest width and height, in points, that each image XObject is
+/// drawn at on any page. Images drawn only from annotations, patterns or
+/// inline are not found.
+pub fn image_placements(doc: &Document) -> HashMap<ObjectId, (f32, f32)> {
+    let mut walker = Walker {
+        doc,
+        placements: HashMap::new(),
+        forms_left: MAX_FORMS,
+    };
+    for page_id in doc.page_iter() {
+        let Ok(content) = doc.get_page_content(page_id) else {
+            continue;
+        };
+        let (own, inherited) = doc.get_page_resources(page_id);
+        let mut resources: Vec<&Dictionary> = own.into_iter().collect();
+        resources.extend(
+            inherited
+                .iter()
+                .filter_map(|id| doc.get_dictionary(*id).ok()),
+        );
+        walker.walk(&content, &resources, IDENTITY, 0);
+    }
+    walker.placements
+}
+
+struct Walker<'a> {
+    doc: &'a Document,
+    placements: HashMap<ObjectId, (f32, f32)>,
+    forms_left: usize,
+}
+
+impl<'a> Walker<'a> {
+    /// Follow a content stream drawn with `base` as its initial CTM.
+    fn walk(&mut self, content: &[u8], resources: &[&'a Dictionary], base: Matrix, depth: usize) {
+        let Ok(content) = Content::decode(content) else {
+            return;
+        };
+        let mut ctm = base;
+        let mut saved = Vec::new();
+        for operation in &content.operations {
+            match operation.operator.as_str() {
+                "q" => saved.push(ctm),
+                "Q" => ctm = saved.pop().unwrap_or(ctm),
+                "cm" => {
+                    if let Some(m) = matrix(&operation.operands) {
+                        ctm = concat(&m, &ctm);
+                    }
+                }
+                "Do" => {
+                    let Some(name) = operation.operands.first().and_then(|o| o.as_name().ok())
+                    else {
+                        continue;
+                    };
+                    if let Some((id, stream)) = self.xobject(resources, name) {
+                        self.draw(id, stream, resources, &ctm, depth);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(
+        &mut self,
+        id: ObjectId,
+        stream: &'a Stream,
+        resources: &[&'a Dictionary],
+        ctm: &Matrix,
+        depth: usize,
+    ) {
+        match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+            // An image fills the unit square, so the CTM's axes are its size.
+            Ok(b"Image") => {
+                let size = self.placements.entry(id).or_insert((0.0, 0.0));
+                size.0 = size.0.max(ctm[0].hypot(ctm[1]));
+                size.1 = size.1.max(ctm[2].hypot(ctm[3]));
+            }
+            Ok(b"Form") if depth < MAX_FORM_DEPTH && self.forms_left > 0 => {
+                self.forms_left -= 1;
+                let form_matrix = stream
+                    .dict
+                    .get(b"Matrix")
+                    .and_then(Object::as_array)
+                    .ok()
+                    .and_then(|values| matrix(values))
+                    .unwrap_or(IDENTITY);
+                // Forms without resources of their own use their parent's.
+                let own = stream
+                    .dict
+                    .get_deref(b"Resources", self.doc)
+                    .and_then(Object::as_dict)
+                    .ok();
+                let form_resources = match own {
+                    Some(own) => vec![own],
+                    None => resources.to_vec(),
+                };
+                let content = stream
+                    .decompressed_content()
+                    .unwrap_or_else(|_| stream.content.clone());
+                self.walk(
+                    &content,
+                    &form_resources,
+                    concat(&form_matrix, ctm),
+                    depth + 1,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn xobject(&self, resources: &[&'a Dictionary], name: &[u8]) -> Option<(ObjectId, &'a Stream)> {
+        let doc = self.doc;
+        resources.iter().find_map(|resources| {
+            let xobjects = resources
+                .get_deref(b"XObject", doc)
+                .and_then(Object::as_dict)
+                .ok()?;
+            let id = xobjects.get(name).and_then(Object::as_reference).ok()?;
+            Some((id, doc.get_object(id).and_then(Object::as_stream).ok()?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn image(doc: &mut Document) -> ObjectId {
+        doc.add_object(Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 1, "Height" => 1 },
+            vec![0],
+        ))
+    }
+
+    fn form(
+        doc: &mut Document,
+        matrix: [f32; 6],
+        resources: Option<Dictionary>,
+        content: &str,
+    ) -> ObjectId {
+        let mut dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Form",
+            "Matrix" => matrix.iter().map(|&value| value.into()).collect::<Vec<Object>>(),
+        };
+        if let Some(resources) = resources {
+            dict.set("Resources", resources);
+        }
+        doc.add_object(Stream::new(dict, content.as_bytes().to_vec()))
+    }
+
+    /// A one-page document whose page draws `content` with `xobjects`.
+    fn page(doc: &mut Document, xobjects: Dictionary, content: &str) {
+        let pages_id = doc.new_object_id();
+        let content = doc.add_object(Stream::new(dictionary! {}, content.as_bytes().to_vec()));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            "Resources" => dictionary! { "XObject" => xobjects },
+            "Contents" => content,
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page.into()],
+                "Count" => 1,
+            }),
+        );
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+    }
+
+    fn assert_size(placements: &HashMap<ObjectId, (f32, f32)>, id: ObjectId, size: (f32, f32)) {
+        let (width, height) = placements[&id];
+        assert!(
+            (width - size.0).abs() < 0.001 && (height - size.1).abs() < 0.001,
+            "Drawn at {}x{}, expected {}x{}",
+            width,
+            height,
+            size.0,
+            size.1
+        );
+    }
+
+    #[test]
+    fn follows_the_ctm_through_saves_and_forms() {
+        let mut doc = Document::with_version("1.5");
+        let (large, rotated, unscaled) = (image(&mut doc), image(&mut doc), image(&mut doc));
+        // Has no resources of its own, so it draws with its parent's, including
+        // itself; the depth limit stops it.
+        let nested = form(
+            &mut doc,
+            [1.0, 0.0, 0.0, 1.0, 100.0, 0.0],
+            None,
+            "q 10 0 0 10 0 0 cm /Large Do Q /Nested Do",
+        );
+        let outer = form(
+            &mut doc,
+            [1.5, 0.0, 0.0, 1.5, 5.0, 5.0],
+            Some(dictionary! {
+                "XObject" => dictionary! {
+                    "Large" => large,
+                    "Rotated" => rotated,
+                    "Nested" => nested,
+                },
+            }),
+            "q 0 20 -40 0 0 0 cm /Rotated Do Q /Nested Do",
+        );
+        page(
+            &mut doc,
+            dictionary! {
+                "Large" => large,
+                "Unscaled" => unscaled,
+                "Outer" => outer,
+            },
+            "q 2 0 0 2 10 10 cm q 100 0 0 50 0 0 cm /Large Do Q /Outer Do Q /Unscaled Do",
+        );
+
+        let placements = image_placements(&doc);
+        assert_eq!(placements.len(), 3);
+        // The largest of the page's 200x100 and the nested form's 30x30.
+        assert_size(&placements, large, (200.0, 100.0));
+        // Scaled by 2 on the page and 1.5 by the form, and turned a quarter.
+        assert_size(&placements, rotated, (60.0, 120.0));
+        // Drawn after the outer Q restored the identity CTM.
+        assert_size(&placements, unscaled, (1.0, 1.0));
+    }
+
+    #[test]
+    fn effective_dpi_is_that_of_the_less_detailed_axis() {
+        // 600x300 pixels on a 200x100 point area is 216 DPI both ways.
+        assert_eq!(effective_dpi(600, 300, 200.0, 100.0), Some(216.0));
+        // Squeezing it horizontally only adds horizontal detail.
+        assert_eq!(effective_dpi(600, 300, 100.0, 100.0), Some(216.0));
+        assert_eq!(effective_dpi(600, 300, 200.0, 200.0), Some(108.0));
+        // Images drawn with no area have no resolution.
+        assert_eq!(effective_dpi(600, 300, 0.0, 100.0), None);
+    }
+}
@@ -0,0 +1,386 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::io::{self, Write};
+
+/// Objects packed into one object stream. A reader inflates and parses the
+/// whole stream to get at any object in it, so streams are kept small.
+const OBJECTS_PER_STREAM: usize = 100;
+
+/// Trailer keys that describe the cross-reference section the document was
+/// read from rather than the document itself.
+const XREF_KEYS: [&[u8]; 9] = [
+    b"Type",
+    b"Size",
+    b"Index",
+    b"W",
+    b"Prev",
+    b"XRefStm",
+    b"Filter",
+    b"DecodeParms",
+    b"Length",
+];
+
+/// Whether a document can be saved with object streams. Encrypted documents
+/// cannot: their strings are encrypted object by object, which does not
+/// hold for objects inside an object stream.
+pub fn supports_object_streams(doc: &Document) -> bool {
+    !doc.trailer.has(b"Encrypt")
+}
+
+/// Serialize a document as PDF 1.5, with every non-stream object packed into
+/// Flate-compressed object streams and a cross-reference stream in place of
+/// the classic table and trailer.
+pub fn save_with_object_streams(doc: &Document) -> io::Result<Vec<u8>> {
+    // Streams and objects of a non-zero generation cannot be compressed.
+    let (packed, direct): (Vec<_>, Vec<_>) =
+        doc.objects.iter().partition(|((_, generation), object)| {
+            *generation == 0 && !matches!(object, Object::Stream(_))
+        });
+
+    let last_id = doc
+        .objects
+        .keys()
+        .map(|(number, _)| *number)
+        .fold(doc.max_id, u32::max);
+    let mut next_id = last_id + 1;
+    let mut entries = vec![Entry::Free; last_id as usize + 1];
+
+    let mut out = Vec::new();
+    let version = if doc.version.as_str() < "1.5" {
+        "1.5"
+    } else {
+        doc.version.as_str()
+    };
+    writeln!(out, "%PDF-{}", version)?;
+    // Bytes above 127 mark the file as binary for transfer tools.
+    out.extend_from_slice(b"%\xE2\xE3\xCF\xD3\n");
+
+    for (&(number, generation), object) in direct {
+        entries[number as usize] = Entry::Offset(out.len(), generation);
+        write_indirect(&mut out, (number, generation), object);
+    }
+
+    for chunk in packed.chunks(OBJECTS_PER_STREAM) {
+        let container = next_id;
+        next_id += 1;
+        let mut header = Vec::new();
+        let mut body = Vec::new();
+        for (index, (&(number, _), object)) in chunk.iter().enumerate() {
+            entries[number as usize] = Entry::Packed(container, index as u16);
+            write!(header, "{} {} ", number, body.len())?;
+            write_object(&mut body, object);
+            body.push(b'\n');
+        }
+        let first = header.len();
+        header.extend(body);
+
+        let mut dict = Dictionary::new();
+        dict.set("Type", Object::Name(b"ObjStm".to_vec()));
+        dict.set("N", chunk.len() as i64);
+        dict.set("First", first as i64);
+        dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+        let stream = Object::Stream(Stream::new(dict, deflate(&header)?));
+        entries.push(Entry::Offset(out.len(), 0));
+        write_indirect(&mut out, (container, 0), &stream);
+    }
+
+    let xref_id = next_id;
+    let xref_offset = out.len();
+    entries.push(Entry::Offset(xref_offset, 0));
+
+    // Offsets and object numbers share the middle field.
+    let widest = entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::Free => 0,
+            Entry::Offset(offset, _) => *offset as u64,
+            Entry::Packed(container, _) => *container as u64,
+        })
+        .max()
+        .unwrap_or(0);
+    let width = (8 - widest.leading_zeros() as usize / 8).max(1);
+    let mut table = Vec::with_capacity(entries.len() * (width + 3));
+    for (number, entry) in entries.iter().enumerate() {
+        let (kind, field, extra) = match entry {
+            Entry::Free if number == 0 => (0, 0, u16::MAX),
+            Entry::Free => (0, 0, 0),
+            Entry::Offset(offset, generation) => (1, *offset as u64, *generation),
+            Entry::Packed(container, index) => (2, *container as u64, *index),
+        };
+        table.push(kind);
+        table.extend_from_slice(&field.to_be_bytes()[8 - width..]);
+        table.extend_from_slice(&extra.to_be_bytes());
+    }
+
+    let mut dict = doc.trailer.clone();
+    for key in XREF_KEYS {
+        dict.remove(key);
+    }
+    dict.set("Type", Object::Name(b"XRef".to_vec()));
+    dict.set("Size", entries.len() as i64);
+    dict.set(
+        "W",
+        Object::Array(vec![
+            Object::Integer(1),
+            Object::Integer(width as i64),
+            Object::Integer(2),
+        ]),
+    );
+    dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+    let stream = Object::Stream(Stream::new(dict, deflate(&table)?));
+    write_indirect(&mut out, (xref_id, 0), &stream);
+
+    write!(out, "startxref\n{}\n%%EOF\n", xref_offset)?;
+    Ok(out)
+}
+
+/// Where an object is found, by object number.
+#[derive(Clone, Copy)]
+enum Entry {
+    Free,
+    /// Byte offset and generation of an object written on its own.
+    Offset(usize, u16),
+    /// Object stream number and index of an object packed into one.
+    Packed(u32, u16),
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn write_indirect(out: &mut Vec<u8>, (number, generation): ObjectId, object: &Object) {
+    out.extend_from_slice(format!("{} {} obj\n", number, generation).as_bytes());
+    write_object(out, object);
+    out.extend_from_slice(b"\nendobj\n");
+}
+
+/// Objects that must be set apart from a preceding token by whitespace.
+fn needs_separator(object: &Object) -> bool {
+    matches!(
+        object,
+        Object::Null
+            | Object::Boolean(_)
+            | Object::Integer(_)
+            | Object::Real(_)
+            | Object::Reference(_)
+    )
+}
+
+fn write_object(out: &mut Vec<u8>, object: &Object) {
+    match object {
+        Object::Null => out.extend_from_slice(b"null"),
+        Object::Boolean(value) => out.extend_from_slice(if *value { b"true" } else { b"false" }),
+        Object::Integer(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Real(value) => out.extend_from_slice(value.to_string().as_bytes()),
+        Object::Name(name) => write_name(out, name),
+        Object::String(text, StringFormat::Literal) => write_literal(out, text),
+        Object::String(text, StringFormat::Hexadecimal) => {
+            out.push(b'<');
+            for byte in text {
+                out.extend_from_slice(format!("{:02X}", byte).as_bytes());
+            }
+            out.push(b'>');
+        }
+        Object::Array(items) => {
+            out.push(b'[');
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 && needs_separator(item) {
+                    out.push(b' ');
+                }
+                write_object(out, item);
+            }
+            out.push(b']');
+        }
+        Object::Dictionary(dict) => write_dictionary(out, dict, None),
+        Object::Stream(stream) => {
+            // The length is written directly: an indirect one may be stale.
+            write_dictionary(out, &stream.dict, Some(stream.content.len()));
+            out.extend_from_slice(b"\nstream\n");
+            out.extend_from_slice(&stream.content);
+            out.extend_from_slice(b"\nendstream");
+        }
+        Object::Reference((number, generation)) => {
+            out.extend_from_slice(format!("{} {} R", number, generation).as_bytes());
+        }
+    }
+}
+
+fn write_dictionary(out: &mut Vec<u8>, dict: &Dictionary, length: Option<usize>) {
+    out.extend_from_slice(b"<<");
+    for (key, value) in dict.iter() {
+        if length.is_some() && key.as_slice() == b"Length" {
+            continue;
+        }
+        write_name(out, key);
+        if needs_separator(value) {
+            out.push(b' ');
+        }
+        write_object(out, value);
+    }
+    if let Some(length) = length {
+        out.extend_from_slice(format!("/Length {}", length).as_bytes());
+    }
+    out.extend_from_slice(b">>");
+}
+
+fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+    out.push(b'/');
+    for &byte in name {
+        // Delimiters, whitespace and bytes outside `!`..=`~` become `#xx`.
+        if b" \t\n\r\x0C()<>[]{}/%#".contains(&byte) || !(33..=126).contains(&byte) {
+            out.extend_from_slice(format!("#{:02X}", byte).as_bytes());
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+/// Backslashes, parentheses and carriage returns are escaped; escaping every
+/// parenthesis saves checking that they balance.
+fn write_literal(out: &mut Vec<u8>, text: &[u8]) {
+    out.push(b'(');
+    for &byte in text {
+        match byte {
+            b'\\' | b'(' | b')' => out.extend_from_slice(&[b'\\', byte]),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(byte),
+        }
+    }
+    out.push(b')');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use lopdf::dictionary;
+    use std::io::Read;
+
+    fn document(stream_len: usize) -> Document {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let content = doc.add_object(Stream::new(dictionary! {}, vec![b' '; stream_len]));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.5.into()],
+            "Contents" => content,
+        });
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => vec![page.into()],
+                "Count" => 1,
+            }),
+        );
+        let info = doc.add_object(dictionary! {
+            "Title" => Object::String(b"a (b) \\ c\r\n".to_vec(), StringFormat::Literal),
+            "Keywords" => Object::String(vec![0, 255, 40], StringFormat::Hexadecimal),
+            "A b#c/d" => vec![Object::Null, true.into(), (-3).into(), Object::Name(vec![1])],
+        });
+        // Objects of a non-zero generation are written on their own.
+        doc.max_id += 1;
+        doc.objects.insert((doc.max_id, 2), Object::Integer(7));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        doc.trailer.set("Info", info);
+        doc
+    }
+
+    /// The fields of the cross-reference stream, and its widths.
+    fn xref_entries(out: &[u8]) -> (Vec<(u8, u64, u16)>, usize) {
+        let text = String::from_utf8_lossy(out);
+        let start = text.rfind("startxref\n").unwrap() + "startxref\n".len();
+        let offset: usize = text[start..].lines().next().unwrap().parse().unwrap();
+        let object = &out[offset..];
+        let dict_end = object.windows(7).position(|w| w == b"stream\n").unwrap();
+        let dict = String::from_utf8_lossy(&object[..dict_end]);
+        let widths = &dict[dict.find("/W[").unwrap() + 3..];
+        let widths: Vec<usize> = widths[..widths.find(']').unwrap()]
+            .split(' ')
+            .map(|width| width.parse().unwrap())
+            .collect();
+        assert_eq!(widths[0], 1);
+        assert_eq!(widths[2], 2);
+
+        let data = &object[dict_end + 7..];
+        let data = &data[..data.windows(10).position(|w| w == b"\nendstream").unwrap()];
+        let mut table = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut table).unwrap();
+        let entry_len = 3 + widths[1];
+        assert_eq!(table.len() % entry_len, 0);
+        let entries = table
+            .chunks(entry_len)
+            .map(|entry| {
+                let field = entry[1..1 + widths[1]]
+                    .iter()
+                    .fold(0, |value, byte| value << 8 | *byte as u64);
+                let extra = u16::from_be_bytes([entry[entry_len - 2], entry[entry_len - 1]]);
+                (entry[0], field, extra)
+            })
+            .collect();
+        (entries, widths[1])
+    }
+
+    #[test]
+    fn xref_stream_fields_are_as_wide_as_needed() {
+        for stream_len in [10, 300, 70_000] {
+            let doc = document(stream_len);
+            let out = save_with_object_streams(&doc).unwrap();
+            let (entries, width) = xref_entries(&out);
+
+            let widest = entries.iter().map(|entry| entry.1).max().unwrap();
+            assert!(widest < 1 << (8 * width));
+            assert!(width == 1 || widest >= 1 << (8 * (width - 1)));
+            if stream_len == 70_000 {
+                assert_eq!(width, 3);
+            }
+
+            assert_eq!(entries[0], (0, 0, u16::MAX));
+            for (number, &(kind, field, extra)) in entries.iter().enumerate() {
+                match kind {
+                    0 => assert!(
+                        number == 0 || !doc.objects.keys().any(|id| id.0 as usize == number)
+                    ),
+                    1 => {
+                        let header = format!("{} {} obj\n", number, extra);
+                        assert!(out[field as usize..].starts_with(header.as_bytes()));
+                    }
+                    2 => assert_eq!(entries[field as usize].0, 1),
+                    _ => panic!("Unknown entry type {}", kind),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn saved_document_loads_back() {
+        let doc = document(300);
+        let out = save_with_object_streams(&doc).unwrap();
+        assert!(out.starts_with(b"%PDF-1.5\n"));
+        let loaded = Document::load_mem(&out).unwrap();
+
+        assert_eq!(
+            loaded.trailer.get(b"Root").ok(),
+            doc.trailer.get(b"Root").ok()
+        );
+        assert_eq!(
+            loaded.trailer.get(b"Info").ok(),
+            doc.trailer.get(b"Info").ok()
+        );
+        for (id, object) in &doc.objects {
+            let found = loaded.get_object(*id).unwrap();
+            match (object, found) {
+                (Object::Stream(expected), Object::Stream(found)) => {
+                    assert_eq!(found.content, expected.content);
+                    assert_eq!(found.dict.get(b"Length").ok(), Some(&Object::Integer(300)));
+                }
+                _ => assert_eq!(found, object, "object {:?}", id),
+            }
+        }
+    }
+}
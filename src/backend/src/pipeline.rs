@@ -0,0 +1,799 @@
+use candid::{CandidType, Deserialize};
+use std::collections::HashSet;
+
+use crate::callbacks::JobOutcome;
+use crate::costs::{self, Usage};
+use crate::csv_analyzer::{AnalysisOptions, CsvAnalyzer};
+use crate::error::MarketplaceError;
+use crate::{log, metrics};
+use crate::pdf::{self, PdfCompressor};
+use crate::queue::{self, Task};
+use crate::text_summarizer::{SummarizationOptions, TextSummarizer};
+use crate::{agents, JobResult, PIPELINES, PIPELINE_INPUTS, RESULTS};
+
+/// Version recorded on pipeline jobs.
+pub const VERSION: &str = "1.0.0";
+
+/// Maximum number of steps a single pipeline may contain.
+const MAX_STEPS: usize = 10;
+
+/// Data flowing between pipeline steps.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StepData {
+    Bytes(Vec<u8>),
+    Text(String),
+}
+
+/// The agent a step runs and its options.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StepAgent {
+    CompressPdf {
+        quality: u8,
+    },
+    ExtractPdfText,
+    Summarize {
+        tone: String,
+        include_quotes: bool,
+    },
+    AnalyzeCsv {
+        preset: String,
+        primary_metric: Option<String>,
+        segment_column: Option<String>,
+        include_visuals: bool,
+    },
+}
+
+/// Where a step takes its input from.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StepInput {
+    /// The data submitted with the pipeline.
+    Pipeline,
+    /// The output of an earlier step.
+    Step(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PipelineStepSpec {
+    pub id: String,
+    pub agent: StepAgent,
+    pub input: StepInput,
+    pub version: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PipelineRequest {
+    pub input: StepData,
+    pub steps: Vec<PipelineStepSpec>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum StepStatus {
+    Pending,
+    Queued,
+    Running,
+    Succeeded,
+    Failed(String),
+    /// Not run because a step it depends on failed.
+    Skipped,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PipelineStatus {
+    /// Quoted, waiting for payment and execution.
+    Quoted,
+    Running,
+    Succeeded,
+    /// Some steps failed; the price of the steps that did not run is refunded.
+    PartiallyFailed,
+    /// No step succeeded; the full price is refunded.
+    Failed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PipelineStep {
+    pub id: String,
+    pub agent: StepAgent,
+    pub input: StepInput,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub price: f64,
+    pub status: StepStatus,
+    pub output: Option<StepData>,
+    pub started_at: Option<u64>,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Pipeline {
+    pub job_id: String,
+    pub status: PipelineStatus,
+    pub steps: Vec<PipelineStep>,
+    pub refunded: f64,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StepQuote {
+    pub step_id: String,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub price: f64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PipelineQuote {
+    pub quote: crate::Quote,
+    pub steps: Vec<StepQuote>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DataKind {
+    Bytes,
+    Text,
+}
+
+impl StepData {
+    fn kind(&self) -> DataKind {
+        match self {
+            StepData::Bytes(_) => DataKind::Bytes,
+            StepData::Text(_) => DataKind::Text,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            StepData::Bytes(bytes) => bytes,
+            StepData::Text(text) => text.as_bytes(),
+        }
+    }
+
+    fn as_text(&self) -> Result<&str, MarketplaceError> {
+        match self {
+            StepData::Text(text) => Ok(text),
+            StepData::Bytes(_) => Err(MarketplaceError::invalid(
+                "input",
+                "Expected text input, got binary data",
+            )),
+        }
+    }
+}
+
+impl StepAgent {
+    pub fn agent_id(&self) -> &'static str {
+        match self {
+            StepAgent::CompressPdf { .. } => agents::PDF_COMPRESSOR,
+            StepAgent::ExtractPdfText => agents::PDF_TEXT_EXTRACTOR,
+            StepAgent::Summarize { .. } => agents::TEXT_SUMMARIZER,
+            StepAgent::AnalyzeCsv { .. } => agents::CSV_ANALYZER,
+        }
+    }
+
+    /// Short human-readable description, used when pricing the step.
+    pub fn describe(&self) -> String {
+        match self {
+            StepAgent::CompressPdf { quality } => {
+                format!("Compress PDF | Quality target: {}%", quality)
+            }
+            StepAgent::ExtractPdfText => "Extract text from PDF".to_string(),
+            StepAgent::Summarize {
+                tone,
+                include_quotes,
+            } => format!(
+                "Summarize text | Tone: {} | Include quotes: {}",
+                tone,
+                if *include_quotes { "Yes" } else { "No" }
+            ),
+            StepAgent::AnalyzeCsv {
+                preset,
+                include_visuals,
+                ..
+            } => format!(
+                "Analyze CSV dataset | Preset: {} | Include visuals: {}",
+                preset,
+                if *include_visuals { "Yes" } else { "No" }
+            ),
+        }
+    }
+
+    /// Whether this agent can take the given data as input.
+    pub fn accepts_data(&self, data: &StepData) -> bool {
+        self.accepts(data.kind())
+    }
+
+    fn accepts(&self, kind: DataKind) -> bool {
+        match self {
+            StepAgent::CompressPdf { .. } | StepAgent::ExtractPdfText => kind == DataKind::Bytes,
+            StepAgent::Summarize { .. } => kind == DataKind::Text,
+            StepAgent::AnalyzeCsv { .. } => true,
+        }
+    }
+
+    fn produces(&self) -> DataKind {
+        match self {
+            StepAgent::CompressPdf { .. } => DataKind::Bytes,
+            _ => DataKind::Text,
+        }
+    }
+
+    /// Run the agent on a single input with the pinned version, adding its LLM
+    /// calls to `usage`.
+    pub async fn run(
+        &self,
+        version: &str,
+        input: &StepData,
+        usage: &mut Usage,
+    ) -> Result<StepData, MarketplaceError> {
+        match self {
+            StepAgent::CompressPdf { quality } => {
+                let pdf_bytes = input.as_bytes();
+                let compressed = PdfCompressor::new(*quality).compress(pdf_bytes.to_vec())?;
+                metrics::observe_compression(pdf_bytes.len(), compressed.pdf.len());
+                Ok(StepData::Bytes(compressed.pdf))
+            }
+            StepAgent::ExtractPdfText => pdf::extract_text(input.as_bytes()).map(StepData::Text),
+            StepAgent::Summarize {
+                tone,
+                include_quotes,
+            } => {
+                let options = SummarizationOptions::new(tone.clone(), *include_quotes);
+                TextSummarizer::with_version(options, version)?
+                    .summarize(input.as_text()?, usage)
+                    .await
+                    .map(StepData::Text)
+            }
+            StepAgent::AnalyzeCsv {
+                preset,
+                primary_metric,
+                segment_column,
+                include_visuals,
+            } => {
+                let options = AnalysisOptions::new(
+                    preset.clone(),
+                    primary_metric.clone(),
+                    segment_column.clone(),
+                    *include_visuals,
+                );
+                CsvAnalyzer::with_version(options, version)?
+                    .analyze(input.as_bytes(), usage)
+                    .await
+                    .map(StepData::Text)
+            }
+        }
+    }
+}
+
+/// Validate a pipeline request and turn it into unpriced steps.
+///
+/// Steps may only consume the pipeline input or the output of a step declared
+/// before them, which keeps the graph acyclic. `resolve` pins each step to an
+/// agent version.
+pub fn plan(
+    request: &PipelineRequest,
+    resolve: impl Fn(&str, Option<&str>) -> Result<String, MarketplaceError>,
+) -> Result<Vec<PipelineStep>, MarketplaceError> {
+    let invalid = |reason: String| MarketplaceError::invalid("steps", reason);
+    if request.steps.is_empty() {
+        return Err(invalid("Pipeline must contain at least one step".to_string()));
+    }
+    if request.steps.len() > MAX_STEPS {
+        return Err(invalid(format!(
+            "Pipeline cannot have more than {} steps",
+            MAX_STEPS
+        )));
+    }
+
+    let mut seen = HashSet::new();
+    let mut steps: Vec<PipelineStep> = Vec::with_capacity(request.steps.len());
+
+    for spec in &request.steps {
+        if spec.id.trim().is_empty() {
+            return Err(invalid("Step id cannot be empty".to_string()));
+        }
+        if !seen.insert(spec.id.clone()) {
+            return Err(invalid(format!("Duplicate step id: {}", spec.id)));
+        }
+
+        let input_kind = match &spec.input {
+            StepInput::Pipeline => request.input.kind(),
+            StepInput::Step(parent_id) => steps
+                .iter()
+                .find(|step| step.id == *parent_id)
+                .map(|step| step.agent.produces())
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Step {} depends on unknown or later step {}",
+                        spec.id, parent_id
+                    ))
+                })?,
+        };
+
+        if !spec.agent.accepts(input_kind) {
+            return Err(invalid(format!(
+                "Step {} cannot accept the output of its input",
+                spec.id
+            )));
+        }
+
+        let agent_id = spec.agent.agent_id();
+        steps.push(PipelineStep {
+            id: spec.id.clone(),
+            agent: spec.agent.clone(),
+            input: spec.input.clone(),
+            agent_id: agent_id.to_string(),
+            agent_version: resolve(agent_id, spec.version.as_deref())?,
+            price: 0.0,
+            status: StepStatus::Pending,
+            output: None,
+            started_at: None,
+            completed_at: None,
+        });
+    }
+
+    Ok(steps)
+}
+
+impl Pipeline {
+    /// Steps whose input is available and that have not been queued yet.
+    pub fn ready_steps(&self) -> Vec<String> {
+        self.steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Pending)
+            .filter(|step| match &step.input {
+                StepInput::Pipeline => true,
+                StepInput::Step(parent_id) => self
+                    .step(parent_id)
+                    .map(|parent| parent.status == StepStatus::Succeeded)
+                    .unwrap_or(false),
+            })
+            .map(|step| step.id.clone())
+            .collect()
+    }
+
+    pub fn step(&self, step_id: &str) -> Option<&PipelineStep> {
+        self.steps.iter().find(|step| step.id == step_id)
+    }
+
+    pub fn step_mut(&mut self, step_id: &str) -> Option<&mut PipelineStep> {
+        self.steps.iter_mut().find(|step| step.id == step_id)
+    }
+
+    /// Mark every step downstream of a failed step as skipped.
+    pub fn skip_dependents(&mut self, failed_id: &str) {
+        let mut failed = vec![failed_id.to_string()];
+        while let Some(parent_id) = failed.pop() {
+            for step in self.steps.iter_mut() {
+                let depends = matches!(&step.input, StepInput::Step(id) if *id == parent_id);
+                if depends && step.status == StepStatus::Pending {
+                    step.status = StepStatus::Skipped;
+                    failed.push(step.id.clone());
+                }
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.steps.iter().all(|step| {
+            matches!(
+                step.status,
+                StepStatus::Succeeded | StepStatus::Failed(_) | StepStatus::Skipped
+            )
+        })
+    }
+
+    /// Settle a finished pipeline and return the amount to refund: the price
+    /// of every step that did not succeed.
+    pub fn finish(&mut self, now: u64) -> f64 {
+        let succeeded = self
+            .steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Succeeded)
+            .count();
+
+        self.status = if succeeded == self.steps.len() {
+            PipelineStatus::Succeeded
+        } else if succeeded == 0 {
+            PipelineStatus::Failed
+        } else {
+            PipelineStatus::PartiallyFailed
+        };
+
+        let refund: f64 = self
+            .steps
+            .iter()
+            .filter(|step| step.status != StepStatus::Succeeded)
+            .map(|step| step.price)
+            .sum();
+        self.refunded = (refund * 100.0).round() / 100.0;
+        self.completed_at = Some(now);
+        self.refunded
+    }
+
+    /// Text used as the job result once the pipeline has finished.
+    pub fn summary(&self) -> String {
+        let last = self.steps.last();
+        match last.map(|step| (&step.status, &step.output)) {
+            Some((StepStatus::Succeeded, Some(StepData::Text(text)))) => text.clone(),
+            Some((StepStatus::Succeeded, Some(StepData::Bytes(bytes)))) => {
+                format!("Pipeline produced {} bytes of binary output", bytes.len())
+            }
+            _ => {
+                let failed: Vec<String> = self
+                    .steps
+                    .iter()
+                    .filter_map(|step| match &step.status {
+                        StepStatus::Failed(reason) => Some(format!("{}: {}", step.id, reason)),
+                        _ => None,
+                    })
+                    .collect();
+                format!(
+                    "Pipeline did not complete. Failed steps: {}",
+                    failed.join("; ")
+                )
+            }
+        }
+    }
+}
+
+/// Queue every step of a pipeline whose input is available.
+pub fn enqueue_ready(job_id: &str) {
+    let ready = PIPELINES.with(|pipelines| {
+        let mut pipelines = pipelines.borrow_mut();
+        let Some(pipeline) = pipelines.get_mut(job_id) else {
+            return Vec::new();
+        };
+
+        let ready = pipeline.ready_steps();
+        for step_id in &ready {
+            if let Some(step) = pipeline.step_mut(step_id) {
+                step.status = StepStatus::Queued;
+            }
+        }
+        ready
+    });
+
+    for step_id in ready {
+        queue::enqueue(Task::PipelineStep {
+            job_id: job_id.to_string(),
+            step_id,
+        });
+    }
+}
+
+/// Execute one queued step, then queue its dependents or settle the pipeline.
+pub async fn run_step(job_id: &str, step_id: &str) {
+    let started_at = ic_cdk::api::time();
+    let prepared = PIPELINES.with(|pipelines| {
+        let mut pipelines = pipelines.borrow_mut();
+        let pipeline = pipelines.get_mut(job_id)?;
+        let input = match &pipeline.step(step_id)?.input {
+            StepInput::Pipeline => {
+                PIPELINE_INPUTS.with(|inputs| inputs.borrow().get(job_id).cloned())?
+            }
+            StepInput::Step(parent_id) => pipeline.step(parent_id)?.output.clone()?,
+        };
+
+        let step = pipeline.step_mut(step_id)?;
+        step.status = StepStatus::Running;
+        step.started_at = Some(started_at);
+        Some((step.agent.clone(), step.agent_version.clone(), input))
+    });
+
+    let Some((agent, version, input)) = prepared else {
+        return;
+    };
+
+    let started = costs::instructions();
+    let mut usage = Usage::default();
+    let outcome = agent.run(&version, &input, &mut usage).await;
+    usage.instructions = costs::instructions() - started;
+    costs::charge(job_id, &usage);
+
+    let pending = complete_step(job_id, step_id, outcome);
+    costs::track_storage(job_id);
+    if pending {
+        enqueue_ready(job_id);
+    }
+}
+
+/// Fail a step whose execution was cut short by a trap.
+pub fn interrupt_step(job_id: &str, step_id: &str) {
+    log::error(Some(job_id), format!("Step {} was interrupted by a trap", step_id));
+    complete_step(
+        job_id,
+        step_id,
+        Err(MarketplaceError::internal(
+            "Step was interrupted before completing",
+        )),
+    );
+    costs::track_storage(job_id);
+}
+
+/// Fail every step that was running when the canister was upgraded. Its
+/// task was lost with the message that ran it, so it would otherwise stay
+/// `Running` and its pipeline would never settle or refund.
+pub fn recover_interrupted() {
+    let running: Vec<(String, String)> = PIPELINES.with(|pipelines| {
+        pipelines
+            .borrow()
+            .iter()
+            .flat_map(|(job_id, pipeline)| {
+                pipeline
+                    .steps
+                    .iter()
+                    .filter(|step| step.status == StepStatus::Running)
+                    .map(move |step| (job_id.clone(), step.id.clone()))
+            })
+            .collect()
+    });
+
+    for (job_id, step_id) in running {
+        log::error(Some(&job_id), format!("Step {} was interrupted by an upgrade", step_id));
+        complete_step(
+            &job_id,
+            &step_id,
+            Err(MarketplaceError::internal(
+                "Step was interrupted by an upgrade before completing",
+            )),
+        );
+    }
+}
+
+/// Record the outcome of a step and settle the pipeline once every step has
+/// finished. Returns whether the pipeline still has work left.
+fn complete_step(
+    job_id: &str,
+    step_id: &str,
+    outcome: Result<StepData, MarketplaceError>,
+) -> bool {
+    let completed_at = ic_cdk::api::time();
+
+    let settled = PIPELINES.with(|pipelines| {
+        let mut pipelines = pipelines.borrow_mut();
+        let pipeline = pipelines.get_mut(job_id)?;
+        let step = pipeline.step_mut(step_id)?;
+        step.completed_at = Some(completed_at);
+
+        match outcome {
+            Ok(output) => {
+                step.status = StepStatus::Succeeded;
+                step.output = Some(output);
+            }
+            Err(err) => {
+                log::warn(Some(job_id), format!("Step {} failed: {}", step_id, err));
+                step.status = StepStatus::Failed(err.to_string());
+                pipeline.skip_dependents(step_id);
+            }
+        }
+
+        if !pipeline.is_finished() {
+            return None;
+        }
+        let refund = pipeline.finish(completed_at);
+        let outcome = match pipeline.status {
+            PipelineStatus::Succeeded => JobOutcome::Succeeded,
+            PipelineStatus::PartiallyFailed => JobOutcome::PartiallyFailed,
+            _ => JobOutcome::Failed,
+        };
+        Some((refund, outcome, pipeline.summary()))
+    });
+
+    let Some((refund, outcome, summary)) = settled else {
+        return true;
+    };
+
+    PIPELINE_INPUTS.with(|inputs| inputs.borrow_mut().remove(job_id));
+    let result = JobResult {
+        job_id: job_id.to_string(),
+        output: summary,
+        completed_at,
+    };
+    RESULTS.with(|results| {
+        results
+            .borrow_mut()
+            .insert(job_id.to_string(), result.clone());
+    });
+
+    if refund > 0.0 {
+        crate::record_refund(job_id, refund, "Pipeline steps failed or were skipped");
+    }
+    crate::job_finished(job_id, outcome, &result);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(id: &str, agent: StepAgent, input: StepInput) -> PipelineStepSpec {
+        PipelineStepSpec {
+            id: id.to_string(),
+            agent,
+            input,
+            version: None,
+        }
+    }
+
+    fn summarize() -> StepAgent {
+        StepAgent::Summarize {
+            tone: "neutral".to_string(),
+            include_quotes: false,
+        }
+    }
+
+    fn after(parent: &str) -> StepInput {
+        StepInput::Step(parent.to_string())
+    }
+
+    fn plan_steps(
+        input: StepData,
+        steps: Vec<PipelineStepSpec>,
+    ) -> Result<Vec<PipelineStep>, MarketplaceError> {
+        plan(&PipelineRequest { input, steps }, |_, _| {
+            Ok("1.0.0".to_string())
+        })
+    }
+
+    fn pdf() -> StepData {
+        StepData::Bytes(b"%PDF-1.4".to_vec())
+    }
+
+    fn pipeline(prices_and_statuses: &[(f64, StepStatus)]) -> Pipeline {
+        let requested: Vec<PipelineStepSpec> = (0..prices_and_statuses.len())
+            .map(|i| spec(&format!("s{}", i), summarize(), StepInput::Pipeline))
+            .collect();
+        let mut steps = plan_steps(StepData::Text("text".to_string()), requested).unwrap();
+        for (step, (price, status)) in steps.iter_mut().zip(prices_and_statuses) {
+            step.price = *price;
+            step.status = status.clone();
+        }
+        running(steps)
+    }
+
+    fn running(steps: Vec<PipelineStep>) -> Pipeline {
+        Pipeline {
+            job_id: "job".to_string(),
+            status: PipelineStatus::Running,
+            steps,
+            refunded: 0.0,
+            created_at: 0,
+            completed_at: None,
+        }
+    }
+
+    #[test]
+    fn plan_chains_steps_on_earlier_outputs() {
+        let steps = plan_steps(
+            pdf(),
+            vec![
+                spec(
+                    "compress",
+                    StepAgent::CompressPdf { quality: 70 },
+                    StepInput::Pipeline,
+                ),
+                spec("extract", StepAgent::ExtractPdfText, after("compress")),
+                spec("summary", summarize(), after("extract")),
+            ],
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = steps.iter().map(|step| step.id.as_str()).collect();
+        assert_eq!(ids, ["compress", "extract", "summary"]);
+        assert_eq!(steps[2].agent_id, agents::TEXT_SUMMARIZER);
+        assert!(steps.iter().all(|step| step.status == StepStatus::Pending));
+    }
+
+    #[test]
+    fn plan_rejects_forward_and_unknown_references() {
+        // Only steps declared earlier can be depended on, so there are no
+        // cycles to detect.
+        let forward = plan_steps(
+            StepData::Text("text".to_string()),
+            vec![
+                spec("a", summarize(), after("b")),
+                spec("b", summarize(), StepInput::Pipeline),
+            ],
+        );
+        assert!(matches!(
+            forward,
+            Err(MarketplaceError::InvalidInput { .. })
+        ));
+
+        let itself = plan_steps(
+            StepData::Text("text".to_string()),
+            vec![spec("a", summarize(), after("a"))],
+        );
+        assert!(matches!(itself, Err(MarketplaceError::InvalidInput { .. })));
+
+        let unknown = plan_steps(
+            StepData::Text("text".to_string()),
+            vec![spec("a", summarize(), after("missing"))],
+        );
+        assert!(matches!(
+            unknown,
+            Err(MarketplaceError::InvalidInput { .. })
+        ));
+    }
+
+    #[test]
+    fn plan_rejects_bad_shapes() {
+        let text = || StepData::Text("text".to_string());
+        assert!(plan_steps(text(), Vec::new()).is_err());
+
+        let duplicate = vec![
+            spec("a", summarize(), StepInput::Pipeline),
+            spec("a", summarize(), StepInput::Pipeline),
+        ];
+        assert!(plan_steps(text(), duplicate).is_err());
+
+        // Summaries are text, which the PDF compressor cannot take.
+        let mismatched = vec![
+            spec("a", summarize(), StepInput::Pipeline),
+            spec("b", StepAgent::CompressPdf { quality: 70 }, after("a")),
+        ];
+        assert!(plan_steps(text(), mismatched).is_err());
+
+        let at_limit: Vec<_> = (0..MAX_STEPS)
+            .map(|i| spec(&i.to_string(), summarize(), StepInput::Pipeline))
+            .collect();
+        assert_eq!(plan_steps(text(), at_limit).unwrap().len(), MAX_STEPS);
+
+        let over_limit: Vec<_> = (0..=MAX_STEPS)
+            .map(|i| spec(&i.to_string(), summarize(), StepInput::Pipeline))
+            .collect();
+        assert!(plan_steps(text(), over_limit).is_err());
+    }
+
+    #[test]
+    fn failed_steps_skip_their_dependents_only() {
+        let steps = plan_steps(
+            StepData::Text("text".to_string()),
+            vec![
+                spec("a", summarize(), StepInput::Pipeline),
+                spec("b", summarize(), after("a")),
+                spec("c", summarize(), after("b")),
+                spec("d", summarize(), StepInput::Pipeline),
+            ],
+        )
+        .unwrap();
+        let mut pipeline = running(steps);
+        assert_eq!(pipeline.ready_steps(), ["a", "d"]);
+
+        pipeline.step_mut("a").unwrap().status = StepStatus::Failed("boom".to_string());
+        pipeline.skip_dependents("a");
+        let statuses: Vec<&StepStatus> = pipeline.steps.iter().map(|step| &step.status).collect();
+        assert_eq!(
+            statuses[1..],
+            [
+                &StepStatus::Skipped,
+                &StepStatus::Skipped,
+                &StepStatus::Pending
+            ]
+        );
+        assert!(!pipeline.is_finished());
+    }
+
+    #[test]
+    fn finish_refunds_every_step_that_did_not_succeed() {
+        let mut all_good = pipeline(&[(0.3, StepStatus::Succeeded), (0.2, StepStatus::Succeeded)]);
+        assert_eq!(all_good.finish(5), 0.0);
+        assert_eq!(all_good.status, PipelineStatus::Succeeded);
+        assert_eq!(all_good.completed_at, Some(5));
+
+        let mut partial = pipeline(&[
+            (0.3, StepStatus::Succeeded),
+            (0.25, StepStatus::Failed("boom".to_string())),
+            (0.1, StepStatus::Skipped),
+        ]);
+        assert_eq!(partial.finish(5), 0.35);
+        assert_eq!(partial.status, PipelineStatus::PartiallyFailed);
+        assert_eq!(partial.refunded, 0.35);
+
+        let mut failed = pipeline(&[
+            (0.3, StepStatus::Failed("boom".to_string())),
+            (0.2, StepStatus::Skipped),
+        ]);
+        assert_eq!(failed.finish(5), 0.5);
+        assert_eq!(failed.status, PipelineStatus::Failed);
+    }
+}
@@ -0,0 +1,98 @@
+use candid::{CandidType, Deserialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use crate::timers::{self, TimerTask};
+use crate::{batch, pipeline};
+
+/// A unit of work executed by the job queue.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Task {
+    PipelineStep { job_id: String, step_id: String },
+    BatchItem { job_id: String, index: u32 },
+}
+
+thread_local! {
+    static QUEUE: RefCell<VecDeque<Task>> = RefCell::default();
+    static DRAINING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Append a task and make sure the queue is being drained.
+pub fn enqueue(task: Task) {
+    QUEUE.with(|queue| queue.borrow_mut().push_back(task));
+    kick();
+}
+
+/// Restart draining if tasks are waiting, e.g. after an upgrade.
+pub fn resume() {
+    if QUEUE.with(|queue| !queue.borrow().is_empty()) {
+        kick();
+    }
+}
+
+/// Number of tasks waiting to run.
+pub fn len() -> usize {
+    QUEUE.with(|queue| queue.borrow().len())
+}
+
+/// Tasks still waiting to run, for persisting across upgrades.
+pub fn snapshot() -> Vec<Task> {
+    QUEUE.with(|queue| queue.borrow().iter().cloned().collect())
+}
+
+pub fn restore(tasks: Vec<Task>) {
+    QUEUE.with(|queue| queue.borrow_mut().extend(tasks));
+}
+
+fn kick() {
+    if DRAINING.with(|draining| draining.replace(true)) {
+        return;
+    }
+    // Drain from a timer rather than the caller's message, so tasks neither
+    // add to the caller's instructions nor trap its call.
+    timers::set_timer_at(ic_cdk::api::time(), TimerTask::DrainQueue);
+}
+
+/// Let the next `enqueue` start draining again after the drain timer task
+/// failed without running.
+pub fn stalled() {
+    DRAINING.with(|draining| draining.set(false));
+}
+
+/// Run queued tasks one at a time until the queue is empty.
+pub async fn drain() {
+    let mut in_flight = InFlight(None);
+    while let Some(task) = QUEUE.with(|queue| queue.borrow_mut().pop_front()) {
+        in_flight.0 = Some(task.clone());
+        run(task).await;
+        in_flight.0 = None;
+    }
+}
+
+async fn run(task: Task) {
+    match task {
+        Task::PipelineStep { job_id, step_id } => pipeline::run_step(&job_id, &step_id).await,
+        Task::BatchItem { job_id, index } => batch::run_item(&job_id, index).await,
+    }
+}
+
+/// Tracks the task being run by `drain`.
+///
+/// If a task traps after an await, its future is dropped during cleanup; the
+/// drop marks the task as failed and releases the queue so it is not left
+/// stuck for good.
+struct InFlight(Option<Task>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(task) = self.0.take() {
+            match task {
+                Task::PipelineStep { job_id, step_id } => {
+                    pipeline::interrupt_step(&job_id, &step_id)
+                }
+                Task::BatchItem { job_id, index } => batch::interrupt_item(&job_id, index),
+            }
+        }
+        DRAINING.with(|draining| draining.set(false));
+    }
+}
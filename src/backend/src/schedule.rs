@@ -0,0 +1,317 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::cron::CronExpr;
+use crate::error::MarketplaceError;
+use crate::ledger;
+use crate::pipeline::{StepAgent, StepData};
+use crate::timers::{self, TimerTask};
+use crate::{
+    batch, PaymentInfo, PaymentStatus, CREDITS, JOB_OWNERS, PAYMENTS, SCHEDULES, SCHEDULE_INPUTS,
+};
+
+/// Shortest interval allowed between two runs of a recurring schedule.
+const MIN_INTERVAL_SECONDS: u64 = 60;
+
+/// Longest interval allowed between two runs of a recurring schedule: a leap
+/// year.
+const MAX_INTERVAL_SECONDS: u64 = 366 * 24 * 60 * 60;
+
+/// Number of past runs kept on each schedule.
+const MAX_RUN_HISTORY: usize = 20;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Prefix of the transaction ids of runs paid from prepaid credit.
+const CREDIT_CHARGE: &str = "credit:";
+
+/// When a schedule runs. All times are nanoseconds since the epoch.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Trigger {
+    /// Run once at the given time.
+    Once { at: u64 },
+    /// Run at `start_at` and then every `every_seconds`.
+    Interval { start_at: u64, every_seconds: u64 },
+    /// Run whenever a five-field cron expression matches (UTC), e.g. `0 9 * * 1`.
+    Cron(String),
+}
+
+/// How each run is paid for.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ChargeSource {
+    /// Deduct from the owner's prepaid credit balance.
+    Credit,
+    /// Pull from an ICRC-2 allowance the owner granted this canister on the ICP ledger.
+    Icrc2Allowance,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ScheduleStatus {
+    Active,
+    Paused,
+    /// A one-off schedule that has run, or a trigger with no future matches.
+    Finished,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ScheduleRequest {
+    pub agent: StepAgent,
+    pub version: Option<String>,
+    pub input: StepData,
+    pub trigger: Trigger,
+    pub charge: ChargeSource,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ScheduleRun {
+    /// Batch job created for the run, if it could be charged.
+    pub job_id: Option<String>,
+    pub ran_at: u64,
+    pub charged: f64,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Schedule {
+    pub id: String,
+    pub owner: Principal,
+    pub agent: StepAgent,
+    pub agent_id: String,
+    pub agent_version: String,
+    pub trigger: Trigger,
+    pub charge: ChargeSource,
+    pub price_per_run: f64,
+    pub status: ScheduleStatus,
+    pub next_run_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub runs: Vec<ScheduleRun>,
+    pub created_at: u64,
+}
+
+/// Check a trigger and return its first run time strictly after `now`.
+pub fn first_run(trigger: &Trigger, now: u64) -> Result<u64, MarketplaceError> {
+    let invalid = |reason: String| MarketplaceError::invalid("trigger", reason);
+    match trigger {
+        Trigger::Once { at } => {
+            if *at <= now {
+                return Err(invalid("Scheduled time must be in the future".to_string()));
+            }
+            Ok(*at)
+        }
+        Trigger::Interval { every_seconds, .. } => {
+            if *every_seconds < MIN_INTERVAL_SECONDS {
+                return Err(invalid(format!(
+                    "Interval must be at least {} seconds",
+                    MIN_INTERVAL_SECONDS
+                )));
+            }
+            if *every_seconds > MAX_INTERVAL_SECONDS {
+                return Err(invalid(format!(
+                    "Interval must be at most {} seconds",
+                    MAX_INTERVAL_SECONDS
+                )));
+            }
+            next_run(trigger, now).ok_or_else(|| invalid("Interval never runs".to_string()))
+        }
+        Trigger::Cron(expr) => {
+            CronExpr::parse(expr).map_err(invalid)?;
+            next_run(trigger, now)
+                .ok_or_else(|| invalid("Cron expression never matches".to_string()))
+        }
+    }
+}
+
+/// Next run strictly after `after`, or `None` when the trigger is exhausted
+/// or the next run would not fit in a timestamp.
+fn next_run(trigger: &Trigger, after: u64) -> Option<u64> {
+    match trigger {
+        Trigger::Once { .. } => None,
+        Trigger::Interval {
+            start_at,
+            every_seconds,
+        } => {
+            if *start_at > after {
+                return Some(*start_at);
+            }
+            let every = every_seconds.checked_mul(NANOS_PER_SECOND)?;
+            let elapsed = (after - start_at).checked_div(every)? + 1;
+            start_at.checked_add(elapsed.checked_mul(every)?)
+        }
+        Trigger::Cron(expr) => {
+            let cron = CronExpr::parse(expr).ok()?;
+            cron.next_after(after / NANOS_PER_SECOND)
+                .and_then(|seconds| seconds.checked_mul(NANOS_PER_SECOND))
+        }
+    }
+}
+
+/// Arm the timer for a schedule's next run if it is active.
+pub fn arm(schedule: &Schedule) {
+    if schedule.status != ScheduleStatus::Active {
+        return;
+    }
+    if let Some(at) = schedule.next_run_at {
+        timers::set_timer_at(
+            at,
+            TimerTask::RunSchedule {
+                schedule_id: schedule.id.clone(),
+                at,
+            },
+        );
+    }
+}
+
+/// Re-arm the timers of every active schedule, e.g. after an upgrade.
+///
+/// Runs missed while the canister was being upgraded fire immediately.
+pub fn rearm_all() {
+    SCHEDULES.with(|schedules| schedules.borrow().values().for_each(arm));
+}
+
+/// Run a schedule that was due at `at`, then arm its next run.
+pub async fn run(schedule_id: &str, at: u64) {
+    let now = ic_cdk::api::time();
+
+    // Advance the schedule first so a failing run is not retried in a loop.
+    // Timers left over from before a pause or resume no longer match
+    // `next_run_at` and are ignored.
+    let schedule = SCHEDULES.with(|schedules| {
+        let mut schedules = schedules.borrow_mut();
+        let schedule = schedules.get_mut(schedule_id)?;
+        if schedule.status != ScheduleStatus::Active || schedule.next_run_at != Some(at) {
+            return None;
+        }
+
+        schedule.next_run_at = next_run(&schedule.trigger, at.max(now));
+        if schedule.next_run_at.is_none() {
+            schedule.status = ScheduleStatus::Finished;
+        }
+        arm(schedule);
+        Some(schedule.clone())
+    });
+
+    let Some(schedule) = schedule else {
+        return;
+    };
+
+    let outcome = start_run(&schedule).await;
+    let run = match outcome {
+        Ok(job_id) => ScheduleRun {
+            job_id: Some(job_id),
+            ran_at: now,
+            charged: schedule.price_per_run,
+            error: None,
+        },
+        Err(err) => ScheduleRun {
+            job_id: None,
+            ran_at: now,
+            charged: 0.0,
+            error: Some(err.to_string()),
+        },
+    };
+
+    SCHEDULES.with(|schedules| {
+        if let Some(schedule) = schedules.borrow_mut().get_mut(schedule_id) {
+            if let Some(err) = &run.error {
+                // Stop charging attempts until the owner fixes the problem.
+                if schedule.status == ScheduleStatus::Active {
+                    schedule.status = ScheduleStatus::Paused;
+                }
+                schedule.last_error = Some(err.clone());
+            }
+            schedule.runs.push(run);
+            if schedule.runs.len() > MAX_RUN_HISTORY {
+                schedule.runs.remove(0);
+            }
+        }
+    });
+}
+
+/// Charge for one run and queue it as a single-item batch job.
+async fn start_run(schedule: &Schedule) -> Result<String, MarketplaceError> {
+    crate::resolve_agent_version(&schedule.agent_id, Some(&schedule.agent_version))?;
+
+    let input = SCHEDULE_INPUTS.with(|inputs| inputs.borrow().get(&schedule.id).cloned());
+    let input = input.ok_or_else(|| MarketplaceError::not_found("Schedule input"))?;
+
+    let transaction_id = charge(schedule).await?;
+
+    let (job_id, _) = crate::create_batch_job(
+        schedule.owner,
+        schedule.agent.clone(),
+        &schedule.agent_id,
+        schedule.agent_version.clone(),
+        vec![input],
+        schedule.price_per_run,
+    );
+
+    PAYMENTS.with(|payments| {
+        payments.borrow_mut().insert(
+            job_id.clone(),
+            PaymentInfo {
+                job_id: job_id.clone(),
+                status: PaymentStatus::Completed,
+                transaction_id: Some(transaction_id.clone()),
+            },
+        );
+    });
+
+    crate::payment_completed(&job_id, Some(transaction_id));
+    batch::enqueue_all(&job_id);
+    Ok(job_id)
+}
+
+/// Take payment for one run and return a transaction reference.
+async fn charge(schedule: &Schedule) -> Result<String, MarketplaceError> {
+    let price = schedule.price_per_run;
+
+    match schedule.charge {
+        ChargeSource::Credit => CREDITS.with(|credits| {
+            let mut credits = credits.borrow_mut();
+            let balance = credits.entry(schedule.owner).or_insert(0);
+            let (available, needed) = (*balance, ledger::icp_to_e8s(price));
+            if available < needed {
+                return Err(MarketplaceError::TransferFailed {
+                    reason: format!(
+                        "Insufficient credit: {} ICP available, {} ICP needed",
+                        ledger::e8s_to_icp(available),
+                        ledger::e8s_to_icp(needed)
+                    ),
+                });
+            }
+            *balance = available - needed;
+            Ok(format!("{}{}", CREDIT_CHARGE, schedule.id))
+        }),
+        ChargeSource::Icrc2Allowance => {
+            let block = ledger::transfer_from(
+                schedule.owner,
+                price,
+                schedule.id.as_bytes(),
+                ic_cdk::api::time(),
+            )
+            .await?;
+            Ok(format!("icrc2:{}", block))
+        }
+    }
+}
+
+/// Put `amount` ICP refunded on a job back on its owner's credit balance if
+/// the job was paid from prepaid credit.
+pub fn return_credit(job_id: &str, amount: f64) {
+    let paid_from_credit = PAYMENTS.with(|payments| {
+        payments
+            .borrow()
+            .get(job_id)
+            .and_then(|payment| payment.transaction_id.as_deref())
+            .is_some_and(|transaction_id| transaction_id.starts_with(CREDIT_CHARGE))
+    });
+    let owner = JOB_OWNERS.with(|owners| owners.borrow().get(job_id).copied());
+    let (true, Some(owner)) = (paid_from_credit, owner) else {
+        return;
+    };
+
+    CREDITS.with(|credits| {
+        let mut credits = credits.borrow_mut();
+        let balance = credits.entry(owner).or_insert(0);
+        *balance = balance.saturating_add(ledger::icp_to_e8s(amount));
+    });
+}
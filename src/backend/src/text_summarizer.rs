@@ -1,4 +1,7 @@
-use ic_llm::Model;
+use crate::agents;
+use crate::costs::{self, Usage};
+use crate::error::MarketplaceError;
+use crate::log;
 
 #[derive(Clone, Debug)]
 pub struct SummarizationOptions {
@@ -15,39 +18,119 @@ impl SummarizationOptions {
     }
 }
 
+type PromptBuilder = fn(&TextSummarizer, &str) -> String;
+
+/// Prompt templates by semantic version, oldest first. The last entry is the
+/// current version; older entries stay until they are retired so that jobs
+/// pinned to them keep producing the same prompt.
+const PROMPT_TEMPLATES: &[(&str, PromptBuilder)] = &[("1.0.0", TextSummarizer::build_prompt_v1)];
+
+/// Tones with dedicated instructions, plus the fallback.
+const TONES: &[&str] = &[
+    "Executive Summary",
+    "Creative Highlights",
+    "Technical Abstract",
+    "Bullet Digest",
+    "",
+];
+
+/// All prompt template versions, oldest first.
+pub fn versions() -> Vec<&'static str> {
+    PROMPT_TEMPLATES
+        .iter()
+        .map(|(version, _)| *version)
+        .collect()
+}
+
+/// Render a template version with placeholder inputs for every tone.
+pub fn template_samples(version: &str) -> Option<Vec<String>> {
+    let (_, build_prompt) = PROMPT_TEMPLATES.iter().find(|(v, _)| *v == version)?;
+
+    let mut samples = Vec::new();
+    for tone in TONES {
+        for include_quotes in [false, true] {
+            let summarizer =
+                TextSummarizer::new(SummarizationOptions::new(tone.to_string(), include_quotes));
+            samples.push(build_prompt(&summarizer, "{text}"));
+        }
+    }
+    Some(samples)
+}
+
 pub struct TextSummarizer {
     options: SummarizationOptions,
+    build_prompt: PromptBuilder,
 }
 
 impl TextSummarizer {
+    /// Create a summarizer using the current prompt template.
     pub fn new(options: SummarizationOptions) -> Self {
-        Self { options }
+        let (_, build_prompt) = PROMPT_TEMPLATES[PROMPT_TEMPLATES.len() - 1];
+        Self {
+            options,
+            build_prompt,
+        }
+    }
+
+    /// Create a summarizer pinned to a specific prompt template version.
+    pub fn with_version(
+        options: SummarizationOptions,
+        version: &str,
+    ) -> Result<Self, MarketplaceError> {
+        let (_, build_prompt) = PROMPT_TEMPLATES
+            .iter()
+            .find(|(v, _)| *v == version)
+            .ok_or_else(|| MarketplaceError::AgentUnavailable {
+                agent_id: agents::TEXT_SUMMARIZER.to_string(),
+                reason: format!("Unknown summarizer version: {}", version),
+            })?;
+        Ok(Self {
+            options,
+            build_prompt: *build_prompt,
+        })
     }
 
     /// Generate a summary based on the text and options
-    pub async fn summarize(&self, text: &str) -> Result<String, String> {
+    pub async fn summarize(
+        &self,
+        text: &str,
+        usage: &mut Usage,
+    ) -> Result<String, MarketplaceError> {
         if text.trim().is_empty() {
-            return Err("Text cannot be empty".to_string());
+            return Err(MarketplaceError::invalid("text", "Text cannot be empty"));
         }
 
         // Validate text length (50,000 characters max)
         if text.len() > 50_000 {
-            return Err("Text exceeds maximum length of 50,000 characters".to_string());
+            return Err(MarketplaceError::invalid(
+                "text",
+                "Text exceeds maximum length of 50,000 characters",
+            ));
         }
 
         // Build the prompt based on tone and options
-        let prompt = self.build_prompt(text);
+        let prompt = self.prompt(text);
         
-        ic_cdk::println!("Generating summary with tone: {}, include_quotes: {}", 
-            self.options.tone, self.options.include_quotes);
+        log::debug(
+            None,
+            format!(
+                "Generating summary with tone: {}, include_quotes: {}",
+                self.options.tone, self.options.include_quotes
+            ),
+        );
         
         // Call LLM to generate summary
-        let summary = ic_llm::prompt(Model::Qwen3_32B, &prompt).await;
+        let summary = costs::prompt(usage, &prompt).await;
         
         Ok(summary)
     }
 
-    fn build_prompt(&self, text: &str) -> String {
+    /// The prompt for `text`, built with this summarizer's template version.
+    pub fn prompt(&self, text: &str) -> String {
+        (self.build_prompt)(self, text)
+    }
+
+    fn build_prompt_v1(&self, text: &str) -> String {
         let tone_instructions = match self.options.tone.as_str() {
             "Executive Summary" => {
                 "Create a concise executive summary suitable for leadership and decision-makers. \
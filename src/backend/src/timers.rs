@@ -0,0 +1,143 @@
+// Timers multiplexed over the canister global timer.
+//
+// This stands in for `ic-cdk-timers`, which is not among the crate's locked
+// dependencies and cannot be added to this build. The two cannot be used
+// together: `ic-cdk-timers` exports `canister_global_timer` as well. Moving
+// to it means replacing `set_timer_at` with `ic_cdk_timers::set_timer` and
+// dropping `TimerTask` for closures; callers already re-arm their timers in
+// `post_upgrade`, as `ic-cdk-timers` also requires.
+//
+// As in `ic-cdk-timers`, the global timer only dispatches due tasks: each
+// one runs in a message of its own, a call the canister makes to itself. A
+// task that traps rolls back its own message and nothing else, so the timer
+// stays armed for the others.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::{ArgDecoderConfig, RejectionCode};
+
+use crate::{callbacks, log, queue, schedule, webhooks};
+
+/// Method the global timer calls to run a task in a message of its own.
+const RUN_TASK: &str = "<timers internal> run_task";
+
+/// Work to perform when a timer fires.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TimerTask {
+    /// Run a schedule that is due at the given time (ns).
+    RunSchedule { schedule_id: String, at: u64 },
+    /// Attempt delivery `index` of a job's callbacks, due at the given time (ns).
+    DeliverCallback { job_id: String, index: u32, at: u64 },
+    /// Attempt delivery of a webhook event, due at the given time (ns).
+    DeliverWebhook {
+        owner: Principal,
+        event_id: String,
+        at: u64,
+    },
+    /// Drain the job queue.
+    DrainQueue,
+}
+
+thread_local! {
+    static TIMERS: RefCell<BTreeMap<(u64, u64), TimerTask>> = RefCell::default();
+    static NEXT_TIMER_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Run `task` at or after `at` (ns since the epoch).
+///
+/// Timers are multiplexed over the canister global timer, which is re-armed
+/// for the earliest pending task. They live on the heap only; owners re-arm
+/// what they need in `post_upgrade`.
+pub fn set_timer_at(at: u64, task: TimerTask) {
+    let id = NEXT_TIMER_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    TIMERS.with(|timers| timers.borrow_mut().insert((at, id), task));
+    arm();
+}
+
+fn arm() {
+    let next = TIMERS.with(|timers| timers.borrow().keys().next().map(|(at, _)| *at));
+    // A deadline of 0 deactivates the global timer; never pass it by accident.
+    ic_cdk::api::set_global_timer(next.map(|at| at.max(1)).unwrap_or(0));
+}
+
+fn take_due(now: u64) -> Vec<(u64, TimerTask)> {
+    TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+        let pending = timers.split_off(&(now + 1, 0));
+        std::mem::replace(&mut *timers, pending)
+            .into_iter()
+            .map(|((at, _), task)| (at, task))
+            .collect()
+    })
+}
+
+#[export_name = "canister_global_timer"]
+extern "C" fn global_timer() {
+    ic_cdk::setup();
+
+    let due = take_due(ic_cdk::api::time());
+    // Armed before dispatching, so nothing below can leave it disarmed.
+    arm();
+    for (at, task) in due {
+        ic_cdk::spawn(dispatch(at, task));
+    }
+}
+
+/// Run a task through a call to this canister, and deal with the call
+/// failing: a task the system could not start is tried again, one that
+/// trapped is dropped, as `ic-cdk-timers` does.
+async fn dispatch(at: u64, task: TimerTask) {
+    let result: Result<(), _> = ic_cdk::call(ic_cdk::api::id(), RUN_TASK, (task.clone(),)).await;
+    let Err((code, message)) = result else {
+        return;
+    };
+    if code == RejectionCode::SysTransient {
+        set_timer_at(at, task);
+        return;
+    }
+    log::error(None, format!("Timer task {:?} failed: {}", task, message));
+    if let TimerTask::DrainQueue = task {
+        queue::stalled();
+    }
+}
+
+// Symbols with spaces only link on wasm, so native builds get a stand-in.
+#[cfg_attr(
+    target_family = "wasm",
+    export_name = "canister_update <timers internal> run_task"
+)]
+#[cfg_attr(
+    not(target_family = "wasm"),
+    export_name = "canister_update_timers_internal_run_task"
+)]
+extern "C" fn run_task() {
+    ic_cdk::setup();
+    if ic_cdk::caller() != ic_cdk::api::id() {
+        ic_cdk::trap("run_task can only be called by the canister itself");
+    }
+    let (task,): (TimerTask,) = ic_cdk::api::call::arg_data(ArgDecoderConfig::default());
+
+    match task {
+        TimerTask::RunSchedule { schedule_id, at } => {
+            ic_cdk::spawn(async move { schedule::run(&schedule_id, at).await })
+        }
+        TimerTask::DeliverCallback { job_id, index, at } => {
+            ic_cdk::spawn(async move { callbacks::deliver(&job_id, index, at).await })
+        }
+        TimerTask::DeliverWebhook {
+            owner,
+            event_id,
+            at,
+        } => ic_cdk::spawn(async move { webhooks::deliver(owner, &event_id, at).await }),
+        TimerTask::DrainQueue => ic_cdk::spawn(queue::drain()),
+    }
+    // Replied once the task reaches its first await: a trap before then
+    // rejects the call.
+    ic_cdk::api::call::reply(());
+}
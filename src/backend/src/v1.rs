@@ -0,0 +1,291 @@
+// The methods as first published, which return errors as text. Each one
+// forwards to the typed method of the same name in the crate root, exported
+// with a `v2_` prefix.
+
+use crate::{
+    AgentCostReport, AgentVersion, ApiKeyInfo, ApiKeyRequest, AuditEntry, Batch, BatchQuote,
+    BatchRequest, CallbackDelivery, JobCallback, JobFilter, JobQuery, JobRequest, JobResult,
+    LogEntry, LogFilter, NewApiKey, PaymentInfo, PaymentRequest, PdfCompressionOptions, Pipeline,
+    PipelineQuote, PipelineRequest, Quote, RefundInfo, Schedule, ScheduleRequest, WebhookInfo,
+    WebhookRegistration,
+};
+
+#[ic_cdk::update]
+fn compress_pdf(
+    pdf_bytes: Vec<u8>,
+    quality: u8,
+    options: Option<PdfCompressionOptions>,
+) -> Result<Vec<u8>, String> {
+    Ok(crate::compress_pdf(pdf_bytes, quality, options)?.pdf)
+}
+
+#[ic_cdk::update]
+async fn summarize_text(
+    text: String,
+    tone: String,
+    include_quotes: bool,
+    job_id: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<String, String> {
+    Ok(crate::summarize_text(text, tone, include_quotes, job_id, idempotency_key).await?)
+}
+
+#[ic_cdk::update]
+async fn analyze_csv(
+    csv_bytes: Vec<u8>,
+    preset: String,
+    primary_metric: Option<String>,
+    segment_column: Option<String>,
+    include_visuals: bool,
+    job_id: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<String, String> {
+    Ok(crate::analyze_csv(
+        csv_bytes,
+        preset,
+        primary_metric,
+        segment_column,
+        include_visuals,
+        job_id,
+        idempotency_key,
+    )
+    .await?)
+}
+
+#[ic_cdk::update]
+async fn get_quote(request: String, idempotency_key: Option<String>) -> Result<Quote, String> {
+    Ok(crate::get_quote(request, idempotency_key).await?)
+}
+
+#[ic_cdk::update]
+async fn get_agent_quote(
+    agent_id: String,
+    request: String,
+    version: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<Quote, String> {
+    Ok(crate::get_agent_quote(agent_id, request, version, idempotency_key).await?)
+}
+
+#[ic_cdk::update]
+async fn initiate_payment(
+    job_id: String,
+    idempotency_key: Option<String>,
+) -> Result<PaymentRequest, String> {
+    Ok(crate::initiate_payment(job_id, idempotency_key).await?)
+}
+
+#[ic_cdk::query]
+fn check_payment_status(job_id: String) -> Result<PaymentInfo, String> {
+    Ok(crate::check_payment_status(job_id)?)
+}
+
+#[ic_cdk::update]
+async fn complete_payment(job_id: String, transaction_id: String) -> Result<(), String> {
+    Ok(crate::complete_payment(job_id, transaction_id).await?)
+}
+
+#[ic_cdk::update]
+async fn execute_job(job_id: String, idempotency_key: Option<String>) -> Result<JobResult, String> {
+    Ok(crate::execute_job(job_id, idempotency_key).await?)
+}
+
+#[ic_cdk::query]
+fn get_job_result(job_id: String) -> Result<JobResult, String> {
+    Ok(crate::get_job_result(job_id)?)
+}
+
+#[ic_cdk::update]
+fn register_job_callback(
+    job_id: String,
+    callback: JobCallback,
+) -> Result<CallbackDelivery, String> {
+    Ok(crate::register_job_callback(job_id, callback)?)
+}
+
+#[ic_cdk::query]
+fn get_callback_deliveries(job_id: String) -> Result<Vec<CallbackDelivery>, String> {
+    Ok(crate::get_callback_deliveries(job_id)?)
+}
+
+/// The caller's newest jobs, one page at most (controllers see every
+/// account's). Paging, filtering and sorting are only offered by
+/// `v2_list_jobs`.
+#[ic_cdk::query]
+fn list_jobs() -> Vec<(String, JobRequest)> {
+    crate::list_jobs(JobQuery::default())
+        .map(|page| {
+            page.jobs
+                .into_iter()
+                .map(|listing| (listing.job_id, listing.job))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[ic_cdk::query]
+fn count_jobs(filter: JobFilter) -> Result<u64, String> {
+    Ok(crate::count_jobs(filter)?)
+}
+
+#[ic_cdk::update]
+async fn quote_pipeline(
+    request: PipelineRequest,
+    idempotency_key: Option<String>,
+) -> Result<PipelineQuote, String> {
+    Ok(crate::quote_pipeline(request, idempotency_key).await?)
+}
+
+#[ic_cdk::update]
+fn execute_pipeline(job_id: String) -> Result<Pipeline, String> {
+    Ok(crate::execute_pipeline(job_id)?)
+}
+
+#[ic_cdk::query]
+fn get_pipeline(job_id: String) -> Result<Pipeline, String> {
+    Ok(crate::get_pipeline(job_id)?)
+}
+
+#[ic_cdk::query]
+fn get_refund(job_id: String) -> Result<RefundInfo, String> {
+    Ok(crate::get_refund(job_id)?)
+}
+
+#[ic_cdk::update]
+async fn submit_batch(
+    request: BatchRequest,
+    idempotency_key: Option<String>,
+) -> Result<BatchQuote, String> {
+    Ok(crate::submit_batch(request, idempotency_key).await?)
+}
+
+#[ic_cdk::update]
+fn execute_batch(job_id: String) -> Result<Batch, String> {
+    Ok(crate::execute_batch(job_id)?)
+}
+
+#[ic_cdk::query]
+fn get_batch(job_id: String) -> Result<Batch, String> {
+    Ok(crate::get_batch(job_id)?)
+}
+
+#[ic_cdk::update]
+async fn create_schedule(
+    request: ScheduleRequest,
+    idempotency_key: Option<String>,
+) -> Result<Schedule, String> {
+    Ok(crate::create_schedule(request, idempotency_key).await?)
+}
+
+#[ic_cdk::query]
+fn list_schedules() -> Vec<Schedule> {
+    crate::list_schedules()
+}
+
+#[ic_cdk::update]
+fn pause_schedule(schedule_id: String) -> Result<Schedule, String> {
+    Ok(crate::pause_schedule(schedule_id)?)
+}
+
+#[ic_cdk::update]
+fn resume_schedule(schedule_id: String) -> Result<Schedule, String> {
+    Ok(crate::resume_schedule(schedule_id)?)
+}
+
+#[ic_cdk::update]
+fn delete_schedule(schedule_id: String) -> Result<(), String> {
+    Ok(crate::delete_schedule(schedule_id)?)
+}
+
+#[ic_cdk::update]
+async fn deposit_credit(amount: f64, idempotency_key: Option<String>) -> Result<f64, String> {
+    Ok(crate::deposit_credit(amount, idempotency_key).await?)
+}
+
+#[ic_cdk::query]
+fn get_credit_balance() -> f64 {
+    crate::get_credit_balance()
+}
+
+#[ic_cdk::update]
+async fn set_webhook(url: String) -> Result<WebhookRegistration, String> {
+    Ok(crate::set_webhook(url).await?)
+}
+
+#[ic_cdk::query]
+fn get_webhook() -> Option<WebhookInfo> {
+    crate::get_webhook()
+}
+
+#[ic_cdk::update]
+fn delete_webhook() -> Result<(), String> {
+    Ok(crate::delete_webhook()?)
+}
+
+#[ic_cdk::update]
+async fn create_api_key(request: ApiKeyRequest) -> Result<NewApiKey, String> {
+    Ok(crate::create_api_key(request).await?)
+}
+
+#[ic_cdk::update]
+fn revoke_api_key(key_id: String) -> Result<(), String> {
+    Ok(crate::revoke_api_key(key_id)?)
+}
+
+#[ic_cdk::query]
+fn list_api_keys() -> Vec<ApiKeyInfo> {
+    crate::list_api_keys()
+}
+
+#[ic_cdk::update]
+async fn quote_with_api_key(
+    api_key: String,
+    agent_id: String,
+    request: String,
+    version: Option<String>,
+) -> Result<Quote, String> {
+    Ok(crate::quote_with_api_key(api_key, agent_id, request, version).await?)
+}
+
+#[ic_cdk::update]
+async fn execute_job_with_api_key(api_key: String, job_id: String) -> Result<JobResult, String> {
+    Ok(crate::execute_job_with_api_key(api_key, job_id).await?)
+}
+
+#[ic_cdk::query]
+fn get_cost_report(cycles_per_icp: u64) -> Result<Vec<AgentCostReport>, String> {
+    Ok(crate::get_cost_report(cycles_per_icp)?)
+}
+
+#[ic_cdk::query]
+fn get_logs(filter: LogFilter) -> Result<Vec<LogEntry>, String> {
+    Ok(crate::get_logs(filter)?)
+}
+
+#[ic_cdk::update]
+fn set_log_redaction(redact: bool) -> Result<(), String> {
+    Ok(crate::set_log_redaction(redact)?)
+}
+
+#[ic_cdk::query]
+fn get_audit_log(after_seq: Option<u64>, limit: Option<u32>) -> Result<Vec<AuditEntry>, String> {
+    Ok(crate::get_audit_log(after_seq, limit)?)
+}
+
+#[ic_cdk::query]
+fn list_agent_versions(agent_id: String) -> Result<Vec<AgentVersion>, String> {
+    Ok(crate::list_agent_versions(agent_id)?)
+}
+
+#[ic_cdk::update]
+fn deprecate_agent_version(
+    agent_id: String,
+    version: String,
+    window_seconds: u64,
+) -> Result<AgentVersion, String> {
+    Ok(crate::deprecate_agent_version(
+        agent_id,
+        version,
+        window_seconds,
+    )?)
+}
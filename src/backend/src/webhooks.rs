@@ -0,0 +1,551 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use sha2::{Digest, Sha256};
+
+use crate::callbacks::JobOutcome;
+use crate::error::MarketplaceError;
+use crate::json::Json;
+use crate::timers::{self, TimerTask};
+use crate::{
+    JobResult, RefundInfo, JOB_OWNERS, WEBHOOKS, WEBHOOK_DELIVERIES, WEBHOOK_EVENT_COUNTER,
+};
+
+/// Name of the query used to strip webhook responses down to their status.
+pub const TRANSFORM_METHOD: &str = "transform_webhook_response";
+
+const MAX_URL_LENGTH: usize = 2_048;
+
+/// Webhook responses are only inspected for their status code.
+const MAX_RESPONSE_BYTES: u64 = 2_048;
+
+/// Delivery attempts made before an event is given up on.
+const MAX_ATTEMPTS: usize = 6;
+
+/// Delay before the first retry; doubled after every failed attempt.
+const RETRY_BASE_SECONDS: u64 = 30;
+
+/// Number of deliveries kept per account.
+const MAX_DELIVERY_HISTORY: usize = 100;
+
+/// Nodes on the subnet taking part in each outcall, used to budget cycles.
+const SUBNET_SIZE: u128 = 13;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    JobPaid,
+    JobSucceeded,
+    JobFailed,
+    RefundIssued,
+}
+
+impl EventType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::JobPaid => "job.paid",
+            EventType::JobSucceeded => "job.succeeded",
+            EventType::JobFailed => "job.failed",
+            EventType::RefundIssued => "refund.issued",
+        }
+    }
+}
+
+/// A webhook endpoint registered by an account.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    pub url: String,
+    /// Key used to sign payloads. Only returned when the webhook is set.
+    secret: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookInfo {
+    pub url: String,
+    pub created_at: u64,
+}
+
+/// Returned once when a webhook is set; the secret cannot be read back later.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookRegistration {
+    pub url: String,
+    pub secret: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// The endpoint rejected the event or every attempt failed.
+    Failed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookAttempt {
+    pub at: u64,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookDelivery {
+    /// Also sent as the `Idempotency-Key` header.
+    pub event_id: String,
+    pub event_type: EventType,
+    pub job_id: String,
+    pub url: String,
+    /// Exact JSON body sent on every attempt.
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<WebhookAttempt>,
+    pub next_attempt_at: Option<u64>,
+    pub created_at: u64,
+}
+
+/// Check that a URL can be used as a webhook endpoint.
+///
+/// Plain `http` is only accepted for `localhost`, so a stand-in receiver can
+/// be used against a local replica.
+fn validate_url(url: &str) -> Result<(), MarketplaceError> {
+    let invalid = |reason: String| MarketplaceError::invalid("url", reason);
+    if url.len() > MAX_URL_LENGTH {
+        return Err(invalid(format!(
+            "Webhook URL cannot be longer than {} characters",
+            MAX_URL_LENGTH
+        )));
+    }
+    if url.chars().any(char::is_whitespace) {
+        return Err(invalid("Webhook URL cannot contain whitespace".to_string()));
+    }
+
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| invalid("Webhook URL must include a scheme".to_string()))?;
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('@')
+        .next()
+        .unwrap_or_default();
+    let hostname = host.split(':').next().unwrap_or_default();
+    if hostname.is_empty() {
+        return Err(invalid("Webhook URL must include a host".to_string()));
+    }
+
+    match scheme {
+        "https" => Ok(()),
+        "http" if matches!(hostname, "localhost" | "127.0.0.1") => Ok(()),
+        _ => Err(invalid("Webhook URL must use https".to_string())),
+    }
+}
+
+/// Set the caller's webhook, replacing any existing one with a new secret.
+pub async fn set(
+    owner: Principal,
+    url: String,
+) -> Result<WebhookRegistration, MarketplaceError> {
+    validate_url(&url)?;
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| {
+            MarketplaceError::internal(format!(
+                "Failed to generate secret ({:?}): {}",
+                code, message
+            ))
+        })?;
+    let secret = format!("whsec_{}", hex::encode(random));
+    let created_at = ic_cdk::api::time();
+
+    WEBHOOKS.with(|webhooks| {
+        webhooks.borrow_mut().insert(
+            owner,
+            Webhook {
+                url: url.clone(),
+                secret: secret.clone(),
+                created_at,
+            },
+        );
+    });
+
+    Ok(WebhookRegistration {
+        url,
+        secret,
+        created_at,
+    })
+}
+
+pub fn get(owner: Principal) -> Option<WebhookInfo> {
+    WEBHOOKS.with(|webhooks| {
+        webhooks.borrow().get(&owner).map(|webhook| WebhookInfo {
+            url: webhook.url.clone(),
+            created_at: webhook.created_at,
+        })
+    })
+}
+
+/// Remove the caller's webhook. Pending deliveries are abandoned.
+pub fn remove(owner: Principal) -> Result<(), MarketplaceError> {
+    WEBHOOKS
+        .with(|webhooks| webhooks.borrow_mut().remove(&owner))
+        .ok_or_else(|| MarketplaceError::not_found("Webhook"))?;
+
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        if let Some(deliveries) = deliveries.borrow_mut().get_mut(&owner) {
+            for delivery in deliveries
+                .iter_mut()
+                .filter(|delivery| delivery.status == DeliveryStatus::Pending)
+            {
+                delivery.status = DeliveryStatus::Failed;
+                delivery.next_attempt_at = None;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Recent deliveries to the caller's webhook, newest first.
+pub fn deliveries(owner: Principal) -> Vec<WebhookDelivery> {
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        deliveries
+            .borrow()
+            .get(&owner)
+            .map(|deliveries| deliveries.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+pub fn job_paid(job_id: &str, amount: f64, transaction_id: Option<String>) {
+    emit(
+        job_id,
+        EventType::JobPaid,
+        Json::object([
+            ("job_id", job_id.into()),
+            ("amount", amount.into()),
+            ("currency", "ICP".into()),
+            ("transaction_id", transaction_id.into()),
+        ]),
+    );
+}
+
+pub fn job_finished(job_id: &str, outcome: &JobOutcome, result: &JobResult) {
+    let (event_type, outcome) = match outcome {
+        JobOutcome::Succeeded => (EventType::JobSucceeded, "succeeded"),
+        JobOutcome::PartiallyFailed => (EventType::JobSucceeded, "partially_failed"),
+        JobOutcome::Failed => (EventType::JobFailed, "failed"),
+    };
+
+    emit(
+        job_id,
+        event_type,
+        Json::object([
+            ("job_id", job_id.into()),
+            ("outcome", outcome.into()),
+            ("output", result.output.as_str().into()),
+            ("completed_at", result.completed_at.into()),
+        ]),
+    );
+}
+
+pub fn refund_issued(refund: &RefundInfo) {
+    emit(
+        &refund.job_id,
+        EventType::RefundIssued,
+        Json::object([
+            ("job_id", refund.job_id.as_str().into()),
+            ("amount", refund.amount.into()),
+            ("currency", refund.currency.as_str().into()),
+            ("reason", refund.reason.as_str().into()),
+            ("issued_at", refund.issued_at.into()),
+        ]),
+    );
+}
+
+/// Queue an event for the webhook of the account that owns the job, if any.
+fn emit(job_id: &str, event_type: EventType, data: Json) {
+    let Some(owner) = JOB_OWNERS.with(|owners| owners.borrow().get(job_id).copied()) else {
+        return;
+    };
+    let Some(url) = WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow()
+            .get(&owner)
+            .map(|webhook| webhook.url.clone())
+    }) else {
+        return;
+    };
+
+    let now = ic_cdk::api::time();
+    let event_id = WEBHOOK_EVENT_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        format!("evt_{:016}", *counter)
+    });
+
+    let payload = Json::object([
+        ("id", event_id.as_str().into()),
+        ("type", event_type.name().into()),
+        ("created_at", now.into()),
+        ("data", data),
+    ])
+    .to_string();
+
+    let delivery = WebhookDelivery {
+        event_id: event_id.clone(),
+        event_type,
+        job_id: job_id.to_string(),
+        url,
+        payload,
+        status: DeliveryStatus::Pending,
+        attempts: Vec::new(),
+        next_attempt_at: Some(now),
+        created_at: now,
+    };
+    arm(owner, &delivery);
+
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        let deliveries = deliveries.entry(owner).or_default();
+        deliveries.push(delivery);
+
+        // Drop the oldest settled deliveries once the history is full.
+        while deliveries.len() > MAX_DELIVERY_HISTORY {
+            match deliveries
+                .iter()
+                .position(|delivery| delivery.status != DeliveryStatus::Pending)
+            {
+                Some(index) => deliveries.remove(index),
+                None => break,
+            };
+        }
+    });
+}
+
+fn arm(owner: Principal, delivery: &WebhookDelivery) {
+    if delivery.status != DeliveryStatus::Pending {
+        return;
+    }
+    if let Some(at) = delivery.next_attempt_at {
+        timers::set_timer_at(
+            at,
+            TimerTask::DeliverWebhook {
+                owner,
+                event_id: delivery.event_id.clone(),
+                at,
+            },
+        );
+    }
+}
+
+/// Re-arm every pending delivery after an upgrade.
+///
+/// A delivery whose request was in flight during the upgrade has no attempt
+/// time left; it is counted as a failed attempt and retried as usual.
+pub fn rearm_all() {
+    let interrupted: Vec<(Principal, String)> = WEBHOOK_DELIVERIES.with(|deliveries| {
+        let mut interrupted = Vec::new();
+        for (owner, deliveries) in deliveries.borrow().iter() {
+            for delivery in deliveries {
+                let pending = delivery.status == DeliveryStatus::Pending;
+                if pending && delivery.next_attempt_at.is_none() {
+                    interrupted.push((*owner, delivery.event_id.clone()));
+                }
+                arm(*owner, delivery);
+            }
+        }
+        interrupted
+    });
+
+    let now = ic_cdk::api::time();
+    for (owner, event_id) in interrupted {
+        let outcome = Err("Interrupted by a canister upgrade".to_string());
+        if let Some(delivery) = record_attempt(owner, &event_id, outcome, now) {
+            arm(owner, &delivery);
+        }
+    }
+}
+
+/// Attempt a delivery that was due at `at`, scheduling a retry on failure.
+pub async fn deliver(owner: Principal, event_id: &str, at: u64) {
+    let secret = WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow()
+            .get(&owner)
+            .map(|webhook| webhook.secret.clone())
+    });
+
+    // Clear `next_attempt_at` while the request is in flight so a stale
+    // timer cannot start a second attempt.
+    let prepared = WEBHOOK_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        let delivery = deliveries
+            .get_mut(&owner)?
+            .iter_mut()
+            .find(|delivery| delivery.event_id == event_id)?;
+        if delivery.status != DeliveryStatus::Pending || delivery.next_attempt_at != Some(at) {
+            return None;
+        }
+        delivery.next_attempt_at = None;
+        Some(delivery.clone())
+    });
+
+    let (Some(secret), Some(delivery)) = (secret, prepared) else {
+        return;
+    };
+
+    let outcome = post(&delivery, &secret).await;
+    if let Some(delivery) = record_attempt(owner, event_id, outcome, ic_cdk::api::time()) {
+        arm(owner, &delivery);
+    }
+}
+
+/// Log an attempt made at `now` and schedule the retry if it failed and the
+/// failure is worth retrying.
+fn record_attempt(
+    owner: Principal,
+    event_id: &str,
+    outcome: Result<u16, String>,
+    now: u64,
+) -> Option<WebhookDelivery> {
+    WEBHOOK_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        let delivery = deliveries
+            .get_mut(&owner)?
+            .iter_mut()
+            .find(|delivery| delivery.event_id == event_id)?;
+
+        let (status_code, error, retryable) = match outcome {
+            Ok(status) if (200..300).contains(&status) => (Some(status), None, false),
+            Ok(status) => (
+                Some(status),
+                Some(format!("Endpoint responded with status {}", status)),
+                status == 408 || status == 429 || status >= 500,
+            ),
+            Err(err) => (None, Some(err), true),
+        };
+
+        delivery.attempts.push(WebhookAttempt {
+            at: now,
+            status_code,
+            error: error.clone(),
+        });
+
+        if error.is_none() {
+            delivery.status = DeliveryStatus::Delivered;
+        } else if !retryable || delivery.attempts.len() >= MAX_ATTEMPTS {
+            delivery.status = DeliveryStatus::Failed;
+        } else {
+            let backoff = RETRY_BASE_SECONDS << (delivery.attempts.len() - 1);
+            delivery.next_attempt_at = Some(now + backoff * NANOS_PER_SECOND);
+        }
+        Some(delivery.clone())
+    })
+}
+
+/// POST an event and return the response status code.
+async fn post(delivery: &WebhookDelivery, secret: &str) -> Result<u16, String> {
+    // Signed over the event creation time so every attempt, and every
+    // replica making the outcall, sends identical bytes.
+    let timestamp = (delivery.created_at / NANOS_PER_SECOND).to_string();
+    let signature = sign(secret, &timestamp, &delivery.payload);
+
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("User-Agent", "icpay-agent-marketplace"),
+        ("Idempotency-Key", delivery.event_id.as_str()),
+        ("X-Marketplace-Event", delivery.event_type.name()),
+        ("X-Marketplace-Timestamp", timestamp.as_str()),
+        ("X-Marketplace-Signature", signature.as_str()),
+    ]
+    .into_iter()
+    .map(|(name, value)| HttpHeader {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+    .collect();
+
+    let request = CanisterHttpRequestArgument {
+        url: delivery.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(delivery.payload.as_bytes().to_vec()),
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_METHOD.to_string(),
+            Vec::new(),
+        )),
+    };
+
+    let cycles = outcall_cycles(&request);
+    let (response,) = http_request(request, cycles)
+        .await
+        .map_err(|(code, message)| format!("HTTP outcall failed ({:?}): {}", code, message))?;
+
+    let status = response.status;
+    u16::try_from(&status.0).map_err(|_| format!("Invalid response status {}", status))
+}
+
+/// `v1=` followed by the hex HMAC-SHA256 of `"{timestamp}.{payload}"`.
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let message = format!("{}.{}", timestamp, payload);
+    format!(
+        "v1={}",
+        hex::encode(hmac_sha256(secret.as_bytes(), message.as_bytes()))
+    )
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_key: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+    let outer_key: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+
+    let inner = Sha256::new()
+        .chain_update(&inner_key)
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(&outer_key)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Cycles to attach to an outcall, following the published pricing formula.
+fn outcall_cycles(request: &CanisterHttpRequestArgument) -> u128 {
+    let request_bytes = request.url.len()
+        + request
+            .headers
+            .iter()
+            .map(|header| header.name.len() + header.value.len())
+            .sum::<usize>()
+        + request.body.as_ref().map_or(0, Vec::len)
+        + TRANSFORM_METHOD.len();
+    let response_bytes = request.max_response_bytes.unwrap_or(MAX_RESPONSE_BYTES);
+
+    (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE
+        + 400 * SUBNET_SIZE * request_bytes as u128
+        + 800 * SUBNET_SIZE * response_bytes as u128
+}
+
+/// Keep only the status of a webhook response so replicas reach consensus.
+pub fn transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
@@ -174,6 +174,7 @@ export default function CsvAnalyzerAgent() {
             primaryMetric: primaryMetricToUse.trim() || undefined,
             segmentColumn: segmentColumnToUse.trim() || undefined,
             includeVisuals: includeVisualsToUse,
+            jobId,
           });
 
           console.log("CSV analysis completed successfully");
@@ -85,11 +85,12 @@ export default function TextSummarizerAgent() {
 
     await requestQuote({
       request: quoteDescription,
-      execute: async () => {
+      execute: async (jobId) => {
         const summaryText = await summarizeText({
           text: text.trim(),
           tone: selectedTone,
           includeQuotes,
+          jobId,
         });
         return {
           summary: summaryText,
@@ -0,0 +1,27 @@
+import { Ed25519KeyIdentity } from "@dfinity/identity";
+import { canisterId, createActor } from "../../../declarations/backend";
+
+const IDENTITY_STORAGE_KEY = "icpay-agent-identity";
+
+/**
+ * The backend rejects anonymous callers, so each browser signs its calls
+ * with a session key kept in local storage. Jobs and quotes stay tied to it
+ * across reloads.
+ */
+const loadIdentity = (): Ed25519KeyIdentity => {
+  const stored = localStorage.getItem(IDENTITY_STORAGE_KEY);
+  if (stored) {
+    try {
+      return Ed25519KeyIdentity.fromJSON(stored);
+    } catch {
+      // Fall through and replace an unreadable key
+    }
+  }
+  const identity = Ed25519KeyIdentity.generate();
+  localStorage.setItem(IDENTITY_STORAGE_KEY, JSON.stringify(identity.toJSON()));
+  return identity;
+};
+
+export const backend = createActor(canisterId, {
+  agentOptions: { identity: loadIdentity() },
+});
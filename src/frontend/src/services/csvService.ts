@@ -1,4 +1,4 @@
-import { backend } from "../../../declarations/backend";
+import { backend } from "./backend";
 
 export interface AnalyzeCsvParams {
   file: File;
@@ -6,6 +6,7 @@ export interface AnalyzeCsvParams {
   primaryMetric?: string;
   segmentColumn?: string;
   includeVisuals: boolean;
+  jobId?: string;
 }
 
 // Maximum file size: 150 MB
@@ -17,6 +18,7 @@ export const analyzeCsv = async ({
   primaryMetric,
   segmentColumn,
   includeVisuals,
+  jobId,
 }: AnalyzeCsvParams): Promise<string> => {
   console.log("Starting CSV analysis...", {
     fileName: file.name,
@@ -52,13 +54,18 @@ export const analyzeCsv = async ({
     const segmentColumnOpt: [] | [string] = segmentColumn && segmentColumn.trim() 
       ? [segmentColumn.trim()] 
       : [];
+    // Passing the job id runs the prompt version the job was quoted for
+    const jobIdOpt: [] | [string] = jobId ? [jobId] : [];
+    const idempotencyKeyOpt: [] | [string] = jobId ? [`analyze_csv:${jobId}`] : [];
 
     const result = await backend.analyze_csv(
       csvBytes,
       preset,
       primaryMetricOpt,
       segmentColumnOpt,
-      includeVisuals
+      includeVisuals,
+      jobIdOpt,
+      idempotencyKeyOpt
     );
 
     console.log("Backend analyze_csv result:", result);
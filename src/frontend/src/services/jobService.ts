@@ -1,4 +1,4 @@
-import { backend } from "../../../declarations/backend";
+import { backend } from "./backend";
 import { JobResult } from "@/types/payment";
 
 /**
@@ -8,7 +8,8 @@ import { JobResult } from "@/types/payment";
  * @returns Payment request information
  */
 export const initiatePayment = async (jobId: string) => {
-  const result = await backend.initiate_payment(jobId);
+  // Keyed by job so a retried request returns the same payment request
+  const result = await backend.initiate_payment(jobId, [`initiate_payment:${jobId}`]);
   if ('Ok' in result) {
     return result.Ok;
   } else {
@@ -38,7 +39,8 @@ export const completePayment = async (
  * @returns The job result containing the output
  */
 export const executeJob = async (jobId: string): Promise<JobResult> => {
-  const result = await backend.execute_job(jobId);
+  // Keyed by job so a retry after a dropped response returns the same result
+  const result = await backend.execute_job(jobId, [`execute_job:${jobId}`]);
   if ('Ok' in result) {
     return result.Ok;
   } else {
@@ -1,4 +1,4 @@
-import { backend } from "../../../declarations/backend";
+import { backend } from "./backend";
 import { CompressionStats } from "@/types/pdf";
 
 export interface PdfCompressionResponse {
@@ -22,7 +22,7 @@ export const compressPdf = async (
   const arrayBuffer = await file.arrayBuffer();
   const pdfBytes = new Uint8Array(arrayBuffer);
 
-  const result = await backend.compress_pdf(pdfBytes, quality);
+  const result = await backend.compress_pdf(pdfBytes, quality, []);
   if ("Ok" in result) {
     const compressed =
       result.Ok instanceof Uint8Array ? result.Ok : new Uint8Array(result.Ok);
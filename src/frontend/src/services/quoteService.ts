@@ -1,8 +1,8 @@
-import { backend } from "../../../declarations/backend";
+import { backend } from "./backend";
 import { Quote } from "@/types/quote";
 
 export const getQuote = async (request: string): Promise<Quote> => {
-  const result = await backend.get_quote(request);
+  const result = await backend.get_quote(request, []);
   if ('Ok' in result) {
     return result.Ok;
   } else {
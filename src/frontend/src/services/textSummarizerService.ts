@@ -1,9 +1,10 @@
-import { backend } from "../../../declarations/backend";
+import { backend } from "./backend";
 
 export interface SummarizeTextParams {
   text: string;
   tone: string;
   includeQuotes: boolean;
+  jobId?: string;
 }
 
 export const MAX_TEXT_LENGTH = 50_000; // 50,000 characters max
@@ -12,6 +13,7 @@ export const summarizeText = async ({
   text,
   tone,
   includeQuotes,
+  jobId,
 }: SummarizeTextParams): Promise<string> => {
   if (!text.trim()) {
     throw new Error("Text cannot be empty");
@@ -23,7 +25,16 @@ export const summarizeText = async ({
     );
   }
 
-  const result = await backend.summarize_text(text, tone, includeQuotes);
+  // Passing the job id runs the prompt version the job was quoted for
+  const jobIdOpt: [] | [string] = jobId ? [jobId] : [];
+  const idempotencyKeyOpt: [] | [string] = jobId ? [`summarize_text:${jobId}`] : [];
+  const result = await backend.summarize_text(
+    text,
+    tone,
+    includeQuotes,
+    jobIdOpt,
+    idempotencyKeyOpt
+  );
   if ("Ok" in result) {
     return result.Ok;
   }
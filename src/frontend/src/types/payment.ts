@@ -10,6 +10,10 @@ export interface Quote {
   price: number;
   currency: string;
   job_id: string;
+  agent_id: string;
+  agent_version: string;
+  prompt_hash: [] | [string];
+  expires_at: bigint;
 }
 
 export interface PaymentResult {
@@ -2,4 +2,8 @@ export interface Quote {
   price: number;
   currency: string;
   job_id: string;
+  agent_id: string;
+  agent_version: string;
+  prompt_hash: [] | [string];
+  expires_at: bigint;
 }
\ No newline at end of file